@@ -0,0 +1 @@
+pub mod lua51;
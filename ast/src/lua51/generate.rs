@@ -0,0 +1,852 @@
+use super::{BinOp, Block, Call, Expr, Field, Function, Stat, UnOp};
+
+/// Keys every generated table has, assignments never add fields so
+/// traversals stay valid and lengths stay fixed
+const FIELDS: [&str; 3] = ["a", "b", "c"];
+/// Items every generated table has at least
+const ITEMS: usize = 3;
+const WORDS: [&str; 7] = ["", "x", "lua", "Hello", "a b", "42", "\n"];
+/// Strings assigned back to variables are cut to this, so loops can not
+/// grow them exponentially
+const MAX_STRING: f64 = 40.0;
+
+/** Config - Shape of the programs a Generator makes */
+#[derive(Debug, Clone)]
+pub struct Config {
+	/// statements in a block at most
+	pub block_len: usize,
+	/// nesting of blocks and functions at most
+	pub depth: usize,
+	/// nesting of expressions at most
+	pub expr_depth: usize,
+	/// iterations of a loop at most
+	pub loop_len: usize,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			block_len: 5,
+			depth: 4,
+			expr_depth: 3,
+			loop_len: 4,
+		}
+	}
+}
+
+/// xorshift64*, small and the same on every platform
+struct Rng(u64);
+
+impl Rng {
+	/// Seeds through splitmix64, so close seeds give unrelated programs
+	fn new(seed: u64) -> Self {
+		let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		// the state must not be zero
+		Self((z ^ (z >> 31)).max(1))
+	}
+
+	fn next(&mut self) -> u64 {
+		self.0 ^= self.0 >> 12;
+		self.0 ^= self.0 << 25;
+		self.0 ^= self.0 >> 27;
+		self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+	}
+
+	/// Uniform in 0..n
+	fn below(&mut self, n: usize) -> usize {
+		(self.next() % n as u64) as usize
+	}
+
+	fn chance(&mut self, percent: usize) -> bool {
+		self.below(100) < percent
+	}
+
+	fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+		items[self.below(items.len())]
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+	Number,
+	String,
+	Boolean,
+	/// a table with FIELDS and ITEMS or more items, all holding the type
+	/// given by whether it is a string table
+	Table {
+		strings: bool,
+	},
+	/// by index in the signatures
+	Function(usize),
+}
+
+impl Type {
+	const VALUES: [Type; 5] = [
+		Type::Number,
+		Type::String,
+		Type::Boolean,
+		Type::Table { strings: false },
+		Type::Table { strings: true },
+	];
+
+	fn element(self) -> Type {
+		match self {
+			Type::Table { strings: true } => Type::String,
+			_ => Type::Number,
+		}
+	}
+}
+
+struct Signature {
+	params: Vec<Type>,
+	vararg: bool,
+	ret: Type,
+}
+
+struct Var {
+	name: String,
+	ty: Type,
+	global: bool,
+	/// loop counters and control variables, never assigned
+	fixed: bool,
+}
+
+/// State of the function being generated
+struct Frame {
+	/// whether its varargs are numbers the generator passed
+	vararg: bool,
+	/// loops around the current statement, inside the function
+	loops: usize,
+}
+
+fn name(name: &str) -> Expr {
+	Expr::Global(name.to_string())
+}
+
+fn string(s: &str) -> Expr {
+	Expr::String(s.to_string())
+}
+
+fn number(n: f64) -> Expr {
+	Expr::Number(n)
+}
+
+fn call(func: Expr, args: Vec<Expr>) -> Expr {
+	Expr::Call(Box::new(Call {
+		func,
+		method: None,
+		args,
+	}))
+}
+
+fn library(lib: &str, func: &str, args: Vec<Expr>) -> Expr {
+	call(Expr::index(name(lib), string(func)), args)
+}
+
+fn method(object: Expr, method: &str, args: Vec<Expr>) -> Expr {
+	Expr::Call(Box::new(Call {
+		func: object,
+		method: Some(method.to_string()),
+		args,
+	}))
+}
+
+fn call_stat(expr: Expr) -> Stat {
+	match expr {
+		Expr::Call(call) => Stat::Call(*call),
+		_ => unreachable!(),
+	}
+}
+
+/**
+ * Generator - Random Lua 5.1 programs from a seed, for fuzzing the
+ * toolchain. Programs always terminate: loops have constant bounds or
+ * counters, and functions only call those defined before them. Values are
+ * typed so they rarely raise errors, and traversals with `pairs` only count
+ * so output does not depend on table order
+ */
+pub struct Generator {
+	rng: Rng,
+	config: Config,
+	scopes: Vec<Vec<Var>>,
+	frames: Vec<Frame>,
+	signatures: Vec<Signature>,
+	names: usize,
+	depth: usize,
+}
+
+impl Generator {
+	pub fn new(seed: u64, config: Config) -> Self {
+		Self {
+			rng: Rng::new(seed),
+			config,
+			scopes: vec![],
+			frames: vec![],
+			signatures: vec![],
+			names: 0,
+			depth: 0,
+		}
+	}
+
+	/// A main chunk, returning some of its variables
+	pub fn chunk(&mut self) -> Function {
+		self.scopes.push(vec![]);
+		self.frames.push(Frame {
+			vararg: false,
+			loops: 0,
+		});
+		let mut body = vec![];
+		for _ in 0..self.config.block_len * 2 {
+			body.extend(self.stat().into_iter().map(Into::into));
+		}
+		let values = (0..self.rng.below(3))
+			.map(|_| {
+				let ty = self.rng.pick(&Type::VALUES);
+				self.expr(ty, 0)
+			})
+			.collect();
+		body.push(Stat::Return(values).into());
+		self.frames.pop();
+		self.scopes.pop();
+
+		Function {
+			vararg: true,
+			body,
+			..Function::default()
+		}
+	}
+
+	fn fresh(&mut self, prefix: &str) -> String {
+		self.names += 1;
+		format!("{prefix}{}", self.names)
+	}
+
+	fn declare(&mut self, name: &str, ty: Type, fixed: bool) {
+		self.scopes.last_mut().unwrap().push(Var {
+			name: name.to_string(),
+			ty,
+			global: false,
+			fixed,
+		});
+	}
+
+	/// A variable in scope passing `filter`, with its type
+	fn choose(&mut self, filter: impl Fn(&Var, &[Signature]) -> bool) -> Option<(Expr, Type)> {
+		let vars = self
+			.scopes
+			.iter()
+			.flatten()
+			.filter(|var| filter(var, &self.signatures))
+			.collect::<Vec<_>>();
+		if vars.is_empty() {
+			return None;
+		}
+		let var = vars[self.rng.below(vars.len())];
+		let expr = match var.global {
+			true => name(&var.name),
+			false => Expr::Name(var.name.clone()),
+		};
+		Some((expr, var.ty))
+	}
+
+	/// A variable of type `ty`, if any is in scope
+	fn var(&mut self, ty: Type) -> Option<Expr> {
+		self.choose(|var, _| var.ty == ty).map(|(var, _)| var)
+	}
+
+	fn nested<T>(&mut self, scope: Vec<Var>, f: impl FnOnce(&mut Self) -> T) -> T {
+		self.scopes.push(scope);
+		self.depth += 1;
+		let result = f(self);
+		self.depth -= 1;
+		self.scopes.pop();
+		result
+	}
+
+	fn block(&mut self) -> Block {
+		let mut block = vec![];
+		for _ in 0..=self.rng.below(self.config.block_len) {
+			block.extend(self.stat().into_iter().map(Into::into));
+		}
+		block
+	}
+
+	fn stat(&mut self) -> Vec<Stat> {
+		let nested = self.depth < self.config.depth;
+		let choice = match nested {
+			true => self.rng.below(15),
+			false => self.rng.below(6),
+		};
+		match choice {
+			0 | 1 => self.local(),
+			2 => self.assign(),
+			3 | 4 => self.print(),
+			5 => self.swap(),
+			6 => self.function(),
+			7 => self.if_(),
+			8 => self.numeric_for(),
+			9 => self.while_(),
+			10 => self.repeat(),
+			11 => self.ipairs(),
+			12 => self.pairs(),
+			13 => self.protected(),
+			_ => self.break_or_do(),
+		}
+	}
+
+	fn local(&mut self) -> Vec<Stat> {
+		let ty = self.rng.pick(&Type::VALUES);
+		let value = self.expr(ty, 0);
+		// globals only at the top of the chunk, where they always run
+		if self.depth == 0 && self.rng.chance(30) {
+			let global = self.fresh("g");
+			self.scopes[0].push(Var {
+				name: global.clone(),
+				ty,
+				global: true,
+				fixed: false,
+			});
+			return vec![Stat::Assign(vec![name(&global)], vec![value])];
+		}
+		let local = self.fresh("v");
+		self.declare(&local, ty, false);
+		vec![Stat::Local(vec![local], vec![value])]
+	}
+
+	/// `value`, cut short if it is a string
+	fn bounded(&mut self, ty: Type, value: Expr) -> Expr {
+		match ty {
+			Type::String => library(
+				"string",
+				"sub",
+				vec![value, number(1.0), number(MAX_STRING)],
+			),
+			_ => value,
+		}
+	}
+
+	fn assign(&mut self) -> Vec<Stat> {
+		if self.rng.chance(40) {
+			if let Some((target, ty)) = self.choose(|var, _| matches!(var.ty, Type::Table { .. })) {
+				let ty = ty.element();
+				let key = match self.rng.chance(50) {
+					true => string(self.rng.pick(&FIELDS)),
+					false => number((self.rng.below(ITEMS) + 1) as f64),
+				};
+				let value = self.expr(ty, 0);
+				let value = self.bounded(ty, value);
+				return vec![Stat::Assign(vec![Expr::index(target, key)], vec![value])];
+			}
+		}
+
+		let assignable =
+			|var: &Var, _: &[Signature]| !var.fixed && !matches!(var.ty, Type::Function(_));
+		let Some((target, ty)) = self.choose(assignable) else {
+			return self.local();
+		};
+		let value = self.expr(ty, 0);
+		let value = self.bounded(ty, value);
+		vec![Stat::Assign(vec![target], vec![value])]
+	}
+
+	/// `a, b = b, a`
+	fn swap(&mut self) -> Vec<Stat> {
+		let ty = self.rng.pick(&Type::VALUES);
+		let assignable = |var: &Var, _: &[Signature]| var.ty == ty && !var.fixed;
+		let (Some((a, _)), Some((b, _))) = (self.choose(assignable), self.choose(assignable))
+		else {
+			return self.print();
+		};
+		vec![Stat::Assign(vec![a.clone(), b.clone()], vec![b, a])]
+	}
+
+	fn print(&mut self) -> Vec<Stat> {
+		let args = (0..=self.rng.below(3))
+			.map(|_| {
+				let ty = self.rng.pick(&Type::VALUES);
+				match ty {
+					// tables print their address
+					Type::Table { .. } => Expr::unary(UnOp::Len, self.expr(ty, 0)),
+					_ => self.expr(ty, 0),
+				}
+			})
+			.collect();
+		vec![call_stat(call(name("print"), args))]
+	}
+
+	/// A local function, called later by its name
+	fn function(&mut self) -> Vec<Stat> {
+		let params = (0..self.rng.below(4))
+			.map(|_| self.rng.pick(&Type::VALUES))
+			.collect::<Vec<_>>();
+		let vararg = self.rng.chance(30);
+		let ret = self.rng.pick(&Type::VALUES);
+
+		let names = params.iter().map(|_| self.fresh("p")).collect::<Vec<_>>();
+		let scope = names
+			.iter()
+			.zip(&params)
+			.map(|(name, ty)| Var {
+				name: name.clone(),
+				ty: *ty,
+				global: false,
+				fixed: false,
+			})
+			.collect();
+		self.frames.push(Frame { vararg, loops: 0 });
+		let body = self.nested(scope, |gen| {
+			let mut body = gen.block();
+			body.push(gen.ret(ret).into());
+			body
+		});
+		self.frames.pop();
+
+		self.signatures.push(Signature {
+			params,
+			vararg,
+			ret,
+		});
+		let ty = Type::Function(self.signatures.len() - 1);
+		let function = Function {
+			params: names,
+			vararg,
+			body,
+			..Function::default()
+		};
+		let local = self.fresh("f");
+		let stat = match self.rng.chance(50) {
+			true => Stat::LocalFunction(local.clone(), function),
+			false => Stat::Local(
+				vec![local.clone()],
+				vec![Expr::Function(Box::new(function))],
+			),
+		};
+		// declared after its body, so it never calls itself
+		self.declare(&local, ty, true);
+		vec![stat]
+	}
+
+	/// `return` of a value of type `ty`, sometimes as a tail call
+	fn ret(&mut self, ty: Type) -> Stat {
+		if self.rng.chance(30) {
+			if let Some(call) = self.call(ty) {
+				return Stat::Return(vec![call]);
+			}
+		}
+		Stat::Return(vec![self.expr(ty, 0)])
+	}
+
+	fn if_(&mut self) -> Vec<Stat> {
+		let cond = self.expr(Type::Boolean, 0);
+		let then = self.nested(vec![], Self::block);
+		let otherwise = match self.rng.below(3) {
+			0 => vec![],
+			1 => self.nested(vec![], Self::block),
+			// elseif
+			_ => self.if_().into_iter().map(Into::into).collect(),
+		};
+		vec![Stat::If(cond, then, otherwise)]
+	}
+
+	/// Body of a loop, declaring `scope`
+	fn loop_body(&mut self, scope: Vec<Var>, mut prefix: Block) -> Block {
+		self.frames.last_mut().unwrap().loops += 1;
+		let body = self.nested(scope, |gen| {
+			prefix.extend(gen.block());
+			prefix
+		});
+		self.frames.last_mut().unwrap().loops -= 1;
+		body
+	}
+
+	fn fixed(name: &str, ty: Type) -> Var {
+		Var {
+			name: name.to_string(),
+			ty,
+			global: false,
+			fixed: true,
+		}
+	}
+
+	fn numeric_for(&mut self) -> Vec<Stat> {
+		let count = self.rng.below(self.config.loop_len + 1) as f64;
+		let start = self.rng.below(5) as f64 - 2.0;
+		let step = self.rng.pick(&[1.0, 2.0, -1.0, 0.5]);
+		let limit = start + step * (count - 1.0);
+		let var = self.fresh("i");
+		let scope = vec![Self::fixed(&var, Type::Number)];
+		let body = self.loop_body(scope, vec![]);
+		let step = (step != 1.0).then(|| number(step));
+		vec![Stat::NumericFor(
+			var,
+			number(start),
+			number(limit),
+			step,
+			body,
+		)]
+	}
+
+	/// A local counter starting at 0, and `counter = counter + 1`
+	fn counter(&mut self) -> (String, Stat, Stat) {
+		let counter = self.fresh("n");
+		let declare = Stat::Local(vec![counter.clone()], vec![number(0.0)]);
+		let increment = Stat::Assign(
+			vec![Expr::Name(counter.clone())],
+			vec![Expr::binary(
+				BinOp::Add,
+				Expr::Name(counter.clone()),
+				number(1.0),
+			)],
+		);
+		self.declare(&counter, Type::Number, true);
+		(counter, declare, increment)
+	}
+
+	fn bound(&mut self, counter: &str, op: BinOp) -> Expr {
+		let limit = self.rng.below(self.config.loop_len + 1) as f64;
+		Expr::binary(op, Expr::Name(counter.to_string()), number(limit))
+	}
+
+	fn while_(&mut self) -> Vec<Stat> {
+		let (counter, declare, increment) = self.counter();
+		let cond = self.bound(&counter, BinOp::Lt);
+		let body = self.loop_body(vec![], vec![increment.into()]);
+		vec![declare, Stat::While(cond, body)]
+	}
+
+	fn repeat(&mut self) -> Vec<Stat> {
+		let (counter, declare, increment) = self.counter();
+		let body = self.loop_body(vec![], vec![increment.into()]);
+		let mut cond = self.bound(&counter, BinOp::Ge);
+		if self.rng.chance(30) {
+			let other = self.expr(Type::Boolean, 1);
+			cond = Expr::binary(BinOp::Or, cond, other);
+		}
+		vec![declare, Stat::Repeat(body, cond)]
+	}
+
+	fn table(&mut self) -> (Expr, Type) {
+		let strings = self.rng.chance(50);
+		let ty = Type::Table { strings };
+		let table = self.var(ty).unwrap_or_else(|| self.constructor(ty));
+		(table, ty)
+	}
+
+	fn ipairs(&mut self) -> Vec<Stat> {
+		let (table, ty) = self.table();
+		let (key, value) = (self.fresh("k"), self.fresh("e"));
+		let scope = vec![
+			Self::fixed(&key, Type::Number),
+			Self::fixed(&value, ty.element()),
+		];
+		let body = self.loop_body(scope, vec![]);
+		let iterator = call(name("ipairs"), vec![table]);
+		vec![Stat::GenericFor(vec![key, value], vec![iterator], body)]
+	}
+
+	/// Counts the fields of a table, the order `pairs` gives them in
+	/// differs between implementations
+	fn pairs(&mut self) -> Vec<Stat> {
+		let (table, _) = self.table();
+		let (counter, declare, increment) = self.counter();
+		let iterator = call(name("pairs"), vec![table]);
+		let names = vec![self.fresh("k"), self.fresh("e")];
+		let count = vec![increment.into()];
+		let print = call_stat(call(name("print"), vec![Expr::Name(counter)]));
+		vec![
+			declare,
+			Stat::GenericFor(names, vec![iterator], count),
+			print,
+		]
+	}
+
+	/// `print(pcall(...))` of an error or a function in scope
+	fn protected(&mut self) -> Vec<Stat> {
+		let function = match self.rng.chance(30) {
+			true => None,
+			false => self.choose(|var, _| matches!(var.ty, Type::Function(_))),
+		};
+		let args = match function {
+			Some((function, Type::Function(sig))) => {
+				let mut args = vec![function];
+				args.extend(self.args(sig));
+				args
+			}
+			_ => {
+				let msg = self.expr(Type::String, 1);
+				vec![name("error"), msg]
+			}
+		};
+		vec![call_stat(call(
+			name("print"),
+			vec![call(name("pcall"), args)],
+		))]
+	}
+
+	fn break_or_do(&mut self) -> Vec<Stat> {
+		match self.frames.last().unwrap().loops {
+			0 => vec![Stat::Do(self.nested(vec![], Self::block))],
+			_ => {
+				let cond = self.expr(Type::Boolean, 0);
+				vec![Stat::If(cond, vec![Stat::Break.into()], vec![])]
+			}
+		}
+	}
+
+	fn args(&mut self, sig: usize) -> Vec<Expr> {
+		let params = self.signatures[sig].params.clone();
+		let mut args = params
+			.into_iter()
+			.map(|ty| self.expr(ty, 1))
+			.collect::<Vec<_>>();
+		if self.signatures[sig].vararg {
+			for _ in 0..self.rng.below(3) {
+				args.push(self.expr(Type::Number, 1));
+			}
+		}
+		args
+	}
+
+	/// A call of a function in scope returning `ty`
+	fn call(&mut self, ty: Type) -> Option<Expr> {
+		let (func, Type::Function(sig)) = self.choose(|var, signatures| match var.ty {
+			Type::Function(sig) => signatures[sig].ret == ty,
+			_ => false,
+		})?
+		else {
+			unreachable!()
+		};
+		let args = self.args(sig);
+		Some(call(func, args))
+	}
+
+	/// Table of type `ty`, its values are leaves so constructing one never
+	/// recurses
+	fn constructor(&mut self, ty: Type) -> Expr {
+		let element = ty.element();
+		let mut fields = vec![];
+		for _ in 0..ITEMS + self.rng.below(3) {
+			fields.push(Field::Item(self.expr(element, self.config.expr_depth)));
+		}
+		for field in FIELDS {
+			let value = self.expr(element, self.config.expr_depth);
+			fields.insert(
+				self.rng.below(fields.len() + 1),
+				Field::Pair(string(field), value),
+			);
+		}
+		Expr::Table(fields)
+	}
+
+	fn literal(&mut self, ty: Type) -> Expr {
+		match ty {
+			Type::Number => {
+				let n = self.rng.below(20) as f64 - 5.0;
+				match self.rng.chance(20) {
+					true => number(n / 4.0),
+					false => number(n),
+				}
+			}
+			Type::String => string(self.rng.pick(&WORDS)),
+			Type::Boolean => Expr::Bool(self.rng.chance(50)),
+			Type::Table { .. } => self.constructor(ty),
+			Type::Function(_) => unreachable!(),
+		}
+	}
+
+	/// An expression giving a value of type `ty`
+	fn expr(&mut self, ty: Type, depth: usize) -> Expr {
+		if depth >= self.config.expr_depth || self.rng.chance(30) {
+			return match self.rng.chance(50) {
+				true => self.var(ty).unwrap_or_else(|| self.literal(ty)),
+				false => self.literal(ty),
+			};
+		}
+		if self.rng.chance(10) {
+			if let Some(call) = self.call(ty) {
+				return call;
+			}
+		}
+		let depth = depth + 1;
+		match ty {
+			Type::Number => self.number(depth),
+			Type::String => self.string(depth),
+			Type::Boolean => self.boolean(depth),
+			_ => self.var(ty).unwrap_or_else(|| self.literal(ty)),
+		}
+	}
+
+	fn field(&mut self, ty: Type, depth: usize) -> Expr {
+		let table = Type::Table {
+			strings: ty == Type::String,
+		};
+		let table = self.expr(table, depth);
+		let key = match self.rng.chance(50) {
+			true => string(self.rng.pick(&FIELDS)),
+			false => number((self.rng.below(ITEMS) + 1) as f64),
+		};
+		Expr::index(table, key)
+	}
+
+	fn number(&mut self, depth: usize) -> Expr {
+		let n = Type::Number;
+		match self.rng.below(12) {
+			0..=2 => {
+				let op = self.rng.pick(&[BinOp::Add, BinOp::Sub, BinOp::Mul]);
+				Expr::binary(op, self.expr(n, depth), self.expr(n, depth))
+			}
+			3 => {
+				// never by zero, NaN prints differently between platforms
+				let op = self.rng.pick(&[BinOp::Div, BinOp::Mod]);
+				let divisor = self.rng.pick(&[2.0, 3.0, -4.0, 0.5]);
+				Expr::binary(op, self.expr(n, depth), number(divisor))
+			}
+			4 => {
+				let exponent = self.rng.below(3) as f64;
+				Expr::binary(BinOp::Pow, self.expr(n, depth), number(exponent))
+			}
+			5 => Expr::unary(UnOp::Neg, self.expr(n, depth)),
+			6 => {
+				let (table, _) = self.table();
+				Expr::unary(UnOp::Len, table)
+			}
+			7 => match self.rng.chance(50) {
+				true => Expr::unary(UnOp::Len, self.expr(Type::String, depth)),
+				false => method(self.expr(Type::String, depth), "len", vec![]),
+			},
+			8 => self.field(n, depth),
+			9 => {
+				let func = self.rng.pick(&["floor", "abs", "max", "min"]);
+				let mut args = vec![self.expr(n, depth)];
+				if matches!(func, "max" | "min") {
+					args.push(self.expr(n, depth));
+				}
+				library("math", func, args)
+			}
+			10 if self.frames.last().unwrap().vararg => match self.rng.chance(50) {
+				true => call(name("select"), vec![string("#"), Expr::Vararg]),
+				false => Expr::paren(Expr::binary(BinOp::Or, Expr::Vararg, number(0.0))),
+			},
+			_ => {
+				let cond = self.expr(Type::Boolean, depth);
+				let (a, b) = (self.expr(n, depth), self.expr(n, depth));
+				Expr::binary(BinOp::Or, Expr::binary(BinOp::And, cond, a), b)
+			}
+		}
+	}
+
+	fn string(&mut self, depth: usize) -> Expr {
+		let s = Type::String;
+		match self.rng.below(9) {
+			0..=2 => {
+				let (a, b) = match self.rng.below(3) {
+					0 => (self.expr(s, depth), self.expr(s, depth)),
+					1 => (self.expr(s, depth), self.expr(Type::Number, depth)),
+					_ => (self.expr(Type::Number, depth), self.expr(s, depth)),
+				};
+				Expr::binary(BinOp::Concat, a, b)
+			}
+			3 => {
+				let ty = self.rng.pick(&[Type::Number, Type::Boolean]);
+				call(name("tostring"), vec![self.expr(ty, depth)])
+			}
+			4 => {
+				let start = self.rng.below(5) as f64 - 2.0;
+				let end = self.rng.below(7) as f64 - 2.0;
+				let subject = self.expr(s, depth);
+				match self.rng.chance(50) {
+					true => library("string", "sub", vec![subject, number(start), number(end)]),
+					false => method(subject, "sub", vec![number(start)]),
+				}
+			}
+			5 => {
+				let func = self.rng.pick(&["upper", "lower", "reverse"]);
+				match self.rng.chance(50) {
+					true => library("string", func, vec![self.expr(s, depth)]),
+					false => method(self.expr(s, depth), func, vec![]),
+				}
+			}
+			6 => {
+				let count = number(self.rng.below(4) as f64);
+				method(self.expr(s, depth), "rep", vec![count])
+			}
+			7 => {
+				let value = self.expr(Type::Number, depth);
+				match self.rng.chance(50) {
+					true => library("string", "format", vec![string("%.3f"), value]),
+					false => {
+						let subject = self.expr(s, depth);
+						library("string", "format", vec![string("[%s|%5s]"), subject, value])
+					}
+				}
+			}
+			_ => match self.rng.chance(50) {
+				true => self.field(s, depth),
+				false => {
+					let ty = self.rng.pick(&Type::VALUES);
+					call(name("type"), vec![self.expr(ty, depth)])
+				}
+			},
+		}
+	}
+
+	fn boolean(&mut self, depth: usize) -> Expr {
+		let b = Type::Boolean;
+		match self.rng.below(6) {
+			0 | 1 => {
+				let op = self.rng.pick(&[
+					BinOp::Lt,
+					BinOp::Le,
+					BinOp::Gt,
+					BinOp::Ge,
+					BinOp::Eq,
+					BinOp::Ne,
+				]);
+				let ty = self.rng.pick(&[Type::Number, Type::String]);
+				Expr::binary(op, self.expr(ty, depth), self.expr(ty, depth))
+			}
+			2 => Expr::unary(UnOp::Not, self.expr(b, depth)),
+			3 => {
+				let op = self.rng.pick(&[BinOp::And, BinOp::Or]);
+				Expr::binary(op, self.expr(b, depth), self.expr(b, depth))
+			}
+			4 => {
+				let ty = self.rng.pick(&Type::VALUES);
+				let value = call(name("type"), vec![self.expr(ty, depth)]);
+				Expr::binary(BinOp::Eq, value, string("number"))
+			}
+			_ => {
+				let ty = self.rng.pick(&Type::VALUES);
+				let (a, b) = (self.expr(ty, depth), self.expr(ty, depth));
+				Expr::binary(BinOp::Eq, a, b)
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Config, Generator};
+
+	fn source(seed: u64) -> String {
+		Generator::new(seed, Config::default()).chunk().to_string()
+	}
+
+	#[test]
+	fn test_generate() {
+		assert_eq!(source(1), source(1));
+		assert_ne!(source(1), source(2));
+
+		let sources = (0..50).map(source).collect::<Vec<_>>();
+		for feature in [
+			"function", "while", "repeat", "ipairs", "pairs", "pcall", "...",
+		] {
+			assert!(
+				sources.iter().any(|source| source.contains(feature)),
+				"no program uses {feature}"
+			);
+		}
+	}
+}
@@ -0,0 +1,231 @@
+mod generate;
+mod printer;
+pub use generate::{Config, Generator};
+pub use printer::{Options, Printer};
+
+/**
+ * Span - Half-open range of the source a node was built from: byte
+ * offsets for parsed text, instruction indices for decompiled bytecode
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+	pub start: usize,
+	pub end: usize,
+}
+
+impl Span {
+	pub fn new(start: usize, end: usize) -> Self {
+		Self { start, end }
+	}
+
+	/// Span of the single position `pos`
+	pub fn at(pos: usize) -> Self {
+		Self::new(pos, pos + 1)
+	}
+
+	/// Smallest span covering both
+	pub fn join(self, other: Span) -> Self {
+		Self::new(self.start.min(other.start), self.end.max(other.end))
+	}
+}
+
+/** Spanned - A node with the span it came from */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+	pub node: T,
+	pub span: Span,
+}
+
+impl<T> Spanned<T> {
+	pub fn new(node: T, span: Span) -> Self {
+		Self { node, span }
+	}
+
+	/// Rewrites the node, keeping its span
+	pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Spanned<U> {
+		Spanned::new(f(self.node), self.span)
+	}
+}
+
+impl<T> From<T> for Spanned<T> {
+	fn from(node: T) -> Self {
+		Self::new(node, Span::default())
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+	Or,
+	And,
+	Lt,
+	Le,
+	Gt,
+	Ge,
+	Eq,
+	Ne,
+	Concat,
+	Add,
+	Sub,
+	Mul,
+	Div,
+	Mod,
+	Pow,
+}
+
+impl BinOp {
+	fn precedence(self) -> u8 {
+		match self {
+			Self::Or => 1,
+			Self::And => 2,
+			Self::Lt | Self::Le | Self::Gt | Self::Ge | Self::Eq | Self::Ne => 3,
+			Self::Concat => 4,
+			Self::Add | Self::Sub => 5,
+			Self::Mul | Self::Div | Self::Mod => 6,
+			Self::Pow => 8,
+		}
+	}
+
+	fn right_associative(self) -> bool {
+		matches!(self, Self::Concat | Self::Pow)
+	}
+
+	fn symbol(self) -> &'static str {
+		match self {
+			Self::Or => "or",
+			Self::And => "and",
+			Self::Lt => "<",
+			Self::Le => "<=",
+			Self::Gt => ">",
+			Self::Ge => ">=",
+			Self::Eq => "==",
+			Self::Ne => "~=",
+			Self::Concat => "..",
+			Self::Add => "+",
+			Self::Sub => "-",
+			Self::Mul => "*",
+			Self::Div => "/",
+			Self::Mod => "%",
+			Self::Pow => "^",
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnOp {
+	Neg,
+	Not,
+	Len,
+}
+
+const UNARY_PRECEDENCE: u8 = 7;
+const ATOM_PRECEDENCE: u8 = 9;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+	Nil,
+	Bool(bool),
+	Number(f64),
+	String(String),
+	Vararg,
+	Name(String), // local or upvalue
+	Global(String),
+	Index(Box<Spanned<Expr>>, Box<Spanned<Expr>>),
+	Call(Box<Call>),
+	Function(Box<Function>),
+	Table(Vec<Field>),
+	Binary(BinOp, Box<Spanned<Expr>>, Box<Spanned<Expr>>),
+	Unary(UnOp, Box<Spanned<Expr>>),
+	Paren(Box<Spanned<Expr>>), // truncates calls and varargs to one value
+}
+
+impl Expr {
+	pub fn binary(
+		op: BinOp,
+		left: impl Into<Spanned<Expr>>,
+		right: impl Into<Spanned<Expr>>,
+	) -> Self {
+		Self::Binary(op, Box::new(left.into()), Box::new(right.into()))
+	}
+
+	pub fn unary(op: UnOp, operand: impl Into<Spanned<Expr>>) -> Self {
+		Self::Unary(op, Box::new(operand.into()))
+	}
+
+	pub fn index(object: impl Into<Spanned<Expr>>, key: impl Into<Spanned<Expr>>) -> Self {
+		Self::Index(Box::new(object.into()), Box::new(key.into()))
+	}
+
+	pub fn paren(inner: impl Into<Spanned<Expr>>) -> Self {
+		Self::Paren(Box::new(inner.into()))
+	}
+
+	/// Whether the expression may produce several values
+	pub fn is_multi(&self) -> bool {
+		matches!(self, Self::Call(_) | Self::Vararg)
+	}
+
+	fn precedence(&self) -> u8 {
+		match self {
+			Self::Binary(op, _, _) => op.precedence(),
+			Self::Unary(_, _) => UNARY_PRECEDENCE,
+			// written as 1/0, -1/0 and 0/0
+			Self::Number(n) if !n.is_finite() => BinOp::Div.precedence(),
+			Self::Number(n) if n.is_sign_negative() => UNARY_PRECEDENCE,
+			_ => ATOM_PRECEDENCE,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+	pub func: Expr,
+	pub method: Option<String>,
+	pub args: Vec<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+	Item(Expr),
+	Pair(Expr, Expr),
+}
+
+pub type Block = Vec<Spanned<Stat>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stat {
+	Local(Vec<String>, Vec<Expr>),
+	LocalFunction(String, Function),
+	Assign(Vec<Expr>, Vec<Expr>),
+	Call(Call),
+	Do(Block),
+	If(Expr, Block, Block),
+	While(Expr, Block),
+	Repeat(Block, Expr),
+	NumericFor(String, Expr, Expr, Option<Expr>, Block),
+	GenericFor(Vec<String>, Vec<Expr>, Block),
+	Return(Vec<Expr>),
+	Break,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Function {
+	pub params: Vec<String>,
+	pub vararg: bool,
+	pub body: Block,
+	pub span: Span,
+}
+
+const KEYWORDS: [&str; 21] = [
+	"and", "break", "do", "else", "elseif", "end", "false", "for", "function", "if", "in", "local",
+	"nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+/// Whether `name` can be written as a Lua name
+pub fn is_identifier(name: &str) -> bool {
+	let mut chars = name.chars();
+	chars
+		.next()
+		.is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+		&& !KEYWORDS.contains(&name)
+}
@@ -0,0 +1,538 @@
+use super::{
+	is_identifier, Block, Call, Expr, Field, Function, Spanned, Stat, UnOp, UNARY_PRECEDENCE,
+};
+use std::fmt::Display;
+
+/** Options - How the printer lays out the source */
+#[derive(Debug, Clone)]
+pub struct Options {
+	/// written once per nesting level in front of each line
+	pub indent: String,
+	/// puts everything on one line with as little whitespace as the
+	/// tokens allow
+	pub minify: bool,
+}
+
+impl Default for Options {
+	fn default() -> Self {
+		Self {
+			indent: "\t".to_string(),
+			minify: false,
+		}
+	}
+}
+
+impl Options {
+	/// Indents with `width` spaces instead of tabs
+	pub fn spaces(width: usize) -> Self {
+		Self {
+			indent: " ".repeat(width),
+			..Self::default()
+		}
+	}
+
+	pub fn minified() -> Self {
+		Self {
+			indent: String::new(),
+			minify: true,
+		}
+	}
+}
+
+fn quote(s: &str) -> String {
+	let mut out = String::from("\"");
+	for byte in s.bytes() {
+		match byte {
+			b'"' => out.push_str("\\\""),
+			b'\\' => out.push_str("\\\\"),
+			b'\n' => out.push_str("\\n"),
+			b'\r' => out.push_str("\\r"),
+			b'\t' => out.push_str("\\t"),
+			0x20..=0x7e => out.push(byte as char),
+			_ => out.push_str(&format!("\\{byte:03}")),
+		}
+	}
+	out.push('"');
+	out
+}
+
+fn number(n: f64) -> String {
+	if n.is_nan() {
+		"0/0".to_string()
+	} else if n.is_infinite() {
+		if n > 0.0 { "1/0" } else { "-1/0" }.to_string()
+	} else if n.fract() == 0.0 && n.abs() < 1e15 {
+		if n == 0.0 && n.is_sign_negative() {
+			"-0".to_string()
+		} else {
+			format!("{}", n as i64)
+		}
+	} else {
+		format!("{n}")
+	}
+}
+
+/// Whether two tokens written back to back would lex differently
+fn needs_space(last: char, first: char) -> bool {
+	let word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+	(word(last) && word(first))
+		|| (last.is_ascii_digit() && first == '.')
+		|| (last == '.' && (first == '.' || first.is_ascii_digit()))
+		|| matches!(
+			(last, first),
+			('-', '-') | ('[', '[' | '=') | ('=' | '<' | '>' | '~', '=')
+		)
+}
+
+/// Appends `text` to `out`, separated by a space only where required
+fn glue(out: &mut String, text: &str) {
+	if let (Some(last), Some(first)) = (out.chars().last(), text.chars().next()) {
+		if needs_space(last, first) {
+			out.push(' ');
+		}
+	}
+	out.push_str(text);
+}
+
+/**
+ * Printer - Writes an AST as Lua 5.1 source, one statement per line,
+ * indented as the options say. Only adds the parentheses precedence
+ * requires, the output parses back to the same tree
+ */
+#[derive(Default)]
+pub struct Printer {
+	out: String,
+	depth: usize,
+	options: Options,
+}
+
+impl Printer {
+	pub fn new(options: Options) -> Self {
+		Self {
+			out: String::new(),
+			depth: 0,
+			options,
+		}
+	}
+
+	pub fn finish(self) -> String {
+		self.out
+	}
+
+	fn line(&mut self, text: &str) {
+		if self.options.minify {
+			glue(&mut self.out, text);
+			return;
+		}
+		for _ in 0..self.depth {
+			self.out.push_str(&self.options.indent);
+		}
+		self.out.push_str(text);
+		self.out.push('\n');
+	}
+
+	/// Joins tokens with a space, or with none where it is not needed when
+	/// minifying
+	fn words(&self, words: &[&str]) -> String {
+		if !self.options.minify {
+			return words.join(" ");
+		}
+		let mut out = String::new();
+		for word in words {
+			glue(&mut out, word);
+		}
+		out
+	}
+
+	fn separator(&self) -> &'static str {
+		if self.options.minify {
+			","
+		} else {
+			", "
+		}
+	}
+
+	fn names(&self, names: &[String]) -> String {
+		names.join(self.separator())
+	}
+
+	fn nested(&mut self, block: &Block) {
+		self.depth += 1;
+		self.block(block);
+		self.depth -= 1;
+	}
+
+	pub fn block(&mut self, block: &Block) {
+		for (i, stat) in block.iter().enumerate() {
+			// return and break must end a block
+			if matches!(stat.node, Stat::Return(_) | Stat::Break) && i + 1 != block.len() {
+				self.stat(&Stat::Do(vec![stat.clone()]));
+			} else {
+				self.stat(&stat.node);
+			}
+		}
+	}
+
+	fn stat(&mut self, stat: &Stat) {
+		match stat {
+			Stat::Local(names, values) if values.is_empty() => {
+				let line = self.words(&["local", &self.names(names)]);
+				self.line(&line);
+			}
+			Stat::Local(names, values) => {
+				let values = self.list(values);
+				let line = self.words(&["local", &self.names(names), "=", &values]);
+				self.line(&line);
+			}
+			Stat::LocalFunction(name, function) => {
+				let header = self.words(&["local function", name]);
+				self.function(&header, function)
+			}
+			Stat::Assign(targets, values) => match (&targets[..], &values[..]) {
+				([Expr::Global(name)], [Expr::Function(function)]) if is_identifier(name) => {
+					let header = self.words(&["function", name]);
+					self.function(&header, function)
+				}
+				_ => {
+					let targets = self.list(targets);
+					let values = self.list(values);
+					let line = self.words(&[&targets, "=", &values]);
+					self.line(&line);
+				}
+			},
+			Stat::Call(call) => {
+				let call = self.call(call);
+				// a statement starting with ( would continue the previous one
+				if call.starts_with('(') {
+					self.line(&format!(";{call}"));
+				} else {
+					self.line(&call);
+				}
+			}
+			Stat::Do(body) => {
+				self.line("do");
+				self.nested(body);
+				self.line("end");
+			}
+			Stat::If(cond, then, otherwise) => {
+				let cond = self.expr(cond, 0);
+				let line = self.words(&["if", &cond, "then"]);
+				self.line(&line);
+				self.nested(then);
+				let mut otherwise = otherwise;
+				while let [Spanned {
+					node: Stat::If(cond, then, rest),
+					..
+				}] = &otherwise[..]
+				{
+					let cond = self.expr(cond, 0);
+					let line = self.words(&["elseif", &cond, "then"]);
+					self.line(&line);
+					self.nested(then);
+					otherwise = rest;
+				}
+				if !otherwise.is_empty() {
+					self.line("else");
+					self.nested(otherwise);
+				}
+				self.line("end");
+			}
+			Stat::While(cond, body) => {
+				let cond = self.expr(cond, 0);
+				let line = self.words(&["while", &cond, "do"]);
+				self.line(&line);
+				self.nested(body);
+				self.line("end");
+			}
+			Stat::Repeat(body, cond) => {
+				self.line("repeat");
+				self.nested(body);
+				let cond = self.expr(cond, 0);
+				let line = self.words(&["until", &cond]);
+				self.line(&line);
+			}
+			Stat::NumericFor(name, start, limit, step, body) => {
+				let mut exprs = vec![self.expr(start, 0), self.expr(limit, 0)];
+				if let Some(step) = step {
+					exprs.push(self.expr(step, 0));
+				}
+				let exprs = exprs.join(self.separator());
+				let line = self.words(&["for", name, "=", &exprs, "do"]);
+				self.line(&line);
+				self.nested(body);
+				self.line("end");
+			}
+			Stat::GenericFor(names, exprs, body) => {
+				let exprs = self.list(exprs);
+				let line = self.words(&["for", &self.names(names), "in", &exprs, "do"]);
+				self.line(&line);
+				self.nested(body);
+				self.line("end");
+			}
+			Stat::Return(values) if values.is_empty() => self.line("return"),
+			Stat::Return(values) => {
+				let values = self.list(values);
+				let line = self.words(&["return", &values]);
+				self.line(&line);
+			}
+			Stat::Break => self.line("break"),
+		}
+	}
+
+	/// Writes `header(params)`, the body and `end`
+	fn function(&mut self, header: &str, function: &Function) {
+		let mut params = function.params.clone();
+		if function.vararg {
+			params.push("...".to_string());
+		}
+		self.line(&format!("{header}({})", self.names(&params)));
+		self.nested(&function.body);
+		self.line("end");
+	}
+
+	fn list(&mut self, exprs: &[Expr]) -> String {
+		exprs
+			.iter()
+			.map(|expr| self.expr(expr, 0))
+			.collect::<Vec<_>>()
+			.join(self.separator())
+	}
+
+	fn call(&mut self, call: &Call) -> String {
+		let func = self.prefix(&call.func);
+		let args = self.list(&call.args);
+		match &call.method {
+			Some(method) => format!("{func}:{method}({args})"),
+			None => format!("{func}({args})"),
+		}
+	}
+
+	/// An expression calls and indexing can be applied to
+	fn prefix(&mut self, expr: &Expr) -> String {
+		match expr {
+			Expr::Name(_)
+			| Expr::Global(_)
+			| Expr::Index(_, _)
+			| Expr::Call(_)
+			| Expr::Paren(_) => self.expr(expr, 0),
+			_ => format!("({})", self.expr(expr, 0)),
+		}
+	}
+
+	/// Writes `expr`, parenthesised when it binds looser than `precedence`
+	pub fn expr(&mut self, expr: &Expr, precedence: u8) -> String {
+		let text = match expr {
+			Expr::Nil => "nil".to_string(),
+			Expr::Bool(b) => b.to_string(),
+			Expr::Number(n) => number(*n),
+			Expr::String(s) => quote(s),
+			Expr::Vararg => "...".to_string(),
+			Expr::Name(name) => name.clone(),
+			Expr::Global(name) if is_identifier(name) => name.clone(),
+			Expr::Global(name) => format!("_G[{}]", quote(name)),
+			Expr::Index(object, key) => {
+				let object = self.prefix(&object.node);
+				match &key.node {
+					Expr::String(name) if is_identifier(name) => format!("{object}.{name}"),
+					key => format!("{object}[{}]", self.expr(key, 0)),
+				}
+			}
+			Expr::Call(call) => self.call(call),
+			Expr::Function(function) => {
+				// the body goes on lines of its own, indented one level deeper
+				// than the statement the function is part of
+				let mut printer = Printer {
+					out: String::new(),
+					depth: self.depth,
+					options: self.options.clone(),
+				};
+				printer.function("function", function);
+				let indent = self.options.indent.repeat(self.depth);
+				let text = printer.out.strip_prefix(&indent).unwrap_or(&printer.out);
+				text.trim_end_matches('\n').to_string()
+			}
+			Expr::Table(fields) if fields.is_empty() => "{}".to_string(),
+			Expr::Table(fields) => {
+				let fields = fields
+					.iter()
+					.map(|field| match field {
+						Field::Item(value) => self.expr(value, 0),
+						Field::Pair(Expr::String(name), value) if is_identifier(name) => {
+							let value = self.expr(value, 0);
+							self.words(&[name, "=", &value])
+						}
+						Field::Pair(key, value) => {
+							let key = format!("[{}]", self.expr(key, 0));
+							let value = self.expr(value, 0);
+							self.words(&[&key, "=", &value])
+						}
+					})
+					.collect::<Vec<_>>();
+				format!("{{{}}}", fields.join(self.separator()))
+			}
+			Expr::Binary(op, left, right) => {
+				let prec = op.precedence();
+				let (left_prec, right_prec) = if op.right_associative() {
+					(prec + 1, prec)
+				} else {
+					(prec, prec + 1)
+				};
+				let left = self.expr(&left.node, left_prec);
+				let right = self.expr(&right.node, right_prec);
+				self.words(&[&left, op.symbol(), &right])
+			}
+			Expr::Unary(op, operand) => {
+				let operand = self.expr(&operand.node, UNARY_PRECEDENCE);
+				match op {
+					UnOp::Not => self.words(&["not", &operand]),
+					UnOp::Len => format!("#{operand}"),
+					// -- would start a comment
+					UnOp::Neg if operand.starts_with('-') => format!("- {operand}"),
+					UnOp::Neg => format!("-{operand}"),
+				}
+			}
+			Expr::Paren(inner) => format!("({})", self.expr(&inner.node, 0)),
+		};
+
+		if expr.precedence() < precedence {
+			format!("({text})")
+		} else {
+			text
+		}
+	}
+}
+
+impl Function {
+	/// Source of the function body laid out as `options` say
+	pub fn to_source(&self, options: &Options) -> String {
+		let mut printer = Printer::new(options.clone());
+		printer.block(&self.body);
+		printer.finish()
+	}
+}
+
+impl Display for Function {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.to_source(&Options::default()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::lua51::BinOp;
+
+	fn name(name: &str) -> Expr {
+		Expr::Name(name.to_string())
+	}
+
+	fn body(stats: Vec<Stat>) -> Function {
+		Function {
+			body: stats.into_iter().map(Spanned::from).collect(),
+			..Function::default()
+		}
+	}
+
+	#[test]
+	fn test_parentheses() {
+		let cases = [
+			(
+				Expr::binary(
+					BinOp::Mul,
+					Expr::binary(BinOp::Add, name("a"), name("b")),
+					name("c"),
+				),
+				"(a + b) * c",
+			),
+			(
+				Expr::binary(
+					BinOp::Sub,
+					name("a"),
+					Expr::binary(BinOp::Sub, name("b"), name("c")),
+				),
+				"a - (b - c)",
+			),
+			(
+				Expr::binary(
+					BinOp::Pow,
+					Expr::binary(BinOp::Pow, name("a"), name("b")),
+					name("c"),
+				),
+				"(a ^ b) ^ c",
+			),
+			(
+				Expr::binary(
+					BinOp::Concat,
+					name("a"),
+					Expr::binary(BinOp::Concat, name("b"), name("c")),
+				),
+				"a .. b .. c",
+			),
+			(
+				Expr::unary(UnOp::Neg, Expr::binary(BinOp::Pow, name("a"), name("b"))),
+				"-a ^ b",
+			),
+			(
+				Expr::binary(BinOp::Pow, Expr::unary(UnOp::Neg, name("a")), name("b")),
+				"(-a) ^ b",
+			),
+			(Expr::unary(UnOp::Neg, Expr::Number(-1.0)), "- -1"),
+			(
+				Expr::index(
+					Expr::String("s".to_string()),
+					Expr::String("len".to_string()),
+				),
+				"(\"s\").len",
+			),
+		];
+		for (expr, expected) in cases {
+			assert_eq!(Printer::default().expr(&expr, 0), expected);
+		}
+	}
+
+	#[test]
+	fn test_layout() {
+		let function = body(vec![
+			Stat::Local(vec!["x".to_string()], vec![Expr::Number(1.0)]),
+			Stat::While(
+				Expr::binary(BinOp::Lt, name("x"), Expr::Number(10.0)),
+				vec![
+					Stat::Assign(
+						vec![name("x")],
+						vec![Expr::binary(BinOp::Add, name("x"), Expr::Number(1.0))],
+					)
+					.into(),
+					Stat::Break.into(),
+					Stat::Call(Call {
+						func: Expr::paren(name("f")),
+						method: None,
+						args: vec![Expr::binary(
+							BinOp::Sub,
+							name("x"),
+							Expr::unary(UnOp::Neg, name("x")),
+						)],
+					})
+					.into(),
+				],
+			),
+			Stat::Return(vec![Expr::binary(
+				BinOp::Concat,
+				Expr::Number(1.0),
+				Expr::Vararg,
+			)]),
+		]);
+
+		assert_eq!(
+			function.to_string(),
+			"local x = 1\nwhile x < 10 do\n\tx = x + 1\n\tdo\n\t\tbreak\n\tend\n\t;(f)(x - -x)\nend\nreturn 1 .. ...\n"
+		);
+		assert_eq!(
+			function.to_source(&Options::spaces(2)),
+			"local x = 1\nwhile x < 10 do\n  x = x + 1\n  do\n    break\n  end\n  ;(f)(x - -x)\nend\nreturn 1 .. ...\n"
+		);
+		assert_eq!(
+			function.to_source(&Options::minified()),
+			"local x=1 while x<10 do x=x+1 do break end;(f)(x- -x)end return 1 .. ..."
+		);
+	}
+}
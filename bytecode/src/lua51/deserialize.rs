@@ -77,9 +77,13 @@ fn chunk(reader: &mut Reader, header: &Header) -> Proto {
 	// local list
 	let locals = Some(load_vec(reader, header, |reader, header| {
 		let name = reader.string(header.1);
-		let start = reader.int(header.0 as usize);
-		let end = reader.int(header.0 as usize);
-		Local(name, start, end)
+		let start_pc = reader.int(header.0 as usize);
+		let end_pc = reader.int(header.0 as usize);
+		Local {
+			name,
+			start_pc,
+			end_pc,
+		}
 	}));
 
 	// upvalues list
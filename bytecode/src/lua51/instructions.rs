@@ -147,7 +147,7 @@ pub const OPCODES: [Opcode; 38] = [
 	opcode!(Opmode::iABC, Reg, RK, RK),		// LT
 	opcode!(Opmode::iABC, Reg, RK, RK),		// LE
 
-	opcode!(Opmode::iABC, Reg, Reg),		// TEST
+	opcode!(Opmode::iAC, Reg, Reg),			// TEST
 	opcode!(Opmode::iABC, Reg, Reg, Reg),	// TESTSET
 
 	opcode!(Opmode::iABC, Reg, Reg, Reg),	// CALL
@@ -241,6 +241,21 @@ impl Opcode {
 		)
 	}
 
+	pub fn encode_ABC(opcode: u8, a: u8, b: u16, c: u16) -> u32 {
+		(opcode as u32 & 0x3f)
+			| ((a as u32) << 6)
+			| (((c as u32) & 0x1ff) << (6 + 8))
+			| (((b as u32) & 0x1ff) << (6 + 8 + 9))
+	}
+
+	pub fn encode_ABx(opcode: u8, a: u8, bx: u32) -> u32 {
+		(opcode as u32 & 0x3f) | ((a as u32) << 6) | ((bx & 0x3ffff) << (6 + 8))
+	}
+
+	pub fn encode_AsBx(opcode: u8, a: u8, sbx: i32) -> u32 {
+		Self::encode_ABx(opcode, a, (sbx + 0x1ffff) as u32)
+	}
+
 	pub fn from_serialized(serialized: u32) -> Instruction {
 		let opcode = (serialized & 0x3f) as u8;
 		let mut instruction = OPCODES[opcode as usize].clone();
@@ -281,13 +296,13 @@ impl Opcode {
 					operand.set_i32(b_val);
 				}
 			}
-			Opcode::iAC(a, _) => {
-				let (a_val, b_val, _) = Self::ABC(serialized);
+			Opcode::iAC(a, c) => {
+				let (a_val, _, c_val) = Self::ABC(serialized);
 				if let Some(operand) = a {
 					operand.set(a_val.into());
 				}
-				if let Some(operand) = a {
-					operand.set(b_val.into());
+				if let Some(operand) = c {
+					operand.set(c_val.into());
 				}
 			}
 		}
@@ -295,9 +310,33 @@ impl Opcode {
 		(opcode, instruction)
 	}
 
+	/// Encodes the instruction back into the form from_serialized reads
+	pub fn to_serialized(opcode: u8, instruction: &Opcode) -> u32 {
+		let operand = |value: &Option<Value>| match value {
+			Some(Value::Reg(r)) => *r as u32,
+			Some(Value::Kst(k) | Value::RK(k)) => *k,
+			Some(Value::sBx(sbx)) => (*sbx + 0x1ffff) as u32,
+			None => 0,
+		};
+		let a = operand(instruction.get_a()) as u8;
+		match instruction {
+			Opcode::iABC(..) | Opcode::iAC(..) => Self::encode_ABC(
+				opcode,
+				a,
+				operand(instruction.get_b()) as u16,
+				operand(instruction.get_c()) as u16,
+			),
+			Opcode::iABx(..) => Self::encode_ABx(opcode, a, operand(instruction.get_bx())),
+			Opcode::iAsBx(..) | Opcode::isBx(..) => {
+				Self::encode_ABx(opcode, a, operand(instruction.get_sbx()))
+			}
+		}
+	}
+
 	pub fn get_a(&self) -> &Option<Value> {
 		match &self {
 			Opcode::iABC(a, _, _) => a,
+			Opcode::iAC(a, _) => a,
 			Opcode::iABx(a, _) => a,
 			Opcode::iAsBx(a, _) => a,
 			_ => &None,
@@ -329,6 +368,7 @@ impl Opcode {
 	pub fn get_c(&self) -> &Option<Value> {
 		match &self {
 			Opcode::iABC(_, _, c) => c,
+			Opcode::iAC(_, c) => c,
 			_ => &None,
 		}
 	}
@@ -349,4 +389,31 @@ mod tests {
 		assert_eq!(inst.get_b().unwrap(), Reg(0));
 		assert_eq!(inst.get_c(), &None);
 	}
+
+	#[test]
+	fn test_encoding() {
+		let (opcode, inst) = Opcode::from_serialized(Opcode::encode_ABC(9, 1, 0x100, 2));
+		assert_eq!(opcode, 9);
+		assert_eq!(inst.get_a().unwrap(), Reg(1));
+		assert_eq!(inst.get_b().unwrap(), RK(0x100));
+		assert_eq!(inst.get_c().unwrap(), RK(2));
+
+		let (_, inst) = Opcode::from_serialized(Opcode::encode_AsBx(22, 0, -3));
+		assert_eq!(inst.get_sbx().unwrap(), sBx(-3));
+
+		// TFORLOOP only carries A and C
+		let (_, inst) = Opcode::from_serialized(Opcode::encode_ABC(33, 4, 0, 2));
+		assert_eq!(inst.get_a().unwrap(), Reg(4));
+		assert_eq!(inst.get_c().unwrap(), Reg(2));
+
+		for serialized in [
+			Opcode::encode_ABC(12, 3, 0x1ff, 7),
+			Opcode::encode_ABx(36, 2, 5),
+			Opcode::encode_AsBx(32, 1, -20),
+			Opcode::encode_AsBx(22, 0, 3),
+		] {
+			let (opcode, inst) = Opcode::from_serialized(serialized);
+			assert_eq!(Opcode::to_serialized(opcode, &inst), serialized);
+		}
+	}
 }
@@ -3,11 +3,14 @@ pub use compile::compile;
 mod deserialize;
 pub use deserialize::deserialize_bytecode;
 pub mod instructions;
+pub mod number;
+mod serialize;
+pub use serialize::serialize_bytecode;
 pub const SIGNATURE: &[u8] = b"\x1BLua";
 
 pub type Header = (u8, u8, u8, u8); // int, size_t, instr, lua_number
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Constants {
 	Nil,
 	Boolean(bool),
@@ -15,10 +18,15 @@ pub enum Constants {
 	String(String),
 }
 
-#[derive(Debug)]
-pub struct Local(String, u64, u64);
+/// A local variable, live from start_pc up to but excluding end_pc
+#[derive(Debug, Clone)]
+pub struct Local {
+	pub name: String,
+	pub start_pc: u64,
+	pub end_pc: u64,
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct Proto {
 	pub source: String,
 	pub line_defined: u32,
@@ -0,0 +1,105 @@
+/// Formats like C's `%.<precision>g`
+pub fn format_g(n: f64, precision: usize) -> String {
+	if n.is_nan() {
+		return if n.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+	}
+	if n.is_infinite() {
+		return if n < 0.0 { "-inf" } else { "inf" }.to_string();
+	}
+
+	let precision = precision.max(1);
+	let scientific = format!("{:.*e}", precision - 1, n);
+	let (mantissa, exponent) = scientific.split_once('e').unwrap();
+	let exponent = exponent.parse::<i32>().unwrap();
+
+	if exponent < -4 || exponent >= precision as i32 {
+		let sign = if exponent < 0 { '-' } else { '+' };
+		format!("{}e{}{:02}", trim_fraction(mantissa), sign, exponent.abs())
+	} else {
+		let decimals = (precision as i32 - 1 - exponent) as usize;
+		trim_fraction(&format!("{n:.decimals$}")).to_string()
+	}
+}
+
+fn trim_fraction(s: &str) -> &str {
+	if s.contains('.') {
+		s.trim_end_matches('0').trim_end_matches('.')
+	} else {
+		s
+	}
+}
+
+/// Number to string coercion, LUAI_NUMFFORMAT is "%.14g"
+pub fn number_to_string(n: f64) -> String {
+	format_g(n, 14)
+}
+
+/// String to number coercion of luaO_str2d, surrounding whitespace is allowed
+pub fn string_to_number(s: &str) -> Option<f64> {
+	let s = s.trim_matches(|c: char| c.is_ascii_whitespace() || c == '\x0b');
+	let (negative, digits) = match s.strip_prefix('-') {
+		Some(rest) => (true, rest),
+		None => (false, s.strip_prefix('+').unwrap_or(s)),
+	};
+
+	if let Some(hex) = digits
+		.strip_prefix("0x")
+		.or_else(|| digits.strip_prefix("0X"))
+	{
+		if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+			return None;
+		}
+		let n = hex
+			.chars()
+			.fold(0.0, |n, c| n * 16.0 + c.to_digit(16).unwrap() as f64);
+		return Some(if negative { -n } else { n });
+	}
+
+	// like strtod, parse also accepts inf and nan
+	if digits.is_empty() || digits.starts_with(['+', '-']) {
+		return None;
+	}
+	s.parse::<f64>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{format_g, number_to_string, string_to_number};
+
+	#[test]
+	fn test_number_to_string() {
+		let cases = [
+			(1.0, "1"),
+			(-0.0, "-0"),
+			(0.1, "0.1"),
+			(1.0 / 3.0, "0.33333333333333"),
+			(100.0, "100"),
+			(1e14, "1e+14"),
+			(123456789012345.0, "1.2345678901234e+14"),
+			(12345678901234.5, "12345678901234"),
+			(1e-5, "1e-05"),
+			(0.0001, "0.0001"),
+			(2f64.powi(53), "9.007199254741e+15"),
+			(f64::INFINITY, "inf"),
+			(f64::NEG_INFINITY, "-inf"),
+		];
+		for (n, s) in cases {
+			assert_eq!(number_to_string(n), s, "{n:?}");
+		}
+		assert_eq!(format_g(2.5, 1), "2");
+		assert_eq!(format_g(0.000123456, 3), "0.000123");
+	}
+
+	#[test]
+	fn test_string_to_number() {
+		assert_eq!(string_to_number(" 10 "), Some(10.0));
+		assert_eq!(string_to_number("0x1F"), Some(31.0));
+		assert_eq!(string_to_number("-0x10"), Some(-16.0));
+		assert_eq!(string_to_number("1e3"), Some(1000.0));
+		assert_eq!(string_to_number(".5"), Some(0.5));
+		assert_eq!(string_to_number(""), None);
+		assert_eq!(string_to_number("1 2"), None);
+		assert_eq!(string_to_number("0x"), None);
+		assert_eq!(string_to_number("--1"), None);
+	}
+}
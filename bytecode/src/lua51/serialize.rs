@@ -0,0 +1,176 @@
+use super::{instructions::Opcode, Constants, Header, Proto, SIGNATURE};
+use crate::{
+	shared::{UnsupportedWidth, Writer},
+	Bytecode,
+	LuaVersion::Lua51,
+};
+
+fn header(writer: &mut Writer, header: &Header) {
+	writer.bytes(SIGNATURE);
+	writer.byte(b'\x51');
+	writer.byte(0); // official format
+	writer.byte(1); // little endian
+	writer.byte(header.0);
+	writer.byte(header.1);
+	writer.byte(header.2);
+	writer.byte(header.3);
+	writer.byte(0); // floating point numbers
+}
+
+fn dump_vec<V>(
+	writer: &mut Writer,
+	header: &Header,
+	list: &[V],
+	dump: impl Fn(&mut Writer, &Header, &V),
+) {
+	writer.int(header.0 as usize, list.len() as u64);
+	for item in list {
+		dump(writer, header, item);
+	}
+}
+
+fn chunk(writer: &mut Writer, header: &Header, proto: &Proto) {
+	// an empty source goes without its NUL like luac writes nested functions
+	match proto.source.is_empty() {
+		true => writer.int(header.1 as usize, 0),
+		false => writer.string(header.1, &proto.source),
+	}
+	writer.int(header.0 as usize, proto.line_defined as u64);
+	writer.int(header.0 as usize, proto.last_line_defined as u64);
+	writer.byte(proto.nupvals);
+	writer.byte(proto.nparams);
+	writer.byte(proto.is_vararg_flag);
+	writer.byte(proto.max_stack_size);
+
+	dump_vec(
+		writer,
+		header,
+		&proto.instructions,
+		|writer, header, (opcode, inst)| {
+			writer.int(
+				header.2 as usize,
+				Opcode::to_serialized(*opcode, inst) as u64,
+			)
+		},
+	);
+
+	dump_vec(
+		writer,
+		header,
+		&proto.constants,
+		|writer, header, constant| match constant {
+			Constants::Nil => writer.byte(0),
+			Constants::Boolean(b) => {
+				writer.byte(1);
+				writer.byte(*b as u8);
+			}
+			Constants::Number(n) => {
+				writer.byte(3);
+				writer.number(*n);
+			}
+			Constants::String(s) => {
+				writer.byte(4);
+				writer.string(header.1, s);
+			}
+		},
+	);
+
+	dump_vec(writer, header, &proto.prototypes, chunk);
+
+	// debug info is optional, missing lists are written empty
+	let lines = proto.source_lines.as_deref().unwrap_or_default();
+	dump_vec(writer, header, lines, |writer, header, line| {
+		writer.int(header.0 as usize, *line)
+	});
+	let locals = proto.locals.as_deref().unwrap_or_default();
+	dump_vec(writer, header, locals, |writer, header, local| {
+		writer.string(header.1, &local.name);
+		writer.int(header.0 as usize, local.start_pc);
+		writer.int(header.0 as usize, local.end_pc);
+	});
+	let upvals = proto.upvals.as_deref().unwrap_or_default();
+	dump_vec(writer, header, upvals, |writer, header, name| {
+		writer.string(header.1, name)
+	});
+}
+
+/**
+ * serialize_bytecode - Dumps `proto` in the format of luac with the sizes
+ * `header` gives, what deserialize_bytecode and the reference VM load.
+ * Fails when an int, size_t or instruction is neither 4 nor 8 bytes
+ */
+pub fn serialize_bytecode(header: &Header, proto: &Proto) -> Result<Bytecode, UnsupportedWidth> {
+	let mut writer = Writer::new(&[header.0, header.1, header.2])?;
+	self::header(&mut writer, header);
+	chunk(&mut writer, header, proto);
+
+	Ok(Bytecode {
+		version: Lua51,
+		buff: writer.into_bytes(),
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::serialize_bytecode;
+	use crate::{
+		lua51::{deserialize_bytecode, instructions::Opcode, Constants, Local, Proto},
+		shared::UnsupportedWidth,
+	};
+
+	#[test]
+	fn test_round_trip() {
+		let closure = Proto {
+			nparams: 1,
+			max_stack_size: 2,
+			instructions: vec![Opcode::from_serialized(Opcode::encode_ABC(30, 0, 2, 0))],
+			source_lines: Some(vec![2]),
+			locals: Some(vec![]),
+			upvals: Some(vec![]),
+			..Default::default()
+		};
+		let proto = Proto {
+			source: "@test.lua".to_string(),
+			is_vararg_flag: 2,
+			max_stack_size: 3,
+			instructions: vec![
+				Opcode::from_serialized(Opcode::encode_ABx(36, 0, 0)),
+				Opcode::from_serialized(Opcode::encode_AsBx(22, 0, -1)),
+				Opcode::from_serialized(Opcode::encode_ABC(30, 0, 1, 0)),
+			],
+			constants: vec![
+				Constants::Nil,
+				Constants::Boolean(true),
+				Constants::Number(-0.5),
+				Constants::String(String::new()),
+			],
+			prototypes: vec![closure],
+			source_lines: Some(vec![1, 3, 3]),
+			locals: Some(vec![Local {
+				name: "f".to_string(),
+				start_pc: 1,
+				end_pc: 3,
+			}]),
+			upvals: Some(vec![]),
+			..Default::default()
+		};
+
+		let header = (4, 8, 4, 8);
+		let bytecode = serialize_bytecode(&header, &proto).unwrap();
+		let (read, copy) = deserialize_bytecode(&bytecode);
+		assert_eq!(read, header);
+		assert_eq!(format!("{copy:?}"), format!("{proto:?}"));
+		assert_eq!(
+			serialize_bytecode(&header, &copy).unwrap().buff,
+			bytecode.buff
+		);
+	}
+
+	#[test]
+	fn test_unsupported_width() {
+		let proto = Proto::default();
+		let error = serialize_bytecode(&(4, 2, 4, 8), &proto).unwrap_err();
+		assert_eq!(error, UnsupportedWidth(2));
+		assert!(serialize_bytecode(&(8, 8, 8, 8), &proto).is_ok());
+	}
+}
@@ -1,2 +1,4 @@
 mod reader;
 pub use reader::Reader;
+mod writer;
+pub use writer::{UnsupportedWidth, Writer};
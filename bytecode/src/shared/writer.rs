@@ -0,0 +1,64 @@
+use std::{error::Error, fmt::Display};
+
+/// Integer width in bytes Writer can not write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedWidth(pub u8);
+
+impl Display for UnsupportedWidth {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "integers of {} bytes are not supported", self.0)
+	}
+}
+
+impl Error for UnsupportedWidth {}
+
+/// Writes what Reader reads, little endian
+pub struct Writer {
+	buff: Vec<u8>,
+	widths: Vec<u8>, // the ones int was checked for
+}
+
+impl Writer {
+	/// Writer of integers `widths` bytes wide, each must be 4 or 8
+	pub fn new(widths: &[u8]) -> Result<Self, UnsupportedWidth> {
+		if let Some(n) = widths.iter().find(|n| !matches!(n, 4 | 8)) {
+			return Err(UnsupportedWidth(*n));
+		}
+		Ok(Self {
+			buff: vec![],
+			widths: widths.to_vec(),
+		})
+	}
+
+	pub fn into_bytes(self) -> Vec<u8> {
+		self.buff
+	}
+
+	pub fn byte(&mut self, byte: u8) {
+		self.buff.push(byte);
+	}
+
+	pub fn bytes(&mut self, bytes: &[u8]) {
+		self.buff.extend_from_slice(bytes);
+	}
+
+	/// `n` is one of the widths given to new
+	pub fn int(&mut self, n: usize, value: u64) {
+		debug_assert!(self.widths.contains(&(n as u8)), "unchecked width {n}");
+		match n {
+			4 => self.bytes(&(value as u32).to_le_bytes()),
+			_ => self.bytes(&value.to_le_bytes()),
+		}
+	}
+
+	pub fn number(&mut self, number: f64) {
+		self.bytes(&number.to_bits().to_le_bytes());
+	}
+
+	/// Length counting the null character, then the string with it
+	pub fn string(&mut self, size_t: u8, string: &str) {
+		self.int(size_t as usize, string.len() as u64 + 1);
+		self.bytes(string.as_bytes());
+		self.byte(0);
+	}
+}
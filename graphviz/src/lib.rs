@@ -4,11 +4,16 @@ use std::{collections::HashMap, fmt::Display};
 
 pub struct Instance {
 	label: String,
+	attributes: Vec<(String, String)>, // key, value
 }
 
 impl Display for Instance {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-		write!(f, "[ label = \"{}\" ];", self.label)
+		write!(f, "[ label = \"{}\"", self.label)?;
+		for (key, value) in &self.attributes {
+			write!(f, " {} = \"{}\"", key, value)?;
+		}
+		write!(f, " ];")
 	}
 }
 
@@ -43,10 +48,21 @@ impl Digraph {
 			name.to_string(),
 			Instance {
 				label: label.to_string(),
+				attributes: vec![],
 			},
 		);
 	}
 
+	/// Sets an attribute like fillcolor on an instance added before
+	pub fn set_attribute(&mut self, name: &str, key: &str, value: &str) {
+		if let Some(instance) = self.instances.get_mut(name) {
+			instance.attributes.retain(|(other, _)| other != key);
+			instance
+				.attributes
+				.push((key.to_string(), value.to_string()));
+		}
+	}
+
 	pub fn add_edge(&mut self, inst1: &str, inst2: &str, label: Option<&str>) {
 		self.edges.push(Edge(
 			if label.is_some() {
@@ -106,7 +122,11 @@ mod tests {
 		graph.add_instance("option2", "Option 2");
 		graph.add_edge("option", "option1", Some("Route 1"));
 		graph.add_edge("option", "option2", None);
+		graph.set_attribute("option1", "color", "red");
 
 		println!("{}", graph);
+		assert!(graph
+			.to_string()
+			.contains("option1 [ label = \"Option 1\" color = \"red\" ];"));
 	}
 }
@@ -6,11 +6,15 @@ mod traits;
 #[cfg(test)]
 mod tests {
 	use crate::{
-		lua51::{IRContext, CFG},
+		lua51::{
+			passes::{differential::Harness, manager::PassManager},
+			IRContext, CFG,
+		},
 		traits::Context,
 	};
-	use bytecode::lua51::{compile, deserialize_bytecode, Header, Proto};
-	use std::{fs::canonicalize, path::Path};
+	use ast::lua51::{Config, Generator};
+	use bytecode::lua51::{compile, deserialize_bytecode, serialize_bytecode, Header, Proto};
+	use std::{env::temp_dir, fs, fs::canonicalize, path::Path};
 
 	const TEST_FILE: &str = "../examples/test1.lua";
 
@@ -45,12 +49,45 @@ mod tests {
 		let idx = context.constants.add_string("fake ok!");
 		println!("new constants: {:?}", context.constants.get_all());
 
-		// TODO: does not seem to remap
-		context.remap_constant(ok_idx, idx);
+		context.remap_constant(ok_idx, idx).unwrap();
+		assert!(context.get_constant_references(ok_idx).is_empty());
 
 		println!("{}", context);
 
 		// let kst_insts = context.get_constant_instructions();
 		// println!("abc: {:?}", kst_insts);
 	}
+
+	#[test]
+	fn test_fuzz() {
+		let path = temp_dir().join(format!("fuzz-{}.lua", std::process::id()));
+		for seed in 0..20 {
+			let source = Generator::new(seed, Config::default()).chunk().to_string();
+			fs::write(&path, &source).unwrap();
+			let bc = compile(&path).expect("Unable to compile bytecode");
+			let (header, proto) = deserialize_bytecode(&bc);
+			let ctx = IRContext::from_proto(proto);
+
+			let harness = Harness::new();
+			let divergence = harness.check(&ctx, PassManager::lua51).unwrap();
+			assert!(
+				divergence.is_none(),
+				"seed {seed}: {}\n{source}",
+				divergence.unwrap()
+			);
+
+			// the transformed function survives serializing
+			let mut transformed = ctx.clone();
+			PassManager::lua51().run(&mut transformed).unwrap();
+			let bc = serialize_bytecode(&header, &transformed.to_proto()).unwrap();
+			let (_, proto) = deserialize_bytecode(&bc);
+			let reloaded = IRContext::from_proto(proto);
+			assert_eq!(
+				harness.run(&ctx, 0),
+				harness.run(&reloaded, 0),
+				"seed {seed}"
+			);
+		}
+		fs::remove_file(&path).ok();
+	}
 }
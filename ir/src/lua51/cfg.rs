@@ -1,14 +1,16 @@
+use super::context::IRInstructions;
 use bytecode::lua51::instructions::Instruction;
 use graphviz::Digraph;
 use std::{collections::HashMap, fmt::Display, ops::Range};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Target {
 	Jmp(usize),            // jump to block from sBx
 	ForLoop(usize, usize), // jump to ForPrep + 1 from sBx or PC + 2
 	TForLoop(usize),       // PC + 2
 	ForPrep(usize),        // jump to ForLoop block from sBx
-	BinCond(usize, usize), // proceeding block with jump, PC + 2 for EQ,LT,GT when skipping jump
+	BinCond(usize, usize), // proceeding block with jump, PC + 2 for EQ,LT,GT,TEST,TESTSET when skipping jump
+	Return,                // leaves the function
 	NOP,                   // proceed to next instruction as normal
 }
 
@@ -25,8 +27,13 @@ pub fn make_labels(insts: &Vec<Instruction>) -> Vec<Range<usize>> {
 	while let Some((pc, (opcode, inst))) = iter.next() {
 		let pc = pc as i32;
 		match *opcode {
-			23 | 24 | 25 => {
+			23..=27 => {
+				labels.push(pc);
+			}
+			2 if inst.get_c().unwrap().reg() != 0 => {
+				// LOADBOOL with C skips the next instruction
 				labels.push(pc);
+				labels.push(pc + 1);
 			}
 			22 => {
 				labels.push(pc);
@@ -42,6 +49,9 @@ pub fn make_labels(insts: &Vec<Instruction>) -> Vec<Range<usize>> {
 			33 => {
 				labels.push(pc);
 			}
+			30 => {
+				labels.push(pc);
+			}
 			_ => {}
 		}
 	}
@@ -78,8 +88,8 @@ pub fn make_blocks(insts: &Vec<Instruction>) -> Vec<Block> {
 
 		let instr = insts.get(last_pc);
 		let target = match instr {
-			Some((23 | 24 | 25, ..)) => Target::BinCond(
-				// EQ,LE,GE all skip the proceeding jump instruction if true
+			Some((23..=27, ..)) => Target::BinCond(
+				// EQ,LE,GE,TEST,TESTSET all skip the proceeding jump instruction if true
 				get_block(&labels, last_pc + 1),
 				get_block(&labels, last_pc + 2),
 			),
@@ -105,6 +115,11 @@ pub fn make_blocks(insts: &Vec<Instruction>) -> Vec<Block> {
 				&labels,
 				(last_pc as i32 + inst.get_sbx().unwrap().sbx()) as usize + 1,
 			)),
+			Some((2, inst)) if inst.get_c().unwrap().reg() != 0 => {
+				// LOADBOOL always skips the proceeding instruction when C is set
+				Target::Jmp(get_block(&labels, last_pc + 2))
+			}
+			Some((30, _)) => Target::Return,
 			_ => Target::NOP,
 		};
 
@@ -118,6 +133,17 @@ pub fn make_blocks(insts: &Vec<Instruction>) -> Vec<Block> {
 }
 
 pub fn visualize_blocks(blocks: &Vec<Block>) -> String {
+	visualize_blocks_with(blocks, |i, block| format!("Block {}: {:?}", i, block.range))
+}
+
+/// Graph of the blocks, each labelled by `label` from its index
+pub fn visualize_blocks_with(blocks: &[Block], label: impl Fn(usize, &Block) -> String) -> String {
+	format!("{}", graph_blocks_with(blocks, label))
+}
+
+/// Graph of the blocks with instances named "Block<index>", to be styled
+/// before it is written
+pub fn graph_blocks_with(blocks: &[Block], label: impl Fn(usize, &Block) -> String) -> Digraph {
 	let mut graph = Digraph::new();
 	let start_block = "StartBlock";
 	let end_block = &format!("Block{}", blocks.len());
@@ -140,10 +166,10 @@ pub fn visualize_blocks(blocks: &Vec<Block>) -> String {
 				graph.add_edge(&name, &format!("Block{}", i + 1), None); // add edge to following jump
 			}
 			Target::NOP => graph.add_edge(&name, &format!("Block{}", i + 1), None),
-			_ => {}
+			Target::Return => graph.add_edge(&name, end_block, None),
 		}
 
-		graph.add_instance(&name, &format!("Block {}: {:?}", i, block.range));
+		graph.add_instance(&name, &label(i, block));
 	}
 
 	graph
@@ -152,7 +178,17 @@ pub fn visualize_blocks(blocks: &Vec<Block>) -> String {
 		.filter(|name| name.as_str() != start_block)
 		.for_each(|name| graph.add_edge(start_block, name, None));
 
-	format!("{}", graph)
+	graph
+}
+
+impl Block {
+	pub fn range(&self) -> &Range<usize> {
+		&self.range
+	}
+
+	pub fn target(&self) -> &Target {
+		&self.target
+	}
 }
 
 pub struct CFG {
@@ -166,10 +202,59 @@ impl CFG {
 		}
 	}
 
+	pub fn from_instructions(insts: &IRInstructions) -> Self {
+		Self::new(&insts.to_instructions())
+	}
+
 	pub fn get_block(&self, block: usize) -> Option<&Block> {
 		self.blocks.get(block)
 	}
 
+	pub fn len(&self) -> usize {
+		self.blocks.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.blocks.is_empty()
+	}
+
+	/// Block containing the instruction at `pc`
+	pub fn block_of(&self, pc: usize) -> Option<usize> {
+		self.blocks
+			.iter()
+			.position(|block| block.range.contains(&pc))
+	}
+
+	/// Blocks control can flow to from `block`, the function exit is omitted
+	pub fn successors(&self, block: usize) -> Vec<usize> {
+		let mut succs = match self.blocks[block].target {
+			Target::Jmp(target) | Target::ForPrep(target) => vec![target],
+			Target::BinCond(jmp1, jmp2) | Target::ForLoop(jmp1, jmp2) => vec![jmp1, jmp2],
+			Target::TForLoop(target) => vec![block + 1, target],
+			Target::NOP => vec![block + 1],
+			Target::Return => vec![],
+		};
+		succs.retain(|succ| *succ < self.blocks.len());
+		succs.dedup();
+		succs
+	}
+
+	pub fn predecessors(&self, block: usize) -> Vec<usize> {
+		(0..self.blocks.len())
+			.filter(|pred| self.successors(*pred).contains(&block))
+			.collect()
+	}
+
+	/// Graphviz graph of the blocks with labels of their own
+	pub fn visualize_with(&self, label: impl Fn(usize, &Block) -> String) -> String {
+		visualize_blocks_with(&self.blocks, label)
+	}
+
+	/// Graph of the blocks with labels of their own, see `graph_blocks_with`
+	pub fn graph_with(&self, label: impl Fn(usize, &Block) -> String) -> Digraph {
+		graph_blocks_with(&self.blocks, label)
+	}
+
 	pub fn iter(&self) -> CFGIterator {
 		CFGIterator {
 			cfg: &self,
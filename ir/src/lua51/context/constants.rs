@@ -2,6 +2,7 @@ use std::fmt::{Debug, Display};
 
 use bytecode::lua51::Constants;
 
+#[derive(Clone)]
 pub struct IRConstant {
 	val: Constants,
 }
@@ -17,6 +18,21 @@ impl IRConstant {
 		Self { val: c }
 	}
 
+	pub fn get(&self) -> &Constants {
+		&self.val
+	}
+
+	/// Raw equality, numbers compare by bits so 0 and -0 stay apart
+	pub fn same(&self, other: &IRConstant) -> bool {
+		match (&self.val, &other.val) {
+			(Constants::Nil, Constants::Nil) => true,
+			(Constants::Boolean(a), Constants::Boolean(b)) => a == b,
+			(Constants::Number(a), Constants::Number(b)) => a.to_bits() == b.to_bits(),
+			(Constants::String(a), Constants::String(b)) => a == b,
+			_ => false,
+		}
+	}
+
 	pub fn string(s: &str) -> Self {
 		Self {
 			val: Constants::String(s.to_string()),
@@ -70,7 +86,8 @@ impl IRConstant {
 	}
 }
 
-#[derive(Debug)]
+/// RK operands only reach the first 256 entries, IRContext::spill_constants loads the rest
+#[derive(Debug, Clone)]
 pub struct IRConstants {
 	constants: Vec<IRConstant>,
 }
@@ -89,6 +106,37 @@ impl IRConstants {
 		&self.constants
 	}
 
+	pub fn len(&self) -> usize {
+		self.constants.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.constants.is_empty()
+	}
+
+	pub fn get(&self, idx: usize) -> Option<&IRConstant> {
+		self.constants.get(idx)
+	}
+
+	/// Rebuilds the pool from the old entries listed in `order`, references are
+	/// not updated, see IRContext::reorder_constants
+	pub fn reorder(&mut self, order: &[usize]) {
+		self.constants = order
+			.iter()
+			.map(|idx| self.constants[*idx].clone())
+			.collect();
+	}
+
+	/// Index of a constant equal to `constant`, it is appended if there is none
+	pub fn add(&mut self, constant: IRConstant) -> usize {
+		if let Some(pos) = self.constants.iter().position(|c| c.same(&constant)) {
+			return pos;
+		}
+
+		self.constants.push(constant);
+		self.constants.len() - 1
+	}
+
 	pub fn get_string(&mut self, str: &str) -> Option<usize> {
 		self.constants.iter().position(|c| c.is_string(str))
 	}
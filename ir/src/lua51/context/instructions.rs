@@ -1,7 +1,59 @@
 use super::super::get_opcode_name;
 use crate::traits::{IROperand, Operand};
-use bytecode::lua51::instructions::{Instruction, Opcode, Value};
-use std::fmt::Display;
+use bytecode::lua51::{
+	instructions::{Instruction, Opcode, Value},
+	Local,
+};
+use std::{collections::HashMap, error::Error, fmt::Display, ops::Range};
+
+/// Largest distance a relative jump can encode in sBx
+const MAX_SBX: i64 = 0x1ffff;
+
+#[derive(Debug, PartialEq)]
+pub enum EditError {
+	/// pc is past the end of the instructions
+	OutOfBounds(usize),
+	/// pc is an operand of the CLOSURE or SETLIST before it
+	InsidePseudo(usize),
+	/// the instruction at pc implicitly skips the one after it
+	SplitsSkip(usize),
+	/// the jump that would end up at pc does not fit into sBx
+	JumpOutOfRange(usize),
+	/// a jump targets a label no instruction carries
+	UndefinedLabel(Label),
+	/// more than one instruction carries the label
+	DuplicateLabel(Label),
+	/// the instruction at pc needs a temporary register above the stack limit
+	NoFreeRegister(usize),
+}
+
+impl Display for EditError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::OutOfBounds(pc) => write!(f, "pc {pc} is out of bounds"),
+			Self::InsidePseudo(pc) => write!(f, "pc {pc} is a pseudo instruction"),
+			Self::SplitsSkip(pc) => write!(f, "edit separates the skip at pc {pc} from its target"),
+			Self::JumpOutOfRange(pc) => write!(f, "jump at pc {pc} is out of sBx range"),
+			Self::UndefinedLabel(label) => write!(f, "{label} is not defined"),
+			Self::DuplicateLabel(label) => write!(f, "{label} is defined more than once"),
+			Self::NoFreeRegister(pc) => {
+				write!(f, "no free register for the instruction at pc {pc}")
+			}
+		}
+	}
+}
+
+impl Error for EditError {}
+
+/// Names the instruction a jump lands on, offsets are derived from it when resolving
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Label(pub usize);
+
+impl Display for Label {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "L{}", self.0)
+	}
+}
 
 impl IROperand<Value> {
 	pub fn get_kst(&self) -> Option<usize> {
@@ -17,11 +69,28 @@ impl IROperand<Value> {
 			Self::None => None,
 		}
 	}
+
+	/// Register index of a register or RK operand, RK constants yield None
+	pub fn get_reg(&self) -> Option<u8> {
+		match self.value()? {
+			Value::Reg(reg) => Some(*reg),
+			val @ Value::RK(_) => match val.get_rk() {
+				Value::Reg(reg) => Some(reg),
+				_ => None,
+			},
+			_ => None,
+		}
+	}
 }
 
+#[derive(Clone)]
 pub struct IRInstruction {
 	opcode: usize,
 	val: Opcode,
+	line: Option<u64>,
+	pseudo: bool, // operand of the CLOSURE or SETLIST before it
+	target: Option<Label>,
+	labels: Vec<Label>,
 }
 
 impl Display for IRInstruction {
@@ -39,9 +108,114 @@ impl IRInstruction {
 		Self {
 			opcode: opcode as usize,
 			val: inst,
+			line: None,
+			pseudo: false,
+			target: None,
+			labels: vec![],
+		}
+	}
+
+	pub fn new_abc(opcode: usize, a: u8, b: u16, c: u16) -> Self {
+		Self::from_instruction(Opcode::from_serialized(Opcode::encode_ABC(
+			opcode as u8,
+			a,
+			b,
+			c,
+		)))
+	}
+
+	pub fn new_abx(opcode: usize, a: u8, bx: u32) -> Self {
+		Self::from_instruction(Opcode::from_serialized(Opcode::encode_ABx(
+			opcode as u8,
+			a,
+			bx,
+		)))
+	}
+
+	pub fn new_asbx(opcode: usize, a: u8, sbx: i32) -> Self {
+		Self::from_instruction(Opcode::from_serialized(Opcode::encode_AsBx(
+			opcode as u8,
+			a,
+			sbx,
+		)))
+	}
+
+	/// JMP, FORLOOP or FORPREP landing on `target`, its sBx is filled in when resolving
+	pub fn new_jump(opcode: usize, a: u8, target: Label) -> Self {
+		let mut inst = Self::new_asbx(opcode, a, 0);
+		inst.target = Some(target);
+		inst
+	}
+
+	/// Marks the instruction as an upvalue capture of a CLOSURE or the block of a SETLIST
+	pub fn into_pseudo(mut self) -> Self {
+		self.pseudo = true;
+		self
+	}
+
+	#[inline]
+	pub fn is_pseudo(&self) -> bool {
+		self.pseudo
+	}
+
+	#[inline]
+	pub fn line(&self) -> Option<u64> {
+		self.line
+	}
+
+	pub fn set_line(&mut self, line: Option<u64>) {
+		self.line = line;
+	}
+
+	/// Label a JMP, FORLOOP or FORPREP lands on, None for raw sBx offsets
+	#[inline]
+	pub fn target(&self) -> Option<Label> {
+		self.target
+	}
+
+	pub fn set_target(&mut self, target: Option<Label>) {
+		self.target = target;
+	}
+
+	/// Labels naming this instruction
+	#[inline]
+	pub fn labels(&self) -> &[Label] {
+		&self.labels
+	}
+
+	pub fn add_label(&mut self, label: Label) {
+		self.labels.push(label);
+	}
+
+	/// Removes the labels, copies of an instruction must not carry the labels of the original
+	pub fn take_labels(&mut self) -> Vec<Label> {
+		std::mem::take(&mut self.labels)
+	}
+
+	/// Absolute target of JMP, FORLOOP and FORPREP when placed at `pc`
+	pub fn jump_target(&self, pc: usize) -> Option<usize> {
+		match self.opcode {
+			22 | 31 | 32 => {
+				let sbx = self.val.get_sbx().unwrap().sbx();
+				Some((pc as i64 + 1 + sbx as i64) as usize)
+			}
+			_ => None,
 		}
 	}
 
+	/// Whether the instruction may skip over the one following it
+	pub fn is_skip(&self) -> bool {
+		match self.opcode {
+			23..=27 | 33 => true,
+			2 => self.get_c().get_reg() != Some(0),
+			_ => false,
+		}
+	}
+
+	pub fn to_instruction(&self) -> Instruction {
+		(self.opcode as u8, self.val)
+	}
+
 	#[inline]
 	pub fn is(&self, opcode: usize) -> bool {
 		self.opcode == opcode
@@ -75,7 +249,7 @@ impl IRInstruction {
 	#[inline]
 	pub fn get_bx(&self) -> IROperand<Value> {
 		if let Some(bx) = self.val.get_bx() {
-			IROperand::Operand(Operand::A, *bx)
+			IROperand::Operand(Operand::Bx, *bx)
 		} else {
 			IROperand::None
 		}
@@ -84,7 +258,7 @@ impl IRInstruction {
 	#[inline]
 	pub fn get_sbx(&self) -> IROperand<Value> {
 		if let Some(sbx) = self.val.get_sbx() {
-			IROperand::Operand(Operand::A, *sbx)
+			IROperand::Operand(Operand::sBx, *sbx)
 		} else {
 			IROperand::None
 		}
@@ -93,7 +267,7 @@ impl IRInstruction {
 	#[inline]
 	pub fn get_c(&self) -> IROperand<Value> {
 		if let Some(c) = self.val.get_c() {
-			IROperand::Operand(Operand::A, *c)
+			IROperand::Operand(Operand::C, *c)
 		} else {
 			IROperand::None
 		}
@@ -176,17 +350,51 @@ impl IRInstruction {
 	}
 }
 
+/// Instructions edits have added and removed so far
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Edits {
+	pub added: usize,
+	pub removed: usize,
+}
+
+/**
+ * IRInstructions - Instructions of one function
+ * Jumps refer to the instruction they land on through labels, every edit
+ * resolves them back into sBx offsets so the encoded instructions stay valid.
+ * The scopes of locals from debug info are moved along likewise
+ */
+#[derive(Clone)]
 pub struct IRInstructions {
 	instructions: Vec<IRInstruction>,
+	next_label: usize,
+	edits: Edits,
+	locals: Vec<Local>,
 }
 
 impl IRInstructions {
 	pub fn from_instructions(insts: Vec<Instruction>) -> Self {
-		Self {
-			instructions: insts
+		Self::from_ir(
+			insts
 				.into_iter()
-				.map(|inst| IRInstruction::from_instruction(inst))
+				.map(IRInstruction::from_instruction)
 				.collect(),
+		)
+	}
+
+	/// Jumps without a label are bound to the instruction their sBx points to
+	pub fn from_ir(mut instructions: Vec<IRInstruction>) -> Self {
+		let mut next_label = instructions
+			.iter()
+			.flat_map(|inst| inst.labels.iter().chain(inst.target.iter()))
+			.map(|label| label.0 + 1)
+			.max()
+			.unwrap_or(0);
+		bind_offsets(&mut instructions, 0..usize::MAX, &mut next_label);
+		Self {
+			instructions,
+			next_label,
+			edits: Edits::default(),
+			locals: vec![],
 		}
 	}
 
@@ -194,10 +402,291 @@ impl IRInstructions {
 		&self.instructions
 	}
 
+	pub fn edits(&self) -> Edits {
+		self.edits
+	}
+
+	/// Line of every instruction, instructions without one share the line before them
+	pub fn lines(&self) -> Vec<u64> {
+		let mut last = 0;
+		self.instructions
+			.iter()
+			.map(|inst| {
+				last = inst.line.unwrap_or(last);
+				last
+			})
+			.collect()
+	}
+
+	pub fn set_lines(&mut self, lines: &[u64]) {
+		for (inst, line) in self.instructions.iter_mut().zip(lines) {
+			inst.line = Some(*line);
+		}
+	}
+
+	/// Local variables from debug info, their pcs follow the edits
+	pub fn locals(&self) -> &[Local] {
+		&self.locals
+	}
+
+	pub fn set_locals(&mut self, locals: Vec<Local>) {
+		self.locals = locals;
+	}
+
+	/// Sets `locals` of instructions rearranged so the one at `pc` is now
+	/// at `moved[pc]`, or gone. A scope covers where its instructions went,
+	/// bounds at either end of the function stay there and scopes left
+	/// without instructions are dropped
+	pub fn set_moved_locals(&mut self, locals: &[Local], moved: &[Option<usize>]) {
+		let len = self.instructions.len() as u64;
+		self.locals = locals
+			.iter()
+			.filter_map(|local| {
+				let start = (local.start_pc as usize).min(moved.len());
+				let end = (local.end_pc as usize).min(moved.len());
+				let pcs = moved[start..end].iter().flatten();
+				let first = *pcs.clone().min()? as u64;
+				let last = *pcs.max()? as u64;
+				Some(Local {
+					name: local.name.clone(),
+					start_pc: if start == 0 { 0 } else { first },
+					end_pc: if end == moved.len() { len } else { last + 1 },
+				})
+			})
+			.collect();
+	}
+
+	pub fn set_pseudo(&mut self, pseudo: &[bool]) {
+		for (inst, pseudo) in self.instructions.iter_mut().zip(pseudo) {
+			inst.pseudo = *pseudo;
+		}
+	}
+
+	/// A label no instruction carries yet
+	pub fn new_label(&mut self) -> Label {
+		self.next_label += 1;
+		Label(self.next_label - 1)
+	}
+
+	/// Label of the instruction at `pc`, one is added if it has none
+	pub fn label_at(&mut self, pc: usize) -> Option<Label> {
+		if let Some(label) = self.instructions.get(pc)?.labels.first() {
+			return Some(*label);
+		}
+		let label = self.new_label();
+		self.instructions[pc].labels.push(label);
+		Some(label)
+	}
+
+	/// pc of the instruction carrying `label`
+	pub fn find_label(&self, label: Label) -> Option<usize> {
+		self.instructions
+			.iter()
+			.position(|inst| inst.labels.contains(&label))
+	}
+
+	/// Rewrites the sBx of every labelled jump, needed after changing targets through get_mut
+	pub fn resolve_labels(&mut self) -> Result<(), EditError> {
+		resolve(&mut self.instructions)
+	}
+
+	/// Inserts `insts` so they run whenever `pc` would, jumps to `pc` now land on them
+	pub fn insert_before(&mut self, pc: usize, insts: Vec<IRInstruction>) -> Result<(), EditError> {
+		self.splice(pc..pc, insts, true).map(|_| ())
+	}
+
+	/// Inserts `insts` after `pc` and its pseudo instructions, jumps past `pc` skip them
+	pub fn insert_after(&mut self, pc: usize, insts: Vec<IRInstruction>) -> Result<(), EditError> {
+		if pc >= self.instructions.len() {
+			return Err(EditError::OutOfBounds(pc));
+		}
+		let end = self.group_end(pc);
+		self.splice(end..end, insts, false).map(|_| ())
+	}
+
+	/// Removes `pc` together with its pseudo instructions, jumps to it land on the next instruction
+	pub fn remove(&mut self, pc: usize) -> Result<Vec<IRInstruction>, EditError> {
+		if pc >= self.instructions.len() {
+			return Err(EditError::OutOfBounds(pc));
+		}
+		let end = self.group_end(pc);
+		self.splice(pc..end, vec![], true)
+	}
+
+	/// Replaces `range` with `insts`, jumps into the range land on the first new instruction
+	pub fn replace_range(
+		&mut self,
+		range: Range<usize>,
+		insts: Vec<IRInstruction>,
+	) -> Result<Vec<IRInstruction>, EditError> {
+		self.splice(range, insts, true)
+	}
+
+	/// Moves `range` in front of `to`, labels and jump targets travel with the instructions
+	pub fn move_range(&mut self, range: Range<usize>, to: usize) -> Result<(), EditError> {
+		let len = self.instructions.len();
+		if range.start > range.end || range.end > len || to > len {
+			return Err(EditError::OutOfBounds(range.end.max(to)));
+		}
+		if to > range.start && to < range.end {
+			return Err(EditError::OutOfBounds(to));
+		}
+		if range.is_empty() || to == range.start || to == range.end {
+			return Ok(());
+		}
+		for pc in [range.start, range.end, to] {
+			if self.instructions.get(pc).is_some_and(|inst| inst.pseudo) {
+				return Err(EditError::InsidePseudo(pc));
+			}
+		}
+		// neither a skip left behind nor one moved may lose the instruction it skips
+		for pc in [
+			range.start.checked_sub(1),
+			range.end.checked_sub(1),
+			to.checked_sub(1),
+		]
+		.into_iter()
+		.flatten()
+		{
+			let inst = &self.instructions[pc];
+			if !inst.pseudo && inst.is_skip() {
+				return Err(EditError::SplitsSkip(pc));
+			}
+		}
+
+		let mut instructions = self.instructions.clone();
+		let moved = instructions.drain(range.clone()).collect::<Vec<_>>();
+		let at = if to >= range.end {
+			to - range.len()
+		} else {
+			to
+		};
+		instructions.splice(at..at, moved);
+		resolve(&mut instructions)?;
+
+		// scope bounds go where the instruction they name went
+		let mut order = (0..len).collect::<Vec<_>>();
+		let moved = order.drain(range).collect::<Vec<_>>();
+		order.splice(at..at, moved);
+		let mut position = vec![len; len + 1];
+		for (new, old) in order.into_iter().enumerate() {
+			position[old] = new;
+		}
+		for local in &mut self.locals {
+			let start = position[(local.start_pc as usize).min(len)];
+			let end = position[(local.end_pc as usize).min(len)];
+			local.start_pc = start as u64;
+			local.end_pc = end.max(start) as u64;
+		}
+
+		self.instructions = instructions;
+		Ok(())
+	}
+
+	fn group_end(&self, pc: usize) -> usize {
+		let mut end = pc + 1;
+		while self.instructions.get(end).is_some_and(|inst| inst.pseudo) {
+			end += 1;
+		}
+		end
+	}
+
+	/// Replaces `range` with `insts` and returns the removed instructions.
+	/// Labels of the range, or of the insertion point when `redirect` is set,
+	/// move onto the first new instruction. New jumps without a label are
+	/// bound where their sBx points. Nothing changes if an error is returned
+	fn splice(
+		&mut self,
+		range: Range<usize>,
+		mut insts: Vec<IRInstruction>,
+		redirect: bool,
+	) -> Result<Vec<IRInstruction>, EditError> {
+		let len = self.instructions.len();
+		if range.start > range.end || range.end > len {
+			return Err(EditError::OutOfBounds(range.end));
+		}
+		for pc in [range.start, range.end] {
+			if self.instructions.get(pc).is_some_and(|inst| inst.pseudo) {
+				return Err(EditError::InsidePseudo(pc));
+			}
+		}
+		let replaces_one = range.len() == 1 && insts.len() == 1;
+		if let Some(prev) = range.start.checked_sub(1) {
+			let inst = &self.instructions[prev];
+			if !inst.pseudo && inst.is_skip() && !replaces_one {
+				return Err(EditError::SplitsSkip(prev));
+			}
+		}
+
+		let line = if redirect {
+			self.instructions.get(range.start)
+		} else {
+			range.start.checked_sub(1).map(|pc| &self.instructions[pc])
+		}
+		.or(self.instructions.last())
+		.and_then(|inst| inst.line);
+		for inst in &mut insts {
+			inst.line = inst.line.or(line);
+		}
+
+		let mut instructions = self.instructions.clone();
+		let mut next_label = self.next_label;
+		let added = insts.len();
+		let mut removed = instructions
+			.splice(range.clone(), insts)
+			.collect::<Vec<_>>();
+
+		let mut moved = removed
+			.iter_mut()
+			.flat_map(|inst| inst.take_labels())
+			.collect::<Vec<_>>();
+		if redirect && range.is_empty() && added != 0 {
+			if let Some(inst) = instructions.get_mut(range.start + added) {
+				moved.extend(inst.take_labels());
+			}
+		}
+		if let Some(inst) = instructions.get_mut(range.start) {
+			inst.labels.extend(moved);
+		}
+
+		bind_offsets(
+			&mut instructions,
+			range.start..range.start + added,
+			&mut next_label,
+		);
+		resolve(&mut instructions)?;
+
+		self.instructions = instructions;
+		self.next_label = next_label;
+		self.edits.added += added;
+		self.edits.removed += removed.len();
+		for local in &mut self.locals {
+			local.start_pc = shift_bound(local.start_pc, &range, added, false);
+			local.end_pc = shift_bound(local.end_pc, &range, added, true);
+		}
+		Ok(removed)
+	}
+
+	pub fn to_instructions(&self) -> Vec<Instruction> {
+		self.instructions
+			.iter()
+			.map(|inst| inst.to_instruction())
+			.collect()
+	}
+
+	pub fn len(&self) -> usize {
+		self.instructions.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.instructions.is_empty()
+	}
+
 	pub fn get(&self, idx: usize) -> Option<&IRInstruction> {
 		self.instructions.get(idx)
 	}
 
+	/// Changed targets and labels take effect on the next edit or `resolve_labels`
 	pub fn get_mut(&mut self, idx: usize) -> Option<&mut IRInstruction> {
 		self.instructions.get_mut(idx)
 	}
@@ -222,6 +711,78 @@ impl IRInstructions {
 	}
 }
 
+/// Labels the target of every jump in `range` that has none and lands inside the instructions
+fn bind_offsets(instructions: &mut [IRInstruction], range: Range<usize>, next_label: &mut usize) {
+	for pc in range.start..range.end.min(instructions.len()) {
+		let inst = &instructions[pc];
+		if inst.pseudo || inst.target.is_some() {
+			continue;
+		}
+		let Some(target) = inst.jump_target(pc) else {
+			continue;
+		};
+		let Some(dest) = instructions.get_mut(target) else {
+			continue;
+		};
+		let label = match dest.labels.first() {
+			Some(label) => *label,
+			None => {
+				*next_label += 1;
+				dest.labels.push(Label(*next_label - 1));
+				Label(*next_label - 1)
+			}
+		};
+		instructions[pc].target = Some(label);
+	}
+}
+
+/// Rewrites the sBx of every labelled jump, nothing changes if one cannot be resolved
+fn resolve(instructions: &mut [IRInstruction]) -> Result<(), EditError> {
+	let mut positions = HashMap::new();
+	for (pc, inst) in instructions.iter().enumerate() {
+		for label in &inst.labels {
+			if positions.insert(*label, pc).is_some() {
+				return Err(EditError::DuplicateLabel(*label));
+			}
+		}
+	}
+
+	let mut offsets = vec![];
+	for (pc, inst) in instructions.iter().enumerate() {
+		if let Some(label) = inst.target {
+			let target = *positions
+				.get(&label)
+				.ok_or(EditError::UndefinedLabel(label))?;
+			let sbx = target as i64 - (pc as i64 + 1);
+			if !(-MAX_SBX..=MAX_SBX + 1).contains(&sbx) {
+				return Err(EditError::JumpOutOfRange(pc));
+			}
+			offsets.push((pc, sbx as i32));
+		}
+	}
+
+	for (pc, sbx) in offsets {
+		instructions[pc].set_sbx(Value::sBx(sbx));
+	}
+	Ok(())
+}
+
+/// Scope bound `pc` after replacing `range` with `added` instructions, the
+/// new instructions are inside a scope starting or ending in the range
+fn shift_bound(pc: u64, range: &Range<usize>, added: usize, end: bool) -> u64 {
+	let pc = pc as usize;
+	let shifted = if pc <= range.start {
+		pc
+	} else if pc >= range.end {
+		pc - range.len() + added
+	} else if end {
+		range.start + added
+	} else {
+		range.start
+	};
+	shifted as u64
+}
+
 pub struct IRInstructionIterator<'a> {
 	instructions: &'a IRInstructions,
 	current: usize,
@@ -235,3 +796,228 @@ impl<'a> Iterator for IRInstructionIterator<'a> {
 		self.instructions.get(self.current - 1)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{EditError, IRInstruction, IRInstructions, Label};
+	use crate::lua51::IRContext;
+	use bytecode::lua51::{instructions::Opcode, Local, Proto};
+
+	fn instructions(insts: Vec<u32>) -> IRInstructions {
+		let mut insts = IRInstructions::from_instructions(
+			insts.into_iter().map(Opcode::from_serialized).collect(),
+		);
+		insts.set_lines(&(1..=insts.len() as u64).collect::<Vec<_>>());
+		insts
+	}
+
+	fn targets(insts: &IRInstructions) -> Vec<Option<usize>> {
+		insts
+			.iter()
+			.enumerate()
+			.map(|(pc, inst)| inst.jump_target(pc))
+			.collect()
+	}
+
+	#[test]
+	fn test_jump_fixup() {
+		let mut insts = instructions(vec![
+			Opcode::encode_ABx(1, 0, 0),     // LOADK 0 0
+			Opcode::encode_AsBx(22, 0, 1),   // JMP 1
+			Opcode::encode_ABx(1, 0, 1),     // LOADK 0 1
+			Opcode::encode_ABC(30, 0, 2, 0), // RETURN 0 2
+		]);
+
+		// code inserted before a jump target runs on the jump
+		insts
+			.insert_before(3, vec![IRInstruction::new_abc(0, 1, 0, 0)])
+			.unwrap();
+		assert_eq!(targets(&insts)[1], Some(3));
+		assert_eq!(insts.get(3).unwrap().line(), Some(4));
+
+		// code inserted after the instruction before it does not
+		insts
+			.insert_after(2, vec![IRInstruction::new_abc(0, 2, 0, 0)])
+			.unwrap();
+		assert_eq!(targets(&insts)[1], Some(4));
+		assert_eq!(insts.get(3).unwrap().line(), Some(3));
+
+		insts.remove(2).unwrap();
+		insts.remove(2).unwrap();
+		assert_eq!(targets(&insts)[1], Some(2));
+		assert!(insts.get(2).unwrap().is(0));
+	}
+
+	#[test]
+	fn test_loop_fixup() {
+		let mut insts = instructions(vec![
+			Opcode::encode_AsBx(32, 0, 1),   // FORPREP 0 1
+			Opcode::encode_ABC(12, 4, 4, 3), // ADD 4 4 3
+			Opcode::encode_AsBx(31, 0, -2),  // FORLOOP 0 -2
+			Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+		]);
+
+		insts
+			.replace_range(
+				1..2,
+				vec![
+					IRInstruction::new_abc(0, 5, 3, 0),
+					IRInstruction::new_abc(12, 4, 4, 5),
+				],
+			)
+			.unwrap();
+		assert_eq!(targets(&insts), vec![Some(3), None, None, Some(1), None]);
+	}
+
+	#[test]
+	fn test_edit_errors() {
+		// EQ, then a JMP over as many instructions as sBx can encode
+		let mut raw = vec![
+			Opcode::encode_ABC(23, 0, 0, 1),
+			Opcode::encode_AsBx(22, 0, 0x20000),
+		];
+		raw.resize(0x20002, Opcode::encode_ABC(0, 0, 0, 0));
+		raw.push(Opcode::encode_ABC(30, 0, 1, 0));
+		let mut insts = instructions(raw);
+		let nop = || vec![IRInstruction::new_abc(0, 0, 0, 0)];
+
+		assert_eq!(insts.insert_after(0, nop()), Err(EditError::SplitsSkip(0)));
+		assert_eq!(insts.remove(1).err(), Some(EditError::SplitsSkip(0)));
+		assert_eq!(
+			insts.insert_before(5, nop()),
+			Err(EditError::JumpOutOfRange(1))
+		);
+		assert_eq!(
+			insts.insert_before(0x30000, nop()),
+			Err(EditError::OutOfBounds(0x30000))
+		);
+		assert_eq!(insts.len(), 0x20003);
+
+		// landing on new code keeps the distance
+		insts.insert_before(0x20002, nop()).unwrap();
+		assert_eq!(insts.get(1).unwrap().jump_target(1), Some(0x20002));
+	}
+
+	#[test]
+	fn test_labels() {
+		let mut insts = instructions(vec![
+			Opcode::encode_AsBx(22, 0, 1),   // JMP 1
+			Opcode::encode_ABx(1, 0, 0),     // LOADK 0 0
+			Opcode::encode_ABC(30, 0, 2, 0), // RETURN 0 2
+		]);
+		let ret = insts.get(0).unwrap().target().unwrap();
+		assert_eq!(insts.label_at(2), Some(ret));
+		assert_eq!(insts.find_label(ret), Some(2));
+
+		// the jump follows its label when the code is reordered
+		let mut moved = insts.get_all().clone();
+		moved.swap(1, 2);
+		let mut insts = IRInstructions::from_ir(moved);
+		insts.resolve_labels().unwrap();
+		assert_eq!(targets(&insts)[0], Some(1));
+
+		let loadk = insts.label_at(2).unwrap();
+		insts
+			.insert_after(2, vec![IRInstruction::new_jump(22, 0, loadk)])
+			.unwrap();
+		assert_eq!(targets(&insts)[3], Some(2));
+
+		insts.get_mut(0).unwrap().set_target(Some(Label(99)));
+		assert_eq!(
+			insts.resolve_labels(),
+			Err(EditError::UndefinedLabel(Label(99)))
+		);
+		insts.get_mut(0).unwrap().set_target(Some(ret));
+
+		// copies must drop the labels of the original
+		let copy = insts.get(1).unwrap().clone();
+		assert_eq!(
+			insts.insert_after(3, vec![copy]),
+			Err(EditError::DuplicateLabel(ret))
+		);
+		let mut copy = insts.get(1).unwrap().clone();
+		copy.take_labels();
+		insts.insert_after(3, vec![copy]).unwrap();
+		assert_eq!(insts.find_label(ret), Some(1));
+	}
+
+	#[test]
+	fn test_pseudo_instructions() {
+		let mut context = IRContext::from_proto(Proto {
+			instructions: vec![
+				Opcode::encode_ABx(36, 1, 0),    // CLOSURE 1 0
+				Opcode::encode_ABC(0, 0, 0, 0),  // MOVE 0 0, captures R0
+				Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+			]
+			.into_iter()
+			.map(Opcode::from_serialized)
+			.collect(),
+			prototypes: vec![Proto {
+				nupvals: 1,
+				..Default::default()
+			}],
+			..Default::default()
+		});
+		assert_eq!(context.pseudo_instructions(), vec![false, true, false]);
+
+		let insts = &mut context.instructions;
+		let nop = || vec![IRInstruction::new_abc(0, 0, 0, 0)];
+		assert_eq!(
+			insts.insert_before(1, nop()),
+			Err(EditError::InsidePseudo(1))
+		);
+
+		// the capture stays attached to its CLOSURE
+		insts.insert_after(0, nop()).unwrap();
+		assert_eq!(
+			context.pseudo_instructions(),
+			vec![false, true, false, false]
+		);
+
+		context.instructions.remove(0).unwrap();
+		assert_eq!(context.pseudo_instructions(), vec![false, false]);
+	}
+
+	#[test]
+	fn test_local_scopes() {
+		let mut insts = instructions(vec![
+			Opcode::encode_ABC(3, 0, 1, 0),  // LOADNIL 0 1
+			Opcode::encode_ABx(1, 0, 0),     // LOADK 0 0
+			Opcode::encode_ABx(1, 1, 0),     // LOADK 1 0
+			Opcode::encode_ABC(12, 2, 0, 1), // ADD 2 0 1
+			Opcode::encode_ABC(30, 2, 2, 0), // RETURN 2 2
+		]);
+		let local = |name: &str, start_pc, end_pc| Local {
+			name: name.to_string(),
+			start_pc,
+			end_pc,
+		};
+		insts.set_locals(vec![local("a", 2, 5), local("b", 3, 5), local("c", 4, 5)]);
+		let scopes = |insts: &IRInstructions| {
+			insts
+				.locals()
+				.iter()
+				.map(|local| (local.start_pc, local.end_pc))
+				.collect::<Vec<_>>()
+		};
+		let nop = || IRInstruction::new_abc(0, 0, 0, 0);
+
+		insts.insert_before(0, vec![nop()]).unwrap();
+		assert_eq!(scopes(&insts), vec![(3, 6), (4, 6), (5, 6)]);
+		insts.remove(1).unwrap();
+		assert_eq!(scopes(&insts), vec![(2, 5), (3, 5), (4, 5)]);
+
+		// what replaces the start of a scope is inside it
+		insts.replace_range(2..4, vec![nop()]).unwrap();
+		assert_eq!(scopes(&insts), vec![(2, 4), (2, 4), (3, 4)]);
+
+		// bounds follow the instruction they name
+		insts.move_range(0..1, 3).unwrap();
+		assert_eq!(scopes(&insts), vec![(1, 4), (1, 4), (3, 4)]);
+
+		// rearranged wholesale, scopes cover where their instructions went
+		let locals = vec![local("a", 0, 4), local("b", 1, 2), local("c", 2, 3)];
+		insts.set_moved_locals(&locals, &[Some(3), Some(1), None, Some(0)]);
+		assert_eq!(scopes(&insts), vec![(0, 4), (1, 2)]);
+	}
+}
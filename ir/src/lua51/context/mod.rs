@@ -1,30 +1,43 @@
-use std::fmt::Display;
+use std::{cmp::Ordering, fmt::Display};
 
 use crate::traits::{Context, IROperand};
-use bytecode::lua51::{instructions::Value, Proto};
-
-use self::instructions::IRInstruction;
+use bytecode::lua51::{instructions::Value, Local, Proto};
 
 mod constants;
 mod instructions;
 
+pub use constants::{IRConstant, IRConstants};
+pub use instructions::{EditError, Edits, IRInstruction, IRInstructions, Label};
+
 pub type Source = String;
 pub type NumberOfUpvalues = u8;
 pub type NumberOfParams = u8;
 pub type IsVararg = u8;
 pub type MaxStackSize = u8;
 
+/// Registers a function may use, MAXSTACK in lparser.h
+pub const MAX_STACK: usize = 250;
+
+/// Largest constant index an RK operand can address
+pub const MAX_RK_CONSTANT: usize = 0xff;
+
 /**
  * IRContext - A wrapper for a Proto
- * Strips debug information
+ * Keeps line information, local and upvalue names. Local scopes follow
+ * the instructions they cover through edits
  */
+#[derive(Clone)]
 pub struct IRContext {
 	/* normal proto stuff */
 	source: Source,
+	line_defined: u32,
+	last_line_defined: u32,
 
 	nupvalues: NumberOfUpvalues,
 	nparams: NumberOfParams,
 	vararg: IsVararg,
+	max_stack_size: MaxStackSize,
+	upvalue_names: Vec<String>,
 
 	pub instructions: instructions::IRInstructions,
 	pub constants: constants::IRConstants,
@@ -33,11 +46,15 @@ pub struct IRContext {
 
 impl IRContext {
 	pub fn from_proto(proto: Proto) -> Self {
-		Self {
+		let mut context = Self {
 			source: proto.source,
+			line_defined: proto.line_defined,
+			last_line_defined: proto.last_line_defined,
 			nupvalues: proto.nupvals,
 			nparams: proto.nparams,
 			vararg: proto.is_vararg_flag,
+			max_stack_size: proto.max_stack_size,
+			upvalue_names: proto.upvals.unwrap_or_default(),
 			instructions: instructions::IRInstructions::from_instructions(proto.instructions),
 			constants: constants::IRConstants::from_constants(proto.constants),
 			closures: proto
@@ -45,57 +62,511 @@ impl IRContext {
 				.into_iter()
 				.map(|proto| Box::new(Self::from_proto(proto)))
 				.collect(),
+		};
+
+		let pseudo = context.find_pseudo_instructions();
+		context.instructions.set_pseudo(&pseudo);
+		if let Some(lines) = proto.source_lines {
+			context.instructions.set_lines(&lines);
+		}
+		if let Some(locals) = proto.locals {
+			context.instructions.set_locals(locals);
+		}
+
+		context
+	}
+
+	/// The function as a Proto again, for serialize_bytecode
+	pub fn to_proto(&self) -> Proto {
+		let has_lines = self
+			.instructions
+			.get_all()
+			.iter()
+			.any(|inst| inst.line().is_some());
+		Proto {
+			source: self.source.clone(),
+			line_defined: self.line_defined,
+			last_line_defined: self.last_line_defined,
+			nupvals: self.nupvalues,
+			nparams: self.nparams,
+			is_vararg_flag: self.vararg,
+			max_stack_size: self.max_stack_size,
+			instructions: self.instructions.to_instructions(),
+			constants: self
+				.constants
+				.get_all()
+				.iter()
+				.map(|constant| constant.get().clone())
+				.collect(),
+			prototypes: self
+				.closures
+				.iter()
+				.map(|closure| closure.to_proto())
+				.collect(),
+			source_lines: has_lines.then(|| self.instructions.lines()),
+			locals: Some(self.locals().to_vec()),
+			upvals: Some(self.upvalue_names.clone()),
 		}
 	}
 
-	fn get_constant_references(&self, constant_idx: usize) -> Vec<(usize, IROperand<Value>)> {
-		let insts = self.get_constant_instructions();
-		let mut vals = vec![];
+	/// Chunk name, empty for functions nested in a chunk
+	pub fn source(&self) -> &str {
+		&self.source
+	}
+
+	pub fn nupvalues(&self) -> NumberOfUpvalues {
+		self.nupvalues
+	}
+
+	pub fn nparams(&self) -> NumberOfParams {
+		self.nparams
+	}
+
+	pub fn max_stack_size(&self) -> MaxStackSize {
+		self.max_stack_size
+	}
+
+	pub fn set_max_stack_size(&mut self, max_stack_size: MaxStackSize) {
+		self.max_stack_size = max_stack_size;
+	}
+
+	pub fn vararg(&self) -> IsVararg {
+		self.vararg
+	}
 
-		for pc in insts {
-			if let Some(inst) = self.instructions.get(pc) {
-				let consts = get_constant_values(inst);
-				for kst_operand in consts {
-					if let Some(kst) = kst_operand.get_kst() {
-						vals.push((pc, kst_operand))
+	/// Local variables from debug info, empty when stripped
+	pub fn locals(&self) -> &[Local] {
+		self.instructions.locals()
+	}
+
+	/// Upvalue names from debug info, empty when stripped
+	pub fn upvalue_names(&self) -> &[String] {
+		&self.upvalue_names
+	}
+
+	/// Marks instructions that are operands of the instruction before them rather
+	/// than executed themselves: the upvalue MOVE/GETUPVAL list after CLOSURE and
+	/// the raw block number after a SETLIST with C = 0
+	pub fn pseudo_instructions(&self) -> Vec<bool> {
+		self.instructions
+			.iter()
+			.map(|inst| inst.is_pseudo())
+			.collect()
+	}
+
+	pub(crate) fn find_pseudo_instructions(&self) -> Vec<bool> {
+		let len = self.instructions.len();
+		let mut pseudo = vec![false; len];
+
+		let mut pc = 0;
+		while pc < len {
+			let inst = self.instructions.get(pc).unwrap();
+			let skip = match inst.opcode() {
+				36 => inst
+					.get_bx()
+					.get_reg()
+					.and_then(|bx| self.closures.get(bx as usize))
+					.map_or(0, |closure| closure.nupvalues as usize),
+				34 if inst.get_c().get_reg() == Some(0) => 1,
+				_ => 0,
+			};
+			pseudo[pc + 1..(pc + 1 + skip).min(len)].fill(true);
+			pc += 1 + skip;
+		}
+
+		pseudo
+	}
+
+	/// Moves constant `order[i]` to index i
+	/// `order` must be a permutation of the pool, constants pushed out of
+	/// reach of RK operands are spilled
+	pub fn reorder_constants(&mut self, order: &[usize]) -> Result<(), EditError> {
+		let mut seen = vec![false; self.constants.len()];
+		for idx in order {
+			assert!(!seen[*idx], "constant {idx} is listed twice");
+			seen[*idx] = true;
+		}
+		assert!(seen.iter().all(|seen| *seen), "order is not a permutation");
+
+		self.rebuild_constants(order);
+		self.spill_constants().map(|_| ())
+	}
+
+	/// Stable sort of the constant pool
+	pub fn sort_constants_by(
+		&mut self,
+		mut compare: impl FnMut(&IRConstant, &IRConstant) -> Ordering,
+	) -> Result<(), EditError> {
+		let mut order = (0..self.constants.len()).collect::<Vec<_>>();
+		order.sort_by(|a, b| {
+			compare(
+				self.constants.get(*a).unwrap(),
+				self.constants.get(*b).unwrap(),
+			)
+		});
+		self.reorder_constants(&order)
+	}
+
+	/// Drops constants no instruction references, returns how many were removed
+	pub fn remove_unused_constants(&mut self) -> usize {
+		let mut used = vec![false; self.constants.len()];
+		for inst in self.instructions.iter() {
+			for (reference, operand) in get_operand_references(inst) {
+				if reference == Reference::Constant {
+					used[operand.get_kst().unwrap()] = true;
+				}
+			}
+		}
+
+		let order = (0..used.len()).filter(|idx| used[*idx]).collect::<Vec<_>>();
+		let removed = used.len() - order.len();
+		self.rebuild_constants(&order);
+		removed
+	}
+
+	/// Merges equal constants into their first occurrence, returns how many were merged
+	pub fn merge_duplicate_constants(&mut self) -> usize {
+		let all = self.constants.get_all();
+		let mut order: Vec<usize> = vec![];
+		let mut map = vec![0; all.len()];
+		for (idx, constant) in all.iter().enumerate() {
+			match order.iter().position(|kept| all[*kept].same(constant)) {
+				Some(new) => map[idx] = new,
+				None => {
+					map[idx] = order.len();
+					order.push(idx);
+				}
+			}
+		}
+
+		let merged = all.len() - order.len();
+		self.map_constants(&map);
+		self.constants.reorder(&order);
+		merged
+	}
+
+	/// Loads RK operands above constant 255 into temporaries
+	/// Each such operand gets a LOADK into a register past max_stack_size right
+	/// before its instruction, which reads the register instead. Returns the
+	/// number of spilled operands
+	pub fn spill_constants(&mut self) -> Result<usize, EditError> {
+		let base = self.max_stack_size as usize;
+		let mut needed = 0;
+		let mut spilled = 0;
+		let mut pc = 0;
+
+		while pc < self.instructions.len() {
+			let mut inst = self.instructions.get(pc).unwrap().clone();
+			let far = get_operand_references(&inst)
+				.into_iter()
+				.filter(|(reference, operand)| {
+					*reference == Reference::Constant
+						&& matches!(operand.value(), Some(Value::RK(_)))
+						&& operand.get_kst().unwrap() > MAX_RK_CONSTANT
+				})
+				.map(|(_, operand)| operand)
+				.collect::<Vec<_>>();
+			if far.is_empty() {
+				pc += 1;
+				continue;
+			}
+			if base + far.len() > MAX_STACK {
+				return Err(EditError::NoFreeRegister(pc));
+			}
+
+			inst.take_labels();
+			let mut insts = vec![];
+			for (i, mut operand) in far.into_iter().enumerate() {
+				let reg = (base + i) as u8;
+				insts.push(IRInstruction::new_abx(
+					1,
+					reg,
+					operand.get_kst().unwrap() as u32,
+				));
+				operand.modify(Value::RK(reg as u32));
+				inst.modify(operand);
+			}
+			let loads = insts.len();
+			insts.push(inst);
+
+			self.instructions.replace_range(pc..pc + 1, insts)?;
+			needed = needed.max(loads);
+			spilled += loads;
+			pc += loads + 1;
+		}
+
+		self.max_stack_size = (base + needed) as u8;
+		Ok(spilled)
+	}
+
+	/// Frees a register for each of `names` right after the parameters, and
+	/// after `arg` when VARARG_NEEDSARG makes one, by moving every register
+	/// above up. Debug info, when kept, names them as locals. Returns the
+	/// first freed register. LOADNILs reaching across are split, any other
+	/// range of registers doing so gives NoFreeRegister
+	pub fn reserve_registers(&mut self, names: &[&str]) -> Result<u8, EditError> {
+		let first = self.nparams as usize + (self.vararg & 4 != 0) as usize;
+		let count = names.len();
+		if self.max_stack_size as usize + count > MAX_STACK {
+			return Err(EditError::NoFreeRegister(0));
+		}
+
+		let mut split = vec![];
+		for (pc, inst) in self.instructions.iter().enumerate() {
+			if inst.is_pseudo() {
+				continue;
+			}
+			match register_range(inst) {
+				Some((start, end)) if start < first && end >= first => {
+					let skipped = pc > 0 && {
+						let prev = self.instructions.get(pc - 1).unwrap();
+						!prev.is_pseudo() && prev.is_skip()
+					};
+					if !inst.is(3) || skipped {
+						return Err(EditError::NoFreeRegister(pc));
 					}
+					split.push(pc);
 				}
+				_ => {}
+			}
+		}
+		for pc in split.into_iter().rev() {
+			let inst = self.instructions.get(pc).unwrap();
+			let a = inst.get_a().get_reg().unwrap();
+			let b = inst.get_b().get_reg().unwrap() as u16;
+			let below = IRInstruction::new_abc(3, a, first as u16 - 1, 0);
+			let above = IRInstruction::new_abc(3, first as u8, b, 0);
+			self.instructions
+				.replace_range(pc..pc + 1, vec![below, above])?;
+		}
+
+		// captures after a CLOSURE name registers, a SETLIST block does not
+		let mut captures = false;
+		for pc in 0..self.instructions.len() {
+			let inst = self.instructions.get(pc).unwrap();
+			if !inst.is_pseudo() {
+				captures = inst.is(36);
+			} else if !captures {
+				continue;
 			}
+			let refs = get_operand_references(inst);
+			let inst = self.instructions.get_mut(pc).unwrap();
+			for (reference, mut operand) in refs {
+				let reg = match operand.get_reg() {
+					Some(reg) if reference == Reference::Register && reg as usize >= first => {
+						reg + count as u8
+					}
+					_ => continue,
+				};
+				if let IROperand::Operand(_, value) = &mut operand {
+					*value = match value {
+						Value::RK(_) => Value::RK(reg as u32),
+						_ => Value::Reg(reg),
+					};
+				}
+				inst.modify(operand);
+			}
+		}
+		self.max_stack_size += count as u8;
+
+		if !self.locals().is_empty() {
+			let end_pc = self.instructions.len() as u64;
+			let reserved = names.iter().map(|name| Local {
+				name: name.to_string(),
+				start_pc: 0,
+				end_pc,
+			});
+			let mut locals = self.locals().to_vec();
+			let at = first.min(locals.len());
+			locals.splice(at..at, reserved);
+			self.instructions.set_locals(locals);
+		}
+
+		Ok(first as u8)
+	}
+
+	/// Keeps the constants listed in `order`, every reference must be to one of them
+	fn rebuild_constants(&mut self, order: &[usize]) {
+		let mut map = vec![usize::MAX; self.constants.len()];
+		for (new, old) in order.iter().enumerate() {
+			map[*old] = new;
 		}
+		self.map_constants(&map);
+		self.constants.reorder(order);
+	}
 
-		// vals
-		vals
+	/// Points every constant reference to `map[old]`
+	fn map_constants(&mut self, map: &[usize]) {
+		for pc in 0..self.instructions.len() {
+			let refs = get_operand_references(self.instructions.get(pc).unwrap());
+			let inst = self.instructions.get_mut(pc).unwrap();
+			for (reference, mut operand) in refs {
+				if reference != Reference::Constant {
+					continue;
+				}
+				let kst = operand.get_kst().unwrap();
+				if let IROperand::Operand(_, value) = &mut operand {
+					value.set_constant(map[kst]);
+				}
+				inst.modify(operand);
+			}
+		}
+	}
+
+	/// Operands referencing constant `constant_idx`
+	pub fn get_constant_references(&self, constant_idx: usize) -> Vec<(usize, IROperand<Value>)> {
+		self.get_references(Reference::Constant, constant_idx)
+	}
+
+	/// Operands of kind `kind` referencing `idx`, keyed by pc
+	fn get_references(&self, kind: Reference, idx: usize) -> Vec<(usize, IROperand<Value>)> {
+		let mut refs = vec![];
+
+		for (pc, inst) in self.instructions.iter().enumerate() {
+			for (reference, operand) in get_operand_references(inst) {
+				if reference == kind && reference.index(&operand) == Some(idx) {
+					refs.push((pc, operand));
+				}
+			}
+		}
+
+		refs
+	}
+
+	/// Points every reference of kind `kind` to `from` at `to` instead
+	fn remap(&mut self, kind: Reference, from: usize, to: usize) {
+		for (pc, mut operand) in self.get_references(kind, from) {
+			if let IROperand::Operand(_, value) = &mut operand {
+				*value = match value {
+					Value::RK(_) if kind == Reference::Constant => Value::RK(to as u32 + 0x100),
+					Value::RK(_) => Value::RK(to as u32),
+					Value::Kst(_) => Value::Kst(to as u32),
+					_ => Value::Reg(to as u8),
+				};
+			}
+
+			if let Some(inst) = self.instructions.get_mut(pc) {
+				inst.modify(operand);
+			}
+		}
+	}
+}
+
+/// What an operand refers to
+#[derive(Clone, Copy, PartialEq)]
+enum Reference {
+	Register,
+	Constant,
+	Upvalue,
+	Closure,
+}
+
+impl Reference {
+	/// Index the operand refers to
+	fn index(&self, operand: &IROperand<Value>) -> Option<usize> {
+		match self {
+			Self::Register => operand.get_reg().map(|reg| reg as usize),
+			Self::Constant => operand.get_kst(),
+			Self::Upvalue | Self::Closure => operand.value().map(|val| match val {
+				Value::Reg(v) => *v as usize,
+				Value::Kst(v) | Value::RK(v) => *v as usize,
+				Value::sBx(v) => *v as usize,
+			}),
+		}
 	}
 }
 
-fn get_constant_values(inst: &IRInstruction) -> Vec<IROperand<Value>> {
-	let mut values = vec![];
+/**
+ * get_operand_references - Explicit references of an instruction
+ * Registers implied by an operand, like the arguments of a CALL or the
+ * range of a LOADNIL, are not listed
+ */
+fn get_operand_references(inst: &IRInstruction) -> Vec<(Reference, IROperand<Value>)> {
+	use Reference::*;
+
+	let rk = |operand: IROperand<Value>| match operand.get_kst() {
+		Some(_) => (Constant, operand),
+		None => (Register, operand),
+	};
+
+	// upvalue captures of a CLOSURE name a register or an upvalue in B
+	if inst.is_pseudo() {
+		return match inst.opcode() {
+			0 => vec![(Register, inst.get_b())],
+			4 => vec![(Upvalue, inst.get_b())],
+			_ => vec![],
+		};
+	}
+
+	let mut refs = match inst.opcode() {
+		// EQ, LT, LE use A as a flag
+		23..=25 => vec![rk(inst.get_b()), rk(inst.get_c())],
+		22 => vec![],
+		_ => vec![(Register, inst.get_a())],
+	};
 
 	match inst.opcode() {
+		// MOVE, LOADNIL, UNM, NOT, LEN, TESTSET
+		0 | 3 | 18 | 19 | 20 | 27 => refs.push((Register, inst.get_b())),
+
 		// LOADK, GETGLOBAL, SETGLOBAL
-		1 | 5 | 7 => values.push(inst.get_bx()),
+		1 | 5 | 7 => refs.push((Constant, inst.get_bx())),
+
+		// GETUPVAL, SETUPVAL
+		4 | 8 => refs.push((Upvalue, inst.get_b())),
 
 		// GETTABLE, SELF
-		6 | 11 => values.push(inst.get_c()),
+		6 | 11 => {
+			refs.push((Register, inst.get_b()));
+			refs.push(rk(inst.get_c()))
+		}
 
-		// SETTABLE, ADD, SUB, MUL, DIV, MOD, POW, EQ, LT, LE
-		9 | 12 | 13 | 14 | 15 | 16 | 17 | 23 | 24 | 25 => {
-			values.push(inst.get_b());
-			values.push(inst.get_c())
+		// SETTABLE, ADD, SUB, MUL, DIV, MOD, POW
+		9 | 12..=17 => {
+			refs.push(rk(inst.get_b()));
+			refs.push(rk(inst.get_c()))
 		}
+
+		// CONCAT
+		21 => {
+			refs.push((Register, inst.get_b()));
+			refs.push((Register, inst.get_c()))
+		}
+
+		// CLOSURE
+		36 => refs.push((Closure, inst.get_bx())),
 		_ => {}
 	}
 
-	values
-		.into_iter()
-		.filter(|op| match op {
-			IROperand::None => false,
-			_ => true,
-		})
+	refs.into_iter()
+		.filter(|(_, op)| !matches!(op, IROperand::None))
 		.collect()
 }
 
+/// Registers an instruction uses as a range rather than through its operands,
+/// usize::MAX ending ranges open to the top of the stack
+fn register_range(inst: &IRInstruction) -> Option<(usize, usize)> {
+	let raw = |operand: IROperand<Value>| operand.get_reg().map_or(0, |reg| reg as usize);
+	let (a, b, c) = (raw(inst.get_a()), raw(inst.get_b()), raw(inst.get_c()));
+	let open = |n: usize, end: usize| if n == 0 { usize::MAX } else { end };
+	let end = match inst.opcode() {
+		3 => b,
+		11 => a + 1,
+		21 => return Some((b, c)),
+		28 => open(b, (a + b).saturating_sub(1)).max(open(c, a + c.max(2) - 2)),
+		29 => open(b, (a + b).saturating_sub(1)),
+		30 | 37 => open(b, a + b.max(2) - 2),
+		31 | 32 => a + 3,
+		33 => a + 2 + c,
+		34 => open(b, a + b),
+		_ => return None,
+	};
+	Some((a, end))
+}
+
 impl Context for IRContext {
+	type Error = EditError;
+
 	fn get_instructions(&self, opcode: usize) -> Vec<usize> {
 		self.instructions.find_all(opcode)
 	}
@@ -104,8 +575,10 @@ impl Context for IRContext {
 		let mut pcs = vec![];
 
 		for (pc, inst) in self.instructions.iter().enumerate() {
-			let constants = get_constant_values(inst);
-			if constants.len() != 0 {
+			if get_operand_references(inst)
+				.iter()
+				.any(|(reference, _)| *reference == Reference::Constant)
+			{
 				pcs.push(pc);
 			}
 		}
@@ -113,20 +586,34 @@ impl Context for IRContext {
 		pcs
 	}
 
-	fn remap_constant(&mut self, kst1: usize, kst2: usize) {
-		let references = self.get_constant_references(kst1);
-		for (pc, mut operand) in references {
-			// set new constant in operand
-			match &mut operand {
-				IROperand::Operand(operand, value) => value.set_constant(kst2),
-				_ => {}
-			}
+	/// RK operands can only name constants up to 255, those moved past it are
+	/// spilled. Nothing is changed when that fails
+	fn remap_constant(&mut self, kst1: usize, kst2: usize) -> Result<(), EditError> {
+		if kst2 <= MAX_RK_CONSTANT {
+			self.remap(Reference::Constant, kst1, kst2);
+			return Ok(());
+		}
 
-			// update instruction
-			if let Some(inst) = self.instructions.get_mut(pc) {
-				inst.modify(operand);
-			}
+		let (instructions, max_stack_size) = (self.instructions.clone(), self.max_stack_size);
+		self.remap(Reference::Constant, kst1, kst2);
+		if let Err(err) = self.spill_constants() {
+			self.instructions = instructions;
+			self.max_stack_size = max_stack_size;
+			return Err(err);
 		}
+		Ok(())
+	}
+
+	fn remap_register(&mut self, reg1: u8, reg2: u8) {
+		self.remap(Reference::Register, reg1 as usize, reg2 as usize)
+	}
+
+	fn remap_upvalue(&mut self, up1: u8, up2: u8) {
+		self.remap(Reference::Upvalue, up1 as usize, up2 as usize)
+	}
+
+	fn remap_closure(&mut self, closure1: usize, closure2: usize) {
+		self.remap(Reference::Closure, closure1, closure2)
 	}
 }
 
@@ -155,3 +642,355 @@ impl Display for IRContext {
 		write!(f, "\n")
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{EditError, IRConstant, IRContext, Reference, MAX_STACK};
+	use crate::{
+		lua51::fixtures::{context, listing, numbers, proto},
+		traits::Context,
+	};
+	use bytecode::lua51::{instructions::Opcode, Constants, Local, Proto};
+
+	/// pcs referencing `idx`, once per operand
+	fn references(context: &IRContext, kind: Reference, idx: usize) -> Vec<usize> {
+		context
+			.get_references(kind, idx)
+			.into_iter()
+			.map(|(pc, _)| pc)
+			.collect()
+	}
+
+	#[test]
+	fn test_remap_constant() {
+		const K: u16 = 0x100;
+		let mut context = context(
+			vec![
+				Opcode::encode_ABx(1, 0, 0),         // LOADK
+				Opcode::encode_ABx(5, 0, 0),         // GETGLOBAL
+				Opcode::encode_ABx(7, 0, 0),         // SETGLOBAL
+				Opcode::encode_ABC(6, 0, 0, K),      // GETTABLE
+				Opcode::encode_ABC(9, 0, K, K),      // SETTABLE
+				Opcode::encode_ABC(11, 0, 0, K),     // SELF
+				Opcode::encode_ABC(12, 0, K, K + 1), // ADD
+				Opcode::encode_ABC(13, 0, K + 1, K), // SUB
+				Opcode::encode_ABC(14, 0, K, 0),     // MUL
+				Opcode::encode_ABC(15, 0, 0, K),     // DIV
+				Opcode::encode_ABC(16, 0, K, K),     // MOD
+				Opcode::encode_ABC(17, 0, K, K),     // POW
+				Opcode::encode_ABC(23, 1, K, 0),     // EQ
+				Opcode::encode_ABC(24, 1, 0, K),     // LT
+				Opcode::encode_ABC(25, 0, K, K),     // LE
+				Opcode::encode_ABx(1, 0, 1),         // LOADK of another constant
+				Opcode::encode_ABC(30, 0, 1, 0),     // RETURN
+			],
+			numbers(&[0.0, 1.0, 2.0, 3.0, 4.0, 5.0]),
+		);
+		let before = references(&context, Reference::Constant, 0);
+		assert_eq!(
+			context.get_constant_instructions(),
+			(0..16).collect::<Vec<_>>()
+		);
+
+		context.remap_constant(0, 5).unwrap();
+		assert!(context.get_constant_references(0).is_empty());
+		assert_eq!(references(&context, Reference::Constant, 5), before);
+		assert_eq!(references(&context, Reference::Constant, 1), vec![6, 7, 15]);
+
+		let listing = context
+			.instructions
+			.iter()
+			.map(|inst| inst.to_string())
+			.collect::<Vec<_>>();
+		assert_eq!(listing[0], "LOADK     \t0 5");
+		assert_eq!(listing[6], "ADD       \t0 261 257");
+		assert_eq!(listing[12], "EQ        \t1 261 0");
+	}
+
+	#[test]
+	fn test_remap_constant_past_rk() {
+		const K: u16 = 0x100;
+		let mut context = context(
+			vec![
+				Opcode::encode_ABC(12, 0, K, K), // ADD
+				Opcode::encode_ABx(1, 1, 0),     // LOADK
+				Opcode::encode_ABC(30, 0, 2, 0), // RETURN
+			],
+			numbers(&[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]),
+		);
+		context.set_max_stack_size(2);
+		for n in 8..=300 {
+			context.constants.add_number(n as f64);
+		}
+		let listing = |context: &IRContext| {
+			context
+				.instructions
+				.iter()
+				.map(|inst| inst.to_string())
+				.collect::<Vec<_>>()
+		};
+
+		// no registers left to load into, nothing changes
+		let mut full = context.clone();
+		full.set_max_stack_size(MAX_STACK as u8 - 1);
+		assert_eq!(
+			full.remap_constant(0, 300),
+			Err(EditError::NoFreeRegister(0))
+		);
+		assert_eq!(listing(&full), listing(&context));
+
+		context.remap_constant(0, 300).unwrap();
+		assert_eq!(
+			listing(&context),
+			vec![
+				"LOADK     \t2 300",
+				"LOADK     \t3 300",
+				"ADD       \t0 2 3",
+				"LOADK     \t1 300",
+				"RETURN    \t0 2",
+			]
+		);
+		assert_eq!(context.max_stack_size(), 4);
+	}
+
+	#[test]
+	fn test_remap_register() {
+		let mut context = IRContext::from_proto(proto(
+			vec![
+				Opcode::encode_ABC(0, 1, 1, 0),     // MOVE
+				Opcode::encode_ABx(1, 1, 0),        // LOADK
+				Opcode::encode_ABC(2, 1, 1, 1),     // LOADBOOL, only A
+				Opcode::encode_ABC(3, 1, 1, 0),     // LOADNIL
+				Opcode::encode_ABC(4, 1, 1, 0),     // GETUPVAL, B is an upvalue
+				Opcode::encode_ABx(5, 1, 0),        // GETGLOBAL
+				Opcode::encode_ABC(6, 1, 1, 1),     // GETTABLE
+				Opcode::encode_ABx(7, 1, 0),        // SETGLOBAL
+				Opcode::encode_ABC(8, 1, 1, 0),     // SETUPVAL, B is an upvalue
+				Opcode::encode_ABC(9, 1, 1, 0x101), // SETTABLE
+				Opcode::encode_ABC(10, 1, 1, 1),    // NEWTABLE, only A
+				Opcode::encode_ABC(11, 1, 1, 1),    // SELF
+				Opcode::encode_ABC(12, 1, 1, 1),    // ADD
+				Opcode::encode_ABC(18, 1, 1, 0),    // UNM
+				Opcode::encode_ABC(21, 1, 1, 1),    // CONCAT
+				Opcode::encode_ABC(23, 1, 1, 1),    // EQ, A is a flag
+				Opcode::encode_ABC(26, 1, 0, 1),    // TEST
+				Opcode::encode_ABC(27, 1, 1, 1),    // TESTSET
+				Opcode::encode_ABC(28, 1, 1, 1),    // CALL
+				Opcode::encode_AsBx(32, 1, 0),      // FORPREP
+				Opcode::encode_AsBx(31, 1, -1),     // FORLOOP
+				Opcode::encode_ABC(33, 1, 0, 1),    // TFORLOOP
+				Opcode::encode_ABC(34, 1, 1, 1),    // SETLIST
+				Opcode::encode_ABC(35, 1, 0, 0),    // CLOSE
+				Opcode::encode_ABx(36, 1, 0),       // CLOSURE
+				Opcode::encode_ABC(0, 0, 1, 0),     // capture of R1
+				Opcode::encode_ABC(37, 1, 1, 0),    // VARARG
+				Opcode::encode_ABC(30, 1, 1, 0),    // RETURN
+			],
+			numbers(&[0.0]),
+			vec![Proto {
+				nupvals: 1,
+				..Default::default()
+			}],
+		));
+		let before = references(&context, Reference::Register, 1);
+		assert_eq!(before.len(), 42);
+
+		context.remap_register(1, 7);
+		assert!(references(&context, Reference::Register, 1).is_empty());
+		assert_eq!(references(&context, Reference::Register, 7), before);
+
+		// literals and upvalue indices keep their value
+		let listing = context
+			.instructions
+			.iter()
+			.map(|inst| inst.to_string())
+			.collect::<Vec<_>>();
+		assert_eq!(listing[2], "LOADBOOL  \t7 1 1");
+		assert_eq!(listing[4], "GETUPVAL  \t7 1");
+		assert_eq!(listing[9], "SETTABLE  \t7 7 257");
+		assert_eq!(listing[15], "EQ        \t1 7 7");
+		assert_eq!(listing[25], "MOVE      \t0 7");
+	}
+
+	#[test]
+	fn test_remap_upvalue_and_closure() {
+		let mut context = IRContext::from_proto(proto(
+			vec![
+				Opcode::encode_ABC(4, 0, 1, 0),  // GETUPVAL 0 1
+				Opcode::encode_ABC(8, 0, 1, 0),  // SETUPVAL 0 1
+				Opcode::encode_ABx(36, 1, 0),    // CLOSURE 1 0
+				Opcode::encode_ABC(4, 0, 1, 0),  // captures upvalue 1
+				Opcode::encode_ABC(0, 0, 1, 0),  // captures R1
+				Opcode::encode_ABx(36, 2, 1),    // CLOSURE 2 1
+				Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+			],
+			vec![],
+			vec![
+				Proto {
+					nupvals: 2,
+					..Default::default()
+				},
+				Proto::default(),
+			],
+		));
+
+		context.remap_upvalue(1, 3);
+		assert_eq!(references(&context, Reference::Upvalue, 3), vec![0, 1, 3]);
+		assert_eq!(references(&context, Reference::Register, 1), vec![2, 4]);
+
+		context.remap_closure(0, 1);
+		assert_eq!(references(&context, Reference::Closure, 1), vec![2, 5]);
+		assert!(references(&context, Reference::Closure, 0).is_empty());
+	}
+
+	#[test]
+	fn test_constant_pool() {
+		let mut context = IRContext::from_proto(Proto {
+			instructions: vec![
+				Opcode::encode_ABx(5, 0, 3),         // GETGLOBAL 0 "print"
+				Opcode::encode_ABx(1, 1, 4),         // LOADK 1 2
+				Opcode::encode_ABC(12, 1, 1, 0x101), // ADD 1 1 2
+				Opcode::encode_ABC(28, 0, 2, 1),     // CALL 0 2 1
+				Opcode::encode_ABC(30, 0, 1, 0),     // RETURN 0 1
+			]
+			.into_iter()
+			.map(Opcode::from_serialized)
+			.collect(),
+			constants: vec![
+				Constants::Number(0.0),
+				Constants::Number(2.0),
+				Constants::Number(-0.0),
+				Constants::String("print".to_string()),
+				Constants::Number(2.0),
+			],
+			..Default::default()
+		});
+
+		assert_eq!(context.merge_duplicate_constants(), 1);
+		assert_eq!(context.constants.len(), 4);
+		assert_eq!(listing(&context)[1], "LOADK     \t1 1");
+
+		assert_eq!(context.remove_unused_constants(), 2);
+		assert_eq!(listing(&context)[0], "GETGLOBAL \t0 1");
+		assert_eq!(listing(&context)[2], "ADD       \t1 1 256");
+
+		context.reorder_constants(&[1, 0]).unwrap();
+		assert!(context.constants.get(0).unwrap().is_string("print"));
+		assert_eq!(
+			listing(&context)[..3],
+			["GETGLOBAL \t0 0", "LOADK     \t1 1", "ADD       \t1 1 257"]
+		);
+
+		context
+			.sort_constants_by(|a, b| match (a.get(), b.get()) {
+				(Constants::Number(_), Constants::String(_)) => std::cmp::Ordering::Less,
+				(Constants::String(_), Constants::Number(_)) => std::cmp::Ordering::Greater,
+				_ => std::cmp::Ordering::Equal,
+			})
+			.unwrap();
+		assert!(context.constants.get(0).unwrap().is_number(2.0));
+		assert_eq!(listing(&context)[0], "GETGLOBAL \t0 1");
+	}
+
+	#[test]
+	fn test_spill_constants() {
+		// if x == k then x = x + k end, with k pushed past RK range
+		let mut context = IRContext::from_proto(Proto {
+			max_stack_size: 2,
+			instructions: vec![
+				Opcode::encode_ABC(23, 0, 0, 0x100),    // EQ 0 0 k
+				Opcode::encode_AsBx(22, 0, 1),          // JMP 1
+				Opcode::encode_ABC(12, 0, 0, 0x100),    // ADD 0 0 k
+				Opcode::encode_ABC(9, 1, 0x100, 0x100), // SETTABLE 1 k k
+				Opcode::encode_ABC(30, 0, 1, 0),        // RETURN 0 1
+			]
+			.into_iter()
+			.map(Opcode::from_serialized)
+			.collect(),
+			constants: (0..300).map(|n| Constants::Number(n as f64)).collect(),
+			..Default::default()
+		});
+
+		let mut order = (1..300).collect::<Vec<_>>();
+		order.push(0);
+		context.reorder_constants(&order).unwrap();
+
+		assert_eq!(context.max_stack_size(), 4);
+		assert_eq!(
+			listing(&context),
+			vec![
+				"LOADK     \t2 299",
+				"EQ        \t0 0 2",
+				"JMP       \t2",
+				"LOADK     \t2 299",
+				"ADD       \t0 0 2",
+				"LOADK     \t2 299",
+				"LOADK     \t3 299",
+				"SETTABLE  \t1 2 3",
+				"RETURN    \t0 1",
+			]
+		);
+	}
+
+	#[test]
+	fn test_reserve_registers() {
+		// function(a) local x, y = nil; x = a; y = x + 0; return function() y end end
+		let proto = |instructions: Vec<u32>| Proto {
+			nparams: 1,
+			max_stack_size: 3,
+			instructions: instructions
+				.into_iter()
+				.map(Opcode::from_serialized)
+				.collect(),
+			constants: vec![Constants::Number(0.0)],
+			prototypes: vec![Proto {
+				nupvals: 1,
+				..Default::default()
+			}],
+			locals: Some(
+				["a", "x", "y"]
+					.map(|name| Local {
+						name: name.to_string(),
+						start_pc: 0,
+						end_pc: 1,
+					})
+					.to_vec(),
+			),
+			..Default::default()
+		};
+		let mut context = IRContext::from_proto(proto(vec![
+			Opcode::encode_ABC(3, 0, 2, 0),      // LOADNIL 0 2
+			Opcode::encode_ABC(0, 1, 0, 0),      // MOVE 1 0
+			Opcode::encode_ABC(12, 2, 1, 0x100), // ADD 2 1 0
+			Opcode::encode_ABx(36, 1, 0),        // CLOSURE 1 0
+			Opcode::encode_ABC(0, 0, 2, 0),      // capture of R2
+			Opcode::encode_ABC(30, 1, 2, 0),     // RETURN 1 2
+		]));
+
+		assert_eq!(context.reserve_registers(&["r1", "r2"]), Ok(1));
+		assert_eq!(context.max_stack_size(), 5);
+		assert_eq!(
+			listing(&context),
+			vec![
+				"LOADNIL   \t0 0",
+				"LOADNIL   \t3 4",
+				"MOVE      \t3 0",
+				"ADD       \t4 3 256",
+				"CLOSURE   \t3 0",
+				"MOVE      \t0 4",
+				"RETURN    \t3 2",
+			]
+		);
+		let names = context.locals().iter().map(|local| local.name.as_str());
+		assert_eq!(names.collect::<Vec<_>>(), ["a", "r1", "r2", "x", "y"]);
+
+		// return a, x can not be moved apart
+		let mut context = IRContext::from_proto(proto(vec![
+			Opcode::encode_ABC(30, 0, 3, 0), // RETURN 0 3
+		]));
+		assert_eq!(
+			context.reserve_registers(&["r"]),
+			Err(EditError::NoFreeRegister(0))
+		);
+	}
+}
@@ -0,0 +1,169 @@
+use super::RegisterSet;
+use crate::lua51::{context::IRInstruction, IRContext};
+
+/**
+ * DefUse - Registers an instruction touches
+ * `defs` are registers that may be written, `kills` the subset that is
+ * always overwritten. Operands reading or writing up to "top" (B = 0 or
+ * C = 0 on CALL, RETURN, VARARG, SETLIST) are widened to the whole stack
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DefUse {
+	pub uses: RegisterSet,
+	pub defs: RegisterSet,
+	pub kills: RegisterSet,
+}
+
+impl DefUse {
+	fn read(&mut self, reg: Option<u8>) {
+		if let Some(reg) = reg {
+			self.uses.insert(reg);
+		}
+	}
+
+	fn read_range(&mut self, from: usize, to: usize) {
+		self.uses.union(&RegisterSet::from_range(from..to.min(256)));
+	}
+
+	fn write(&mut self, reg: usize) {
+		self.write_range(reg, reg + 1);
+	}
+
+	fn write_range(&mut self, from: usize, to: usize) {
+		let range = RegisterSet::from_range(from..to.min(256));
+		self.defs.union(&range);
+		self.kills.union(&range);
+	}
+
+	fn may_write(&mut self, reg: usize) {
+		self.defs.insert(reg as u8);
+	}
+}
+
+fn arg(inst: &IRInstruction, operand: char) -> usize {
+	match operand {
+		'a' => inst.get_a(),
+		'b' => inst.get_b(),
+		_ => inst.get_c(),
+	}
+	.get_reg()
+	.unwrap_or(0) as usize
+}
+
+fn instruction_def_use(inst: &IRInstruction, stack_top: usize) -> DefUse {
+	let mut du = DefUse::default();
+	let (a, b, c) = (arg(inst, 'a'), arg(inst, 'b'), arg(inst, 'c'));
+
+	match inst.opcode() {
+		// MOVE, UNM, NOT, LEN
+		0 | 18 | 19 | 20 => {
+			du.read(Some(b as u8));
+			du.write(a);
+		}
+		// LOADK, LOADBOOL, GETUPVAL, GETGLOBAL, NEWTABLE, CLOSURE
+		1 | 2 | 4 | 5 | 10 | 36 => du.write(a),
+		// LOADNIL
+		3 => du.write_range(a, b + 1),
+		// GETTABLE
+		6 => {
+			du.read(Some(b as u8));
+			du.read(inst.get_c().get_reg());
+			du.write(a);
+		}
+		// SETGLOBAL, SETUPVAL, TEST
+		7 | 8 | 26 => du.read(Some(a as u8)),
+		// SETTABLE
+		9 => {
+			du.read(Some(a as u8));
+			du.read(inst.get_b().get_reg());
+			du.read(inst.get_c().get_reg());
+		}
+		// SELF
+		11 => {
+			du.read(Some(b as u8));
+			du.read(inst.get_c().get_reg());
+			du.write_range(a, a + 2);
+		}
+		// ADD, SUB, MUL, DIV, MOD, POW
+		12..=17 => {
+			du.read(inst.get_b().get_reg());
+			du.read(inst.get_c().get_reg());
+			du.write(a);
+		}
+		// CONCAT
+		21 => {
+			du.read_range(b, c + 1);
+			du.write(a);
+		}
+		// EQ, LT, LE
+		23..=25 => {
+			du.read(inst.get_b().get_reg());
+			du.read(inst.get_c().get_reg());
+		}
+		// TESTSET only writes A when it does not skip
+		27 => {
+			du.read(Some(b as u8));
+			du.may_write(a);
+		}
+		// CALL, TAILCALL
+		28 | 29 => {
+			du.read(Some(a as u8));
+			du.read_range(a + 1, if b == 0 { stack_top } else { a + b });
+			if inst.is(28) {
+				du.write_range(a, if c == 0 { stack_top } else { a + c - 1 });
+			}
+		}
+		// RETURN
+		30 => du.read_range(a, if b == 0 { stack_top } else { a + b - 1 }),
+		// FORLOOP, the loop variable is only copied when the loop continues
+		31 => {
+			du.read_range(a, a + 3);
+			du.write(a);
+			du.may_write(a + 3);
+		}
+		// FORPREP
+		32 => {
+			du.read_range(a, a + 3);
+			du.write(a);
+		}
+		// TFORLOOP, the control variable is only updated when the loop continues
+		33 => {
+			du.read_range(a, a + 3);
+			du.write_range(a + 3, a + 3 + c);
+			du.may_write(a + 2);
+		}
+		// SETLIST
+		34 => du.read_range(a, if b == 0 { stack_top } else { a + b + 1 }),
+		// VARARG
+		37 => du.write_range(a, if b == 0 { stack_top } else { a + b - 1 }),
+		// JMP, CLOSE
+		_ => {}
+	}
+
+	du
+}
+
+/// Registers read and written by every instruction of `ctx`
+pub fn def_use(ctx: &IRContext) -> Vec<DefUse> {
+	let pseudo = ctx.pseudo_instructions();
+	let stack_top = (ctx.max_stack_size() as usize).max(1);
+
+	let mut table = vec![];
+	let mut owner = 0;
+	for (pc, inst) in ctx.instructions.iter().enumerate() {
+		if !pseudo[pc] {
+			owner = inst.opcode();
+			table.push(instruction_def_use(inst, stack_top));
+			continue;
+		}
+
+		// upvalue captures read the register they close over
+		let mut du = DefUse::default();
+		if owner == 36 && inst.is(0) {
+			du.read(inst.get_b().get_reg());
+		}
+		table.push(du);
+	}
+
+	table
+}
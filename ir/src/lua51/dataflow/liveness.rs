@@ -0,0 +1,64 @@
+use super::{def_use, Analysis, DataflowResults, DefUse, Direction, RegisterSet};
+use crate::lua51::IRContext;
+
+/**
+ * Liveness - Registers whose current value may still be read
+ * Registers captured as upvalues are treated as live everywhere, any
+ * call may observe them through the closure
+ */
+pub struct Liveness {
+	table: Vec<DefUse>,
+	captured: RegisterSet,
+}
+
+impl Liveness {
+	pub fn new(ctx: &IRContext) -> Self {
+		let table = def_use(ctx);
+		let pseudo = ctx.pseudo_instructions();
+
+		let mut captured = RegisterSet::new();
+		for (pc, du) in table.iter().enumerate() {
+			if pseudo[pc] {
+				captured.union(&du.uses);
+			}
+		}
+
+		Self { table, captured }
+	}
+
+	pub fn def_use(&self, pc: usize) -> &DefUse {
+		&self.table[pc]
+	}
+
+	pub fn captured(&self) -> &RegisterSet {
+		&self.captured
+	}
+}
+
+impl Analysis for Liveness {
+	type Domain = RegisterSet;
+
+	const DIRECTION: Direction = Direction::Backward;
+
+	fn bottom(&self) -> RegisterSet {
+		self.captured
+	}
+
+	fn boundary(&self) -> RegisterSet {
+		self.captured
+	}
+
+	fn transfer(&self, pc: usize, state: &mut RegisterSet) {
+		let du = &self.table[pc];
+		state.difference(&du.kills);
+		state.union(&du.uses);
+		state.union(&self.captured);
+	}
+}
+
+impl DataflowResults<Liveness> {
+	/// Whether `reg` may be read after the instruction at `pc`
+	pub fn is_live_after(&self, pc: usize, reg: u8) -> bool {
+		self.after(pc).contains(reg)
+	}
+}
@@ -0,0 +1,324 @@
+use super::CFG;
+use std::{
+	collections::{BTreeSet, VecDeque},
+	ops::Range,
+};
+
+mod defuse;
+mod liveness;
+mod reaching;
+
+pub use defuse::{def_use, DefUse};
+pub use liveness::Liveness;
+pub use reaching::{Definition, ReachingDefinitions, Site};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+	Forward,
+	Backward,
+}
+
+pub trait Lattice: Clone + PartialEq {
+	/// Merges `other` into `self`, returns whether `self` changed
+	fn join(&mut self, other: &Self) -> bool;
+}
+
+/**
+ * Analysis - A dataflow problem over the instructions of one function
+ * The solver works on whole blocks and replays `transfer` to refine
+ * the state down to single instructions
+ */
+pub trait Analysis {
+	type Domain: Lattice;
+
+	const DIRECTION: Direction;
+
+	/// Initial state of every block before solving
+	fn bottom(&self) -> Self::Domain;
+
+	/// State on function entry (forward) or on every function exit (backward)
+	fn boundary(&self) -> Self::Domain;
+
+	/// Applies the instruction at `pc` to `state`, in the direction of the analysis
+	fn transfer(&self, pc: usize, state: &mut Self::Domain);
+}
+
+/// A set of registers, Lua 5.1 addresses at most 256 of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct RegisterSet([u64; 4]);
+
+impl RegisterSet {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn from_range(range: Range<usize>) -> Self {
+		let mut set = Self::new();
+		range.for_each(|reg| set.insert(reg as u8));
+		set
+	}
+
+	pub fn insert(&mut self, reg: u8) {
+		self.0[reg as usize / 64] |= 1 << (reg % 64);
+	}
+
+	pub fn remove(&mut self, reg: u8) {
+		self.0[reg as usize / 64] &= !(1 << (reg % 64));
+	}
+
+	pub fn contains(&self, reg: u8) -> bool {
+		self.0[reg as usize / 64] & (1 << (reg % 64)) != 0
+	}
+
+	pub fn union(&mut self, other: &Self) {
+		for (word, other) in self.0.iter_mut().zip(other.0) {
+			*word |= other;
+		}
+	}
+
+	pub fn difference(&mut self, other: &Self) {
+		for (word, other) in self.0.iter_mut().zip(other.0) {
+			*word &= !other;
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.iter().all(|word| *word == 0)
+	}
+
+	pub fn len(&self) -> usize {
+		self.0.iter().map(|word| word.count_ones() as usize).sum()
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+		(0..=255u8).filter(|reg| self.contains(*reg))
+	}
+}
+
+impl Lattice for RegisterSet {
+	fn join(&mut self, other: &Self) -> bool {
+		let before = *self;
+		self.union(other);
+		before != *self
+	}
+}
+
+impl<T: Ord + Clone> Lattice for BTreeSet<T> {
+	fn join(&mut self, other: &Self) -> bool {
+		let len = self.len();
+		self.extend(other.iter().cloned());
+		len != self.len()
+	}
+}
+
+/**
+ * DataflowResults - Solved block states of an analysis
+ * `entry` and `exit` are in program order for both directions
+ */
+pub struct DataflowResults<A: Analysis> {
+	analysis: A,
+	ranges: Vec<Range<usize>>,
+	entry: Vec<A::Domain>,
+	exit: Vec<A::Domain>,
+}
+
+impl<A: Analysis> DataflowResults<A> {
+	pub fn analysis(&self) -> &A {
+		&self.analysis
+	}
+
+	/// State at the start of `block`
+	pub fn block_entry(&self, block: usize) -> &A::Domain {
+		&self.entry[block]
+	}
+
+	/// State at the end of `block`
+	pub fn block_exit(&self, block: usize) -> &A::Domain {
+		&self.exit[block]
+	}
+
+	fn block_of(&self, pc: usize) -> usize {
+		self.ranges
+			.iter()
+			.position(|range| range.contains(&pc))
+			.expect("pc is outside of the analysed function")
+	}
+
+	/// State right before the instruction at `pc` executes
+	pub fn before(&self, pc: usize) -> A::Domain {
+		let block = self.block_of(pc);
+		match A::DIRECTION {
+			Direction::Forward => {
+				let mut state = self.entry[block].clone();
+				for inst_pc in self.ranges[block].start..pc {
+					self.analysis.transfer(inst_pc, &mut state);
+				}
+				state
+			}
+			Direction::Backward => {
+				let mut state = self.after(pc);
+				self.analysis.transfer(pc, &mut state);
+				state
+			}
+		}
+	}
+
+	/// State right after the instruction at `pc` executes
+	pub fn after(&self, pc: usize) -> A::Domain {
+		let block = self.block_of(pc);
+		match A::DIRECTION {
+			Direction::Forward => {
+				let mut state = self.before(pc);
+				self.analysis.transfer(pc, &mut state);
+				state
+			}
+			Direction::Backward => {
+				let mut state = self.exit[block].clone();
+				for inst_pc in (pc + 1..self.ranges[block].end).rev() {
+					self.analysis.transfer(inst_pc, &mut state);
+				}
+				state
+			}
+		}
+	}
+}
+
+/// Solves `analysis` over `cfg` with a worklist until every block state is stable
+pub fn solve<A: Analysis>(cfg: &CFG, analysis: A) -> DataflowResults<A> {
+	let len = cfg.len();
+	let ranges = cfg
+		.iter()
+		.map(|block| block.range().clone())
+		.collect::<Vec<_>>();
+	let successors = (0..len)
+		.map(|block| cfg.successors(block))
+		.collect::<Vec<_>>();
+	let mut predecessors = vec![vec![]; len];
+	for (block, succs) in successors.iter().enumerate() {
+		for succ in succs {
+			predecessors[*succ].push(block);
+		}
+	}
+
+	let mut entry = vec![analysis.bottom(); len];
+	let mut exit = vec![analysis.bottom(); len];
+
+	// blocks are queued in the order the analysis visits them
+	let mut worklist = match A::DIRECTION {
+		Direction::Forward => (0..len).collect::<VecDeque<_>>(),
+		Direction::Backward => (0..len).rev().collect::<VecDeque<_>>(),
+	};
+	let mut queued = vec![true; len];
+
+	while let Some(block) = worklist.pop_front() {
+		queued[block] = false;
+
+		let dependents = match A::DIRECTION {
+			Direction::Forward => {
+				let mut state = if block == 0 {
+					analysis.boundary()
+				} else {
+					analysis.bottom()
+				};
+				for pred in &predecessors[block] {
+					state.join(&exit[*pred]);
+				}
+				entry[block] = state.clone();
+
+				for pc in ranges[block].clone() {
+					analysis.transfer(pc, &mut state);
+				}
+				if state == exit[block] {
+					continue;
+				}
+				exit[block] = state;
+				&successors[block]
+			}
+			Direction::Backward => {
+				let mut state = if successors[block].is_empty() {
+					analysis.boundary()
+				} else {
+					analysis.bottom()
+				};
+				for succ in &successors[block] {
+					state.join(&entry[*succ]);
+				}
+				exit[block] = state.clone();
+
+				for pc in ranges[block].clone().rev() {
+					analysis.transfer(pc, &mut state);
+				}
+				if state == entry[block] {
+					continue;
+				}
+				entry[block] = state;
+				&predecessors[block]
+			}
+		};
+
+		for dependent in dependents {
+			if !queued[*dependent] {
+				queued[*dependent] = true;
+				worklist.push_back(*dependent);
+			}
+		}
+	}
+
+	DataflowResults {
+		analysis,
+		ranges,
+		entry,
+		exit,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{solve, Liveness, ReachingDefinitions, Site};
+	use crate::lua51::{
+		fixtures::{self, numbers},
+		IRContext, CFG,
+	};
+	use bytecode::lua51::instructions::Opcode;
+
+	fn context() -> IRContext {
+		// local a, b = 1, 2; if a == b then a = b end; return a
+		let instructions = vec![
+			Opcode::encode_ABx(1, 0, 0),     // LOADK 0 0
+			Opcode::encode_ABx(1, 1, 1),     // LOADK 1 1
+			Opcode::encode_ABC(23, 0, 0, 1), // EQ 0 0 1
+			Opcode::encode_AsBx(22, 0, 1),   // JMP 1
+			Opcode::encode_ABC(0, 0, 1, 0),  // MOVE 0 1
+			Opcode::encode_ABC(30, 0, 2, 0), // RETURN 0 2
+			Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+		];
+		fixtures::context(instructions, numbers(&[1.0, 2.0]))
+	}
+
+	#[test]
+	fn test_liveness() {
+		let context = context();
+		let cfg = CFG::from_instructions(&context.instructions);
+		assert_eq!(cfg.successors(0), vec![1, 2]);
+		assert_eq!(cfg.predecessors(3), vec![1, 2]);
+
+		let liveness = solve(&cfg, Liveness::new(&context));
+		assert!(liveness.before(0).is_empty());
+		assert!(liveness.is_live_after(1, 0) && liveness.is_live_after(1, 1));
+		assert!(!liveness.before(4).contains(0));
+		assert!(liveness.before(4).contains(1));
+		assert!(!liveness.is_live_after(4, 1));
+		assert!(liveness.block_exit(4).is_empty());
+	}
+
+	#[test]
+	fn test_reaching_definitions() {
+		let context = context();
+		let cfg = CFG::from_instructions(&context.instructions);
+
+		let reaching = solve(&cfg, ReachingDefinitions::new(&context));
+		assert_eq!(reaching.reaching(0, 0), vec![Site::Entry]);
+		assert_eq!(reaching.reaching(4, 1), vec![Site::Pc(1)]);
+		assert_eq!(reaching.reaching(5, 0), vec![Site::Pc(0), Site::Pc(4)]);
+	}
+}
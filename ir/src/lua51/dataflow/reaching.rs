@@ -0,0 +1,75 @@
+use super::{def_use, Analysis, DataflowResults, DefUse, Direction};
+use crate::lua51::IRContext;
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Site {
+	Entry,     // parameter or the initial nil of a register
+	Pc(usize), // written by an instruction
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Definition {
+	pub register: u8,
+	pub site: Site,
+}
+
+/**
+ * ReachingDefinitions - Which writes of a register may still be visible
+ */
+pub struct ReachingDefinitions {
+	table: Vec<DefUse>,
+	registers: usize,
+}
+
+impl ReachingDefinitions {
+	pub fn new(ctx: &IRContext) -> Self {
+		Self {
+			table: def_use(ctx),
+			registers: ctx.max_stack_size() as usize,
+		}
+	}
+}
+
+impl Analysis for ReachingDefinitions {
+	type Domain = BTreeSet<Definition>;
+
+	const DIRECTION: Direction = Direction::Forward;
+
+	fn bottom(&self) -> Self::Domain {
+		BTreeSet::new()
+	}
+
+	fn boundary(&self) -> Self::Domain {
+		(0..self.registers)
+			.map(|reg| Definition {
+				register: reg as u8,
+				site: Site::Entry,
+			})
+			.collect()
+	}
+
+	fn transfer(&self, pc: usize, state: &mut Self::Domain) {
+		let du = &self.table[pc];
+		if !du.kills.is_empty() {
+			state.retain(|def| !du.kills.contains(def.register));
+		}
+		for register in du.defs.iter() {
+			state.insert(Definition {
+				register,
+				site: Site::Pc(pc),
+			});
+		}
+	}
+}
+
+impl DataflowResults<ReachingDefinitions> {
+	/// Sites whose write of `reg` may be read by the instruction at `pc`
+	pub fn reaching(&self, pc: usize, reg: u8) -> Vec<Site> {
+		self.before(pc)
+			.into_iter()
+			.filter(|def| def.register == reg)
+			.map(|def| def.site)
+			.collect()
+	}
+}
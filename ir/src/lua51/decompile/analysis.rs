@@ -0,0 +1,527 @@
+use crate::lua51::{
+	context::IRInstruction,
+	dataflow::{
+		def_use, solve, Analysis, DefUse, Definition, ReachingDefinitions, RegisterSet, Site,
+	},
+	passes::operands,
+	IRContext, CFG,
+};
+use std::{
+	collections::{BTreeMap, BTreeSet, VecDeque},
+	ops::Range,
+};
+
+/// A local from debug info with the register it lives in
+struct DebugLocal {
+	name: String,
+	register: u8,
+	start: usize,
+	end: usize,
+}
+
+/**
+ * FunctionInfo - What the decompiler needs to know about the registers of
+ * one function. Definitions connected through a common use form a web,
+ * every web that is not folded into an expression becomes a variable
+ */
+pub(super) struct FunctionInfo<'a> {
+	pub ctx: &'a IRContext,
+	pub pseudo: Vec<bool>,
+	pub targets: Vec<bool>, // pcs a jump or skip lands on
+	blocks: Vec<usize>,
+	table: Vec<DefUse>,
+	reads: Vec<Vec<u8>>,
+	before: Vec<BTreeSet<Definition>>,
+	index: BTreeMap<Definition, usize>,
+	parent: Vec<usize>,
+	uses: Vec<Vec<usize>>,
+	captured: Vec<bool>, // by web root
+	live: Vec<RegisterSet>,
+	locals: Vec<DebugLocal>,
+	jumps: Vec<(usize, usize)>, // every transfer other than falling through
+}
+
+impl<'a> FunctionInfo<'a> {
+	pub fn new(ctx: &'a IRContext) -> Self {
+		let len = ctx.instructions.len();
+		let cfg = CFG::from_instructions(&ctx.instructions);
+		let mut blocks = vec![0; len];
+		for (block, range) in cfg.iter().map(|block| block.range().clone()).enumerate() {
+			blocks[range].fill(block);
+		}
+
+		let mut info = Self {
+			ctx,
+			pseudo: ctx.pseudo_instructions(),
+			targets: vec![false; len + 1],
+			blocks,
+			table: def_use(ctx),
+			reads: vec![],
+			before: vec![BTreeSet::new(); len],
+			index: BTreeMap::new(),
+			parent: vec![],
+			uses: vec![],
+			captured: vec![],
+			live: vec![RegisterSet::new(); len + 1],
+			locals: vec![],
+			jumps: vec![],
+		};
+
+		for pc in 0..len {
+			if info.pseudo[pc] {
+				continue;
+			}
+			let inst = ctx.instructions.get(pc).unwrap();
+			if let Some(target) = inst.jump_target(pc) {
+				info.targets[target.min(len)] = true;
+			}
+			if inst.is_skip() {
+				info.targets[(pc + 2).min(len)] = true;
+			}
+		}
+		info.reads = (0..len).map(|pc| info.compute_reads(pc)).collect();
+		for pc in (0..len).filter(|pc| !info.pseudo[*pc]) {
+			let next = pc + 1 + info.pseudo_after(pc);
+			for succ in info.successors(pc) {
+				if succ != next {
+					info.jumps.push((pc, succ));
+				}
+			}
+		}
+
+		let results = solve(&cfg, ReachingDefinitions::new(ctx));
+		for block in 0..cfg.len() {
+			let mut state = results.block_entry(block).clone();
+			for pc in cfg.get_block(block).unwrap().range().clone() {
+				info.before[pc] = state.clone();
+				results.analysis().transfer(pc, &mut state);
+			}
+		}
+
+		for reg in 0..=255u8 {
+			info.add_definition(Definition {
+				register: reg,
+				site: Site::Entry,
+			});
+		}
+		for pc in 0..len {
+			for reg in info.table[pc].defs.iter().collect::<Vec<_>>() {
+				info.add_definition(Definition {
+					register: reg,
+					site: Site::Pc(pc),
+				});
+			}
+		}
+
+		for pc in 0..len {
+			for reg in info.reads[pc].clone() {
+				let reaching = info.reaching(pc, reg);
+				for def in &reaching {
+					let idx = info.index[def];
+					info.uses[idx].push(pc);
+					info.union(idx, info.index[&reaching[0]]);
+				}
+			}
+		}
+
+		info.capture_webs();
+		info.solve_liveness();
+		info.load_locals();
+		info
+	}
+
+	pub fn len(&self) -> usize {
+		self.pseudo.len()
+	}
+
+	pub fn inst(&self, pc: usize) -> &IRInstruction {
+		self.ctx.instructions.get(pc).unwrap()
+	}
+
+	/// Opcode and raw A, B and C of the instruction at `pc`
+	pub fn op(&self, pc: usize) -> (usize, u8, u16, u16) {
+		let inst = self.inst(pc);
+		let (a, b, c) = operands(inst);
+		(inst.opcode(), a, b, c)
+	}
+
+	/// Whether the instruction at `pc` is executed and has `opcode`
+	pub fn is(&self, pc: usize, opcode: usize) -> bool {
+		pc < self.len() && !self.pseudo[pc] && self.inst(pc).is(opcode)
+	}
+
+	/// Number of pseudo instructions following `pc`
+	pub fn pseudo_after(&self, pc: usize) -> usize {
+		self.pseudo[pc + 1..].iter().take_while(|p| **p).count()
+	}
+
+	pub fn same_block(&self, a: usize, b: usize) -> bool {
+		self.blocks.get(a) == self.blocks.get(b)
+	}
+
+	/// Register the multiple results read by a B = 0 operand at `pc` start at
+	pub fn open_register(&self, pc: usize) -> Option<u8> {
+		(0..pc)
+			.rev()
+			.take_while(|prev| self.same_block(*prev, pc))
+			.filter(|prev| !self.pseudo[*prev])
+			.find_map(|prev| match self.op(prev) {
+				(28, a, _, 0) | (37, a, 0, _) => Some(a),
+				_ => None,
+			})
+	}
+
+	/// Whether the JMP at `pc` enters a generic for loop, returns the TFORLOOP
+	pub fn generic_for(&self, pc: usize) -> Option<usize> {
+		let target = self.inst(pc).jump_target(pc)?;
+		(self.is(pc, 22)
+			&& self.is(target, 33)
+			&& self.is(target + 1, 22)
+			&& self.inst(target + 1).jump_target(target + 1) == Some(pc + 1))
+		.then_some(target)
+	}
+
+	/// Registers the instruction at `pc` reads as written in source, the loop
+	/// instructions read their hidden state and are left out, the JMP into a
+	/// generic for reads its explist instead
+	fn compute_reads(&self, pc: usize) -> Vec<u8> {
+		if self.pseudo[pc] {
+			return vec![];
+		}
+		let (opcode, a, b, c) = self.op(pc);
+		let reg = |v: u16| (v <= 0xff).then_some(v as u8);
+		let range =
+			|from: usize, to: usize| (from..to.min(256)).map(|r| r as u8).collect::<Vec<_>>();
+		let open = |from: usize| match self.open_register(pc) {
+			Some(top) if top as usize >= from => range(from, top as usize + 1),
+			_ => vec![],
+		};
+
+		let mut reads = match opcode {
+			0 | 18 | 19 | 20 | 27 => vec![b as u8],
+			6 | 11 => [Some(b as u8), reg(c)].into_iter().flatten().collect(),
+			7 | 8 | 26 => vec![a],
+			9 => [Some(a), reg(b), reg(c)].into_iter().flatten().collect(),
+			12..=17 | 23..=25 => [reg(b), reg(c)].into_iter().flatten().collect(),
+			21 => range(b as usize, c as usize + 1),
+			28 | 29 if b == 0 => [vec![a], open(a as usize + 1)].concat(),
+			28 | 29 => range(a as usize, a as usize + b as usize),
+			// only passes on the results of a tail call before it
+			30 if pc > 0 && self.is(pc - 1, 29) => vec![],
+			30 if b == 0 => open(a as usize),
+			30 => range(a as usize, a as usize + b as usize - 1),
+			32 => range(a as usize, a as usize + 3),
+			34 if b == 0 => [vec![a], open(a as usize + 1)].concat(),
+			34 => range(a as usize, a as usize + b as usize + 1),
+			36 => (pc + 1..pc + 1 + self.pseudo_after(pc))
+				.filter(|capture| self.inst(*capture).is(0))
+				.map(|capture| self.op(capture).2 as u8)
+				.collect(),
+			22 => match self.generic_for(pc) {
+				Some(tforloop) => {
+					let a = self.op(tforloop).1 as usize;
+					range(a, a + 3)
+				}
+				None => vec![],
+			},
+			_ => vec![],
+		};
+		reads.dedup();
+		reads
+	}
+
+	pub fn reads(&self, pc: usize) -> &[u8] {
+		&self.reads[pc]
+	}
+
+	fn add_definition(&mut self, def: Definition) {
+		if !self.index.contains_key(&def) {
+			self.index.insert(def, self.parent.len());
+			self.parent.push(self.parent.len());
+			self.uses.push(vec![]);
+			self.captured.push(false);
+		}
+	}
+
+	fn find(&self, mut idx: usize) -> usize {
+		while self.parent[idx] != idx {
+			idx = self.parent[idx];
+		}
+		idx
+	}
+
+	fn union(&mut self, a: usize, b: usize) {
+		let (a, b) = (self.find(a), self.find(b));
+		if a != b {
+			self.parent[a.max(b)] = a.min(b);
+			self.captured[a.min(b)] |= self.captured[a.max(b)];
+		}
+	}
+
+	/// Definitions of `reg` that may be visible right before `pc`
+	pub fn reaching(&self, pc: usize, reg: u8) -> Vec<Definition> {
+		self.before[pc]
+			.iter()
+			.filter(|def| def.register == reg)
+			.copied()
+			.collect()
+	}
+
+	/// Whether the instruction of `def` may write its register
+	pub fn defines(&self, def: Definition) -> bool {
+		self.index.contains_key(&def)
+	}
+
+	/// Web, and so variable, a definition belongs to
+	pub fn web(&self, def: Definition) -> usize {
+		self.find(self.index[&def])
+	}
+
+	/// Web of the value of `reg` read at `pc`
+	pub fn web_at(&self, pc: usize, reg: u8) -> usize {
+		match self.reaching(pc, reg).first() {
+			Some(def) => self.web(*def),
+			None => self.web(Definition {
+				register: reg,
+				site: Site::Entry,
+			}),
+		}
+	}
+
+	pub fn is_captured(&self, web: usize) -> bool {
+		self.captured[self.find(web)]
+	}
+
+	/// Whether `reg` is read later without being written first
+	pub fn is_live(&self, pc: usize, reg: u8) -> bool {
+		self.live[pc].contains(reg)
+	}
+
+	/// pcs control may continue at after the instruction at `pc`
+	pub fn successors(&self, pc: usize) -> Vec<usize> {
+		let (opcode, _, _, c) = self.op(pc);
+		let inst = self.inst(pc);
+		let mut succs = match opcode {
+			22 | 32 => vec![inst.jump_target(pc).unwrap()],
+			31 => vec![inst.jump_target(pc).unwrap(), pc + 1],
+			23..=27 | 33 => vec![pc + 1, pc + 2],
+			2 if c != 0 => vec![pc + 2],
+			30 => vec![],
+			_ => vec![pc + 1 + self.pseudo_after(pc)],
+		};
+		succs.retain(|succ| *succ < self.len());
+		succs
+	}
+
+	/// Joins every write of a captured register into the captured web, up to
+	/// the CLOSE ending its scope. The closure sees all of them
+	fn capture_webs(&mut self) {
+		for pc in 0..self.len() {
+			if !self.is(pc, 36) {
+				continue;
+			}
+			for reg in self.reads[pc].clone() {
+				let web = self.web_at(pc, reg);
+				self.captured[web] = true;
+
+				let mut seen = vec![false; self.len()];
+				let mut queue = VecDeque::from([pc]);
+				while let Some(at) = queue.pop_front() {
+					if std::mem::replace(&mut seen[at], true) {
+						continue;
+					}
+					if self.is(at, 35) && self.op(at).1 <= reg {
+						continue;
+					}
+					if self.table[at].defs.contains(reg) {
+						let def = self.index[&Definition {
+							register: reg,
+							site: Site::Pc(at),
+						}];
+						self.union(web, def);
+					}
+					queue.extend(self.successors(at));
+				}
+			}
+		}
+	}
+
+	fn solve_liveness(&mut self) {
+		let len = self.len();
+		let mut changed = true;
+		while changed {
+			changed = false;
+			for pc in (0..len).rev() {
+				if self.pseudo[pc] {
+					continue;
+				}
+				let mut state = RegisterSet::new();
+				for succ in self.successors(pc) {
+					state.union(&self.live[succ]);
+				}
+				state.difference(&self.table[pc].kills);
+				for reg in &self.reads[pc] {
+					state.insert(*reg);
+				}
+				if state != self.live[pc] {
+					self.live[pc] = state;
+					changed = true;
+				}
+			}
+		}
+	}
+
+	/// Assigns registers to the locals from debug info, the n-th active local
+	/// lives in register n
+	fn load_locals(&mut self) {
+		let mut locals: Vec<DebugLocal> = vec![];
+		for local in self.ctx.locals() {
+			let (start, end) = (local.start_pc as usize, local.end_pc as usize);
+			let register = locals
+				.iter()
+				.filter(|other| other.start <= start && start < other.end)
+				.count() as u8;
+			locals.push(DebugLocal {
+				name: local.name.clone(),
+				register,
+				start,
+				end,
+			});
+		}
+		self.locals = locals;
+	}
+
+	/// Whether debug info declares a local in `reg` at `pc`
+	pub fn is_local(&self, reg: u8, pc: usize) -> bool {
+		self.locals.iter().any(|local| {
+			local.register == reg
+				&& local.start <= pc
+				&& pc < local.end
+				&& !local.name.starts_with('(')
+		})
+	}
+
+	/// Name debug info gives the variable a definition writes
+	fn local_name(&self, def: Definition) -> Option<&str> {
+		let local = match def.site {
+			Site::Entry => self
+				.locals
+				.iter()
+				.find(|local| local.register == def.register && local.start == 0),
+			// loop variables are declared where the body starts
+			Site::Pc(pc) if self.is(pc, 31) || self.is(pc, 33) => {
+				let jump = if self.is(pc, 31) { pc } else { pc + 1 };
+				let body = self.inst(jump).jump_target(jump)?;
+				self.locals
+					.iter()
+					.find(|local| local.register == def.register && local.start == body)
+			}
+			Site::Pc(pc) => self.locals.iter().find(|local| {
+				local.register == def.register && local.start <= pc + 1 && pc < local.end
+			}),
+		}?;
+		// hidden locals like (for index)
+		(!local.name.starts_with('(')).then_some(local.name.as_str())
+	}
+
+	/// Name of the first definition of `web` debug info names, else of the
+	/// local a read of it falls in, as with tables declared once filled in
+	pub fn web_name(&self, web: usize) -> Option<&str> {
+		let defs = self
+			.index
+			.iter()
+			.filter(|(_, idx)| self.find(**idx) == web)
+			.collect::<Vec<_>>();
+		defs.iter()
+			.find_map(|(def, _)| self.local_name(**def))
+			.or_else(|| {
+				defs.iter().find_map(|(def, idx)| {
+					self.uses[**idx].iter().find_map(|u| {
+						self.locals
+							.iter()
+							.find(|local| {
+								local.register == def.register
+									&& local.start <= *u && *u < local.end
+									&& !local.name.starts_with('(')
+							})
+							.map(|local| local.name.as_str())
+					})
+				})
+			})
+	}
+
+	/// Uses of a definition, reads of a NEWTABLE result filling it in the
+	/// same block are part of the constructor and left out
+	fn value_uses(&self, def: Definition) -> Vec<usize> {
+		let Site::Pc(pc) = def.site else {
+			return self.uses[self.index[&def]].clone();
+		};
+		let mut uses = self.uses[self.index[&def]].clone();
+		if self.is(pc, 10) {
+			uses.retain(|u| {
+				let (opcode, a, _, _) = self.op(*u);
+				!(matches!(opcode, 9 | 34) && a == def.register && self.same_block(pc, *u))
+			});
+		}
+		uses
+	}
+
+	/// Whether control from `start` only leaves through `end`, and nothing
+	/// but the instructions in between jumps into it
+	fn straight(&self, start: usize, end: usize) -> bool {
+		self.jumps.iter().all(|(source, target)| {
+			let inside = (start..end).contains(source);
+			let lands = (start + 1..=end).contains(target);
+			inside == lands
+		})
+	}
+
+	/// Whether the definitions together are read exactly once after `region`,
+	/// on the straight path from it, by an instruction no other definition
+	/// reaches and outside of any named local. Reads within the region
+	/// computing them do not count. Such values are folded into the
+	/// expression reading them
+	pub fn is_temporary(&self, defs: &[Definition], region: Range<usize>) -> bool {
+		self.temporary_use(defs, region).is_some()
+	}
+
+	/// The single read making the definitions temporary, see `is_temporary`
+	pub fn temporary_use(&self, defs: &[Definition], region: Range<usize>) -> Option<usize> {
+		let first = defs.first()?;
+		if self.is_captured(self.web(*first)) {
+			return None;
+		}
+
+		let mut uses = defs
+			.iter()
+			.flat_map(|def| self.value_uses(*def))
+			.filter(|u| !(region.start + 1..region.end).contains(u))
+			.collect::<Vec<_>>();
+		uses.sort();
+		uses.dedup();
+		let [u] = uses[..] else {
+			return None;
+		};
+
+		(u >= region.end
+			&& self.straight(region.start, u)
+			&& !self.is(u, 36)
+			&& !self.is_local(first.register, u)
+			&& self
+				.reaching(u, first.register)
+				.iter()
+				.all(|def| defs.contains(def)))
+		.then_some(u)
+	}
+
+	/// Whether the single definition of `reg` at `pc` is a temporary
+	pub fn is_temp(&self, pc: usize, reg: u8) -> bool {
+		self.is_temporary(
+			&[Definition {
+				register: reg,
+				site: Site::Pc(pc),
+			}],
+			pc..pc + 1,
+		)
+	}
+}
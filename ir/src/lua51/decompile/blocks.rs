@@ -0,0 +1,180 @@
+use super::{cond, declare, upvalue_vars, FunctionDecompiler, Names, Result};
+use crate::lua51::{IRContext, CFG};
+use ast::lua51::{Block, Expr, Printer};
+use std::{cell::RefCell, collections::BTreeSet, fmt::Display, ops::Range};
+
+/**
+ * BlockCode - Pseudocode of one basic block: its instructions as Lua
+ * statements, with values read once within the block folded into the
+ * expression reading them
+ */
+#[derive(Debug, Clone)]
+pub struct BlockCode {
+	pub range: Range<usize>,
+	pub stats: Block,
+	/// what the test ending the block checks, control goes on to the jump
+	/// after it when this holds. The copy a TESTSET makes on the way is
+	/// left out
+	pub condition: Option<Expr>,
+}
+
+impl Display for BlockCode {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let mut printer = Printer::default();
+		printer.block(&self.stats);
+		write!(f, "{}", printer.finish())?;
+		if let Some(condition) = &self.condition {
+			writeln!(f, "-- jump when {}", Printer::default().expr(condition, 0))?;
+		}
+		Ok(())
+	}
+}
+
+impl FunctionDecompiler<'_, '_> {
+	fn lift_block(&mut self, range: Range<usize>) -> Result<BlockCode> {
+		self.scope = Some(range.clone());
+		let mut stats = vec![];
+		let mut condition = None;
+
+		for pc in range.clone() {
+			if self.info.pseudo[pc] {
+				continue;
+			}
+			let (opcode, _, b, c) = self.info.op(pc);
+			match opcode {
+				23..=26 => condition = Some(self.test(&mut stats, pc)?),
+				27 => {
+					let value = self.take(&mut stats, pc, b as u8)?.node;
+					condition = Some(if c != 0 {
+						value
+					} else {
+						cond::negate_value(value)
+					});
+				}
+				// jumps and loop control are the edges of the graph
+				22 | 31..=33 => {}
+				30 if pc > 0 && self.info.is(pc - 1, 29) => {}
+				_ => self.lift(&mut stats, pc)?,
+			}
+		}
+
+		self.flush(&mut stats)?;
+		Ok(BlockCode {
+			range,
+			stats,
+			condition,
+		})
+	}
+}
+
+/**
+ * lift_blocks - Pseudocode of every block of the CFG of `ctx`, in block
+ * order. Unlike `decompile` no control flow is recovered, so this works
+ * on any function, but closures are still decompiled in full
+ */
+pub fn lift_blocks(ctx: &IRContext) -> Result<Vec<BlockCode>> {
+	let mut names = Names::default();
+	let upvalues = upvalue_vars(ctx, &mut names);
+	let mut decompiler = FunctionDecompiler::new(ctx, &mut names, upvalues);
+	let cfg = CFG::from_instructions(&ctx.instructions);
+	let mut codes = cfg
+		.iter()
+		.map(|block| decompiler.lift_block(block.range().clone()))
+		.collect::<Result<Vec<_>>>()?;
+
+	let used = RefCell::new(BTreeSet::new());
+	let collect = |name: &mut String| {
+		used.borrow_mut().insert(name.clone());
+	};
+	let mut globals = BTreeSet::new();
+	for code in &mut codes {
+		declare::rename_block(&mut code.stats, &collect);
+		declare::visit_exprs(&code.stats, &mut |expr| {
+			if let Expr::Global(name) = expr {
+				globals.insert(name.clone());
+			}
+		});
+		if let Some(condition) = &mut code.condition {
+			declare::rename_expr(condition, &collect);
+			declare::visit_expr(condition, &mut |expr| {
+				if let Expr::Global(name) = expr {
+					globals.insert(name.clone());
+				}
+			});
+		}
+	}
+
+	let resolved = names.resolve(&used.into_inner(), &globals);
+	let lookup = |name: &mut String| {
+		if let Some(new) = resolved.get(name) {
+			*name = new.clone();
+		}
+	};
+	for code in &mut codes {
+		declare::rename_block(&mut code.stats, &lookup);
+		if let Some(condition) = &mut code.condition {
+			declare::rename_expr(condition, &lookup);
+		}
+	}
+	Ok(codes)
+}
+
+/// Graphviz graph of the CFG of `ctx` with the pseudocode of each block
+pub fn pseudocode_graph(ctx: &IRContext) -> Result<String> {
+	let codes = lift_blocks(ctx)?;
+	let cfg = CFG::from_instructions(&ctx.instructions);
+	Ok(cfg.visualize_with(|idx, block| {
+		let code = codes[idx]
+			.to_string()
+			.replace('\\', "\\\\")
+			.replace('"', "\\\"")
+			.replace('\n', "\\l");
+		format!("Block {idx}: {:?}\\l{code}", block.range())
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::lift_blocks;
+	use crate::lua51::fixtures::{context, string};
+	use bytecode::lua51::{instructions::Opcode, Constants};
+
+	#[test]
+	fn test_lift_blocks() {
+		let ctx = context(
+			vec![
+				Opcode::encode_ABx(5, 0, 0),           // GETGLOBAL 0 t
+				Opcode::encode_ABC(6, 0, 0, 256 + 1),  // GETTABLE 0 0 "x"
+				Opcode::encode_ABC(24, 0, 0, 256 + 2), // LT 0 0 10
+				Opcode::encode_AsBx(22, 0, 4),         // JMP 4
+				Opcode::encode_ABx(5, 1, 3),           // GETGLOBAL 1 print
+				Opcode::encode_ABC(12, 2, 0, 256 + 4), // ADD 2 0 1
+				Opcode::encode_ABC(28, 1, 2, 1),       // CALL 1 2 1
+				Opcode::encode_AsBx(22, 0, -8),        // JMP -8
+				Opcode::encode_ABC(30, 0, 1, 0),       // RETURN 0 1
+			],
+			vec![
+				string("t"),
+				string("x"),
+				Constants::Number(10.0),
+				string("print"),
+				Constants::Number(1.0),
+			],
+		);
+
+		let codes = lift_blocks(&ctx)
+			.unwrap()
+			.iter()
+			.map(|code| code.to_string())
+			.collect::<Vec<_>>();
+		assert_eq!(
+			codes,
+			[
+				"v1 = t.x\n-- jump when not (v1 < 10)\n",
+				"",
+				"print(v1 + 1)\n",
+				"return\n",
+			]
+		);
+	}
+}
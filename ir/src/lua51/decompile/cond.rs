@@ -0,0 +1,338 @@
+use ast::lua51::{BinOp, Expr, UnOp};
+use std::collections::BTreeMap;
+
+/// Where a branch of a condition goes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Edge {
+	Node(usize),
+	True,
+	False,
+	/// merge point of a value region
+	End,
+}
+
+#[derive(Debug, Clone)]
+pub(super) enum Node {
+	/// goes to `taken` when `cond` holds, else to `fall`
+	Branch { cond: Expr, taken: Edge, fall: Edge },
+	/// TESTSET, ends the region with `value` when its truthiness is
+	/// `polarity`, else goes to `fall`
+	TestSet {
+		value: Expr,
+		polarity: bool,
+		fall: Edge,
+	},
+	/// computes the value of the region
+	Leaf(Expr),
+}
+
+impl Node {
+	fn edges(&self) -> Vec<Edge> {
+		match self {
+			Self::Branch { taken, fall, .. } => vec![*taken, *fall],
+			Self::TestSet { fall, .. } => vec![Edge::End, *fall],
+			Self::Leaf(_) => vec![Edge::End],
+		}
+	}
+}
+
+/// Whether `expr` always evaluates to true or false
+pub(super) fn is_boolean(expr: &Expr) -> bool {
+	match expr {
+		Expr::Bool(_) | Expr::Unary(UnOp::Not, _) => true,
+		Expr::Binary(
+			BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge | BinOp::Eq | BinOp::Ne,
+			_,
+			_,
+		) => true,
+		Expr::Binary(BinOp::And | BinOp::Or, left, right) => {
+			is_boolean(&left.node) && is_boolean(&right.node)
+		}
+		_ => false,
+	}
+}
+
+/// Whether negating `expr` takes no `not`
+fn negates_cleanly(expr: &Expr) -> bool {
+	match expr {
+		Expr::Bool(_) | Expr::Unary(UnOp::Not, _) | Expr::Binary(BinOp::Eq | BinOp::Ne, _, _) => {
+			true
+		}
+		Expr::Binary(BinOp::And | BinOp::Or, left, right) => {
+			negates_cleanly(&left.node) && negates_cleanly(&right.node)
+		}
+		_ => false,
+	}
+}
+
+/// Negation of a condition only its truthiness matters of
+pub(super) fn negate(expr: Expr) -> Expr {
+	match expr {
+		Expr::Binary(op @ (BinOp::And | BinOp::Or), left, right)
+			if negates_cleanly(&left.node) && negates_cleanly(&right.node) =>
+		{
+			let op = if op == BinOp::And {
+				BinOp::Or
+			} else {
+				BinOp::And
+			};
+			Expr::binary(op, left.map(negate), right.map(negate))
+		}
+		Expr::Unary(UnOp::Not, operand) => operand.node,
+		expr => negate_value(expr),
+	}
+}
+
+/// Expression with the value of `not expr`
+pub(super) fn negate_value(expr: Expr) -> Expr {
+	match expr {
+		Expr::Bool(b) => Expr::Bool(!b),
+		Expr::Binary(BinOp::Eq, left, right) => Expr::Binary(BinOp::Ne, left, right),
+		Expr::Binary(BinOp::Ne, left, right) => Expr::Binary(BinOp::Eq, left, right),
+		Expr::Unary(UnOp::Not, operand) if is_boolean(&operand.node) => operand.node,
+		expr => Expr::unary(UnOp::Not, expr),
+	}
+}
+
+fn and(left: Expr, right: Expr) -> Expr {
+	Expr::binary(BinOp::And, left, right)
+}
+
+fn or(left: Expr, right: Expr) -> Expr {
+	Expr::binary(BinOp::Or, left, right)
+}
+
+/// Whether `expr` is a constant other than nil and false
+fn is_truthy(expr: &Expr) -> bool {
+	matches!(expr, Expr::Bool(true) | Expr::Number(_) | Expr::String(_))
+}
+
+/// Value of `if cond then x else y` for leaves x and y
+fn select(cond: &Expr, x: &Expr, y: &Expr) -> Option<Expr> {
+	let boolean = is_boolean(cond);
+	let cond = cond.clone();
+	Some(match (x, y) {
+		(Expr::Bool(true), Expr::Bool(false)) if boolean => cond,
+		(Expr::Bool(true), Expr::Bool(false)) => negate_value(negate_value(cond)),
+		(Expr::Bool(false), Expr::Bool(true)) => negate_value(cond),
+		(Expr::Bool(true), _) if boolean => or(cond, y.clone()),
+		(_, Expr::Bool(false)) if boolean => and(cond, x.clone()),
+		(Expr::Bool(false), _) if boolean => and(negate_value(cond), y.clone()),
+		(_, Expr::Bool(true)) if boolean => or(negate_value(cond), x.clone()),
+		_ if is_truthy(x) => or(and(cond, x.clone()), y.clone()),
+		_ => return None,
+	})
+}
+
+/// Merges the node `id` with its successor if only it leads there, gives
+/// whether anything changed
+fn merge(nodes: &mut BTreeMap<usize, Node>, id: usize, preds: &BTreeMap<usize, usize>) -> bool {
+	let single = |edge: Edge| match edge {
+		Edge::Node(next) if next != id && preds.get(&next) == Some(&1) => Some(next),
+		_ => None,
+	};
+	let mut extra = None;
+	let merged = match &nodes[&id] {
+		Node::Branch { cond, taken, fall } => {
+			let (cond, taken, fall) = (cond.clone(), *taken, *fall);
+			if let Some(next) = single(taken) {
+				match nodes[&next].clone() {
+					Node::Branch {
+						cond: c2,
+						taken: t2,
+						fall: f2,
+					} if f2 == fall => Some((
+						next,
+						Node::Branch {
+							cond: and(cond, c2),
+							taken: t2,
+							fall,
+						},
+					)),
+					Node::Branch {
+						cond: c2,
+						taken: t2,
+						fall: f2,
+					} if t2 == fall => Some((
+						next,
+						Node::Branch {
+							cond: and(cond, negate(c2)),
+							taken: f2,
+							fall,
+						},
+					)),
+					Node::TestSet {
+						value,
+						polarity,
+						fall: f2,
+					} if f2 == fall => Some((
+						next,
+						Node::TestSet {
+							value: if polarity {
+								and(cond, value)
+							} else {
+								or(negate(cond), value)
+							},
+							polarity,
+							fall,
+						},
+					)),
+					Node::Leaf(x) => single(fall).and_then(|other| match &nodes[&other] {
+						Node::Leaf(y) => {
+							let value = select(&cond, &x, y)
+								.or_else(|| select(&negate_value(cond.clone()), y, &x))?;
+							extra = Some(other);
+							Some((next, Node::Leaf(value)))
+						}
+						_ => None,
+					}),
+					_ => None,
+				}
+			} else if let Some(next) = single(fall) {
+				match nodes[&next].clone() {
+					Node::Branch {
+						cond: c2,
+						taken: t2,
+						fall: f2,
+					} if t2 == taken => Some((
+						next,
+						Node::Branch {
+							cond: or(cond, c2),
+							taken,
+							fall: f2,
+						},
+					)),
+					Node::Branch {
+						cond: c2,
+						taken: t2,
+						fall: f2,
+					} if f2 == taken => Some((
+						next,
+						Node::Branch {
+							cond: or(cond, negate(c2)),
+							taken,
+							fall: t2,
+						},
+					)),
+					Node::TestSet {
+						value,
+						polarity,
+						fall: f2,
+					} if f2 == taken => Some((
+						next,
+						Node::TestSet {
+							value: if polarity {
+								and(negate(cond), value)
+							} else {
+								or(cond, value)
+							},
+							polarity,
+							fall: taken,
+						},
+					)),
+					_ => None,
+				}
+			} else {
+				None
+			}
+		}
+		Node::TestSet {
+			value,
+			polarity,
+			fall,
+		} => {
+			let (value, polarity) = (value.clone(), *polarity);
+			let next = single(*fall);
+			match next.map(|next| (next, nodes[&next].clone())) {
+				Some((
+					next,
+					Node::TestSet {
+						value: v2,
+						polarity: p2,
+						fall: f2,
+					},
+				)) if p2 == polarity => Some((
+					next,
+					Node::TestSet {
+						value: if polarity {
+							or(value, v2)
+						} else {
+							and(value, v2)
+						},
+						polarity,
+						fall: f2,
+					},
+				)),
+				Some((next, Node::Leaf(leaf))) => Some((
+					next,
+					Node::Leaf(if polarity {
+						or(value, leaf)
+					} else {
+						and(value, leaf)
+					}),
+				)),
+				_ => None,
+			}
+		}
+		Node::Leaf(_) => None,
+	};
+
+	match merged {
+		Some((next, node)) => {
+			nodes.remove(&next);
+			if let Some(other) = extra {
+				nodes.remove(&other);
+			}
+			nodes.insert(id, node);
+			true
+		}
+		None => false,
+	}
+}
+
+/// Reduces the graph of a condition or value region rooted at the first
+/// node to a single node
+fn reduce(mut nodes: BTreeMap<usize, Node>) -> Option<Node> {
+	let root = *nodes.keys().next()?;
+	while nodes.len() > 1 {
+		let mut preds = BTreeMap::new();
+		for node in nodes.values() {
+			for edge in node.edges() {
+				if let Edge::Node(next) = edge {
+					*preds.entry(next).or_insert(0) += 1;
+				}
+			}
+		}
+		// inner nodes first, so chains are merged from their end
+		let ids = nodes.keys().rev().copied().collect::<Vec<_>>();
+		if !ids.into_iter().any(|id| merge(&mut nodes, id, &preds)) {
+			return None;
+		}
+	}
+	nodes.remove(&root)
+}
+
+/// Condition under which control reaches `True` of a graph of branches
+pub(super) fn condition(nodes: BTreeMap<usize, Node>) -> Option<Expr> {
+	match reduce(nodes)? {
+		Node::Branch {
+			cond,
+			taken: Edge::True,
+			fall: Edge::False,
+		} => Some(cond),
+		Node::Branch {
+			cond,
+			taken: Edge::False,
+			fall: Edge::True,
+		} => Some(negate(cond)),
+		_ => None,
+	}
+}
+
+/// Value a region of branches, TESTSETs and leaves computes
+pub(super) fn value(nodes: BTreeMap<usize, Node>) -> Option<Expr> {
+	match reduce(nodes)? {
+		Node::Leaf(value) => Some(value),
+		_ => None,
+	}
+}
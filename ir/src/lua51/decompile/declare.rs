@@ -0,0 +1,434 @@
+use super::FunctionDecompiler;
+use ast::lua51::{Block, Call, Expr, Field, Function, Spanned, Stat};
+use std::{
+	cell::RefCell,
+	collections::{BTreeMap, BTreeSet, HashMap},
+};
+
+/// Expressions a statement evaluates itself, outside its nested blocks.
+/// The condition of a repeat belongs to the end of its body
+fn own_exprs(stat: &Stat) -> Vec<&Expr> {
+	match stat {
+		Stat::Local(_, exprs) | Stat::Return(exprs) => exprs.iter().collect(),
+		Stat::Assign(targets, values) => targets.iter().chain(values).collect(),
+		Stat::Call(call) => call_exprs(call),
+		Stat::If(cond, _, _) | Stat::While(cond, _) => vec![cond],
+		Stat::NumericFor(_, start, limit, step, _) => [Some(start), Some(limit), step.as_ref()]
+			.into_iter()
+			.flatten()
+			.collect(),
+		Stat::GenericFor(_, exprs, _) => exprs.iter().collect(),
+		Stat::LocalFunction(_, _) | Stat::Do(_) | Stat::Repeat(_, _) | Stat::Break => vec![],
+	}
+}
+
+fn call_exprs(call: &Call) -> Vec<&Expr> {
+	std::iter::once(&call.func).chain(&call.args).collect()
+}
+
+fn blocks(stat: &Stat) -> Vec<&Block> {
+	match stat {
+		Stat::If(_, then, otherwise) => vec![then, otherwise],
+		Stat::Do(body)
+		| Stat::While(_, body)
+		| Stat::Repeat(body, _)
+		| Stat::NumericFor(_, _, _, _, body)
+		| Stat::GenericFor(_, _, body) => vec![body],
+		_ => vec![],
+	}
+}
+
+fn blocks_mut(stat: &mut Stat) -> Vec<&mut Block> {
+	match stat {
+		Stat::If(_, then, otherwise) => vec![then, otherwise],
+		Stat::Do(body)
+		| Stat::While(_, body)
+		| Stat::Repeat(body, _)
+		| Stat::NumericFor(_, _, _, _, body)
+		| Stat::GenericFor(_, _, body) => vec![body],
+		_ => vec![],
+	}
+}
+
+fn is_loop(stat: &Stat) -> bool {
+	matches!(
+		stat,
+		Stat::While(_, _) | Stat::Repeat(_, _) | Stat::NumericFor(..) | Stat::GenericFor(..)
+	)
+}
+
+/// Calls `f` on every expression in `block`, nested ones and function
+/// bodies included
+pub(super) fn visit_exprs(block: &Block, f: &mut impl FnMut(&Expr)) {
+	for Spanned { node: stat, .. } in block {
+		for expr in own_exprs(stat) {
+			visit_expr(expr, f);
+		}
+		match stat {
+			Stat::Repeat(_, cond) => visit_expr(cond, f),
+			Stat::LocalFunction(_, function) => visit_exprs(&function.body, f),
+			_ => {}
+		}
+		for block in blocks(stat) {
+			visit_exprs(block, f);
+		}
+	}
+}
+
+pub(super) fn visit_expr(expr: &Expr, f: &mut impl FnMut(&Expr)) {
+	f(expr);
+	match expr {
+		Expr::Index(object, key) | Expr::Binary(_, object, key) => {
+			visit_expr(&object.node, f);
+			visit_expr(&key.node, f);
+		}
+		Expr::Call(call) => call_exprs(call)
+			.into_iter()
+			.for_each(|expr| visit_expr(expr, f)),
+		Expr::Function(function) => visit_exprs(&function.body, f),
+		Expr::Table(fields) => {
+			for field in fields {
+				match field {
+					Field::Item(value) => visit_expr(value, f),
+					Field::Pair(key, value) => {
+						visit_expr(key, f);
+						visit_expr(value, f);
+					}
+				}
+			}
+		}
+		Expr::Unary(_, operand) | Expr::Paren(operand) => visit_expr(&operand.node, f),
+		_ => {}
+	}
+}
+
+fn referenced(expr: &Expr) -> BTreeSet<String> {
+	let mut names = BTreeSet::new();
+	visit_expr(expr, &mut |expr| {
+		if let Expr::Name(name) = expr {
+			names.insert(name.clone());
+		}
+	});
+	names
+}
+
+/// A block of the function being declared in, `parent` is the enclosing
+/// block and the index of the statement holding it
+struct BlockNode {
+	parent: Option<(usize, usize)>,
+	loop_idx: Option<usize>,
+}
+
+struct Occurrences {
+	blocks: Vec<BlockNode>,
+	at: BTreeMap<String, Vec<(usize, usize)>>,
+	loops: usize,
+}
+
+impl Occurrences {
+	fn collect(&mut self, block: &Block, parent: Option<(usize, usize)>, loop_idx: Option<usize>) {
+		let id = self.blocks.len();
+		self.blocks.push(BlockNode { parent, loop_idx });
+		for (idx, Spanned { node: stat, .. }) in block.iter().enumerate() {
+			let mut found = BTreeSet::new();
+			for expr in own_exprs(stat) {
+				found.extend(referenced(expr));
+			}
+			if let Stat::LocalFunction(name, function) = stat {
+				found.insert(name.clone());
+				found.extend(referenced(&Expr::Function(Box::new(function.clone()))));
+			}
+			for name in found {
+				self.at.entry(name).or_default().push((id, idx));
+			}
+
+			let inner = is_loop(stat).then(|| {
+				self.loops += 1;
+				self.loops - 1
+			});
+			let body_id = self.blocks.len();
+			for block in blocks(stat) {
+				self.collect(block, Some((id, idx)), inner);
+			}
+			if let Stat::Repeat(body, cond) = stat {
+				for name in referenced(cond) {
+					self.at.entry(name).or_default().push((body_id, body.len()));
+				}
+			}
+		}
+	}
+
+	fn depth(&self, mut block: usize) -> usize {
+		let mut depth = 0;
+		while let Some((parent, _)) = self.blocks[block].parent {
+			block = parent;
+			depth += 1;
+		}
+		depth
+	}
+
+	/// Deepest block holding both blocks
+	fn common(&self, mut a: usize, mut b: usize) -> usize {
+		let (mut da, mut db) = (self.depth(a), self.depth(b));
+		while da > db {
+			a = self.blocks[a].parent.unwrap().0;
+			da -= 1;
+		}
+		while db > da {
+			b = self.blocks[b].parent.unwrap().0;
+			db -= 1;
+		}
+		while a != b {
+			a = self.blocks[a].parent.unwrap().0;
+			b = self.blocks[b].parent.unwrap().0;
+		}
+		a
+	}
+
+	/// Index of the statement of `block` an occurrence lies in
+	fn index_in(&self, block: usize, (mut at, mut idx): (usize, usize)) -> usize {
+		while at != block {
+			(at, idx) = self.blocks[at].parent.unwrap();
+		}
+		idx
+	}
+}
+
+impl FunctionDecompiler<'_, '_> {
+	/// Whether the variable keeps its value from one iteration of the loop
+	/// to the next, so it has to be declared outside of it
+	fn carried(&self, name: &str, loop_idx: usize) -> bool {
+		let (Some((web, reg)), Some(info)) = (self.webs.get(name), self.loops.get(loop_idx)) else {
+			return false;
+		};
+		self.info.is_live(info.header, *reg)
+			&& self.info.reaching(info.header, *reg).iter().any(|def| {
+				self.info.web(*def) == *web
+					&& matches!(def.site, super::Site::Pc(pc) if info.range.contains(&pc))
+			})
+	}
+
+	/// Adds `local` declarations of the variables of the function, each in
+	/// the innermost block holding all its uses and outside of any loop it
+	/// carries a value around
+	pub(super) fn declare(&self, body: &mut Block) {
+		let mut occurrences = Occurrences {
+			blocks: vec![],
+			at: BTreeMap::new(),
+			loops: 0,
+		};
+		occurrences.collect(body, None, None);
+
+		let mut declarations: BTreeMap<(usize, usize), Vec<String>> = BTreeMap::new();
+		for (name, at) in &occurrences.at {
+			if !self.webs.contains_key(name) || self.bound.contains(name) {
+				continue;
+			}
+			let mut block = at
+				.iter()
+				.map(|(block, _)| *block)
+				.reduce(|a, b| occurrences.common(a, b))
+				.unwrap();
+			'outer: loop {
+				let mut inner = block;
+				loop {
+					let node = &occurrences.blocks[inner];
+					if node.loop_idx.is_some_and(|idx| self.carried(name, idx)) {
+						block = node.parent.unwrap().0;
+						continue 'outer;
+					}
+					match node.parent {
+						Some((parent, _)) => inner = parent,
+						None => break 'outer,
+					}
+				}
+			}
+			let idx = at
+				.iter()
+				.map(|occurrence| occurrences.index_in(block, *occurrence))
+				.min()
+				.unwrap();
+			declarations
+				.entry((block, idx))
+				.or_default()
+				.push(name.clone());
+		}
+
+		let mut counter = 0;
+		insert(body, &mut counter, &mut declarations);
+	}
+}
+
+/// Turns the statement at each place into a declaration, or adds one
+/// before it, numbering blocks as `Occurrences::collect` does
+fn insert(
+	block: &mut Block,
+	counter: &mut usize,
+	declarations: &mut BTreeMap<(usize, usize), Vec<String>>,
+) {
+	let id = *counter;
+	*counter += 1;
+	for stat in block.iter_mut() {
+		for block in blocks_mut(&mut stat.node) {
+			insert(block, counter, declarations);
+		}
+	}
+
+	let mut places = declarations
+		.range((id, 0)..=(id, usize::MAX))
+		.map(|(key, _)| *key)
+		.collect::<Vec<_>>();
+	places.reverse();
+	for place in places {
+		let mut names = declarations.remove(&place).unwrap();
+		let idx = place.1;
+		let mut span = Default::default();
+		if let Some(stat) = block.get_mut(idx) {
+			span = stat.span;
+			if let Some(declared) = declare_in(&mut stat.node, &names) {
+				names.retain(|name| !declared.contains(name));
+			}
+		}
+		if !names.is_empty() {
+			block.insert(idx, Spanned::new(Stat::Local(names, vec![]), span));
+		}
+	}
+}
+
+/// Makes an assignment to only variables in `names` a local statement,
+/// gives the variables it declares
+fn declare_in(stat: &mut Stat, names: &[String]) -> Option<Vec<String>> {
+	let Stat::Assign(targets, values) = stat else {
+		return None;
+	};
+	let declared = targets
+		.iter()
+		.map(|target| match target {
+			Expr::Name(name) if names.contains(name) => Some(name.clone()),
+			_ => None,
+		})
+		.collect::<Option<Vec<_>>>()?;
+
+	match (&declared[..], &mut values[..]) {
+		([name], [Expr::Function(function)]) => {
+			let function = std::mem::take(&mut **function);
+			*stat = Stat::LocalFunction(name.clone(), function);
+		}
+		(_, values) => {
+			let read = values.iter().flat_map(referenced).collect::<BTreeSet<_>>();
+			if declared.iter().any(|name| read.contains(name)) {
+				return None;
+			}
+			let values = match values {
+				[Expr::Nil] if declared.len() == 1 => vec![],
+				values => values.to_vec(),
+			};
+			*stat = Stat::Local(declared.clone(), values);
+		}
+	}
+	Some(declared)
+}
+
+/// Names of all variables `function` and its closures use
+pub(super) fn used_names(function: &Function) -> BTreeSet<String> {
+	let used = RefCell::new(BTreeSet::new());
+	rename_function(&mut function.clone(), &|name: &mut String| {
+		used.borrow_mut().insert(name.clone());
+	});
+	used.into_inner()
+}
+
+/// Replaces every variable name in `function` by its final name
+pub(super) fn rename(function: &mut Function, names: &HashMap<String, String>) {
+	let lookup = |name: &mut String| {
+		if let Some(new) = names.get(name) {
+			*name = new.clone();
+		}
+	};
+	rename_function(function, &lookup);
+}
+
+fn rename_function(function: &mut Function, lookup: &impl Fn(&mut String)) {
+	function.params.iter_mut().for_each(lookup);
+	rename_block(&mut function.body, lookup);
+}
+
+pub(super) fn rename_block(block: &mut Block, lookup: &impl Fn(&mut String)) {
+	for stat in block {
+		match &mut stat.node {
+			Stat::Local(names, exprs) => {
+				names.iter_mut().for_each(lookup);
+				exprs.iter_mut().for_each(|expr| rename_expr(expr, lookup));
+			}
+			Stat::LocalFunction(name, function) => {
+				lookup(name);
+				rename_function(function, lookup);
+			}
+			Stat::Assign(targets, values) => {
+				targets
+					.iter_mut()
+					.chain(values.iter_mut())
+					.for_each(|expr| rename_expr(expr, lookup));
+			}
+			Stat::Call(call) => rename_call(call, lookup),
+			Stat::Do(body) => rename_block(body, lookup),
+			Stat::If(cond, then, otherwise) => {
+				rename_expr(cond, lookup);
+				rename_block(then, lookup);
+				rename_block(otherwise, lookup);
+			}
+			Stat::While(cond, body) | Stat::Repeat(body, cond) => {
+				rename_expr(cond, lookup);
+				rename_block(body, lookup);
+			}
+			Stat::NumericFor(name, start, limit, step, body) => {
+				lookup(name);
+				rename_expr(start, lookup);
+				rename_expr(limit, lookup);
+				if let Some(step) = step {
+					rename_expr(step, lookup);
+				}
+				rename_block(body, lookup);
+			}
+			Stat::GenericFor(names, exprs, body) => {
+				names.iter_mut().for_each(lookup);
+				exprs.iter_mut().for_each(|expr| rename_expr(expr, lookup));
+				rename_block(body, lookup);
+			}
+			Stat::Return(exprs) => exprs.iter_mut().for_each(|expr| rename_expr(expr, lookup)),
+			Stat::Break => {}
+		}
+	}
+}
+
+fn rename_call(call: &mut Call, lookup: &impl Fn(&mut String)) {
+	rename_expr(&mut call.func, lookup);
+	call.args
+		.iter_mut()
+		.for_each(|expr| rename_expr(expr, lookup));
+}
+
+pub(super) fn rename_expr(expr: &mut Expr, lookup: &impl Fn(&mut String)) {
+	match expr {
+		Expr::Name(name) => lookup(name),
+		Expr::Index(object, key) | Expr::Binary(_, object, key) => {
+			rename_expr(&mut object.node, lookup);
+			rename_expr(&mut key.node, lookup);
+		}
+		Expr::Call(call) => rename_call(call, lookup),
+		Expr::Function(function) => rename_function(function, lookup),
+		Expr::Table(fields) => {
+			for field in fields {
+				match field {
+					Field::Item(value) => rename_expr(value, lookup),
+					Field::Pair(key, value) => {
+						rename_expr(key, lookup);
+						rename_expr(value, lookup);
+					}
+				}
+			}
+		}
+		Expr::Unary(_, operand) | Expr::Paren(operand) => rename_expr(&mut operand.node, lookup),
+		_ => {}
+	}
+}
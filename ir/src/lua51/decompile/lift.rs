@@ -0,0 +1,534 @@
+use super::{DecompileError, FunctionDecompiler, Pending, PendingKind, Result};
+use crate::lua51::dataflow::{Definition, Site};
+use ast::lua51::{is_identifier, BinOp, Block, Call, Expr, Field, Span, Spanned, Stat, UnOp};
+use bytecode::lua51::Constants;
+
+const FIELDS_PER_FLUSH: usize = 50;
+
+/// Wraps a call or vararg ending a fixed length list so it gives one value
+fn truncate(mut exprs: Vec<Spanned<Expr>>) -> Vec<Expr> {
+	let last = exprs.pop().map(|last| match last.node.is_multi() {
+		true => Expr::paren(last),
+		false => last.node,
+	});
+	exprs
+		.into_iter()
+		.map(|expr| expr.node)
+		.chain(last)
+		.collect()
+}
+
+impl FunctionDecompiler<'_, '_> {
+	fn next_seq(&mut self) -> usize {
+		self.seq += 1;
+		self.seq
+	}
+
+	fn push_pending(&mut self, reg: u8, pc: usize, expr: Expr, kind: PendingKind) {
+		let seq = self.next_seq();
+		let def = Definition {
+			register: reg,
+			site: Site::Pc(pc),
+		};
+		self.pending.insert(
+			reg,
+			Pending {
+				expr,
+				def,
+				seq,
+				kind,
+			},
+		);
+	}
+
+	/// Writes pending values created up to `seq` as assignments, in the
+	/// order they were computed
+	pub(super) fn flush_to(&mut self, stats: &mut Block, seq: usize) -> Result<()> {
+		let mut flushed = self
+			.pending
+			.iter()
+			.filter(|(_, pending)| pending.seq <= seq)
+			.map(|(reg, pending)| (pending.seq, *reg))
+			.collect::<Vec<_>>();
+		flushed.sort();
+
+		for (_, reg) in flushed {
+			let pending = self.pending.remove(&reg).unwrap();
+			let pc = match pending.def.site {
+				Site::Pc(pc) => pc,
+				Site::Entry => 0,
+			};
+			if self.expression {
+				return Err(DecompileError::Unsupported(pc));
+			}
+			let expr = match pending.kind {
+				PendingKind::Value => pending.expr,
+				PendingKind::Table { fields, .. } => table(fields),
+				_ => return Err(DecompileError::Unsupported(pc)),
+			};
+			let name = self.var(pending.def);
+			let stat = Stat::Assign(vec![Expr::Name(name)], vec![expr]);
+			stats.push(Spanned::new(stat, Span::at(pc)));
+		}
+		Ok(())
+	}
+
+	pub(super) fn flush(&mut self, stats: &mut Block) -> Result<()> {
+		self.flush_to(stats, usize::MAX)
+	}
+
+	/// Adds a statement after everything still pending
+	pub(super) fn emit(&mut self, stats: &mut Block, pc: usize, stat: Stat) -> Result<()> {
+		if self.expression {
+			return Err(DecompileError::Unsupported(pc));
+		}
+		self.flush(stats)?;
+		stats.push(Spanned::new(stat, Span::at(pc)));
+		Ok(())
+	}
+
+	pub(super) fn is_temp(&self, pc: usize, reg: u8) -> bool {
+		let def = Definition {
+			register: reg,
+			site: Site::Pc(pc),
+		};
+		match &self.scope {
+			Some(scope) => self
+				.info
+				.temporary_use(&[def], pc..pc + 1)
+				.is_some_and(|u| scope.contains(&u)),
+			None => self.forced.contains(&def) || self.info.is_temp(pc, reg),
+		}
+	}
+
+	/// Gives `reg` the value `expr` at `pc`
+	fn define(&mut self, stats: &mut Block, pc: usize, reg: u8, expr: Expr) -> Result<()> {
+		if let Some(old) = self.pending.get(&reg) {
+			// overwritten before being read
+			let seq = old.seq;
+			self.flush_to(stats, seq)?;
+		}
+		if self.is_temp(pc, reg) {
+			self.push_pending(reg, pc, expr, PendingKind::Value);
+			Ok(())
+		} else {
+			let name = self.var(Definition {
+				register: reg,
+				site: Site::Pc(pc),
+			});
+			self.emit(stats, pc, Stat::Assign(vec![Expr::Name(name)], vec![expr]))
+		}
+	}
+
+	/// Value of `reg` read at `pc`, a pending value is consumed and spans
+	/// the instruction that computed it
+	pub(super) fn take(&mut self, stats: &mut Block, pc: usize, reg: u8) -> Result<Spanned<Expr>> {
+		let Some(pending) = self.pending.get(&reg) else {
+			let name = Expr::Name(self.var_at(pc, reg));
+			return Ok(Spanned::new(name, Span::at(pc)));
+		};
+		match &pending.kind {
+			PendingKind::Table { temp: false, .. } => {
+				// read as a variable, the constructor is complete
+				let (seq, def) = (pending.seq, pending.def);
+				self.flush_to(stats, seq)?;
+				Ok(Spanned::new(Expr::Name(self.var(def)), Span::at(pc)))
+			}
+			PendingKind::Value | PendingKind::Table { .. } | PendingKind::Open => {
+				let pending = self.pending.remove(&reg).unwrap();
+				self.taken.push(pending.seq);
+				let span = match pending.def.site {
+					Site::Pc(def) => Span::at(def),
+					Site::Entry => Span::at(pc),
+				};
+				let expr = match pending.kind {
+					PendingKind::Table { fields, .. } => table(fields),
+					PendingKind::Open => Expr::paren(Spanned::new(pending.expr, span)),
+					_ => pending.expr,
+				};
+				Ok(Spanned::new(expr, span))
+			}
+			_ => Err(DecompileError::Unsupported(pc)),
+		}
+	}
+
+	fn constant(&self, pc: usize, idx: usize) -> Result<Expr> {
+		let constant = self
+			.info
+			.ctx
+			.constants
+			.get(idx)
+			.ok_or(DecompileError::Unsupported(pc))?;
+		Ok(match constant.get() {
+			Constants::Nil => Expr::Nil,
+			Constants::Boolean(b) => Expr::Bool(*b),
+			Constants::Number(n) => Expr::Number(*n),
+			Constants::String(s) => Expr::String(s.clone()),
+		})
+	}
+
+	pub(super) fn rk(&mut self, stats: &mut Block, pc: usize, value: u16) -> Result<Spanned<Expr>> {
+		if value > 0xff {
+			let constant = self.constant(pc, value as usize - 0x100)?;
+			Ok(Spanned::new(constant, Span::at(pc)))
+		} else {
+			self.take(stats, pc, value as u8)
+		}
+	}
+
+	/// Values of `from` up to the top of the stack set by an open call or
+	/// VARARG, the last one is left open
+	fn open_list(&mut self, stats: &mut Block, pc: usize, from: u8) -> Result<Vec<Expr>> {
+		let top = self
+			.info
+			.open_register(pc)
+			.filter(|top| *top >= from)
+			.ok_or(DecompileError::Unsupported(pc))?;
+		let mut exprs = (from..top)
+			.map(|reg| self.take(stats, pc, reg).map(|expr| expr.node))
+			.collect::<Result<Vec<_>>>()?;
+		match self.pending.remove(&top) {
+			Some(pending) if pending.kind == PendingKind::Open => {
+				self.taken.push(pending.seq);
+				exprs.push(pending.expr);
+				Ok(exprs)
+			}
+			_ => Err(DecompileError::Unsupported(pc)),
+		}
+	}
+
+	fn list(&mut self, stats: &mut Block, pc: usize, from: u8, count: u16) -> Result<Vec<Expr>> {
+		let exprs = (from as usize..from as usize + count as usize)
+			.map(|reg| self.take(stats, pc, reg as u8))
+			.collect::<Result<Vec<_>>>()?;
+		Ok(truncate(exprs))
+	}
+
+	/// Call made by CALL or TAILCALL at `pc`
+	fn call(&mut self, stats: &mut Block, pc: usize, a: u8, b: u16) -> Result<Call> {
+		let method = match self.pending.get(&a) {
+			Some(Pending {
+				kind: PendingKind::Method(key),
+				..
+			}) => Some(key.clone()),
+			_ => None,
+		};
+		let (func, method, first) = match method {
+			Some(key) => {
+				let object = self.pending.remove(&(a + 1));
+				let pending = self.pending.remove(&a).unwrap();
+				match (object, key) {
+					(
+						Some(Pending {
+							kind: PendingKind::SelfArg,
+							..
+						}),
+						Expr::String(name),
+					) if is_identifier(&name) => (pending.expr, Some(name), a + 2),
+					_ => return Err(DecompileError::Unsupported(pc)),
+				}
+			}
+			None => (self.take(stats, pc, a)?.node, None, a + 1),
+		};
+		let args = match b {
+			0 => self.open_list(stats, pc, first)?,
+			_ => self.list(stats, pc, first, b - 1 - (first - a - 1) as u16)?,
+		};
+		Ok(Call { func, method, args })
+	}
+
+	/// Places the `count` results of a call or VARARG at `pc` in `a` onwards
+	fn results(
+		&mut self,
+		stats: &mut Block,
+		pc: usize,
+		a: u8,
+		count: usize,
+		expr: Expr,
+	) -> Result<()> {
+		let regs = a..a + count as u8;
+		if regs.clone().all(|reg| self.is_temp(pc, reg)) {
+			self.push_pending(a, pc, expr, PendingKind::Multi(count));
+			return Ok(());
+		}
+		if regs.clone().any(|reg| self.is_temp(pc, reg)) {
+			return Err(DecompileError::Unsupported(pc));
+		}
+		let targets = regs
+			.map(|reg| {
+				Expr::Name(self.var(Definition {
+					register: reg,
+					site: Site::Pc(pc),
+				}))
+			})
+			.collect();
+		self.emit(stats, pc, Stat::Assign(targets, vec![expr]))
+	}
+
+	/// Takes the `count` values a generic for reads from `a` onwards
+	pub(super) fn explist(
+		&mut self,
+		stats: &mut Block,
+		pc: usize,
+		a: u8,
+		count: usize,
+	) -> Result<Vec<Expr>> {
+		if let Some(Pending {
+			kind: PendingKind::Multi(n),
+			..
+		}) = self.pending.get(&a)
+		{
+			if *n == count {
+				let pending = self.pending.remove(&a).unwrap();
+				return Ok(vec![pending.expr]);
+			}
+		}
+		let mut exprs = (a..a + count as u8)
+			.map(|reg| self.take(stats, pc, reg))
+			.collect::<Result<Vec<_>>>()?;
+		while exprs.len() > 1 && exprs.last().is_some_and(|expr| expr.node == Expr::Nil) {
+			exprs.pop();
+		}
+		Ok(truncate(exprs))
+	}
+
+	/// Adds a constructor field to the table pending in `reg`
+	fn add_field(&mut self, reg: u8, seq: usize, field: Field, item: bool) {
+		if let Some(Pending {
+			kind: PendingKind::Table { fields, items, .. },
+			..
+		}) = self.pending.get_mut(&reg)
+		{
+			fields.push((seq, field));
+			*items += item as usize;
+		}
+	}
+
+	fn is_table(&self, reg: u8) -> bool {
+		matches!(
+			self.pending.get(&reg),
+			Some(Pending {
+				kind: PendingKind::Table { .. },
+				..
+			})
+		)
+	}
+
+	/// Function value of the CLOSURE at `pc`, its upvalues are named after
+	/// the variables captured
+	fn closure(&mut self, pc: usize, idx: usize) -> Result<Expr> {
+		let ctx = self.info.ctx;
+		let child = ctx
+			.closures
+			.get(idx)
+			.ok_or(DecompileError::Unsupported(pc))?;
+		let mut upvalues = vec![];
+		for capture in pc + 1..pc + 1 + self.info.pseudo_after(pc) {
+			let (opcode, _, b, _) = self.info.op(capture);
+			upvalues.push(match opcode {
+				0 => self.var_at(pc, b as u8),
+				4 => self
+					.upvalues
+					.get(b as usize)
+					.cloned()
+					.ok_or(DecompileError::Unsupported(pc))?,
+				_ => return Err(DecompileError::Unsupported(pc)),
+			});
+		}
+		let function = FunctionDecompiler::new(child, self.names, upvalues).run()?;
+		Ok(Expr::Function(Box::new(function)))
+	}
+
+	fn upvalue(&self, pc: usize, idx: u16) -> Result<Expr> {
+		self.upvalues
+			.get(idx as usize)
+			.map(|name| Expr::Name(name.clone()))
+			.ok_or(DecompileError::Unsupported(pc))
+	}
+
+	/// Lifts the instruction at `pc`, which neither jumps nor skips
+	pub(super) fn lift(&mut self, stats: &mut Block, pc: usize) -> Result<()> {
+		let (opcode, a, b, c) = self.info.op(pc);
+		match opcode {
+			0 => {
+				let value = self.take(stats, pc, b as u8)?;
+				self.define(stats, pc, a, value.node)
+			}
+			1 => {
+				let value = self.constant(pc, b as usize)?;
+				self.define(stats, pc, a, value)
+			}
+			2 => self.define(stats, pc, a, Expr::Bool(b != 0)),
+			3 => (a..=b as u8).try_for_each(|reg| self.define(stats, pc, reg, Expr::Nil)),
+			4 => {
+				let value = self.upvalue(pc, b)?;
+				self.define(stats, pc, a, value)
+			}
+			5 => match self.constant(pc, b as usize)? {
+				Expr::String(name) => self.define(stats, pc, a, Expr::Global(name)),
+				_ => Err(DecompileError::Unsupported(pc)),
+			},
+			6 => {
+				let object = self.take(stats, pc, b as u8)?;
+				let key = self.rk(stats, pc, c)?;
+				self.define(stats, pc, a, Expr::index(object, key))
+			}
+			7 => {
+				let Expr::String(name) = self.constant(pc, b as usize)? else {
+					return Err(DecompileError::Unsupported(pc));
+				};
+				let value = self.take(stats, pc, a)?;
+				self.emit(
+					stats,
+					pc,
+					Stat::Assign(vec![Expr::Global(name)], vec![value.node]),
+				)
+			}
+			8 => {
+				let target = self.upvalue(pc, b)?;
+				let value = self.take(stats, pc, a)?;
+				self.emit(stats, pc, Stat::Assign(vec![target], vec![value.node]))
+			}
+			9 if self.is_table(a) => {
+				self.taken.clear();
+				let key = self.rk(stats, pc, b)?;
+				let value = self.rk(stats, pc, c)?;
+				let seq = match self.taken.iter().min() {
+					Some(seq) => *seq,
+					None => self.next_seq(),
+				};
+				self.add_field(a, seq, Field::Pair(key.node, value.node), false);
+				Ok(())
+			}
+			9 => {
+				let object = self.take(stats, pc, a)?;
+				let key = self.rk(stats, pc, b)?;
+				let value = self.rk(stats, pc, c)?;
+				let target = Expr::index(object, key);
+				self.emit(stats, pc, Stat::Assign(vec![target], vec![value.node]))
+			}
+			10 => {
+				if let Some(old) = self.pending.get(&a) {
+					let seq = old.seq;
+					self.flush_to(stats, seq)?;
+				}
+				let kind = PendingKind::Table {
+					temp: self.is_temp(pc, a),
+					fields: vec![],
+					items: 0,
+				};
+				self.push_pending(a, pc, Expr::Table(vec![]), kind);
+				Ok(())
+			}
+			11 => {
+				let object = self.take(stats, pc, b as u8)?;
+				let key = self.rk(stats, pc, c)?;
+				if !self.is_temp(pc, a) || !self.is_temp(pc, a + 1) {
+					return Err(DecompileError::Unsupported(pc));
+				}
+				self.push_pending(a + 1, pc, Expr::Nil, PendingKind::SelfArg);
+				self.push_pending(a, pc, object.node, PendingKind::Method(key.node));
+				Ok(())
+			}
+			12..=17 => {
+				let op = [
+					BinOp::Add,
+					BinOp::Sub,
+					BinOp::Mul,
+					BinOp::Div,
+					BinOp::Mod,
+					BinOp::Pow,
+				][opcode - 12];
+				let left = self.rk(stats, pc, b)?;
+				let right = self.rk(stats, pc, c)?;
+				self.define(stats, pc, a, Expr::binary(op, left, right))
+			}
+			18..=20 => {
+				let op = [UnOp::Neg, UnOp::Not, UnOp::Len][opcode - 18];
+				let operand = self.take(stats, pc, b as u8)?;
+				self.define(stats, pc, a, Expr::unary(op, operand))
+			}
+			21 => {
+				let mut values = (b as u8..=c as u8)
+					.map(|reg| self.take(stats, pc, reg))
+					.collect::<Result<Vec<_>>>()?;
+				let mut expr = values.pop().ok_or(DecompileError::Unsupported(pc))?;
+				while let Some(value) = values.pop() {
+					let span = value.span.join(expr.span);
+					expr = Spanned::new(Expr::binary(BinOp::Concat, value, expr), span);
+				}
+				self.define(stats, pc, a, expr.node)
+			}
+			28 => {
+				let call = self.call(stats, pc, a, b)?;
+				match c {
+					0 => {
+						self.push_pending(a, pc, Expr::Call(Box::new(call)), PendingKind::Open);
+						Ok(())
+					}
+					1 => self.emit(stats, pc, Stat::Call(call)),
+					2 => self.define(stats, pc, a, Expr::Call(Box::new(call))),
+					_ => self.results(stats, pc, a, c as usize - 1, Expr::Call(Box::new(call))),
+				}
+			}
+			29 => {
+				let call = self.call(stats, pc, a, b)?;
+				self.emit(stats, pc, Stat::Return(vec![Expr::Call(Box::new(call))]))
+			}
+			30 => {
+				let values = match b {
+					0 => self.open_list(stats, pc, a)?,
+					_ => self.list(stats, pc, a, b - 1)?,
+				};
+				self.emit(stats, pc, Stat::Return(values))
+			}
+			34 if c != 0 && self.is_table(a) => {
+				self.taken.clear();
+				let values = match b {
+					0 => self.open_list(stats, pc, a + 1)?,
+					_ => self.list(stats, pc, a + 1, b)?,
+				};
+				let Some(Pending {
+					kind: PendingKind::Table { items, .. },
+					..
+				}) = self.pending.get(&a)
+				else {
+					return Err(DecompileError::Unsupported(pc));
+				};
+				if *items != (c as usize - 1) * FIELDS_PER_FLUSH {
+					return Err(DecompileError::Unsupported(pc));
+				}
+				let seqs = self.taken.clone();
+				for (idx, value) in values.into_iter().enumerate() {
+					let seq = match seqs.get(idx) {
+						Some(seq) => *seq,
+						None => self.next_seq(),
+					};
+					self.add_field(a, seq, Field::Item(value), true);
+				}
+				Ok(())
+			}
+			35 => Ok(()),
+			36 => {
+				let function = self.closure(pc, b as usize)?;
+				self.define(stats, pc, a, function)
+			}
+			37 => match b {
+				0 => {
+					self.push_pending(a, pc, Expr::Vararg, PendingKind::Open);
+					Ok(())
+				}
+				1 => Ok(()),
+				2 => self.define(stats, pc, a, Expr::Vararg),
+				_ => self.results(stats, pc, a, b as usize - 1, Expr::Vararg),
+			},
+			_ => Err(DecompileError::Unsupported(pc)),
+		}
+	}
+}
+
+/// Constructor with its fields in the order their values were computed
+pub(super) fn table(mut fields: Vec<(usize, Field)>) -> Expr {
+	fields.sort_by_key(|(seq, _)| *seq);
+	Expr::Table(fields.into_iter().map(|(_, field)| field).collect())
+}
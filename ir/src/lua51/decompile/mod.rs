@@ -0,0 +1,602 @@
+use self::analysis::FunctionInfo;
+use super::{
+	dataflow::{Definition, Site},
+	IRContext,
+};
+use ast::lua51::{is_identifier, Expr, Field, Function, Span};
+use std::{
+	collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+	error::Error,
+	fmt::Display,
+	ops::Range,
+};
+
+mod analysis;
+pub mod blocks;
+mod cond;
+mod declare;
+mod lift;
+mod structure;
+
+#[derive(Debug, PartialEq)]
+pub enum DecompileError {
+	/// the control flow around pc matches no Lua 5.1 statement
+	Unstructured(usize),
+	/// the instruction at pc cannot be written as source
+	Unsupported(usize),
+}
+
+impl Display for DecompileError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Unstructured(pc) => write!(f, "no structured control flow at pc {pc}"),
+			Self::Unsupported(pc) => write!(f, "pc {pc} has no source equivalent"),
+		}
+	}
+}
+
+impl Error for DecompileError {}
+
+type Result<T> = std::result::Result<T, DecompileError>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VarKind {
+	Param,
+	Local,
+	Upvalue,
+}
+
+/**
+ * Names - Every variable of the function tree, named `@id` while
+ * decompiling. Real names are picked at the end, from debug info when
+ * present, so that no two variables and no global share one
+ */
+#[derive(Default)]
+struct Names {
+	vars: Vec<(Option<String>, VarKind)>,
+}
+
+impl Names {
+	fn add(&mut self, hint: Option<&str>, kind: VarKind) -> String {
+		self.vars.push((hint.map(str::to_string), kind));
+		format!("@{}", self.vars.len() - 1)
+	}
+
+	/// Final names of the variables in `used`, avoiding `globals`
+	fn resolve(
+		&self,
+		used: &BTreeSet<String>,
+		globals: &BTreeSet<String>,
+	) -> HashMap<String, String> {
+		let mut taken = globals.clone();
+		let mut counters = [0; 3];
+		let mut names = HashMap::new();
+
+		for (id, (hint, kind)) in self.vars.iter().enumerate() {
+			let var = format!("@{id}");
+			if !used.contains(&var) {
+				continue;
+			}
+			let base = match hint {
+				Some(hint) if is_identifier(hint) => hint.clone(),
+				_ => {
+					let (prefix, counter) = match kind {
+						VarKind::Param => ("p", &mut counters[0]),
+						VarKind::Local => ("v", &mut counters[1]),
+						VarKind::Upvalue => ("u", &mut counters[2]),
+					};
+					*counter += 1;
+					format!("{prefix}{counter}")
+				}
+			};
+			let mut name = base.clone();
+			let mut suffix = 1;
+			while taken.contains(&name) {
+				suffix += 1;
+				name = format!("{base}_{suffix}");
+			}
+			taken.insert(name.clone());
+			names.insert(var, name);
+		}
+
+		names
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PendingKind {
+	Value,
+	/// results of a call or VARARG up to the top of the stack
+	Open,
+	/// fixed number of results of a call or VARARG starting at its register
+	Multi(usize),
+	/// SELF's method, the object is in the next register
+	Method(Expr),
+	SelfArg,
+	/// table being filled in, `temp` when nothing but one instruction reads it
+	Table {
+		temp: bool,
+		fields: Vec<(usize, Field)>,
+		items: usize,
+	},
+}
+
+/// A value computed into a register but not yet written as source
+#[derive(Debug, Clone)]
+struct Pending {
+	expr: Expr,
+	def: Definition,
+	seq: usize,
+	kind: PendingKind,
+}
+
+/// Loop a body belongs to, for `break` and declaration placement
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LoopKind {
+	While,
+	Repeat,
+	For,
+}
+
+#[derive(Debug, Clone)]
+struct LoopContext {
+	kind: LoopKind,
+	head: usize, // where the next iteration starts
+	exit: usize,
+	until: bool, // body of a repeat whose condition ends it
+}
+
+/// A loop in the order its statement appears, pre-order
+#[derive(Debug, Clone)]
+struct LoopInfo {
+	header: usize, // first pc of an iteration
+	range: Range<usize>,
+}
+
+/// State a failed attempt at structuring is rolled back to
+struct Snapshot {
+	pending: BTreeMap<u8, Pending>,
+	seq: usize,
+	loops: usize,
+}
+
+struct FunctionDecompiler<'a, 'n> {
+	info: FunctionInfo<'a>,
+	names: &'n mut Names,
+	vars: HashMap<usize, String>,       // web to name
+	webs: HashMap<String, (usize, u8)>, // name to web and register
+	bound: HashSet<String>,             // declared by parameters and for loops
+	upvalues: Vec<String>,
+	pending: BTreeMap<u8, Pending>,
+	forced: BTreeSet<Definition>, // definitions folded as if temporary
+	taken: Vec<usize>,            // seqs of the pending values read last
+	seq: usize,
+	expression: bool,            // no statements may be emitted
+	active: Vec<(usize, usize)>, // loops being structured, header and back jump
+	loops: Vec<LoopInfo>,
+	scope: Option<Range<usize>>, // basic block temporaries are confined to
+}
+
+impl<'a, 'n> FunctionDecompiler<'a, 'n> {
+	fn new(ctx: &'a IRContext, names: &'n mut Names, upvalues: Vec<String>) -> Self {
+		Self {
+			info: FunctionInfo::new(ctx),
+			names,
+			vars: HashMap::new(),
+			webs: HashMap::new(),
+			bound: HashSet::new(),
+			upvalues,
+			pending: BTreeMap::new(),
+			forced: BTreeSet::new(),
+			taken: vec![],
+			seq: 0,
+			expression: false,
+			active: vec![],
+			loops: vec![],
+			scope: None,
+		}
+	}
+
+	fn run(mut self) -> Result<Function> {
+		let ctx = self.info.ctx;
+		let params = (0..ctx.nparams())
+			.map(|reg| {
+				let name = self.var(Definition {
+					register: reg,
+					site: Site::Entry,
+				});
+				self.bound.insert(name.clone());
+				name
+			})
+			.collect();
+
+		let (mut body, _) = self.block(0..self.info.len(), None)?;
+		self.declare(&mut body);
+
+		Ok(Function {
+			params,
+			vararg: ctx.vararg() & 2 != 0,
+			body,
+			span: Span::new(0, self.info.len()),
+		})
+	}
+
+	/// Variable of the web `def` belongs to
+	fn var(&mut self, def: Definition) -> String {
+		let web = self.info.web(def);
+		self.web_var(web, def.register)
+	}
+
+	/// Variable holding the value of `reg` read at `pc`
+	fn var_at(&mut self, pc: usize, reg: u8) -> String {
+		let web = self.info.web_at(pc, reg);
+		self.web_var(web, reg)
+	}
+
+	fn web_var(&mut self, web: usize, reg: u8) -> String {
+		if let Some(name) = self.vars.get(&web) {
+			return name.clone();
+		}
+		let param = reg < self.info.ctx.nparams()
+			&& web
+				== self.info.web(Definition {
+					register: reg,
+					site: Site::Entry,
+				});
+		let kind = if param {
+			VarKind::Param
+		} else {
+			VarKind::Local
+		};
+		let name = self.names.add(self.info.web_name(web), kind);
+		self.vars.insert(web, name.clone());
+		self.webs.insert(name.clone(), (web, reg));
+		name
+	}
+
+	fn snapshot(&self) -> Snapshot {
+		Snapshot {
+			pending: self.pending.clone(),
+			seq: self.seq,
+			loops: self.loops.len(),
+		}
+	}
+
+	fn restore(&mut self, snapshot: Snapshot) {
+		self.pending = snapshot.pending;
+		self.seq = snapshot.seq;
+		self.loops.truncate(snapshot.loops);
+		self.forced.clear();
+		self.expression = false;
+	}
+}
+
+/// Variables for the upvalues of the main function `ctx`
+fn upvalue_vars(ctx: &IRContext, names: &mut Names) -> Vec<String> {
+	(0..ctx.nupvalues() as usize)
+		.map(|idx| {
+			let hint = ctx.upvalue_names().get(idx).map(String::as_str);
+			names.add(hint, VarKind::Upvalue)
+		})
+		.collect()
+}
+
+fn collect_globals(function: &Function, globals: &mut BTreeSet<String>) {
+	declare::visit_exprs(&function.body, &mut |expr| {
+		if let Expr::Global(name) = expr {
+			globals.insert(name.clone());
+		}
+	});
+}
+
+/**
+ * decompile - Recovers Lua source from a function and its closures.
+ * Structured statements are recovered from the jump patterns luac emits,
+ * temporaries are folded into the expressions reading them and locals
+ * are named from debug info when it is present
+ */
+pub fn decompile(ctx: &IRContext) -> Result<Function> {
+	let mut names = Names::default();
+	let upvalues = upvalue_vars(ctx, &mut names);
+	let mut function = FunctionDecompiler::new(ctx, &mut names, upvalues).run()?;
+
+	let mut globals = BTreeSet::new();
+	collect_globals(&function, &mut globals);
+	let resolved = names.resolve(&declare::used_names(&function), &globals);
+	declare::rename(&mut function, &resolved);
+	Ok(function)
+}
+
+/// Lua source of the main function `ctx`
+pub fn decompile_source(ctx: &IRContext) -> Result<String> {
+	decompile(ctx).map(|function| function.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{decompile, decompile_source};
+	use crate::lua51::{
+		fixtures::{context, proto, string},
+		IRContext,
+	};
+	use ast::lua51::{BinOp, Expr, Span, Spanned, Stat, UnOp};
+	use bytecode::lua51::{instructions::Opcode, Constants, Local, Proto};
+
+	fn local(name: &str, start_pc: u64, end_pc: u64) -> Local {
+		Local {
+			name: name.to_string(),
+			start_pc,
+			end_pc,
+		}
+	}
+
+	#[test]
+	fn test_if_else() {
+		let ctx = context(
+			vec![
+				Opcode::encode_ABx(5, 0, 0),     // GETGLOBAL 0 x
+				Opcode::encode_ABC(26, 0, 0, 0), // TEST 0 0
+				Opcode::encode_AsBx(22, 0, 4),   // JMP 4
+				Opcode::encode_ABx(5, 1, 1),     // GETGLOBAL 1 print
+				Opcode::encode_ABC(0, 2, 0, 0),  // MOVE 2 0
+				Opcode::encode_ABC(28, 1, 2, 1), // CALL 1 2 1
+				Opcode::encode_AsBx(22, 0, 3),   // JMP 3
+				Opcode::encode_ABx(5, 1, 1),     // GETGLOBAL 1 print
+				Opcode::encode_ABx(1, 2, 2),     // LOADK 2 "no"
+				Opcode::encode_ABC(28, 1, 2, 1), // CALL 1 2 1
+				Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+			],
+			vec![string("x"), string("print"), string("no")],
+		);
+
+		assert_eq!(
+			decompile_source(&ctx).unwrap(),
+			"local v1 = x\nif v1 then\n\tprint(v1)\nelse\n\tprint(\"no\")\nend\n"
+		);
+
+		let ctx = context(
+			vec![
+				Opcode::encode_ABx(5, 0, 0),     // GETGLOBAL 0 a
+				Opcode::encode_ABC(26, 0, 0, 0), // TEST 0 0
+				Opcode::encode_AsBx(22, 0, 3),   // JMP 3
+				Opcode::encode_ABx(5, 0, 1),     // GETGLOBAL 0 f
+				Opcode::encode_ABC(28, 0, 1, 1), // CALL 0 1 1
+				Opcode::encode_AsBx(22, 0, 8),   // JMP 8
+				Opcode::encode_ABx(5, 0, 2),     // GETGLOBAL 0 b
+				Opcode::encode_ABC(26, 0, 0, 0), // TEST 0 0
+				Opcode::encode_AsBx(22, 0, 3),   // JMP 3
+				Opcode::encode_ABx(5, 0, 3),     // GETGLOBAL 0 g
+				Opcode::encode_ABC(28, 0, 1, 1), // CALL 0 1 1
+				Opcode::encode_AsBx(22, 0, 2),   // JMP 2
+				Opcode::encode_ABx(5, 0, 4),     // GETGLOBAL 0 h
+				Opcode::encode_ABC(28, 0, 1, 1), // CALL 0 1 1
+				Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+			],
+			vec![
+				string("a"),
+				string("f"),
+				string("b"),
+				string("g"),
+				string("h"),
+			],
+		);
+
+		assert_eq!(
+			decompile_source(&ctx).unwrap(),
+			"if a then\n\tf()\nelseif b then\n\tg()\nelse\n\th()\nend\n"
+		);
+	}
+
+	#[test]
+	fn test_loops() {
+		let ctx = context(
+			vec![
+				Opcode::encode_ABx(1, 0, 0),       // LOADK 0 0
+				Opcode::encode_ABx(1, 1, 1),       // LOADK 1 1
+				Opcode::encode_ABx(1, 2, 2),       // LOADK 2 10
+				Opcode::encode_ABx(1, 3, 1),       // LOADK 3 1
+				Opcode::encode_AsBx(32, 1, 1),     // FORPREP 1 1
+				Opcode::encode_ABC(12, 0, 0, 4),   // ADD 0 0 4
+				Opcode::encode_AsBx(31, 1, -2),    // FORLOOP 1 -2
+				Opcode::encode_ABC(24, 0, 259, 0), // LT 0 5 0
+				Opcode::encode_AsBx(22, 0, 2),     // JMP 2
+				Opcode::encode_ABC(13, 0, 0, 257), // SUB 0 0 1
+				Opcode::encode_AsBx(22, 0, -4),    // JMP -4
+				Opcode::encode_ABC(30, 0, 2, 0),   // RETURN 0 2
+				Opcode::encode_ABC(30, 0, 1, 0),   // RETURN 0 1
+			],
+			vec![
+				Constants::Number(0.0),
+				Constants::Number(1.0),
+				Constants::Number(10.0),
+				Constants::Number(5.0),
+			],
+		);
+
+		assert_eq!(
+			decompile_source(&ctx).unwrap(),
+			"local v1 = 0\nfor v2 = 1, 10 do\n\tv1 = v1 + v2\nend\nwhile 5 < v1 do\n\tv1 = v1 - 1\nend\nreturn v1\n"
+		);
+	}
+
+	#[test]
+	fn test_repeat() {
+		let ctx = context(
+			vec![
+				Opcode::encode_ABx(1, 0, 0),       // LOADK 0 0
+				Opcode::encode_ABC(12, 0, 0, 257), // ADD 0 0 1
+				Opcode::encode_ABx(5, 1, 2),       // GETGLOBAL 1 print
+				Opcode::encode_ABC(23, 1, 0, 259), // EQ 1 0 3
+				Opcode::encode_AsBx(22, 0, 1),     // JMP 1
+				Opcode::encode_ABC(2, 2, 0, 1),    // LOADBOOL 2 0 1
+				Opcode::encode_ABC(2, 2, 1, 0),    // LOADBOOL 2 1 0
+				Opcode::encode_ABC(28, 1, 2, 1),   // CALL 1 2 1
+				Opcode::encode_ABx(5, 1, 4),       // GETGLOBAL 1 n
+				Opcode::encode_ABC(25, 0, 1, 0),   // LE 0 1 0
+				Opcode::encode_AsBx(22, 0, -10),   // JMP -10
+				Opcode::encode_ABC(30, 0, 1, 0),   // RETURN 0 1
+			],
+			vec![
+				Constants::Number(0.0),
+				Constants::Number(1.0),
+				string("print"),
+				Constants::Number(3.0),
+				string("n"),
+			],
+		);
+
+		assert_eq!(
+			decompile_source(&ctx).unwrap(),
+			"local v1 = 0\nrepeat\n\tv1 = v1 + 1\n\tprint(v1 == 3)\nuntil n <= v1\n"
+		);
+	}
+
+	#[test]
+	fn test_expressions() {
+		let mut main = proto(
+			vec![
+				Opcode::encode_ABC(10, 0, 2, 1),   // NEWTABLE 0 2 1
+				Opcode::encode_ABx(1, 1, 0),       // LOADK 1 1
+				Opcode::encode_ABx(1, 2, 1),       // LOADK 2 2
+				Opcode::encode_ABx(5, 3, 3),       // GETGLOBAL 3 x
+				Opcode::encode_ABC(9, 0, 258, 3),  // SETTABLE 0 "n" 3
+				Opcode::encode_ABC(34, 0, 2, 1),   // SETLIST 0 2 1
+				Opcode::encode_ABx(5, 1, 4),       // GETGLOBAL 1 a
+				Opcode::encode_ABC(26, 1, 0, 0),   // TEST 1 0
+				Opcode::encode_AsBx(22, 0, 3),     // JMP 3
+				Opcode::encode_ABx(5, 1, 5),       // GETGLOBAL 1 b
+				Opcode::encode_ABC(26, 1, 0, 1),   // TEST 1 1
+				Opcode::encode_AsBx(22, 0, 1),     // JMP 1
+				Opcode::encode_ABx(5, 1, 6),       // GETGLOBAL 1 c
+				Opcode::encode_ABC(11, 2, 0, 263), // SELF 2 0 "insert"
+				Opcode::encode_ABC(0, 4, 1, 0),    // MOVE 4 1
+				Opcode::encode_ABC(28, 2, 3, 1),   // CALL 2 3 1
+				Opcode::encode_ABC(30, 0, 1, 0),   // RETURN 0 1
+			],
+			vec![
+				Constants::Number(1.0),
+				Constants::Number(2.0),
+				string("n"),
+				string("x"),
+				string("a"),
+				string("b"),
+				string("c"),
+				string("insert"),
+			],
+			vec![],
+		);
+		main.locals = Some(vec![local("t", 6, 17), local("y", 13, 17)]);
+		let ctx = IRContext::from_proto(main);
+
+		assert_eq!(
+			decompile_source(&ctx).unwrap(),
+			"local t = {1, 2, n = x}\nlocal y = a and b or c\nt:insert(y)\n"
+		);
+	}
+
+	#[test]
+	fn test_expression_spans() {
+		// return -(x * 2)
+		let ctx = context(
+			vec![
+				Opcode::encode_ABx(5, 0, 0),       // GETGLOBAL 0 x
+				Opcode::encode_ABC(14, 0, 0, 257), // MUL 0 0 2
+				Opcode::encode_ABC(18, 0, 0, 0),   // UNM 0 0
+				Opcode::encode_ABC(30, 0, 2, 0),   // RETURN 0 2
+			],
+			vec![string("x"), Constants::Number(2.0)],
+		);
+
+		let function = decompile(&ctx).unwrap();
+		let [Spanned {
+			node: Stat::Return(values),
+			..
+		}] = function.body.as_slice()
+		else {
+			panic!("{:?}", function.body);
+		};
+		let product = Expr::binary(
+			BinOp::Mul,
+			Spanned::new(Expr::Global("x".to_string()), Span::at(0)),
+			Spanned::new(Expr::Number(2.0), Span::at(1)),
+		);
+		let negation = Expr::unary(UnOp::Neg, Spanned::new(product, Span::at(1)));
+		assert_eq!(values, &vec![negation]);
+	}
+
+	#[test]
+	fn test_varargs() {
+		let mut main = proto(
+			vec![
+				Opcode::encode_ABx(5, 0, 0),     // GETGLOBAL 0 print
+				Opcode::encode_ABC(37, 1, 0, 0), // VARARG 1 0
+				Opcode::encode_ABC(28, 0, 0, 1), // CALL 0 0 1
+				Opcode::encode_ABx(5, 0, 1),     // GETGLOBAL 0 select
+				Opcode::encode_ABx(1, 1, 2),     // LOADK 1 "#"
+				Opcode::encode_ABC(37, 2, 0, 0), // VARARG 2 0
+				Opcode::encode_ABC(29, 0, 0, 0), // TAILCALL 0 0 0
+				Opcode::encode_ABC(30, 0, 0, 0), // RETURN 0 0
+				Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+			],
+			vec![string("print"), string("select"), string("#")],
+			vec![],
+		);
+		main.is_vararg_flag = 2;
+		let ctx = IRContext::from_proto(main);
+
+		assert_eq!(
+			decompile_source(&ctx).unwrap(),
+			"print(...)\nreturn select(\"#\", ...)\n"
+		);
+	}
+
+	#[test]
+	fn test_closure_and_generic_for() {
+		let mut child = proto(
+			vec![
+				Opcode::encode_ABx(5, 1, 0),     // GETGLOBAL 1 pairs
+				Opcode::encode_ABC(0, 2, 0, 0),  // MOVE 2 0
+				Opcode::encode_ABC(28, 1, 2, 4), // CALL 1 2 4
+				Opcode::encode_AsBx(22, 0, 4),   // JMP 4
+				Opcode::encode_ABx(5, 6, 1),     // GETGLOBAL 6 print
+				Opcode::encode_ABC(0, 7, 4, 0),  // MOVE 7 4
+				Opcode::encode_ABC(0, 8, 5, 0),  // MOVE 8 5
+				Opcode::encode_ABC(28, 6, 3, 1), // CALL 6 3 1
+				Opcode::encode_ABC(33, 1, 0, 2), // TFORLOOP 1 2
+				Opcode::encode_AsBx(22, 0, -6),  // JMP -6
+				Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+			],
+			vec![string("pairs"), string("print")],
+			vec![],
+		);
+		child.nparams = 1;
+		child.locals = Some(vec![
+			local("t", 0, 11),
+			local("(for generator)", 3, 10),
+			local("(for state)", 3, 10),
+			local("(for control)", 3, 10),
+			local("k", 4, 8),
+			local("v", 4, 8),
+		]);
+
+		let mut main = proto(
+			vec![
+				Opcode::encode_ABx(36, 0, 0),    // CLOSURE 0 0
+				Opcode::encode_ABC(0, 1, 0, 0),  // MOVE 1 0
+				Opcode::encode_ABx(5, 2, 0),     // GETGLOBAL 2 x
+				Opcode::encode_ABC(28, 1, 2, 1), // CALL 1 2 1
+				Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+			],
+			vec![string("x")],
+			vec![],
+		);
+		main.prototypes = vec![child];
+		main.locals = Some(vec![local("f", 1, 5)]);
+		let ctx = IRContext::from_proto(main);
+
+		assert_eq!(
+			decompile_source(&ctx).unwrap(),
+			"local function f(t)\n\tfor k, v in pairs(t) do\n\t\tprint(k, v)\n\tend\nend\nf(x)\n"
+		);
+	}
+}
@@ -0,0 +1,784 @@
+use super::{
+	cond::{self, negate, Edge, Node},
+	lift::table,
+	DecompileError, FunctionDecompiler, LoopContext, LoopInfo, LoopKind, PendingKind, Result,
+};
+use crate::lua51::dataflow::{Definition, Site};
+use ast::lua51::{BinOp, Block, Expr, Span, Spanned, Stat};
+use std::{collections::BTreeMap, ops::Range};
+
+/// A condition recovered from a chain of tests and jumps, control goes on
+/// at `end` when `expr` holds and jumps to `target` otherwise
+struct Condition {
+	expr: Expr,
+	end: usize,
+	target: usize,
+}
+
+/// Part of a value region, either loads ending in a test or a leaf
+/// computing the value of the region into its register
+enum Part {
+	Test {
+		start: usize,
+		test: usize,
+	},
+	Leaf {
+		start: usize,
+		end: usize,
+		exit: usize,
+	},
+}
+
+impl FunctionDecompiler<'_, '_> {
+	fn jump_target(&self, pc: usize) -> usize {
+		self.info.inst(pc).jump_target(pc).unwrap()
+	}
+
+	fn next(&self, pc: usize) -> usize {
+		pc + 1 + self.info.pseudo_after(pc)
+	}
+
+	/// Whether the instruction at `pc` only computes a value
+	fn is_expression(&self, pc: usize) -> bool {
+		let (opcode, _, b, c) = self.info.op(pc);
+		match opcode {
+			0 | 1 | 3..=6 | 9..=21 | 34 | 36 => true,
+			2 => c == 0,
+			28 => c == 0 || c == 2,
+			37 => b == 0 || b == 2,
+			_ => false,
+		}
+	}
+
+	/// Whether `pc` starts a test followed by its jump
+	fn is_test(&self, pc: usize) -> bool {
+		(23..=27).any(|opcode| self.info.is(pc, opcode)) && self.info.is(pc + 1, 22)
+	}
+
+	/// Whether control at `pc` goes on to `target` with nothing executed
+	fn leaves_to(&self, pc: usize, target: usize) -> bool {
+		pc == target || (self.info.is(pc, 22) && self.jump_target(pc) == target)
+	}
+
+	/// First pc from `pc` on control can reach
+	fn skip_dead(&self, mut pc: usize, end: usize) -> usize {
+		while pc < end && !self.info.targets[pc] {
+			pc += 1;
+		}
+		pc
+	}
+
+	/// Structures the instructions in `range`. A repeat body gives back the
+	/// condition ending it
+	pub(super) fn block(
+		&mut self,
+		range: Range<usize>,
+		lp: Option<&LoopContext>,
+	) -> Result<(Block, Option<Expr>)> {
+		let mut stats = vec![];
+		let mut pc = range.start;
+		let mut keep = false;
+
+		while pc < range.end {
+			if self.info.pseudo[pc] {
+				pc += 1;
+				continue;
+			}
+			if self.info.targets[pc] && !keep {
+				self.flush(&mut stats)?;
+			}
+			keep = false;
+
+			if let Some(back) = self.loop_at(pc, range.end) {
+				pc = self.lift_loop(&mut stats, pc, back)?;
+				continue;
+			}
+
+			let (opcode, _, b, c) = self.info.op(pc);
+			match opcode {
+				23..=27 => {
+					if let Some(end) = self.value_region(&mut stats, pc, range.end)? {
+						pc = end;
+						keep = true;
+						continue;
+					}
+					if let Some(lp) = lp.filter(|lp| lp.until) {
+						let snapshot = self.snapshot();
+						match self.parse_condition(pc, range.end) {
+							Ok(Some(cond)) if cond.end == range.end && cond.target == lp.head => {
+								self.flush(&mut stats)?;
+								return Ok((stats, Some(cond.expr)));
+							}
+							_ => self.restore(snapshot),
+						}
+					}
+					pc = self.if_statement(&mut stats, pc, &range, lp)?;
+				}
+				22 => pc = self.jump(&mut stats, pc, &range, lp)?,
+				32 => pc = self.numeric_for(&mut stats, pc)?,
+				29 => {
+					self.lift(&mut stats, pc)?;
+					pc += 1;
+					if self.info.is(pc, 30) {
+						pc += 1;
+					}
+					pc = self.skip_dead(pc, range.end);
+				}
+				30 => {
+					// every function ends in a RETURN luac adds
+					if !(pc + 1 == self.info.len() && b == 1) {
+						self.lift(&mut stats, pc)?;
+					}
+					pc = self.skip_dead(pc + 1, range.end);
+				}
+				2 if c != 0 => return Err(DecompileError::Unstructured(pc)),
+				31 | 33 => return Err(DecompileError::Unstructured(pc)),
+				_ => {
+					self.lift(&mut stats, pc)?;
+					pc = self.next(pc);
+				}
+			}
+		}
+
+		self.flush(&mut stats)?;
+		Ok((stats, None))
+	}
+
+	/// Back jump closing a loop that starts at `pc`, the outermost one
+	fn loop_at(&self, pc: usize, end: usize) -> Option<usize> {
+		if !self.info.targets[pc] {
+			return None;
+		}
+		(pc + 1..end).rev().find(|back| {
+			self.info.is(*back, 22)
+				&& self.jump_target(*back) == pc
+				&& !self.active.contains(&(pc, *back))
+		})
+	}
+
+	/// Lifts the while or repeat loop from `start` to the jump at `back`
+	fn lift_loop(&mut self, stats: &mut Block, start: usize, back: usize) -> Result<usize> {
+		self.flush(stats)?;
+		self.active.push((start, back));
+		self.loops.push(LoopInfo {
+			header: start,
+			range: start..back + 1,
+		});
+
+		let repeat = back > start + 1 && self.is_test(back - 1) && !self.info.targets[back];
+		let stat = if repeat {
+			let lp = LoopContext {
+				kind: LoopKind::Repeat,
+				head: start,
+				exit: back + 1,
+				until: true,
+			};
+			let (body, until) = self.block(start..back + 1, Some(&lp))?;
+			let until = until.ok_or(DecompileError::Unstructured(back))?;
+			Stat::Repeat(body, until)
+		} else {
+			let lp = LoopContext {
+				kind: LoopKind::While,
+				head: start,
+				exit: back + 1,
+				until: false,
+			};
+			let snapshot = self.snapshot();
+			match self.parse_condition(start, back) {
+				Ok(Some(cond)) if cond.target == back + 1 => {
+					let (body, _) = self.block(cond.end..back, Some(&lp))?;
+					Stat::While(cond.expr, body)
+				}
+				_ => {
+					self.restore(snapshot);
+					let (body, _) = self.block(start..back, Some(&lp))?;
+					Stat::While(Expr::Bool(true), body)
+				}
+			}
+		};
+
+		self.active.pop();
+		stats.push(Spanned::new(stat, Span::new(start, back + 1)));
+		Ok(back + 1)
+	}
+
+	fn numeric_for(&mut self, stats: &mut Block, pc: usize) -> Result<usize> {
+		let forloop = self.jump_target(pc);
+		if !self.info.is(forloop, 31) || self.jump_target(forloop) != pc + 1 {
+			return Err(DecompileError::Unstructured(pc));
+		}
+		let a = self.info.op(pc).1;
+		let start = self.take(stats, pc, a)?.node;
+		let limit = self.take(stats, pc, a + 1)?.node;
+		let step = match self.take(stats, pc, a + 2)?.node {
+			Expr::Number(1.0) => None,
+			step => Some(step),
+		};
+		self.flush(stats)?;
+
+		let var = self.var(Definition {
+			register: a + 3,
+			site: Site::Pc(forloop),
+		});
+		self.bound.insert(var.clone());
+		self.loops.push(LoopInfo {
+			header: pc + 1,
+			range: pc + 1..forloop + 1,
+		});
+		let lp = LoopContext {
+			kind: LoopKind::For,
+			head: forloop,
+			exit: forloop + 1,
+			until: false,
+		};
+		let (body, _) = self.block(pc + 1..forloop, Some(&lp))?;
+		let stat = Stat::NumericFor(var, start, limit, step, body);
+		stats.push(Spanned::new(stat, Span::new(pc, forloop + 1)));
+		Ok(forloop + 1)
+	}
+
+	fn generic_for(&mut self, stats: &mut Block, pc: usize, tforloop: usize) -> Result<usize> {
+		let (_, a, _, c) = self.info.op(tforloop);
+		let exprs = self.explist(stats, pc, a, 3)?;
+		self.flush(stats)?;
+
+		let names = (a + 3..a + 3 + c as u8)
+			.map(|reg| {
+				let name = self.var(Definition {
+					register: reg,
+					site: Site::Pc(tforloop),
+				});
+				self.bound.insert(name.clone());
+				name
+			})
+			.collect();
+		self.loops.push(LoopInfo {
+			header: pc + 1,
+			range: pc + 1..tforloop + 2,
+		});
+		let lp = LoopContext {
+			kind: LoopKind::For,
+			head: tforloop,
+			exit: tforloop + 2,
+			until: false,
+		};
+		let (body, _) = self.block(pc + 1..tforloop, Some(&lp))?;
+		let stat = Stat::GenericFor(names, exprs, body);
+		stats.push(Spanned::new(stat, Span::new(pc, tforloop + 2)));
+		Ok(tforloop + 2)
+	}
+
+	fn jump(
+		&mut self,
+		stats: &mut Block,
+		pc: usize,
+		range: &Range<usize>,
+		lp: Option<&LoopContext>,
+	) -> Result<usize> {
+		if let Some(tforloop) = self.info.generic_for(pc) {
+			return self.generic_for(stats, pc, tforloop);
+		}
+		let target = self.jump_target(pc);
+		if lp.is_some_and(|lp| lp.exit == target) {
+			self.emit(stats, pc, Stat::Break)?;
+			return Ok(self.skip_dead(pc + 1, range.end));
+		}
+		let falls_through = target == pc + 1
+			|| (pc + 1 == range.end && self.leaves_to(range.end, target))
+			|| lp.is_some_and(|lp| {
+				lp.kind == LoopKind::While && lp.head == target && pc + 1 == range.end
+			});
+		if falls_through {
+			return Ok(pc + 1);
+		}
+		// nothing reaches the code jumped over
+		if target > pc && target <= range.end && !self.info.targets[pc + 1..target].contains(&true)
+		{
+			return Ok(target);
+		}
+		Err(DecompileError::Unstructured(pc))
+	}
+
+	fn if_statement(
+		&mut self,
+		stats: &mut Block,
+		pc: usize,
+		range: &Range<usize>,
+		lp: Option<&LoopContext>,
+	) -> Result<usize> {
+		let cond = self
+			.parse_condition(pc, range.end)?
+			.ok_or(DecompileError::Unstructured(pc))?;
+		let (start, target) = (cond.end, cond.target);
+
+		if lp.is_some_and(|lp| lp.exit == target) {
+			self.emit(
+				stats,
+				pc,
+				Stat::If(
+					negate(cond.expr),
+					vec![Spanned::new(Stat::Break, Span::at(start - 1))],
+					vec![],
+				),
+			)?;
+			return Ok(start);
+		}
+		let target = match lp {
+			Some(lp) if lp.kind == LoopKind::While && lp.head == target => range.end,
+			// luac threads jumps to the end of an enclosing if onto its target
+			_ if target > range.end && self.leaves_to(range.end, target) => range.end,
+			_ => target,
+		};
+		if target < start || target > range.end {
+			return Err(DecompileError::Unstructured(pc));
+		}
+
+		// a then block ending in a jump over an else block
+		let (mut then_end, mut end) = (target, target);
+		let escape = target - 1;
+		if target > start && self.info.is(escape, 22) && !(escape >= 1 && self.is_test(escape - 1))
+		{
+			let over = self.jump_target(escape);
+			if lp.is_some_and(|lp| lp.exit == over) {
+				// break
+			} else if over > target {
+				then_end = escape;
+				end = over.min(range.end);
+			} else if lp.is_some_and(|lp| lp.kind == LoopKind::While && lp.head == over) {
+				then_end = escape;
+				end = range.end;
+			}
+		}
+
+		self.flush(stats)?;
+		let inner = lp.map(|lp| LoopContext {
+			until: false,
+			..lp.clone()
+		});
+		let (then, _) = self.block(start..then_end, inner.as_ref())?;
+		let (otherwise, _) = self.block(target..end, inner.as_ref())?;
+		let stat = Stat::If(cond.expr, then, otherwise);
+		stats.push(Spanned::new(stat, Span::new(pc, end)));
+		Ok(end)
+	}
+
+	/// Segments of loads ending in a test and its jump from `start` on
+	fn segments(&self, start: usize, limit: usize) -> Vec<(usize, usize)> {
+		let mut segments = vec![];
+		let mut pos = start;
+		loop {
+			let mut test = pos;
+			while test < limit
+				&& (test == pos || !self.info.targets[test])
+				&& self.is_expression(test)
+			{
+				test = self.next(test);
+			}
+			let entered = test == pos || !self.info.targets[test];
+			if test + 1 >= limit
+				|| !entered || !(23..=26).any(|opcode| self.info.is(test, opcode))
+				|| !self.info.is(test + 1, 22)
+				|| self.info.targets[test + 1]
+			{
+				return segments;
+			}
+			segments.push((pos, test));
+			pos = test + 2;
+		}
+	}
+
+	/// Whether the first `count` segments form a condition, gives the
+	/// target of its false exit
+	fn condition_target(&self, start: usize, segments: &[(usize, usize)]) -> Option<usize> {
+		let end = segments.last()?.1 + 2;
+		let mut external = None;
+		for (idx, (_, test)) in segments.iter().enumerate() {
+			for target in [self.jump_target(test + 1), test + 2] {
+				let inner = segments[idx + 1..]
+					.iter()
+					.any(|(start, _)| *start == target);
+				if target == end || inner {
+					continue;
+				}
+				if (target < start || target > end) && external.is_none_or(|ext| ext == target) {
+					external = Some(target);
+				} else {
+					return None;
+				}
+			}
+		}
+		external
+	}
+
+	/// Condition of the tests from `start` on taking the most segments that
+	/// leave to one place
+	fn parse_condition(&mut self, start: usize, limit: usize) -> Result<Option<Condition>> {
+		let segments = self.segments(start, limit);
+		for count in (1..=segments.len()).rev() {
+			let segments = &segments[..count];
+			let Some(target) = self.condition_target(start, segments) else {
+				continue;
+			};
+			let end = segments[count - 1].1 + 2;
+
+			let snapshot = self.snapshot();
+			self.expression = true;
+			let expr = self.lift_condition(segments, end, target);
+			self.expression = false;
+			match expr {
+				Ok(Some(expr)) => return Ok(Some(Condition { expr, end, target })),
+				_ => self.restore(snapshot),
+			}
+		}
+		Ok(None)
+	}
+
+	fn lift_condition(
+		&mut self,
+		segments: &[(usize, usize)],
+		end: usize,
+		target: usize,
+	) -> Result<Option<Expr>> {
+		let mut nodes = BTreeMap::new();
+		let mut scratch = vec![];
+		let edge = |at: usize| {
+			if at == end {
+				Edge::True
+			} else if at == target {
+				Edge::False
+			} else {
+				Edge::Node(at)
+			}
+		};
+		for (start, test) in segments {
+			let mut pc = *start;
+			while pc < *test {
+				self.lift(&mut scratch, pc)?;
+				pc = self.next(pc);
+			}
+			let cond = self.test(&mut scratch, *test)?;
+			nodes.insert(
+				*start,
+				Node::Branch {
+					cond,
+					taken: edge(self.jump_target(test + 1)),
+					fall: edge(test + 2),
+				},
+			);
+		}
+		Ok(cond::condition(nodes))
+	}
+
+	/// Condition under which the test at `pc` takes its jump
+	pub(super) fn test(&mut self, stats: &mut Block, pc: usize) -> Result<Expr> {
+		let (opcode, a, b, c) = self.info.op(pc);
+		Ok(match opcode {
+			23..=25 => {
+				let left = self.rk(stats, pc, b)?;
+				let right = self.rk(stats, pc, c)?;
+				let (op, negated) = match opcode {
+					23 => (BinOp::Eq, BinOp::Ne),
+					24 => (BinOp::Lt, BinOp::Ge),
+					_ => (BinOp::Le, BinOp::Gt),
+				};
+				match (opcode, a) {
+					(_, 0) if opcode == 23 => Expr::binary(negated, left, right),
+					(_, 0) => cond::negate_value(Expr::binary(op, left, right)),
+					_ => Expr::binary(op, left, right),
+				}
+			}
+			26 => {
+				let value = self.take(stats, pc, a)?.node;
+				if c != 0 {
+					value
+				} else {
+					cond::negate_value(value)
+				}
+			}
+			_ => return Err(DecompileError::Unstructured(pc)),
+		})
+	}
+
+	/// End of the region of tests, TESTSETs and LOADBOOLs from `pc` that
+	/// all jump forward to one merge point
+	fn region_end(&self, pc: usize, limit: usize) -> Option<usize> {
+		let mut end = pc + 1;
+		let mut at = pc;
+		while at < end {
+			if self.info.pseudo[at] {
+				at += 1;
+				continue;
+			}
+			let (opcode, _, _, c) = self.info.op(at);
+			match opcode {
+				23..=27 => {
+					if !self.info.is(at + 1, 22) {
+						return None;
+					}
+					let target = self.jump_target(at + 1);
+					if target <= at + 1 {
+						return None;
+					}
+					end = end.max(target).max(at + 2);
+					at += 2;
+				}
+				22 => {
+					let target = self.jump_target(at);
+					if target <= at {
+						return None;
+					}
+					end = end.max(target).max(at + 1);
+					at += 1;
+				}
+				2 if c != 0 => {
+					end = end.max(at + 2);
+					at += 1;
+				}
+				_ if self.is_expression(at) => {
+					end = end.max(self.next(at));
+					at = self.next(at);
+				}
+				_ => return None,
+			}
+			if end > limit {
+				return None;
+			}
+		}
+		Some(end)
+	}
+
+	/// Splits a value region into its parts
+	fn region_parts(&self, pc: usize, end: usize) -> Option<Vec<Part>> {
+		let mut parts = vec![];
+		let mut pos = pc;
+		while pos < end {
+			let mut at = pos;
+			loop {
+				if at >= end {
+					parts.push(Part::Leaf {
+						start: pos,
+						end,
+						exit: end,
+					});
+					break;
+				}
+				if at > pos && self.info.targets[at] {
+					return None;
+				}
+				if self.info.pseudo[at] {
+					at += 1;
+					continue;
+				}
+				let (opcode, _, _, c) = self.info.op(at);
+				match opcode {
+					23..=27 => {
+						if self.info.targets[at + 1] {
+							return None;
+						}
+						parts.push(Part::Test {
+							start: pos,
+							test: at,
+						});
+						at += 2;
+						break;
+					}
+					22 => {
+						if at == pos {
+							return None;
+						}
+						parts.push(Part::Leaf {
+							start: pos,
+							end: at,
+							exit: self.jump_target(at),
+						});
+						at += 1;
+						break;
+					}
+					2 if c != 0 => {
+						parts.push(Part::Leaf {
+							start: pos,
+							end: at + 1,
+							exit: at + 2,
+						});
+						at += 1;
+						break;
+					}
+					_ => at = self.next(at),
+				}
+			}
+			pos = at;
+		}
+		Some(parts)
+	}
+
+	/// Register the region computes, written by every TESTSET and leaf
+	fn region_register(&self, parts: &[Part]) -> Option<u8> {
+		let mut reg = None;
+		for part in parts {
+			let written = match part {
+				Part::Test { test, .. } if self.info.is(*test, 27) => self.info.op(*test).1,
+				Part::Test { .. } => continue,
+				Part::Leaf { start, end, .. } => {
+					let last = (*start..*end).rev().find(|pc| !self.info.pseudo[*pc])?;
+					self.info.op(last).1
+				}
+			};
+			if reg.is_some_and(|reg| reg != written) {
+				return None;
+			}
+			reg = Some(written);
+		}
+		reg
+	}
+
+	/// Lifts the and/or expression or comparison value starting at the
+	/// test at `pc` if there is one, gives where it ends
+	fn value_region(
+		&mut self,
+		stats: &mut Block,
+		pc: usize,
+		limit: usize,
+	) -> Result<Option<usize>> {
+		let Some(end) = self.region_end(pc, limit) else {
+			return Ok(None);
+		};
+		let Some(parts) = self.region_parts(pc, end) else {
+			return Ok(None);
+		};
+		let Some(reg) = self.region_register(&parts) else {
+			return Ok(None);
+		};
+
+		let defs = (pc..end)
+			.filter(|at| !self.info.pseudo[*at])
+			.map(|at| Definition {
+				register: reg,
+				site: Site::Pc(at),
+			})
+			.filter(|def| self.info.defines(*def))
+			.collect::<Vec<_>>();
+
+		let snapshot = self.snapshot();
+		self.expression = true;
+		self.forced.extend(defs.iter().copied());
+		let value = self.lift_region(&parts, end, reg);
+		self.forced.clear();
+		self.expression = false;
+
+		let value = match value {
+			Ok(Some(value)) => value,
+			_ => {
+				self.restore(snapshot);
+				return Ok(None);
+			}
+		};
+
+		if self.info.is_temporary(&defs, pc..end) {
+			self.pending.insert(
+				reg,
+				super::Pending {
+					expr: value,
+					def: defs[0],
+					seq: {
+						self.seq += 1;
+						self.seq
+					},
+					kind: PendingKind::Value,
+				},
+			);
+		} else {
+			let name = self.var(defs[0]);
+			self.emit(stats, pc, Stat::Assign(vec![Expr::Name(name)], vec![value]))?;
+		}
+		Ok(Some(end))
+	}
+
+	fn lift_region(&mut self, parts: &[Part], end: usize, reg: u8) -> Result<Option<Expr>> {
+		let mut nodes = BTreeMap::new();
+		let mut scratch = vec![];
+		let starts = parts
+			.iter()
+			.map(|part| match part {
+				Part::Test { start, .. } | Part::Leaf { start, .. } => *start,
+			})
+			.collect::<Vec<_>>();
+		let edge = |at: usize| {
+			if at == end {
+				Some(Edge::End)
+			} else if starts.contains(&at) {
+				Some(Edge::Node(at))
+			} else {
+				None
+			}
+		};
+		let outer = self.pending.keys().copied().collect::<Vec<_>>();
+
+		for part in parts {
+			match part {
+				Part::Test { start, test } => {
+					let mut at = *start;
+					while at < *test {
+						self.lift(&mut scratch, at)?;
+						at = self.next(at);
+					}
+					let (opcode, a, b, c) = self.info.op(*test);
+					let jump = self.jump_target(test + 1);
+					let (Some(taken), Some(fall)) = (edge(jump), edge(test + 2)) else {
+						return Ok(None);
+					};
+					// a TEST of the register itself leaves with its value
+					let source = match opcode {
+						27 => Some(b as u8),
+						26 if a == reg && taken == Edge::End => Some(a),
+						_ => None,
+					};
+					let node = if let Some(source) = source {
+						if taken != Edge::End {
+							return Ok(None);
+						}
+						Node::TestSet {
+							value: self.take(&mut scratch, *test, source)?.node,
+							polarity: c != 0,
+							fall,
+						}
+					} else {
+						Node::Branch {
+							cond: self.test(&mut scratch, *test)?,
+							taken,
+							fall,
+						}
+					};
+					nodes.insert(*start, node);
+				}
+				Part::Leaf {
+					start,
+					end: leaf_end,
+					exit,
+				} => {
+					if *exit != end {
+						return Ok(None);
+					}
+					let mut at = *start;
+					while at < *leaf_end {
+						self.lift(&mut scratch, at)?;
+						at = self.next(at);
+					}
+					let value = match self.pending.remove(&reg) {
+						Some(pending) => match pending.kind {
+							PendingKind::Value => pending.expr,
+							PendingKind::Table { fields, .. } => table(fields),
+							_ => return Ok(None),
+						},
+						None => return Ok(None),
+					};
+					nodes.insert(*start, Node::Leaf(value));
+				}
+			}
+			// values computed on one path may not be left for another
+			if self.pending.keys().any(|reg| !outer.contains(reg)) {
+				return Ok(None);
+			}
+		}
+		Ok(cond::value(nodes))
+	}
+}
@@ -0,0 +1,176 @@
+use super::CFG;
+use std::collections::BTreeSet;
+
+/**
+ * Dominators - Dominator tree and dominance frontiers of a CFG
+ * Uses the iterative algorithm from Cooper, Harvey and Kennedy,
+ * block 0 is the entry and unreachable blocks have no dominator
+ */
+pub struct Dominators {
+	idom: Vec<Option<usize>>,
+	children: Vec<Vec<usize>>,
+	frontiers: Vec<BTreeSet<usize>>,
+	order: Vec<usize>, // reverse postorder of reachable blocks
+}
+
+fn reverse_postorder(cfg: &CFG) -> Vec<usize> {
+	let mut visited = vec![false; cfg.len()];
+	let mut order = vec![];
+	if cfg.is_empty() {
+		return order;
+	}
+
+	// (block, next successor to visit)
+	let mut stack = vec![(0, 0)];
+	visited[0] = true;
+	while let Some((block, next)) = stack.pop() {
+		let succs = cfg.successors(block);
+		if let Some(succ) = succs.get(next) {
+			stack.push((block, next + 1));
+			if !visited[*succ] {
+				visited[*succ] = true;
+				stack.push((*succ, 0));
+			}
+		} else {
+			order.push(block);
+		}
+	}
+
+	order.reverse();
+	order
+}
+
+impl Dominators {
+	pub fn new(cfg: &CFG) -> Self {
+		let len = cfg.len();
+		let order = reverse_postorder(cfg);
+		let mut position = vec![usize::MAX; len];
+		for (i, block) in order.iter().enumerate() {
+			position[*block] = i;
+		}
+		let predecessors = (0..len)
+			.map(|block| cfg.predecessors(block))
+			.collect::<Vec<_>>();
+
+		let mut idom: Vec<Option<usize>> = vec![None; len];
+		if len != 0 {
+			idom[0] = Some(0);
+		}
+
+		let intersect = |idom: &Vec<Option<usize>>, mut a: usize, mut b: usize| {
+			while a != b {
+				while position[a] > position[b] {
+					a = idom[a].unwrap();
+				}
+				while position[b] > position[a] {
+					b = idom[b].unwrap();
+				}
+			}
+			a
+		};
+
+		let mut changed = true;
+		while changed {
+			changed = false;
+			for block in order.iter().skip(1) {
+				let mut new_idom = None;
+				for pred in &predecessors[*block] {
+					if idom[*pred].is_none() {
+						continue;
+					}
+					new_idom = Some(match new_idom {
+						None => *pred,
+						Some(current) => intersect(&idom, *pred, current),
+					});
+				}
+				if new_idom != idom[*block] {
+					idom[*block] = new_idom;
+					changed = true;
+				}
+			}
+		}
+
+		let mut children = vec![vec![]; len];
+		for block in order.iter().skip(1) {
+			children[idom[*block].unwrap()].push(*block);
+		}
+
+		let mut frontiers = vec![BTreeSet::new(); len];
+		for block in &order {
+			let preds = predecessors[*block]
+				.iter()
+				.filter(|pred| idom[**pred].is_some())
+				.collect::<Vec<_>>();
+			// the entry also joins the implicit edge from the caller
+			let joins = preds.len() + if *block == 0 { 1 } else { 0 };
+			if joins < 2 {
+				continue;
+			}
+			if *block == 0 {
+				frontiers[0].insert(0);
+			}
+			for pred in preds {
+				let mut runner = *pred;
+				while Some(runner) != idom[*block] {
+					frontiers[runner].insert(*block);
+					if runner == 0 {
+						break;
+					}
+					runner = idom[runner].unwrap();
+				}
+			}
+		}
+
+		// the entry is its own dominator only while solving
+		if len != 0 {
+			idom[0] = None;
+		}
+
+		Self {
+			idom,
+			children,
+			frontiers,
+			order,
+		}
+	}
+
+	/// Immediate dominator of `block`, None for the entry and unreachable blocks
+	pub fn idom(&self, block: usize) -> Option<usize> {
+		self.idom[block]
+	}
+
+	pub fn is_reachable(&self, block: usize) -> bool {
+		block == 0 || self.idom[block].is_some()
+	}
+
+	/// Whether every path from the entry to `b` goes through `a`
+	pub fn dominates(&self, a: usize, b: usize) -> bool {
+		if !self.is_reachable(b) {
+			return false;
+		}
+		let mut block = b;
+		loop {
+			if block == a {
+				return true;
+			}
+			match self.idom[block] {
+				Some(idom) => block = idom,
+				None => return false,
+			}
+		}
+	}
+
+	/// Blocks immediately dominated by `block`
+	pub fn children(&self, block: usize) -> &[usize] {
+		&self.children[block]
+	}
+
+	pub fn frontier(&self, block: usize) -> &BTreeSet<usize> {
+		&self.frontiers[block]
+	}
+
+	/// Reachable blocks in reverse postorder
+	pub fn reverse_postorder(&self) -> &[usize] {
+		&self.order
+	}
+}
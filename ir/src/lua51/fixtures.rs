@@ -0,0 +1,51 @@
+use super::{
+	passes::dce::required_stack_size,
+	vm::{Compat, Value, Vm},
+	IRContext,
+};
+use bytecode::lua51::{instructions::Opcode, Constants, Proto};
+
+/// Function of encoded `instructions` with the registers they use
+pub fn proto(instructions: Vec<u32>, constants: Vec<Constants>, prototypes: Vec<Proto>) -> Proto {
+	let mut proto = Proto {
+		instructions: instructions
+			.into_iter()
+			.map(Opcode::from_serialized)
+			.collect(),
+		constants,
+		prototypes,
+		..Default::default()
+	};
+	proto.max_stack_size = required_stack_size(&IRContext::from_proto(proto.clone())) as u8;
+	proto
+}
+
+/// Context of a function without closures
+pub fn context(instructions: Vec<u32>, constants: Vec<Constants>) -> IRContext {
+	IRContext::from_proto(proto(instructions, constants, vec![]))
+}
+
+/// Instructions as their Display prints them
+pub fn listing(ctx: &IRContext) -> Vec<String> {
+	ctx.instructions
+		.iter()
+		.map(|inst| inst.to_string())
+		.collect()
+}
+
+pub fn string(s: &str) -> Constants {
+	Constants::String(s.to_string())
+}
+
+pub fn numbers(numbers: &[f64]) -> Vec<Constants> {
+	numbers.iter().copied().map(Constants::Number).collect()
+}
+
+/// Calls `ctx` with the standard library open, returns its results or error message
+pub fn run(compat: Compat, ctx: &IRContext) -> Result<Vec<Value>, String> {
+	let mut vm = Vm::new();
+	vm.open_libs();
+	vm.set_compat(compat);
+	let main = vm.load(ctx);
+	vm.call(&main, vec![]).map_err(|err| err.to_string())
+}
@@ -1,7 +1,16 @@
 mod cfg;
 pub use cfg::CFG;
 mod context;
+pub mod dataflow;
+pub mod decompile;
+mod dominators;
+#[cfg(test)]
+mod fixtures;
 mod opcodes;
+pub mod passes;
+pub mod ssa;
+pub mod vm;
 
 pub use context::IRContext;
+pub use dominators::Dominators;
 pub use opcodes::get_opcode_name;
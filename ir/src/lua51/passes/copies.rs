@@ -0,0 +1,325 @@
+use super::{dce::remove_dead_instructions, follows_skip, manager::Analyses, operands};
+use crate::lua51::{
+	context::{EditError, IRInstruction},
+	dataflow::{def_use, solve, Analysis, DefUse, Direction, Lattice, Liveness, RegisterSet},
+	IRContext,
+};
+use bytecode::lua51::instructions::Value;
+
+/// Register another register is known to be a copy of at one point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+	Undefined, // not reached yet
+	Register(u8),
+	Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sources(Vec<Source>);
+
+impl Sources {
+	pub fn get(&self, reg: u8) -> Source {
+		self.0[reg as usize]
+	}
+}
+
+impl Lattice for Sources {
+	fn join(&mut self, other: &Self) -> bool {
+		let mut changed = false;
+		for (copy, other) in self.0.iter_mut().zip(&other.0) {
+			let joined = match (*copy, *other) {
+				(_, Source::Undefined) | (Source::Unknown, _) => continue,
+				(Source::Undefined, other) => other,
+				(Source::Register(a), Source::Register(b)) if a == b => continue,
+				_ => Source::Unknown,
+			};
+			*copy = joined;
+			changed = true;
+		}
+		changed
+	}
+}
+
+/**
+ * AvailableCopies - Registers holding a copy of another register on every
+ * path, made by a MOVE neither register has been written since. Captured
+ * registers take part in no copy, closures may write them at any call
+ */
+pub struct AvailableCopies<'a> {
+	ctx: &'a IRContext,
+	table: Vec<DefUse>,
+	captured: RegisterSet,
+}
+
+impl<'a> AvailableCopies<'a> {
+	pub fn new(ctx: &'a IRContext) -> Self {
+		Self {
+			ctx,
+			table: def_use(ctx),
+			captured: *Liveness::new(ctx).captured(),
+		}
+	}
+}
+
+impl Analysis for AvailableCopies<'_> {
+	type Domain = Sources;
+
+	const DIRECTION: Direction = Direction::Forward;
+
+	fn bottom(&self) -> Self::Domain {
+		Sources(vec![Source::Undefined; 256])
+	}
+
+	fn boundary(&self) -> Self::Domain {
+		Sources(vec![Source::Unknown; 256])
+	}
+
+	fn transfer(&self, pc: usize, state: &mut Self::Domain) {
+		let defs = &self.table[pc].defs;
+		for copy in state.0.iter_mut() {
+			if matches!(copy, Source::Register(src) if defs.contains(*src)) {
+				*copy = Source::Unknown;
+			}
+		}
+		for reg in defs.iter() {
+			state.0[reg as usize] = Source::Unknown;
+		}
+
+		let inst = self.ctx.instructions.get(pc).unwrap();
+		let (a, b, _) = operands(inst);
+		let (a, b) = (a, b as u8);
+		if inst.is(0)
+			&& !inst.is_pseudo()
+			&& a != b && !self.captured.contains(a)
+			&& !self.captured.contains(b)
+		{
+			state.0[a as usize] = Source::Register(b);
+		}
+	}
+}
+
+/// Which of A, B and C name a single register the instruction only reads
+fn register_reads(inst: &IRInstruction) -> (bool, bool, bool) {
+	if inst.is_pseudo() {
+		return (false, false, false);
+	}
+	let (_, b, c) = operands(inst);
+	let (b, c) = (b <= 0xff, c <= 0xff);
+	match inst.opcode() {
+		// MOVE, UNM, NOT, LEN, TESTSET
+		0 | 18 | 19 | 20 | 27 => (false, true, false),
+		// GETTABLE, SELF
+		6 | 11 => (false, true, c),
+		// SETGLOBAL, SETUPVAL, TEST
+		7 | 8 | 26 => (true, false, false),
+		// SETTABLE
+		9 => (true, b, c),
+		// ADD, SUB, MUL, DIV, MOD, POW, EQ, LT, LE
+		12..=17 | 23..=25 => (false, b, c),
+		// CALL, RETURN, CONCAT, the loops and SETLIST read ranges that must stay contiguous
+		_ => (false, false, false),
+	}
+}
+
+/// Replaces reads of copied registers by the register they copy, returns whether any was
+pub fn substitute_copies(ctx: &mut IRContext, analyses: &mut Analyses) -> Result<bool, EditError> {
+	let results = solve(analyses.cfg(ctx), AvailableCopies::new(ctx));
+
+	let mut rewrites = vec![];
+	for pc in 0..ctx.instructions.len() {
+		let inst = ctx.instructions.get(pc).unwrap();
+		let (reads_a, reads_b, reads_c) = register_reads(inst);
+		if !(reads_a || reads_b || reads_c) {
+			continue;
+		}
+
+		let state = results.before(pc);
+		let source = |read: bool, reg: u16| match state.get(reg as u8) {
+			Source::Register(src) if read => src as u16,
+			_ => reg,
+		};
+		let (a, b, c) = operands(inst);
+		let new = (
+			source(reads_a, a as u16) as u8,
+			source(reads_b, b),
+			source(reads_c, c),
+		);
+		if new != (a, b, c) {
+			let new = match inst.opcode() {
+				7 => IRInstruction::new_abx(7, new.0, new.1 as u32),
+				opcode => IRInstruction::new_abc(opcode, new.0, new.1, new.2),
+			};
+			rewrites.push((pc, new));
+		}
+	}
+	drop(results);
+
+	let changed = !rewrites.is_empty();
+	if changed {
+		analyses.invalidate(&[]);
+	}
+	for (pc, new) in rewrites {
+		ctx.instructions.replace_range(pc..pc + 1, vec![new])?;
+	}
+	Ok(changed)
+}
+
+/**
+ * coalesce_moves - Writes results straight into the register a MOVE right
+ * after the instruction copies them to, when the original register is
+ * dead afterwards. Only instructions writing A alone are retargeted, the
+ * results of calls, VARARG and the loops have fixed places
+ */
+pub fn coalesce_moves(ctx: &mut IRContext, analyses: &mut Analyses) -> Result<bool, EditError> {
+	let mut changed = false;
+
+	// MOVE A A copies nothing
+	let moves = (0..ctx.instructions.len())
+		.filter(|pc| {
+			let inst = ctx.instructions.get(*pc).unwrap();
+			let (a, b, _) = operands(inst);
+			inst.is(0) && !inst.is_pseudo() && a as u16 == b
+		})
+		.filter(|pc| !follows_skip(ctx, *pc))
+		.collect::<Vec<_>>();
+	for pc in moves.into_iter().rev() {
+		analyses.invalidate(&[]);
+		ctx.instructions.remove(pc)?;
+		changed = true;
+	}
+
+	let liveness = analyses.liveness(ctx);
+	let mut pairs: Vec<usize> = vec![];
+	for pc in 0..ctx.instructions.len().saturating_sub(1) {
+		let inst = ctx.instructions.get(pc).unwrap();
+		let next = ctx.instructions.get(pc + 1).unwrap();
+		let (a, _, c) = operands(inst);
+		let (dest, src, _) = operands(next);
+		let retargetable = match inst.opcode() {
+			// MOVE, LOADK, GETUPVAL, GETGLOBAL, GETTABLE, NEWTABLE, arithmetic, UNM, NOT, LEN, CONCAT
+			0 | 1 | 4 | 5 | 6 | 10 | 12..=21 => true,
+			// LOADBOOL that does not skip the MOVE
+			2 => c == 0,
+			_ => false,
+		};
+
+		// the MOVE must run exactly when the instruction before it does
+		if !retargetable
+			|| inst.is_pseudo()
+			|| !next.is(0)
+			|| next.is_pseudo()
+			|| src != a as u16
+			|| dest == a
+			|| !next.labels().is_empty()
+			|| follows_skip(ctx, pc)
+			|| liveness.is_live_after(pc + 1, a)
+			|| pairs.last().is_some_and(|last| last + 1 == pc)
+		{
+			continue;
+		}
+		pairs.push(pc);
+	}
+
+	// a retargeted result leaves the liveness of every other register as it was
+	for pc in pairs.into_iter().rev() {
+		analyses.invalidate(&[]);
+		let mut new = ctx.instructions.get(pc).unwrap().clone();
+		let (dest, _, _) = operands(ctx.instructions.get(pc + 1).unwrap());
+		new.take_labels();
+		new.set_a(Value::Reg(dest));
+		ctx.instructions.replace_range(pc..pc + 2, vec![new])?;
+		changed = true;
+	}
+
+	Ok(changed)
+}
+
+/**
+ * eliminate_moves - Copy propagation and MOVE coalescing, followed by
+ * removing the MOVEs left dead. Operands that are part of a register
+ * range, call arguments and results, for loop slots and CONCAT operands,
+ * are never renamed. Runs until nothing changes and returns whether
+ * anything did
+ */
+pub fn eliminate_moves(ctx: &mut IRContext, analyses: &mut Analyses) -> Result<bool, EditError> {
+	let mut changed = false;
+
+	loop {
+		let mut round = substitute_copies(ctx, analyses)?;
+		round |= coalesce_moves(ctx, analyses)?;
+		round |= remove_dead_instructions(ctx, analyses)?;
+
+		if !round {
+			return Ok(changed);
+		}
+		changed = true;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::eliminate_moves;
+	use crate::lua51::{
+		fixtures::{listing, proto, string},
+		passes::manager::Analyses,
+		IRContext,
+	};
+	use bytecode::lua51::{instructions::Opcode, Proto};
+
+	#[test]
+	fn test_eliminate_moves() {
+		// local a = ...; local b = a; local c = b + a; print(c)
+		let mut context = IRContext::from_proto(Proto {
+			nparams: 1,
+			..proto(
+				vec![
+					Opcode::encode_ABC(0, 1, 0, 0),  // MOVE 1 0
+					Opcode::encode_ABC(0, 2, 1, 0),  // MOVE 2 1
+					Opcode::encode_ABx(5, 4, 0),     // GETGLOBAL 4 "print"
+					Opcode::encode_ABC(12, 3, 2, 1), // ADD 3 2 1
+					Opcode::encode_ABC(0, 5, 3, 0),  // MOVE 5 3
+					Opcode::encode_ABC(28, 4, 2, 1), // CALL 4 2 1
+					Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+				],
+				vec![string("print")],
+				vec![],
+			)
+		});
+
+		assert!(eliminate_moves(&mut context, &mut Analyses::default()).unwrap());
+		assert_eq!(
+			listing(&context),
+			vec![
+				"GETGLOBAL \t4 0",
+				"ADD       \t5 0 0",
+				"CALL      \t4 2 1",
+				"RETURN    \t0 1",
+			]
+		);
+		assert!(!eliminate_moves(&mut context, &mut Analyses::default()).unwrap());
+	}
+
+	#[test]
+	fn test_captured_registers() {
+		let mut context = IRContext::from_proto(Proto {
+			nparams: 1,
+			..proto(
+				vec![
+					Opcode::encode_ABC(0, 1, 0, 0),  // MOVE 1 0
+					Opcode::encode_ABx(36, 2, 0),    // CLOSURE 2 0
+					Opcode::encode_ABC(0, 0, 1, 0),  // captures R1
+					Opcode::encode_ABC(28, 2, 1, 1), // CALL 2 1 1, may change R1
+					Opcode::encode_ABC(0, 3, 1, 0),  // MOVE 3 1
+					Opcode::encode_ABC(30, 3, 2, 0), // RETURN 3 2
+				],
+				vec![],
+				vec![Proto {
+					nupvals: 1,
+					..Default::default()
+				}],
+			)
+		});
+
+		assert!(!eliminate_moves(&mut context, &mut Analyses::default()).unwrap());
+	}
+}
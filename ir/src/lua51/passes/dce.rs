@@ -0,0 +1,224 @@
+use super::{manager::Analyses, operands};
+use crate::lua51::{context::EditError, IRContext};
+
+/// Removes every block no path from the entry reaches, returns whether any was
+pub fn remove_unreachable(ctx: &mut IRContext, analyses: &mut Analyses) -> Result<bool, EditError> {
+	let cfg = analyses.cfg(ctx);
+	let dominators = analyses.dominators(ctx);
+	let len = ctx.instructions.len();
+
+	// the final RETURN stays, the VM rejects code ending in anything else
+	let mut runs: Vec<std::ops::Range<usize>> = vec![];
+	for block in (0..cfg.len()).filter(|block| !dominators.is_reachable(*block)) {
+		let range = cfg.get_block(block).unwrap().range().clone();
+		let range = range.start..range.end.min(len.saturating_sub(1));
+		match runs.last_mut() {
+			Some(run) if run.end == range.start => run.end = range.end,
+			_ => runs.push(range),
+		}
+	}
+
+	let mut changed = false;
+	for mut run in runs.into_iter().rev() {
+		// an instruction a reachable skip jumps over keeps its place
+		if run.start > 0 && is_skip(ctx, run.start - 1) {
+			run.start += 1;
+		}
+		if run.is_empty() {
+			continue;
+		}
+		analyses.invalidate(&[]);
+		ctx.instructions.replace_range(run, vec![])?;
+		changed = true;
+	}
+
+	Ok(changed)
+}
+
+fn is_skip(ctx: &IRContext, pc: usize) -> bool {
+	let inst = ctx.instructions.get(pc).unwrap();
+	!inst.is_pseudo() && inst.is_skip()
+}
+
+/// Whether the instruction only writes its registers, without metamethods or errors
+fn is_pure(ctx: &IRContext, pc: usize) -> bool {
+	let inst = ctx.instructions.get(pc).unwrap();
+	let (_, _, c) = operands(inst);
+	match inst.opcode() {
+		// MOVE, LOADK, LOADNIL, GETUPVAL, NEWTABLE, NOT, CLOSURE, VARARG
+		0 | 1 | 3 | 4 | 10 | 19 | 36 | 37 => !inst.is_pseudo(),
+		// LOADBOOL that does not skip
+		2 => c == 0,
+		_ => false,
+	}
+}
+
+/// Removes pure instructions whose results are never read, returns whether any was
+pub fn remove_dead_instructions(
+	ctx: &mut IRContext,
+	analyses: &mut Analyses,
+) -> Result<bool, EditError> {
+	let mut changed = false;
+
+	loop {
+		let liveness = analyses.liveness(ctx);
+
+		let dead = (0..ctx.instructions.len())
+			.filter(|pc| is_pure(ctx, *pc))
+			.filter(|pc| {
+				let du = liveness.analysis().def_use(*pc);
+				let live = liveness.after(*pc);
+				du.defs.iter().all(|reg| !live.contains(reg))
+			})
+			.filter(|pc| *pc == 0 || !is_skip(ctx, pc - 1))
+			.collect::<Vec<_>>();
+
+		if dead.is_empty() {
+			return Ok(changed);
+		}
+		analyses.invalidate(&[]);
+		for pc in dead.into_iter().rev() {
+			ctx.instructions.remove(pc)?;
+		}
+		changed = true;
+	}
+}
+
+/// Registers the instructions need, with the floor of 2 luac always reserves
+pub fn required_stack_size(ctx: &IRContext) -> usize {
+	let mut size = (ctx.nparams() as usize + (ctx.vararg() & 2 != 0) as usize).max(2);
+
+	for inst in ctx.instructions.iter() {
+		let (a, b, c) = operands(inst);
+		let (a, b, c) = (a as usize, b as usize, c as usize);
+		let rk = |operand: usize| if operand <= 0xff { operand + 1 } else { 0 };
+
+		let needed = if inst.is_pseudo() {
+			// captures of a CLOSURE read B, the SETLIST block number is data
+			if inst.is(0) {
+				b + 1
+			} else {
+				0
+			}
+		} else {
+			match inst.opcode() {
+				// MOVE, LOADNIL, UNM, NOT, LEN, TESTSET
+				0 | 3 | 18 | 19 | 20 | 27 => (a + 1).max(b + 1),
+				// GETTABLE
+				6 => (a + 1).max(b + 1).max(rk(c)),
+				// SETTABLE, ADD, SUB, MUL, DIV, MOD, POW
+				9 | 12..=17 => (a + 1).max(rk(b)).max(rk(c)),
+				// SELF
+				11 => (a + 2).max(b + 1).max(rk(c)),
+				// CONCAT
+				21 => (a + 1).max(c + 1),
+				22 => 0,
+				// EQ, LT, LE
+				23..=25 => rk(b).max(rk(c)),
+				// CALL, TAILCALL
+				28 | 29 => (a + 1).max(a + b).max((a + c).saturating_sub(1)),
+				// RETURN, VARARG
+				30 | 37 => (a + 1).max((a + b).saturating_sub(1)),
+				// FORLOOP, FORPREP
+				31 | 32 => a + 4,
+				// TFORLOOP copies the generator state to A + 3 .. A + 5
+				33 => (a + 6).max(a + 3 + c),
+				// SETLIST
+				34 => a + 1 + b,
+				_ => a + 1,
+			}
+		};
+		size = size.max(needed);
+	}
+
+	size
+}
+
+/// Lowers max_stack_size to what the instructions need, returns whether it changed
+pub fn shrink_stack(ctx: &mut IRContext) -> bool {
+	let required = required_stack_size(ctx);
+	if required >= ctx.max_stack_size() as usize {
+		return false;
+	}
+	ctx.set_max_stack_size(required as u8);
+	true
+}
+
+/**
+ * eliminate_dead_code - Removes unreachable blocks and dead pure instructions,
+ * then drops unused constants and shrinks the stack. Returns whether
+ * anything changed
+ */
+pub fn eliminate_dead_code(
+	ctx: &mut IRContext,
+	analyses: &mut Analyses,
+) -> Result<bool, EditError> {
+	let mut changed = remove_unreachable(ctx, analyses)?;
+	changed |= remove_dead_instructions(ctx, analyses)?;
+	changed |= ctx.remove_unused_constants() != 0;
+	changed |= ctx.merge_duplicate_constants() != 0;
+	if shrink_stack(ctx) {
+		analyses.invalidate(&[]);
+		changed = true;
+	}
+	Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{eliminate_dead_code, remove_unreachable};
+	use crate::lua51::{
+		fixtures::{context, listing, numbers},
+		passes::manager::Analyses,
+	};
+	use bytecode::lua51::instructions::Opcode;
+
+	#[test]
+	fn test_eliminate_dead_code() {
+		let mut context = context(
+			vec![
+				Opcode::encode_ABx(1, 0, 0),         // LOADK 0 0, dead
+				Opcode::encode_ABx(1, 1, 1),         // LOADK 1 1
+				Opcode::encode_ABC(12, 2, 1, 0x101), // ADD 2 1 1, may call __add
+				Opcode::encode_AsBx(22, 0, 2),       // JMP 2
+				Opcode::encode_ABx(1, 4, 2),         // LOADK 4 2, unreachable
+				Opcode::encode_ABC(0, 5, 4, 0),      // MOVE 5 4, unreachable
+				Opcode::encode_ABC(0, 3, 1, 0),      // MOVE 3 1
+				Opcode::encode_ABC(30, 3, 2, 0),     // RETURN 3 2
+			],
+			numbers(&[0.0, 1.0, 2.0]),
+		);
+
+		assert!(eliminate_dead_code(&mut context, &mut Analyses::default()).unwrap());
+		assert_eq!(
+			listing(&context),
+			vec![
+				"LOADK     \t1 0",
+				"ADD       \t2 1 256",
+				"JMP       \t0",
+				"MOVE      \t3 1",
+				"RETURN    \t3 2",
+			]
+		);
+		assert_eq!(context.constants.len(), 1);
+		assert_eq!(context.max_stack_size(), 4);
+		assert!(!eliminate_dead_code(&mut context, &mut Analyses::default()).unwrap());
+	}
+
+	#[test]
+	fn test_skipped_instruction() {
+		// the LOADBOOL after the skipping one is only reached through it
+		let mut context = context(
+			vec![
+				Opcode::encode_ABC(2, 0, 0, 1),  // LOADBOOL 0 0 1
+				Opcode::encode_ABC(2, 0, 1, 0),  // LOADBOOL 0 1 0
+				Opcode::encode_ABC(30, 0, 2, 0), // RETURN 0 2
+			],
+			vec![],
+		);
+		assert!(!remove_unreachable(&mut context, &mut Analyses::default()).unwrap());
+		eliminate_dead_code(&mut context, &mut Analyses::default()).unwrap();
+		assert_eq!(context.instructions.len(), 3);
+		assert_eq!(context.max_stack_size(), 2);
+	}
+}
@@ -0,0 +1,396 @@
+use super::manager::{PassError, PassManager};
+use crate::lua51::{
+	vm::{Compat, Limits, LuaError, Value, Vm},
+	IRContext,
+};
+use std::{cell::RefCell, fmt::Display, rc::Rc};
+
+/// What running a chunk with one input did
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+	pub output: Vec<u8>,
+	/// values returned, references by their type as identities can not be
+	/// compared across runs, or the error raised
+	pub result: Result<Vec<String>, String>,
+}
+
+impl Display for Outcome {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.result {
+			Ok(values) => write!(f, "returned ({})", values.join(", "))?,
+			Err(msg) => write!(f, "raised {msg:?}")?,
+		}
+		write!(f, " printing {:?}", String::from_utf8_lossy(&self.output))
+	}
+}
+
+/**
+ * Divergence - An input the transformed chunk behaves differently on.
+ * Shrinking finds the function and pass responsible
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+	/// index of the input in the harness
+	pub input: usize,
+	pub original: Outcome,
+	pub transformed: Outcome,
+	/// closure indices from the main function down to the smallest function
+	/// that diverges when transformed alone, None if none does
+	pub function: Option<Vec<usize>>,
+	/// first pass the pipeline diverges with when cut after it
+	pub pass: Option<String>,
+}
+
+impl Display for Divergence {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "input {} diverged", self.input)?;
+		if let Some(pass) = &self.pass {
+			write!(f, " after pass '{pass}'")?;
+		}
+		if let Some(function) = &self.function {
+			let path = function.iter().map(usize::to_string).collect::<Vec<_>>();
+			match path.is_empty() {
+				true => write!(f, " in the main function")?,
+				false => write!(f, " in function {}", path.join("."))?,
+			}
+		}
+		write!(
+			f,
+			": originally {}, transformed {}",
+			self.original, self.transformed
+		)
+	}
+}
+
+/// Value as compared between runs
+fn render(value: &Value) -> String {
+	match value {
+		Value::Table(_) | Value::Function(_) | Value::Thread(_) => value.type_name().to_string(),
+		_ => format!("{value:?}"),
+	}
+}
+
+/// `msg` without the "chunk:line: " position it starts with
+fn strip_position(msg: &str) -> &str {
+	let mut parts = msg.splitn(3, ':');
+	match (parts.next(), parts.next(), parts.next()) {
+		(Some(_), Some(line), Some(rest))
+			if !line.is_empty() && line.bytes().all(|c| c.is_ascii_digit()) =>
+		{
+			rest.strip_prefix(' ').unwrap_or(rest)
+		}
+		_ => msg,
+	}
+}
+
+/// Function at `path` in `ctx`
+fn function_mut<'a>(ctx: &'a mut IRContext, path: &[usize]) -> &'a mut IRContext {
+	path.iter()
+		.fold(ctx, |ctx, &idx| ctx.closures[idx].as_mut())
+}
+
+/// Paths of `ctx` and all closures in it, with their instruction counts
+fn functions(ctx: &IRContext, path: &mut Vec<usize>, out: &mut Vec<(usize, Vec<usize>)>) {
+	out.push((ctx.instructions.len(), path.clone()));
+	for (idx, closure) in ctx.closures.iter().enumerate() {
+		path.push(idx);
+		functions(closure, path, out);
+		path.pop();
+	}
+}
+
+/**
+ * Harness - Differential testing of passes: runs a chunk before and after
+ * a pipeline under the interpreter with the same inputs, comparing what
+ * they print, return and raise. Runs are sandboxed by `Limits`, inputs the
+ * original exceeds them on are skipped
+ */
+pub struct Harness {
+	/// arguments the chunk is called with, one run each
+	inputs: Vec<Vec<Value>>,
+	limits: Limits,
+	/// whether "chunk:line:" positions of errors must match
+	positions: bool,
+	shrink: bool,
+	compat: Compat,
+}
+
+impl Default for Harness {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Harness {
+	/// Runs without arguments, at most a million instructions each
+	pub fn new() -> Self {
+		Self {
+			inputs: vec![vec![]],
+			limits: Limits {
+				instructions: Some(1_000_000),
+				memory: Some(64 << 20),
+				depth: Some(200),
+			},
+			positions: true,
+			shrink: false,
+			compat: Compat::default(),
+		}
+	}
+
+	/// Sets the argument lists to run with. Tables given are shared by all
+	/// runs, so only values the chunk does not change should be passed
+	pub fn inputs(mut self, inputs: Vec<Vec<Value>>) -> Self {
+		self.inputs = inputs;
+		self
+	}
+
+	pub fn limits(mut self, limits: Limits) -> Self {
+		self.limits = limits;
+		self
+	}
+
+	/// Whether "chunk:line:" positions of errors must match, passes moving
+	/// code between lines may change them
+	pub fn positions(mut self, positions: bool) -> Self {
+		self.positions = positions;
+		self
+	}
+
+	/// Looks for the function and pass responsible for a divergence
+	pub fn shrink(mut self, shrink: bool) -> Self {
+		self.shrink = shrink;
+		self
+	}
+
+	/// Runs both chunks as the VM `compat` picks would, to check them for
+	/// where they are deployed
+	pub fn compat(mut self, compat: Compat) -> Self {
+		self.compat = compat;
+		self
+	}
+
+	/// Runs `ctx` with one of the inputs, None if it exceeded a limit
+	pub fn run(&self, ctx: &IRContext, input: usize) -> Option<Outcome> {
+		let output = Rc::new(RefCell::new(vec![]));
+		let mut vm = Vm::new();
+		vm.open_libs();
+		vm.set_limits(self.limits);
+		vm.set_compat(self.compat);
+		let sink = output.clone();
+		vm.set_output(move |bytes| sink.borrow_mut().extend_from_slice(bytes));
+
+		let main = vm.load(ctx);
+		let result = match vm.call(&main, self.inputs[input].clone()) {
+			Ok(values) => Ok(values.iter().map(render).collect()),
+			Err(LuaError::Limit(_)) => return None,
+			Err(err) => {
+				let msg = err.to_string();
+				match self.positions {
+					true => Err(msg),
+					false => Err(strip_position(&msg).to_string()),
+				}
+			}
+		};
+		let output = output.take();
+		Some(Outcome { output, result })
+	}
+
+	/// First input `transformed` behaves differently from `original` on,
+	/// with both outcomes. Exceeding a limit only after transforming counts
+	/// as different
+	fn compare(
+		&self,
+		original: &IRContext,
+		transformed: &IRContext,
+	) -> Option<(usize, Outcome, Outcome)> {
+		(0..self.inputs.len()).find_map(|input| {
+			let before = self.run(original, input)?;
+			let after = self.run(transformed, input).unwrap_or_else(|| Outcome {
+				output: vec![],
+				result: Err("limit exceeded".to_string()),
+			});
+			(before != after).then_some((input, before, after))
+		})
+	}
+
+	/// Transforms a copy of `ctx` with the pipeline `pipeline` makes and
+	/// compares both. The pipeline is made anew for every attempt of
+	/// shrinking, which cuts it short or applies it to one function
+	pub fn check(
+		&self,
+		ctx: &IRContext,
+		pipeline: impl Fn() -> PassManager,
+	) -> Result<Option<Divergence>, PassError> {
+		let mut transformed = ctx.clone();
+		pipeline().run(&mut transformed)?;
+		let Some((input, original, transformed)) = self.compare(ctx, &transformed) else {
+			return Ok(None);
+		};
+		let mut divergence = Divergence {
+			input,
+			original,
+			transformed,
+			function: None,
+			pass: None,
+		};
+		if self.shrink {
+			self.shrink_divergence(ctx, &pipeline, &mut divergence)?;
+		}
+		Ok(Some(divergence))
+	}
+
+	/// Transforms `ctx` with the first `len` passes of the pipeline, only in
+	/// the function at `path` if given
+	fn transform(
+		ctx: &IRContext,
+		pipeline: &impl Fn() -> PassManager,
+		path: Option<&[usize]>,
+		len: usize,
+	) -> Result<IRContext, PassError> {
+		let mut transformed = ctx.clone();
+		let mut manager = pipeline().truncate(len);
+		match path {
+			Some(path) => manager.run_function(function_mut(&mut transformed, path))?,
+			None => manager.run(&mut transformed)?,
+		};
+		Ok(transformed)
+	}
+
+	fn shrink_divergence(
+		&self,
+		ctx: &IRContext,
+		pipeline: &impl Fn() -> PassManager,
+		divergence: &mut Divergence,
+	) -> Result<(), PassError> {
+		let len = pipeline().names().len();
+
+		let mut candidates = vec![];
+		functions(ctx, &mut vec![], &mut candidates);
+		candidates.sort_by_key(|(size, _)| *size);
+		for (_, path) in candidates {
+			let transformed = Self::transform(ctx, pipeline, Some(&path), len)?;
+			if let Some((input, original, transformed)) = self.compare(ctx, &transformed) {
+				*divergence = Divergence {
+					input,
+					original,
+					transformed,
+					function: Some(path),
+					pass: None,
+				};
+				break;
+			}
+		}
+
+		for cut in 1..=len {
+			let path = divergence.function.as_deref();
+			let transformed = Self::transform(ctx, pipeline, path, cut)?;
+			if let Some((input, original, transformed)) = self.compare(ctx, &transformed) {
+				divergence.input = input;
+				divergence.original = original;
+				divergence.transformed = transformed;
+				divergence.pass = Some(pipeline().names()[cut - 1].to_string());
+				break;
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{strip_position, Harness};
+	use crate::lua51::{
+		context::{EditError, IRInstruction},
+		passes::{
+			manager::{Analyses, FnPass, Pass, PassManager},
+			operands,
+		},
+		IRContext,
+	};
+	use bytecode::lua51::{instructions::Opcode, Constants, Proto};
+
+	/// Turns ADD into SUB
+	struct Miscompile;
+
+	impl Pass for Miscompile {
+		fn name(&self) -> &str {
+			"miscompile"
+		}
+
+		fn run(&mut self, ctx: &mut IRContext, _: &mut Analyses) -> Result<bool, EditError> {
+			let add =
+				(0..ctx.instructions.len()).find(|&pc| ctx.instructions.get(pc).unwrap().is(12));
+			let Some(pc) = add else {
+				return Ok(false);
+			};
+			let (a, b, c) = operands(ctx.instructions.get(pc).unwrap());
+			let sub = IRInstruction::new_abc(13, a, b, c);
+			ctx.instructions.replace_range(pc..pc + 1, vec![sub])?;
+			Ok(true)
+		}
+	}
+
+	#[test]
+	fn test_strip_position() {
+		assert_eq!(strip_position("test:12: boom"), "boom");
+		assert_eq!(strip_position("[string \"x\"]:1: a: b"), "a: b");
+		assert_eq!(strip_position("no position: here"), "no position: here");
+	}
+
+	#[test]
+	fn test_shrink() {
+		// local function f(x) return x + 1 end return f(...)
+		let f = Proto {
+			nparams: 1,
+			max_stack_size: 2,
+			instructions: [
+				Opcode::encode_ABC(12, 1, 0, 256), // ADD 1 0 1
+				Opcode::encode_ABC(30, 1, 2, 0),   // RETURN 1 2
+			]
+			.into_iter()
+			.map(Opcode::from_serialized)
+			.collect(),
+			constants: vec![Constants::Number(1.0)],
+			..Default::default()
+		};
+		let main = Proto {
+			is_vararg_flag: 2,
+			max_stack_size: 3,
+			instructions: [
+				Opcode::encode_ABx(36, 0, 0),    // CLOSURE 0 0
+				Opcode::encode_ABC(0, 1, 0, 0),  // MOVE 1 0
+				Opcode::encode_ABC(37, 2, 2, 0), // VARARG 2 2
+				Opcode::encode_ABC(29, 1, 2, 0), // TAILCALL 1 2 0
+				Opcode::encode_ABC(30, 1, 0, 0), // RETURN 1 0
+			]
+			.into_iter()
+			.map(Opcode::from_serialized)
+			.collect(),
+			prototypes: vec![f],
+			..Default::default()
+		};
+		let ctx = IRContext::from_proto(main);
+		let harness = Harness::new()
+			.inputs(vec![vec![0.0.into()], vec![1.0.into()]])
+			.shrink(true);
+
+		let safe = || {
+			let mut manager = PassManager::new();
+			manager.add(FnPass::new("nothing", |_, _| Ok(false)));
+			manager
+		};
+		assert_eq!(harness.check(&ctx, safe).unwrap(), None);
+
+		let broken = || {
+			let mut manager = safe();
+			manager.add(Miscompile);
+			manager
+		};
+		let divergence = harness.check(&ctx, broken).unwrap().unwrap();
+		assert_eq!(divergence.input, 0);
+		assert_eq!(divergence.original.result, Ok(vec!["1".to_string()]));
+		assert_eq!(divergence.transformed.result, Ok(vec!["-1".to_string()]));
+		assert_eq!(divergence.function, Some(vec![0]));
+		assert_eq!(divergence.pass.as_deref(), Some("miscompile"));
+	}
+}
@@ -0,0 +1,399 @@
+use super::{operands, Rng};
+use crate::lua51::{
+	context::{EditError, IRInstruction, IRInstructions, Label, MAX_RK_CONSTANT, MAX_STACK},
+	IRContext, CFG,
+};
+use std::collections::HashMap;
+
+/**
+ * Flatten - Rewrites a function into a loop dispatching on a state register
+ * over its CFG blocks, laid out in an order the seed picks. Every edge
+ * between blocks sets the state of its target and goes back to the
+ * dispatcher. Tests, TFORLOOP, FORPREP and FORLOOP stay but jump to a state
+ * assignment next to them, so they keep their JMP and their loop registers.
+ * Blocks are not split, so open CALLs and VARARGs stay in front of what
+ * takes their results and CLOSEs run where they were. Every run adds a
+ * dispatcher, so the pass is not meant for a fixpoint
+ */
+pub struct Flatten {
+	rng: Rng,
+}
+
+impl Flatten {
+	pub fn new(seed: u64) -> Self {
+		Self {
+			rng: Rng::new(seed),
+		}
+	}
+
+	/// Flattens `ctx` but not its closures, returns whether it did. Functions
+	/// of one block, without a register to spare or whose blocks the
+	/// dispatcher can not reach are left alone
+	pub fn run(&mut self, ctx: &mut IRContext) -> Result<bool, EditError> {
+		let mut flat = ctx.clone();
+		match self.flatten(&mut flat) {
+			Ok(true) => {
+				*ctx = flat;
+				Ok(true)
+			}
+			Ok(false) | Err(EditError::NoFreeRegister(_)) | Err(EditError::JumpOutOfRange(_)) => {
+				Ok(false)
+			}
+			Err(err) => Err(err),
+		}
+	}
+
+	fn flatten(&mut self, ctx: &mut IRContext) -> Result<bool, EditError> {
+		// the block number of a SETLIST may read as a jump to the CFG
+		if ctx
+			.instructions
+			.iter()
+			.any(|inst| inst.is(34) && inst.get_c().get_reg() == Some(0))
+		{
+			return Ok(false);
+		}
+		if CFG::from_instructions(&ctx.instructions).len() < 2 {
+			return Ok(false);
+		}
+
+		let state = ctx.reserve_registers(&["(state)"])?;
+		let cfg = CFG::from_instructions(&ctx.instructions);
+		let old = ctx.instructions.get_all().clone();
+		let starts = cfg
+			.iter()
+			.enumerate()
+			.map(|(idx, block)| (block.range().start, idx))
+			.collect::<HashMap<_, _>>();
+		// B = 0 takes what the instruction before left, which must stay next to it
+		let open = cfg.iter().any(|block| {
+			let inst = &old[block.range().start];
+			matches!(inst.opcode(), 28 | 29 | 30 | 34) && operands(inst).1 == 0
+		});
+		if open {
+			return Ok(false);
+		}
+
+		let len = cfg.len();
+		let mut states = vec![];
+		while states.len() < len {
+			let state = self.rng.below(1 << 24) as f64;
+			if !states.contains(&state) {
+				states.push(state);
+			}
+		}
+		let kst = states
+			.iter()
+			.map(|state| ctx.constants.add_number(*state))
+			.collect::<Vec<_>>();
+		let mut order = (0..len).collect::<Vec<_>>();
+		for i in (1..len).rev() {
+			order.swap(i, self.rng.below(i + 1));
+		}
+
+		// labels 0..len are the blocks, len the dispatcher
+		let dispatch = Label(len);
+		let mut next_label = len + 1;
+		let at = |mut inst: IRInstruction, line| {
+			inst.set_line(line);
+			inst
+		};
+		// state = the block starting at pc, then back to the dispatcher
+		let goto = |pc: usize, line| -> Option<[IRInstruction; 2]> {
+			let block = *starts.get(&pc)?;
+			Some([
+				at(IRInstruction::new_abx(1, state, kst[block] as u32), line),
+				at(IRInstruction::new_jump(22, 0, dispatch), line),
+			])
+		};
+
+		let line = old[0].line();
+		let mut code = vec![at(
+			IRInstruction::new_abx(1, state, kst[starts[&0]] as u32),
+			line,
+		)];
+		let temp = ctx.max_stack_size();
+		let mut spilled = false;
+		for (i, block) in order.iter().enumerate() {
+			let start = code.len();
+			if i + 1 < len {
+				let mut rk = kst[*block] as u16 + 0x100;
+				if kst[*block] > MAX_RK_CONSTANT {
+					let load = IRInstruction::new_abx(1, temp, kst[*block] as u32);
+					code.push(at(load, line));
+					rk = temp as u16;
+					spilled = true;
+				}
+				code.push(at(IRInstruction::new_abc(23, 1, state as u16, rk), line));
+			}
+			code.push(at(IRInstruction::new_jump(22, 0, Label(*block)), line));
+			if i == 0 {
+				code[start].add_label(dispatch);
+			}
+		}
+		if spilled {
+			if temp as usize + 1 > MAX_STACK {
+				return Ok(false);
+			}
+			ctx.set_max_stack_size(temp + 1);
+		}
+
+		// where each old instruction, or what replaces its terminator, lands
+		let mut moved = vec![None; old.len()];
+		for block in order {
+			let range = cfg.get_block(block).unwrap().range().clone();
+			let start = code.len();
+			let last = range.end - 1;
+			for pc in range.clone() {
+				moved[pc] = Some(start + pc - range.start);
+			}
+			// the old labels go, jumps to blocks are to the new ones
+			let mut body = old[range].to_vec();
+			for inst in &mut body {
+				inst.take_labels();
+			}
+			let term = body.pop().unwrap();
+			code.extend(body);
+			let line = term.line();
+			let target = term.jump_target(last);
+			let exits = match term.opcode() {
+				_ if term.is_pseudo() => {
+					code.push(term);
+					vec![goto(last + 1, line)]
+				}
+				22 => vec![goto(target.unwrap(), line)],
+				// the JMP a test runs when it does not skip goes to pc + 1
+				23..=27 | 33 => {
+					let taken = Label(next_label);
+					next_label += 1;
+					code.push(term);
+					code.push(at(IRInstruction::new_jump(22, 0, taken), line));
+					vec![
+						goto(last + 2, line),
+						goto(last + 1, line).map(|exit| labelled(exit, taken)),
+					]
+				}
+				2 if operands(&term).2 != 0 => {
+					let (a, b, _) = operands(&term);
+					code.push(at(IRInstruction::new_abc(2, a, b, 0), line));
+					vec![goto(last + 2, line)]
+				}
+				31 => {
+					let back = Label(next_label);
+					next_label += 1;
+					let a = operands(&term).0;
+					code.push(at(IRInstruction::new_jump(31, a, back), line));
+					vec![
+						goto(last + 1, line),
+						goto(target.unwrap(), line).map(|exit| labelled(exit, back)),
+					]
+				}
+				32 => {
+					let prepared = Label(next_label);
+					next_label += 1;
+					let a = operands(&term).0;
+					code.push(at(IRInstruction::new_jump(32, a, prepared), line));
+					vec![goto(target.unwrap(), line).map(|exit| labelled(exit, prepared))]
+				}
+				30 => {
+					code.push(term);
+					vec![]
+				}
+				_ => {
+					code.push(term);
+					vec![goto(last + 1, line)]
+				}
+			};
+			for exit in exits {
+				let Some(exit) = exit else {
+					return Ok(false);
+				};
+				code.extend(exit);
+			}
+			code[start].add_label(Label(block));
+		}
+
+		// shuffled, the last block may not end the function with a RETURN
+		if !code
+			.last()
+			.is_some_and(|inst| inst.is(30) && !inst.is_pseudo())
+		{
+			let line = old.last().and_then(|inst| inst.line());
+			code.push(at(IRInstruction::new_abc(30, 0, 1, 0), line));
+		}
+		let locals = ctx.locals().to_vec();
+		ctx.instructions = IRInstructions::from_ir(code);
+		ctx.instructions.resolve_labels()?;
+		ctx.instructions.set_moved_locals(&locals, &moved);
+		Ok(true)
+	}
+}
+
+/// `exit` with `label` on its first instruction
+fn labelled(mut exit: [IRInstruction; 2], label: Label) -> [IRInstruction; 2] {
+	exit[0].add_label(label);
+	exit
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Flatten;
+	use crate::lua51::{
+		fixtures::{run, string},
+		passes::manager::PassManager,
+		vm::{Compat, Value},
+		IRContext,
+	};
+	use bytecode::lua51::{instructions::Opcode, Constants, Local, Proto};
+
+	fn chunk() -> Proto {
+		// function() return i end
+		let closure = Proto {
+			nupvals: 1,
+			max_stack_size: 2,
+			instructions: [
+				Opcode::encode_ABC(4, 0, 0, 0),  // GETUPVAL 0 0
+				Opcode::encode_ABC(30, 0, 2, 0), // RETURN 0 2
+			]
+			.map(Opcode::from_serialized)
+			.to_vec(),
+			..Default::default()
+		};
+		// local t = {}
+		// for i = 1, 3 do t[i] = function() return i end end
+		// local s = 0
+		// for _, f in ipairs(t) do s = s + f() end
+		// if s > 5 then s = s * 2 else s = -s end
+		// return s, select(2, 10, 20, 30)
+		let code = [
+			Opcode::encode_ABC(10, 0, 0, 0),     // NEWTABLE 0 0 0
+			Opcode::encode_ABx(1, 1, 0),         // LOADK 1 1
+			Opcode::encode_ABx(1, 2, 1),         // LOADK 2 3
+			Opcode::encode_ABx(1, 3, 0),         // LOADK 3 1
+			Opcode::encode_AsBx(32, 1, 4),       // FORPREP 1 4
+			Opcode::encode_ABx(36, 5, 0),        // CLOSURE 5 0
+			Opcode::encode_ABC(0, 0, 4, 0),      // capture of R4
+			Opcode::encode_ABC(9, 0, 4, 5),      // SETTABLE 0 4 5
+			Opcode::encode_ABC(35, 4, 0, 0),     // CLOSE 4
+			Opcode::encode_AsBx(31, 1, -5),      // FORLOOP 1 -5
+			Opcode::encode_ABx(1, 1, 2),         // LOADK 1 0
+			Opcode::encode_ABx(5, 2, 3),         // GETGLOBAL 2 "ipairs"
+			Opcode::encode_ABC(0, 3, 0, 0),      // MOVE 3 0
+			Opcode::encode_ABC(28, 2, 2, 4),     // CALL 2 2 4
+			Opcode::encode_AsBx(22, 0, 3),       // JMP 3
+			Opcode::encode_ABC(0, 7, 6, 0),      // MOVE 7 6
+			Opcode::encode_ABC(28, 7, 1, 2),     // CALL 7 1 2
+			Opcode::encode_ABC(12, 1, 1, 7),     // ADD 1 1 7
+			Opcode::encode_ABC(33, 2, 0, 2),     // TFORLOOP 2 2
+			Opcode::encode_AsBx(22, 0, -5),      // JMP -5
+			Opcode::encode_ABC(24, 0, 0x104, 1), // LT 0 5 1
+			Opcode::encode_AsBx(22, 0, 2),       // JMP 2
+			Opcode::encode_ABC(14, 1, 1, 0x105), // MUL 1 1 2
+			Opcode::encode_AsBx(22, 0, 1),       // JMP 1
+			Opcode::encode_ABC(18, 1, 1, 0),     // UNM 1 1
+			Opcode::encode_ABC(0, 2, 1, 0),      // MOVE 2 1
+			Opcode::encode_ABx(5, 3, 6),         // GETGLOBAL 3 "select"
+			Opcode::encode_ABx(1, 4, 5),         // LOADK 4 2
+			Opcode::encode_ABx(1, 5, 7),         // LOADK 5 10
+			Opcode::encode_ABx(1, 6, 8),         // LOADK 6 20
+			Opcode::encode_ABx(1, 7, 9),         // LOADK 7 30
+			Opcode::encode_ABC(28, 3, 5, 0),     // CALL 3 5 0
+			Opcode::encode_ABC(30, 2, 0, 0),     // RETURN 2 0
+		];
+		let number = Constants::Number;
+		let locals = [
+			("t", 1, 33),
+			("(for index)", 4, 10),
+			("(for limit)", 4, 10),
+			("(for step)", 4, 10),
+			("i", 5, 9),
+			("s", 11, 33),
+			("(for generator)", 14, 20),
+			("(for state)", 14, 20),
+			("(for control)", 14, 20),
+			("_", 15, 18),
+			("f", 15, 18),
+		];
+		Proto {
+			max_stack_size: 8,
+			locals: Some(
+				locals
+					.map(|(name, start_pc, end_pc)| Local {
+						name: name.to_string(),
+						start_pc,
+						end_pc,
+					})
+					.to_vec(),
+			),
+			source_lines: Some((1..=code.len() as u64).collect()),
+			instructions: code.map(Opcode::from_serialized).to_vec(),
+			constants: vec![
+				number(1.0),
+				number(3.0),
+				number(0.0),
+				string("ipairs"),
+				number(5.0),
+				number(2.0),
+				string("select"),
+				number(10.0),
+				number(20.0),
+				number(30.0),
+			],
+			prototypes: vec![closure],
+			..Default::default()
+		}
+	}
+
+	fn flatten(seed: u64) -> IRContext {
+		let mut ctx = IRContext::from_proto(chunk());
+		let mut manager = PassManager::new();
+		manager.add(Flatten::new(seed));
+		assert_eq!(manager.run(&mut ctx).map_err(|e| e.to_string()), Ok(true));
+		ctx
+	}
+
+	#[test]
+	fn test_flatten() {
+		let expected = Ok([12.0, 20.0, 30.0].map(Value::from).to_vec());
+		for compat in [Compat::Lua51, Compat::Rerubi] {
+			assert_eq!(run(compat, &IRContext::from_proto(chunk())), expected);
+			for seed in 0..8 {
+				assert_eq!(run(compat, &flatten(seed)), expected, "seed {seed}");
+			}
+		}
+
+		// all jumps between blocks go through the dispatcher
+		let ctx = flatten(0);
+		let dispatcher = ctx.instructions.get(1).unwrap().labels()[0];
+		let jumps = ctx.instructions.iter().filter(|inst| inst.is(22));
+		assert!(
+			jumps
+				.filter(|inst| inst.target() == Some(dispatcher))
+				.count() >= 10
+		);
+
+		let listing = |ctx: IRContext| format!("{:?}", ctx.to_proto());
+		assert_eq!(listing(flatten(1)), listing(flatten(1)));
+		assert_ne!(listing(flatten(1)), listing(flatten(2)));
+	}
+
+	#[test]
+	fn test_locals() {
+		let ctx = flatten(0);
+		let len = ctx.instructions.len() as u64;
+		let locals = ctx.locals();
+		let names = locals.iter().map(|local| local.name.clone());
+		let mut expected = vec!["(state)".to_string()];
+		expected.extend(chunk().locals.unwrap().into_iter().map(|local| local.name));
+		assert_eq!(names.collect::<Vec<_>>(), expected);
+
+		// the state spans the new function, what lived to the end still does
+		assert_eq!((locals[0].start_pc, locals[0].end_pc), (0, len));
+		for name in ["t", "s"] {
+			let local = locals.iter().find(|local| local.name == name).unwrap();
+			assert_eq!(local.end_pc, len, "{name}");
+		}
+		assert!(locals
+			.iter()
+			.all(|local| local.start_pc < local.end_pc && local.end_pc <= len));
+	}
+}
@@ -0,0 +1,556 @@
+use super::{manager::Analyses, operands};
+use crate::lua51::{
+	context::{EditError, IRConstant, IRConstants, IRInstruction},
+	dataflow::{def_use, solve, Analysis, DefUse, Direction, Lattice, Liveness, RegisterSet},
+	IRContext,
+};
+use bytecode::lua51::{
+	number::{number_to_string, string_to_number},
+	Constants,
+};
+
+/// Value of a register at one point
+#[derive(Debug, Clone)]
+pub enum Known {
+	Undefined, // not reached yet
+	Constant(IRConstant),
+	Varying,
+}
+
+impl PartialEq for Known {
+	fn eq(&self, other: &Self) -> bool {
+		match (self, other) {
+			(Self::Undefined, Self::Undefined) | (Self::Varying, Self::Varying) => true,
+			(Self::Constant(a), Self::Constant(b)) => a.same(b),
+			_ => false,
+		}
+	}
+}
+
+impl Known {
+	pub fn constant(&self) -> Option<&IRConstant> {
+		match self {
+			Self::Constant(constant) => Some(constant),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterValues(Vec<Known>);
+
+impl RegisterValues {
+	pub fn get(&self, reg: u8) -> &Known {
+		&self.0[reg as usize]
+	}
+}
+
+impl Lattice for RegisterValues {
+	fn join(&mut self, other: &Self) -> bool {
+		let mut changed = false;
+		for (value, other) in self.0.iter_mut().zip(&other.0) {
+			let joined = match (&*value, other) {
+				(_, Known::Undefined) => continue,
+				(Known::Undefined, other) => other.clone(),
+				(Known::Constant(a), Known::Constant(b)) if a.same(b) => continue,
+				(Known::Varying, _) => continue,
+				_ => Known::Varying,
+			};
+			*value = joined;
+			changed = true;
+		}
+		changed
+	}
+}
+
+/**
+ * ConstantPropagation - Registers holding the same constant on every path
+ * Registers start out nil except for the parameters and `arg`. Captured
+ * registers are never known, closures may write them at any call
+ */
+pub struct ConstantPropagation<'a> {
+	ctx: &'a IRContext,
+	table: Vec<DefUse>,
+	captured: RegisterSet,
+	registers: usize,
+}
+
+impl<'a> ConstantPropagation<'a> {
+	pub fn new(ctx: &'a IRContext) -> Self {
+		let liveness = Liveness::new(ctx);
+		let captured = *liveness.captured();
+		let table = def_use(ctx);
+		let registers = table
+			.iter()
+			.flat_map(|du| du.uses.iter().chain(du.defs.iter()))
+			.map(|reg| reg as usize + 1)
+			.max()
+			.unwrap_or(0)
+			.max(ctx.max_stack_size() as usize);
+
+		Self {
+			ctx,
+			table,
+			captured,
+			registers,
+		}
+	}
+}
+
+impl Analysis for ConstantPropagation<'_> {
+	type Domain = RegisterValues;
+
+	const DIRECTION: Direction = Direction::Forward;
+
+	fn bottom(&self) -> Self::Domain {
+		RegisterValues(vec![Known::Undefined; self.registers])
+	}
+
+	fn boundary(&self) -> Self::Domain {
+		// VARARG_NEEDSARG passes the `arg` table right after the parameters
+		let fixed = self.ctx.nparams() as usize + (self.ctx.vararg() & 4 != 0) as usize;
+		RegisterValues(
+			(0..self.registers)
+				.map(|reg| {
+					if reg < fixed || self.captured.contains(reg as u8) {
+						Known::Varying
+					} else {
+						Known::Constant(IRConstant::nil())
+					}
+				})
+				.collect(),
+		)
+	}
+
+	fn transfer(&self, pc: usize, state: &mut Self::Domain) {
+		let inst = self.ctx.instructions.get(pc).unwrap();
+		if inst.is_pseudo() {
+			return;
+		}
+
+		let value = match inst.opcode() {
+			0..=2 | 12..=21 => evaluate(&self.ctx.constants, inst, state),
+			_ => None,
+		};
+		let (a, b, _) = operands(inst);
+		for reg in self.table[pc].defs.iter() {
+			state.0[reg as usize] = Known::Varying;
+		}
+
+		match (inst.opcode(), value) {
+			(3, _) => {
+				for reg in a..=b as u8 {
+					state.0[reg as usize] = Known::Constant(IRConstant::nil());
+				}
+			}
+			(_, Some(value)) => state.0[a as usize] = Known::Constant(value),
+			_ => {}
+		}
+
+		for reg in self.captured.iter() {
+			state.0[reg as usize] = Known::Varying;
+		}
+	}
+}
+
+fn constant(constants: &IRConstants, idx: usize) -> Known {
+	match constants.get(idx) {
+		Some(constant) => Known::Constant(constant.clone()),
+		None => Known::Varying,
+	}
+}
+
+/// Value of a register or RK operand
+fn operand(constants: &IRConstants, state: &RegisterValues, operand: u16) -> Known {
+	if operand > 0xff {
+		constant(constants, operand as usize - 0x100)
+	} else {
+		state.get(operand as u8).clone()
+	}
+}
+
+/// Constant written to A, if the instruction computes one
+fn evaluate(
+	constants: &IRConstants,
+	inst: &IRInstruction,
+	state: &RegisterValues,
+) -> Option<IRConstant> {
+	let (_, b, c) = operands(inst);
+	let value = match inst.opcode() {
+		0 => operand(constants, state, b),
+		1 => constant(constants, inst.get_bx().get_kst()?),
+		2 => return Some(IRConstant::bool(b != 0)),
+		12..=17 => {
+			let lhs = operand(constants, state, b);
+			let rhs = operand(constants, state, c);
+			return arith(inst.opcode(), lhs.constant()?, rhs.constant()?);
+		}
+		18 => {
+			let n = tonumber(state.get(b as u8).constant()?)?;
+			return Some(IRConstant::number(-n));
+		}
+		19 => {
+			let value = state.get(b as u8);
+			return Some(IRConstant::bool(!truthy(value.constant()?)));
+		}
+		20 => match state.get(b as u8).constant()?.get() {
+			Constants::String(s) => return Some(IRConstant::number(s.len() as f64)),
+			_ => return None,
+		},
+		21 => {
+			let mut concat = String::new();
+			for reg in b..=c {
+				concat.push_str(&tostring(state.get(reg as u8).constant()?)?);
+			}
+			return Some(IRConstant::string(&concat));
+		}
+		_ => return None,
+	};
+	value.constant().cloned()
+}
+
+/// Whether a comparison or test skips the next instruction, if known
+fn condition(
+	constants: &IRConstants,
+	inst: &IRInstruction,
+	state: &RegisterValues,
+) -> Option<bool> {
+	let (a, b, c) = operands(inst);
+	let truth = match inst.opcode() {
+		23..=25 => {
+			let lhs = operand(constants, state, b);
+			let rhs = operand(constants, state, c);
+			let result = compare(inst.opcode(), lhs.constant()?, rhs.constant()?)?;
+			return Some(result != (a != 0));
+		}
+		26 => truthy(state.get(a).constant()?),
+		27 => truthy(state.get(b as u8).constant()?),
+		_ => return None,
+	};
+	Some(truth != (c != 0))
+}
+
+fn truthy(value: &IRConstant) -> bool {
+	!matches!(value.get(), Constants::Nil | Constants::Boolean(false))
+}
+
+fn tonumber(value: &IRConstant) -> Option<f64> {
+	match value.get() {
+		Constants::Number(n) => Some(*n),
+		Constants::String(s) => string_to_number(s),
+		_ => None,
+	}
+}
+
+fn tostring(value: &IRConstant) -> Option<String> {
+	match value.get() {
+		Constants::Number(n) => Some(number_to_string(*n)),
+		Constants::String(s) => Some(s.clone()),
+		_ => None,
+	}
+}
+
+/// Arithmetic of lvm.c, left alone where lcode.c would not fold either
+fn arith(opcode: usize, lhs: &IRConstant, rhs: &IRConstant) -> Option<IRConstant> {
+	let (a, b) = (tonumber(lhs)?, tonumber(rhs)?);
+	let n = match opcode {
+		12 => a + b,
+		13 => a - b,
+		14 => a * b,
+		15 if b != 0.0 => a / b,
+		16 if b != 0.0 => a - (a / b).floor() * b,
+		17 => a.powf(b),
+		_ => return None,
+	};
+	if n.is_nan() {
+		return None;
+	}
+	Some(IRConstant::number(n))
+}
+
+/// Result of EQ, LT or LE, None where it would raise an error or call a metamethod
+fn compare(opcode: usize, lhs: &IRConstant, rhs: &IRConstant) -> Option<bool> {
+	match (opcode, lhs.get(), rhs.get()) {
+		(23, Constants::Number(a), Constants::Number(b)) => Some(a == b),
+		(23, Constants::String(a), Constants::String(b)) => Some(a == b),
+		(23, Constants::Boolean(a), Constants::Boolean(b)) => Some(a == b),
+		(23, Constants::Nil, Constants::Nil) => Some(true),
+		(23, _, _) => Some(false),
+		(24, Constants::Number(a), Constants::Number(b)) => Some(a < b),
+		(24, Constants::String(a), Constants::String(b)) => Some(a.as_bytes() < b.as_bytes()),
+		(25, Constants::Number(a), Constants::Number(b)) => Some(a <= b),
+		(25, Constants::String(a), Constants::String(b)) => Some(a.as_bytes() <= b.as_bytes()),
+		_ => None,
+	}
+}
+
+/// Instruction loading `value` into `reg`
+fn load(ctx: &mut IRContext, reg: u8, value: &IRConstant) -> IRInstruction {
+	match value.get() {
+		Constants::Nil => IRInstruction::new_abc(3, reg, reg as u16, 0),
+		Constants::Boolean(b) => IRInstruction::new_abc(2, reg, *b as u16, 0),
+		_ => IRInstruction::new_abx(1, reg, ctx.constants.add(value.clone()) as u32),
+	}
+}
+
+/// RK operand naming `value`, None if the pool is too large to address it
+fn constant_operand(ctx: &mut IRContext, value: &IRConstant) -> Option<u16> {
+	let existing = ctx.constants.get_all().iter().position(|c| c.same(value));
+	let idx = match existing {
+		Some(idx) => idx,
+		None if ctx.constants.len() <= 0xff => ctx.constants.add(value.clone()),
+		None => return None,
+	};
+	(idx <= 0xff).then_some(idx as u16 + 0x100)
+}
+
+/// The instruction at `pc` with known operands and results folded in
+fn rewrite(ctx: &mut IRContext, pc: usize, state: &RegisterValues) -> Option<IRInstruction> {
+	let inst = ctx.instructions.get(pc).unwrap();
+	let opcode = inst.opcode();
+	let (a, b, c) = operands(inst);
+
+	// comparisons and tests become a JMP over the next instruction or a JMP 0
+	if let Some(skip) = condition(&ctx.constants, inst, state) {
+		return Some(match (opcode, skip) {
+			(27, false) => IRInstruction::new_abc(0, a, b, 0),
+			(_, skip) => IRInstruction::new_asbx(22, 0, skip as i32),
+		});
+	}
+
+	let value = match opcode {
+		0 | 12..=21 => evaluate(&ctx.constants, inst, state),
+		_ => None,
+	};
+	if let Some(value) = value {
+		return Some(load(ctx, a, &value));
+	}
+
+	// known registers in RK operands become constants
+	let (rk_b, rk_c) = match opcode {
+		6 | 11 => (false, true),
+		9 | 12..=17 | 23..=25 => (true, true),
+		_ => (false, false),
+	};
+	let mut substitute = |rk: bool, operand: u16| {
+		if !rk || operand > 0xff {
+			return None;
+		}
+		match state.get(operand as u8) {
+			Known::Constant(value) => constant_operand(ctx, value),
+			_ => None,
+		}
+	};
+	let new_b = substitute(rk_b, b);
+	let new_c = substitute(rk_c, c);
+	if new_b.is_none() && new_c.is_none() {
+		return None;
+	}
+	Some(IRInstruction::new_abc(
+		opcode,
+		a,
+		new_b.unwrap_or(b),
+		new_c.unwrap_or(c),
+	))
+}
+
+/**
+ * fold_constants - Constant folding and propagation
+ * Evaluates arithmetic, NOT, LEN of strings and CONCAT over known values
+ * with Lua 5.1 coercions, replaces reads of known registers by constants
+ * and turns decided comparisons into jumps. Runs until nothing changes and
+ * returns whether anything did
+ */
+pub fn fold_constants(ctx: &mut IRContext, analyses: &mut Analyses) -> Result<bool, EditError> {
+	let mut changed = false;
+
+	loop {
+		let cfg = analyses.cfg(ctx);
+		let dominators = analyses.dominators(ctx);
+		let results = solve(cfg, ConstantPropagation::new(ctx));
+		let states = (0..ctx.instructions.len())
+			.map(|pc| {
+				let block = cfg.block_of(pc)?;
+				dominators.is_reachable(block).then(|| results.before(pc))
+			})
+			.collect::<Vec<_>>();
+		drop(results);
+
+		let mut rewrites = vec![];
+		for (pc, state) in states.iter().enumerate() {
+			let inst = ctx.instructions.get(pc).unwrap();
+			// LOADBOOL already loads a constant, or skips and is control flow
+			if inst.is_pseudo() || inst.is(2) {
+				continue;
+			}
+			if let Some(new) = state.as_ref().and_then(|state| rewrite(ctx, pc, state)) {
+				rewrites.push((pc, new));
+			}
+		}
+
+		if rewrites.is_empty() {
+			return Ok(changed);
+		}
+		analyses.invalidate(&[]);
+		for (pc, new) in rewrites {
+			ctx.instructions.replace_range(pc..pc + 1, vec![new])?;
+		}
+		changed = true;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::fold_constants;
+	use crate::lua51::{
+		fixtures::{context, listing, numbers, proto, string},
+		passes::manager::Analyses,
+		IRContext,
+	};
+	use bytecode::lua51::{instructions::Opcode, Constants, Proto};
+
+	#[test]
+	fn test_fold_branch() {
+		// local a = 1; local b = a + 2; if b == 3 then x = "then" end; return a .. b
+		let mut context = context(
+			vec![
+				Opcode::encode_ABx(1, 1, 0),         // LOADK 1 1
+				Opcode::encode_ABC(12, 2, 1, 0x101), // ADD 2 1 2
+				Opcode::encode_ABC(23, 0, 2, 0x102), // EQ 0 2 3
+				Opcode::encode_AsBx(22, 0, 1),       // JMP 1
+				Opcode::encode_ABx(1, 3, 3),         // LOADK 3 "then"
+				Opcode::encode_ABC(21, 4, 1, 2),     // CONCAT 4 1 2
+				Opcode::encode_ABC(30, 4, 2, 0),     // RETURN 4 2
+			],
+			[numbers(&[1.0, 2.0, 3.0]), vec![string("then")]].concat(),
+		);
+
+		assert!(fold_constants(&mut context, &mut Analyses::default()).unwrap());
+		assert_eq!(
+			listing(&context),
+			vec![
+				"LOADK     \t1 0",
+				"LOADK     \t2 2",
+				"JMP       \t1",
+				"JMP       \t1",
+				"LOADK     \t3 3",
+				"LOADK     \t4 4",
+				"RETURN    \t4 2",
+			]
+		);
+		assert!(context.constants.get(4).unwrap().is_string("13"));
+		assert!(!fold_constants(&mut context, &mut Analyses::default()).unwrap());
+	}
+
+	#[test]
+	fn test_fold_semantics() {
+		let mut context = IRContext::from_proto(Proto {
+			nparams: 1,
+			..proto(
+				vec![
+					Opcode::encode_ABx(1, 1, 0),             // LOADK 1 "10"
+					Opcode::encode_ABC(12, 2, 1, 0x101),     // ADD 2 1 1, coerces "10"
+					Opcode::encode_ABC(15, 3, 0x101, 0x102), // DIV 3 1 3
+					Opcode::encode_ABC(21, 4, 2, 3),         // CONCAT 4 2 3
+					Opcode::encode_ABC(15, 5, 0x101, 0x103), // DIV 5 1 0, not folded
+					Opcode::encode_ABC(20, 6, 1, 0),         // LEN 6 1
+					Opcode::encode_ABC(19, 7, 0, 0),         // NOT 7 0, a parameter
+					Opcode::encode_ABC(19, 7, 6, 0),         // NOT 7 6
+					Opcode::encode_ABC(24, 1, 1, 0x101),     // LT 1 "10" 1, an error
+					Opcode::encode_AsBx(22, 0, 0),           // JMP 0
+					Opcode::encode_ABC(30, 4, 2, 0),         // RETURN 4 2
+				],
+				[vec![string("10")], numbers(&[1.0, 3.0, 0.0])].concat(),
+				vec![],
+			)
+		});
+
+		fold_constants(&mut context, &mut Analyses::default()).unwrap();
+		let listing = listing(&context);
+		assert_eq!(listing[1], "LOADK     \t2 4");
+		assert!(context.constants.get(4).unwrap().is_number(11.0));
+		assert_eq!(listing[3], "LOADK     \t4 6");
+		assert!(context
+			.constants
+			.get(6)
+			.unwrap()
+			.is_string("110.33333333333333"));
+		assert_eq!(listing[4], "DIV       \t5 257 259");
+		assert_eq!(listing[5], "LOADK     \t6 7");
+		assert!(context.constants.get(7).unwrap().is_number(2.0));
+		assert_eq!(listing[6], "NOT       \t7 0");
+		assert_eq!(listing[7], "LOADBOOL  \t7 0 0");
+		assert_eq!(listing[8], "LT        \t1 256 257");
+	}
+
+	#[test]
+	fn test_unknown_values() {
+		let mut context = IRContext::from_proto(Proto {
+			nparams: 1,
+			..proto(
+				vec![
+					Opcode::encode_ABC(26, 0, 0, 0),     // TEST 0 0, a parameter
+					Opcode::encode_AsBx(22, 0, 1),       // JMP 1
+					Opcode::encode_ABx(1, 1, 0),         // LOADK 1 1
+					Opcode::encode_ABC(12, 2, 1, 0x100), // ADD 2 1 1, nil or 1
+					Opcode::encode_ABx(1, 3, 0),         // LOADK 3 1
+					Opcode::encode_ABx(36, 4, 0),        // CLOSURE 4 0
+					Opcode::encode_ABC(0, 0, 3, 0),      // captures R3
+					Opcode::encode_ABC(28, 4, 1, 1),     // CALL 4 1 1
+					Opcode::encode_ABC(12, 5, 3, 0x100), // ADD 5 3 1, R3 may be changed
+					Opcode::encode_ABC(30, 5, 2, 0),     // RETURN 5 2
+				],
+				numbers(&[1.0]),
+				vec![Proto {
+					nupvals: 1,
+					..Default::default()
+				}],
+			)
+		});
+
+		assert!(!fold_constants(&mut context, &mut Analyses::default()).unwrap());
+	}
+
+	#[test]
+	fn test_vararg_arg() {
+		let instructions = vec![
+			Opcode::encode_ABC(19, 2, 1, 0), // NOT 2 1
+			Opcode::encode_ABC(30, 2, 2, 0), // RETURN 2 2
+		];
+
+		// without VARARG_NEEDSARG R1 starts out nil
+		let mut context = context(instructions.clone(), vec![]);
+		assert!(fold_constants(&mut context, &mut Analyses::default()).unwrap());
+		assert_eq!(listing(&context)[0], "LOADBOOL  \t2 1 0");
+
+		// with it R1 holds the `arg` table
+		let mut context = IRContext::from_proto(Proto {
+			nparams: 1,
+			is_vararg_flag: 7,
+			..proto(instructions, vec![], vec![])
+		});
+		assert!(!fold_constants(&mut context, &mut Analyses::default()).unwrap());
+	}
+
+	#[test]
+	fn test_constant_past_stack() {
+		// RK constant 5 lies past the two registers tracked
+		let constants = [string("x")]
+			.into_iter()
+			.chain((1..6).map(|n| Constants::Number(n as f64)))
+			.collect();
+		let mut context = IRContext::from_proto(Proto {
+			max_stack_size: 2,
+			..proto(
+				vec![
+					Opcode::encode_ABx(5, 0, 0),         // GETGLOBAL 0 x
+					Opcode::encode_ABC(12, 0, 0, 0x105), // ADD 0 0 5
+					Opcode::encode_ABC(30, 0, 2, 0),     // RETURN 0 2
+				],
+				constants,
+				vec![],
+			)
+		});
+		assert!(!fold_constants(&mut context, &mut Analyses::default()).unwrap());
+	}
+}
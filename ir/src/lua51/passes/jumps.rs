@@ -0,0 +1,298 @@
+use super::{dce::remove_unreachable, follows_skip, manager::Analyses, operands};
+use crate::lua51::{
+	cfg::Target,
+	context::{EditError, IRInstruction},
+	IRContext,
+};
+
+fn is_jmp(ctx: &IRContext, pc: usize) -> bool {
+	ctx.instructions
+		.get(pc)
+		.is_some_and(|inst| inst.is(22) && !inst.is_pseudo())
+}
+
+/// Points every JMP landing on another JMP at the end of the chain, returns whether any was
+pub fn thread_jumps(ctx: &mut IRContext, analyses: &mut Analyses) -> Result<bool, EditError> {
+	let len = ctx.instructions.len();
+	let mut changed = false;
+
+	for pc in 0..len {
+		let inst = ctx.instructions.get(pc).unwrap();
+		if !is_jmp(ctx, pc) || inst.target().is_none() {
+			continue;
+		}
+
+		let first = inst.jump_target(pc).unwrap();
+		let mut dest = first;
+		let mut steps = 0;
+		while is_jmp(ctx, dest) && steps < len {
+			let next = ctx
+				.instructions
+				.get(dest)
+				.unwrap()
+				.jump_target(dest)
+				.unwrap();
+			// a chain looping back onto itself has no end to jump to
+			if next == pc || next >= len {
+				break;
+			}
+			dest = next;
+			steps += 1;
+		}
+
+		if dest != first {
+			let label = ctx.instructions.label_at(dest).unwrap();
+			ctx.instructions
+				.get_mut(pc)
+				.unwrap()
+				.set_target(Some(label));
+			changed = true;
+		}
+	}
+
+	if changed {
+		analyses.invalidate(&[]);
+		ctx.instructions.resolve_labels()?;
+	}
+	Ok(changed)
+}
+
+/// Removes JMP 0 unless it is the instruction a skip jumps over, returns whether any was
+pub fn remove_nop_jumps(ctx: &mut IRContext, analyses: &mut Analyses) -> Result<bool, EditError> {
+	let nops = (0..ctx.instructions.len())
+		.filter(|pc| is_jmp(ctx, *pc))
+		.filter(|pc| ctx.instructions.get(*pc).unwrap().jump_target(*pc) == Some(pc + 1))
+		.filter(|pc| !follows_skip(ctx, *pc))
+		.collect::<Vec<_>>();
+
+	if !nops.is_empty() {
+		analyses.invalidate(&[]);
+	}
+	for pc in nops.iter().rev() {
+		ctx.instructions.remove(*pc)?;
+	}
+	Ok(!nops.is_empty())
+}
+
+/**
+ * invert_conditions - Rewrites `EQ; JMP 1; JMP L` into `EQ; JMP L` with the
+ * comparison inverted, likewise for LT, LE and TEST. TESTSET only assigns
+ * when it does not skip, so it cannot be inverted. Returns whether any
+ * condition was
+ */
+pub fn invert_conditions(ctx: &mut IRContext, analyses: &mut Analyses) -> Result<bool, EditError> {
+	let mut changed = false;
+	let mut pc = 0;
+
+	while pc + 3 < ctx.instructions.len() {
+		let inst = ctx.instructions.get(pc).unwrap();
+		let invertible = matches!(inst.opcode(), 23..=26) && !inst.is_pseudo();
+		let over = ctx.instructions.get(pc + 1).unwrap();
+		let jump = ctx.instructions.get(pc + 2).unwrap();
+
+		// jumps landing between the two would change where they go
+		if !invertible
+			|| follows_skip(ctx, pc)
+			|| !is_jmp(ctx, pc + 1)
+			|| !is_jmp(ctx, pc + 2)
+			|| over.jump_target(pc + 1) != Some(pc + 3)
+			|| !over.labels().is_empty()
+			|| !jump.labels().is_empty()
+		{
+			pc += 1;
+			continue;
+		}
+		let Some(target) = jump.target() else {
+			pc += 1;
+			continue;
+		};
+
+		let (a, b, c) = operands(inst);
+		let inverted = match inst.opcode() {
+			26 => IRInstruction::new_abc(26, a, b, c ^ 1),
+			opcode => IRInstruction::new_abc(opcode, a ^ 1, b, c),
+		};
+		analyses.invalidate(&[]);
+		ctx.instructions.replace_range(
+			pc..pc + 3,
+			vec![inverted, IRInstruction::new_jump(22, 0, target)],
+		)?;
+		changed = true;
+		pc += 2;
+	}
+
+	Ok(changed)
+}
+
+/**
+ * merge_blocks - Moves a block only reached through the JMP ending another
+ * block in place of that JMP. Only blocks that do not fall through into
+ * the next one are moved, so no JMP has to be added after them. Returns
+ * whether any block was merged
+ */
+pub fn merge_blocks(ctx: &mut IRContext, analyses: &mut Analyses) -> Result<bool, EditError> {
+	let mut changed = false;
+
+	'merge: loop {
+		let cfg = analyses.cfg(ctx);
+
+		for block in 0..cfg.len() {
+			let Target::Jmp(succ) = *cfg.get_block(block).unwrap().target() else {
+				continue;
+			};
+			let jmp = cfg.get_block(block).unwrap().range().end - 1;
+			let range = cfg.get_block(succ).unwrap().range().clone();
+			let falls_through = !matches!(
+				cfg.get_block(succ).unwrap().target(),
+				Target::Jmp(_) | Target::Return
+			);
+
+			if succ == 0
+				|| succ == block
+				|| succ == block + 1
+				|| falls_through
+				|| !is_jmp(ctx, jmp)
+				|| follows_skip(ctx, jmp)
+				|| follows_skip(ctx, range.start)
+				|| follows_skip(ctx, range.end)
+				|| cfg.predecessors(succ) != [block]
+			{
+				continue;
+			}
+
+			analyses.invalidate(&[]);
+			ctx.instructions.move_range(range.clone(), jmp + 1)?;
+			let jmp = if range.start > jmp {
+				jmp
+			} else {
+				jmp - range.len()
+			};
+			ctx.instructions.remove(jmp)?;
+			changed = true;
+			continue 'merge;
+		}
+
+		return Ok(changed);
+	}
+}
+
+/**
+ * simplify_cfg - Threads jump chains, inverts conditions jumping over a
+ * JMP, merges straight-line blocks and drops no-op jumps and the blocks
+ * left unreachable, until nothing changes. Returns whether anything did
+ */
+pub fn simplify_cfg(ctx: &mut IRContext, analyses: &mut Analyses) -> Result<bool, EditError> {
+	let mut changed = false;
+
+	loop {
+		let mut round = thread_jumps(ctx, analyses)?;
+		round |= invert_conditions(ctx, analyses)?;
+		round |= remove_unreachable(ctx, analyses)?;
+		round |= merge_blocks(ctx, analyses)?;
+		round |= remove_nop_jumps(ctx, analyses)?;
+
+		if !round {
+			return Ok(changed);
+		}
+		changed = true;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{invert_conditions, simplify_cfg, thread_jumps};
+	use crate::lua51::{
+		fixtures::{context, listing, numbers},
+		passes::manager::Analyses,
+	};
+	use bytecode::lua51::instructions::Opcode;
+
+	#[test]
+	fn test_thread_jumps() {
+		let mut context = context(
+			vec![
+				Opcode::encode_AsBx(22, 0, 1),   // JMP 1
+				Opcode::encode_ABx(1, 0, 0),     // LOADK 0 0
+				Opcode::encode_AsBx(22, 0, 1),   // JMP 1
+				Opcode::encode_ABx(1, 0, 1),     // LOADK 0 1
+				Opcode::encode_AsBx(22, 0, -1),  // JMP -1, loops onto itself
+				Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+			],
+			numbers(&[1.0, 2.0]),
+		);
+
+		assert!(thread_jumps(&mut context, &mut Analyses::default()).unwrap());
+		assert_eq!(context.instructions.get(0).unwrap().jump_target(0), Some(4));
+		assert!(!thread_jumps(&mut context, &mut Analyses::default()).unwrap());
+
+		assert!(simplify_cfg(&mut context, &mut Analyses::default()).unwrap());
+		assert_eq!(listing(&context), vec!["JMP       \t-1", "RETURN    \t0 1"]);
+	}
+
+	#[test]
+	fn test_invert_conditions() {
+		let mut context = context(
+			vec![
+				Opcode::encode_ABC(23, 0, 0, 1), // EQ 0 0 1
+				Opcode::encode_AsBx(22, 0, 1),   // JMP 1
+				Opcode::encode_AsBx(22, 0, 2),   // JMP 2
+				Opcode::encode_ABx(1, 2, 0),     // LOADK 2 0
+				Opcode::encode_ABC(30, 2, 2, 0), // RETURN 2 2
+				Opcode::encode_ABC(26, 0, 0, 0), // TEST 0 0
+				Opcode::encode_AsBx(22, 0, 1),   // JMP 1
+				Opcode::encode_AsBx(22, 0, -8),  // JMP -8
+				Opcode::encode_ABC(27, 1, 0, 0), // TESTSET 1 0 0
+				Opcode::encode_AsBx(22, 0, 1),   // JMP 1
+				Opcode::encode_AsBx(22, 0, -11), // JMP -11
+				Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+			],
+			numbers(&[1.0]),
+		);
+
+		assert!(invert_conditions(&mut context, &mut Analyses::default()).unwrap());
+		assert_eq!(
+			listing(&context),
+			vec![
+				"EQ        \t1 0 1",
+				"JMP       \t2",
+				"LOADK     \t2 0",
+				"RETURN    \t2 2",
+				"TEST      \t0 1",
+				"JMP       \t-6",
+				"TESTSET   \t1 0 0",
+				"JMP       \t1",
+				"JMP       \t-9",
+				"RETURN    \t0 1",
+			]
+		);
+	}
+
+	#[test]
+	fn test_merge_blocks() {
+		let mut context = context(
+			vec![
+				Opcode::encode_ABx(1, 0, 0),     // LOADK 0 0
+				Opcode::encode_AsBx(22, 0, 2),   // JMP 2
+				Opcode::encode_ABx(1, 1, 1),     // LOADK 1 1
+				Opcode::encode_ABC(30, 1, 2, 0), // RETURN 1 2
+				Opcode::encode_ABC(0, 1, 0, 0),  // MOVE 1 0
+				Opcode::encode_AsBx(22, 0, -4),  // JMP -4
+				Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+			],
+			numbers(&[1.0, 2.0]),
+		);
+
+		assert!(simplify_cfg(&mut context, &mut Analyses::default()).unwrap());
+		assert_eq!(
+			listing(&context),
+			vec![
+				"LOADK     \t0 0",
+				"MOVE      \t1 0",
+				"LOADK     \t1 1",
+				"RETURN    \t1 2",
+				"RETURN    \t0 1",
+			]
+		);
+		assert!(!simplify_cfg(&mut context, &mut Analyses::default()).unwrap());
+	}
+}
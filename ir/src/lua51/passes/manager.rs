@@ -0,0 +1,447 @@
+use super::{
+	copies::eliminate_moves,
+	dce::eliminate_dead_code,
+	flatten::Flatten,
+	fold::fold_constants,
+	jumps::simplify_cfg,
+	peephole::Peephole,
+	strings::EncryptStrings,
+	verify::{verify, VerifyError},
+};
+use crate::lua51::{
+	context::EditError,
+	dataflow::{solve, DataflowResults, Liveness},
+	Dominators, IRContext, CFG,
+};
+use std::{
+	cell::OnceCell,
+	error::Error,
+	fmt::Display,
+	time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+pub enum PassError {
+	/// the pass made an edit the instructions cannot represent
+	Edit(String, EditError),
+	/// the pass left the function in an invalid state, only checked in debug builds
+	Verify(String, VerifyError),
+}
+
+impl Display for PassError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Edit(pass, error) => write!(f, "{pass}: {error}"),
+			Self::Verify(pass, error) => write!(f, "{pass} broke the function: {error}"),
+		}
+	}
+}
+
+impl Error for PassError {}
+
+/// Analyses a pass may leave valid after changing a function
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Preserved {
+	Cfg,
+	Dominators,
+	Liveness,
+}
+
+impl Preserved {
+	pub const ALL: &'static [Preserved] = &[Self::Cfg, Self::Dominators, Self::Liveness];
+}
+
+/**
+ * Analyses - Analyses of one function, computed on first use and kept
+ * until invalidated. Passes taking one invalidate it after each of their
+ * edits, whatever is left cached stays current
+ */
+#[derive(Default)]
+pub struct Analyses {
+	cfg: OnceCell<CFG>,
+	dominators: OnceCell<Dominators>,
+	liveness: OnceCell<DataflowResults<Liveness>>,
+}
+
+impl Analyses {
+	pub fn cfg(&self, ctx: &IRContext) -> &CFG {
+		self.cfg
+			.get_or_init(|| CFG::from_instructions(&ctx.instructions))
+	}
+
+	pub fn dominators(&self, ctx: &IRContext) -> &Dominators {
+		self.dominators
+			.get_or_init(|| Dominators::new(self.cfg(ctx)))
+	}
+
+	pub fn liveness(&self, ctx: &IRContext) -> &DataflowResults<Liveness> {
+		self.liveness
+			.get_or_init(|| solve(self.cfg(ctx), Liveness::new(ctx)))
+	}
+
+	/// Drops every analysis not in `preserved`
+	pub fn invalidate(&mut self, preserved: &[Preserved]) {
+		if !preserved.contains(&Preserved::Cfg) {
+			self.cfg.take();
+		}
+		if !preserved.contains(&Preserved::Dominators) {
+			self.dominators.take();
+		}
+		if !preserved.contains(&Preserved::Liveness) {
+			self.liveness.take();
+		}
+	}
+}
+
+/// A transformation of one function
+pub trait Pass {
+	fn name(&self) -> &str;
+
+	/// Transforms `ctx`, returns whether it changed
+	fn run(&mut self, ctx: &mut IRContext, analyses: &mut Analyses) -> Result<bool, EditError>;
+
+	/// Analyses still valid after the pass changed a function. Passes that
+	/// invalidate `analyses` before each of their edits preserve all of them
+	fn preserves(&self) -> &[Preserved] {
+		&[]
+	}
+}
+
+type PassFn = fn(&mut IRContext, &mut Analyses) -> Result<bool, EditError>;
+
+/// A pass function like `fold_constants`
+pub struct FnPass {
+	name: String,
+	run: PassFn,
+	preserves: &'static [Preserved],
+}
+
+impl FnPass {
+	/// Pass preserving no analyses
+	pub fn new(name: &str, run: PassFn) -> Self {
+		Self {
+			name: name.to_string(),
+			run,
+			preserves: &[],
+		}
+	}
+
+	pub fn preserving(mut self, preserved: &'static [Preserved]) -> Self {
+		self.preserves = preserved;
+		self
+	}
+}
+
+impl Pass for FnPass {
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn run(&mut self, ctx: &mut IRContext, analyses: &mut Analyses) -> Result<bool, EditError> {
+		(self.run)(ctx, analyses)
+	}
+
+	fn preserves(&self) -> &[Preserved] {
+		self.preserves
+	}
+}
+
+impl Pass for Peephole {
+	fn name(&self) -> &str {
+		"peephole"
+	}
+
+	fn run(&mut self, ctx: &mut IRContext, analyses: &mut Analyses) -> Result<bool, EditError> {
+		Peephole::run(self, ctx, analyses).map(|rewrites| rewrites != 0)
+	}
+
+	fn preserves(&self) -> &[Preserved] {
+		Preserved::ALL
+	}
+}
+
+impl Pass for EncryptStrings {
+	fn name(&self) -> &str {
+		"encrypt-strings"
+	}
+
+	fn run(&mut self, ctx: &mut IRContext, _: &mut Analyses) -> Result<bool, EditError> {
+		EncryptStrings::run(self, ctx)
+	}
+}
+
+impl Pass for Flatten {
+	fn name(&self) -> &str {
+		"flatten"
+	}
+
+	fn run(&mut self, ctx: &mut IRContext, _: &mut Analyses) -> Result<bool, EditError> {
+		Flatten::run(self, ctx)
+	}
+}
+
+/// Runs, changes and instructions added and removed by one pass over all functions
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PassStats {
+	pub name: String,
+	pub runs: usize,
+	pub changes: usize,
+	pub added: usize,
+	pub removed: usize,
+	pub time: Duration,
+}
+
+/// Statistics of every pass of a PassManager, in pipeline order
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+	pub passes: Vec<PassStats>,
+	pub iterations: usize,
+}
+
+impl Display for Stats {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		writeln!(
+			f,
+			"{:<20}{:>8}{:>8}{:>8}{:>8}{:>12}",
+			"pass", "runs", "changes", "added", "removed", "time"
+		)?;
+		for pass in &self.passes {
+			writeln!(
+				f,
+				"{:<20}{:>8}{:>8}{:>8}{:>8}{:>12}",
+				pass.name,
+				pass.runs,
+				pass.changes,
+				pass.added,
+				pass.removed,
+				format!("{:.3?}", pass.time)
+			)?;
+		}
+		write!(f, "{} iterations", self.iterations)
+	}
+}
+
+/**
+ * PassManager - Runs a pipeline of passes over a function and all of its
+ * closures. With a fixpoint the pipeline is repeated on each function
+ * until no pass changes it. Debug builds verify a function after every
+ * pass that changed it
+ */
+pub struct PassManager {
+	passes: Vec<Box<dyn Pass>>,
+	fixpoint: Option<usize>,
+	verify: bool,
+	stats: Stats,
+}
+
+impl Default for PassManager {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl PassManager {
+	pub fn new() -> Self {
+		Self {
+			passes: vec![],
+			fixpoint: None,
+			verify: cfg!(debug_assertions),
+			stats: Stats::default(),
+		}
+	}
+
+	/// Folding, copy propagation, CFG simplification, dead code elimination and the
+	/// built-in peephole rules to a fixpoint. Constants are spilled last, folding may
+	/// add ones RK operands cannot address
+	pub fn lua51() -> Self {
+		let mut manager = Self::new();
+		manager.add(FnPass::new("fold", fold_constants).preserving(Preserved::ALL));
+		manager.add(FnPass::new("copies", eliminate_moves).preserving(Preserved::ALL));
+		manager.add(FnPass::new("simplify-cfg", simplify_cfg).preserving(Preserved::ALL));
+		manager.add(FnPass::new("dce", eliminate_dead_code).preserving(Preserved::ALL));
+		manager.add(Peephole::lua51());
+		manager.add(FnPass::new("spill-constants", |ctx, _| {
+			ctx.spill_constants().map(|spilled| spilled != 0)
+		}));
+		manager.fixpoint(16)
+	}
+
+	pub fn add(&mut self, pass: impl Pass + 'static) {
+		self.stats.passes.push(PassStats {
+			name: pass.name().to_string(),
+			..Default::default()
+		});
+		self.passes.push(Box::new(pass));
+	}
+
+	/// Repeats the pipeline on each function until it stops changing, at most `max_iterations` times
+	pub fn fixpoint(mut self, max_iterations: usize) -> Self {
+		self.fixpoint = Some(max_iterations.max(1));
+		self
+	}
+
+	/// Verifies functions after passes change them, on by default in debug builds
+	pub fn verify(mut self, verify: bool) -> Self {
+		self.verify = verify;
+		self
+	}
+
+	pub fn stats(&self) -> &Stats {
+		&self.stats
+	}
+
+	/// Names of the passes, in pipeline order
+	pub fn names(&self) -> Vec<&str> {
+		self.passes.iter().map(|pass| pass.name()).collect()
+	}
+
+	/// Keeps only the first `len` passes of the pipeline
+	pub fn truncate(mut self, len: usize) -> Self {
+		self.passes.truncate(len);
+		self.stats.passes.truncate(len);
+		self
+	}
+
+	/// Runs the pipeline over `ctx` and its closures, returns whether anything changed
+	pub fn run(&mut self, ctx: &mut IRContext) -> Result<bool, PassError> {
+		let mut changed = self.run_function(ctx)?;
+		for closure in ctx.closures.iter_mut() {
+			changed |= self.run(closure)?;
+		}
+		Ok(changed)
+	}
+
+	/// Runs the pipeline over `ctx` alone, leaving its closures as they are
+	pub fn run_function(&mut self, ctx: &mut IRContext) -> Result<bool, PassError> {
+		let mut analyses = Analyses::default();
+		let mut changed = false;
+
+		for _ in 0..self.fixpoint.unwrap_or(1) {
+			self.stats.iterations += 1;
+			let mut round = false;
+
+			for (pass, stats) in self.passes.iter_mut().zip(&mut self.stats.passes) {
+				let before = ctx.instructions.edits();
+				let start = Instant::now();
+				let pass_changed = pass
+					.run(ctx, &mut analyses)
+					.map_err(|error| PassError::Edit(stats.name.clone(), error))?;
+				stats.time += start.elapsed();
+				stats.runs += 1;
+
+				if !pass_changed {
+					continue;
+				}
+				let after = ctx.instructions.edits();
+				stats.changes += 1;
+				stats.added += after.added - before.added;
+				stats.removed += after.removed - before.removed;
+				analyses.invalidate(pass.preserves());
+				round = true;
+
+				if self.verify {
+					verify(ctx).map_err(|error| PassError::Verify(stats.name.clone(), error))?;
+				}
+			}
+
+			changed |= round;
+			if !round {
+				break;
+			}
+		}
+
+		Ok(changed)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Analyses, FnPass, Pass, PassError, PassManager};
+	use crate::lua51::{
+		context::{EditError, IRInstruction},
+		fixtures::{context, listing, numbers, proto},
+		IRContext,
+	};
+	use bytecode::lua51::instructions::Opcode;
+
+	#[test]
+	fn test_pass_manager() {
+		// local a = 1; local b = a + 2; return b, in the main function and a closure
+		let body = || {
+			vec![
+				Opcode::encode_ABx(1, 0, 0),         // LOADK 0 0
+				Opcode::encode_ABC(12, 1, 0, 0x101), // ADD 1 0 1
+				Opcode::encode_ABC(0, 2, 1, 0),      // MOVE 2 1
+				Opcode::encode_AsBx(22, 0, 0),       // JMP 0
+				Opcode::encode_ABC(30, 2, 2, 0),     // RETURN 2 2
+			]
+		};
+		let closure = proto(body(), numbers(&[1.0, 2.0]), vec![]);
+		let mut context = IRContext::from_proto(proto(body(), numbers(&[1.0, 2.0]), vec![closure]));
+
+		let mut manager = PassManager::lua51();
+		assert!(manager.run(&mut context).unwrap());
+		for ctx in [&context, &context.closures[0]] {
+			assert_eq!(listing(ctx), vec!["LOADK     \t2 0", "RETURN    \t2 2"]);
+			assert!(ctx.constants.get(0).unwrap().is_number(3.0));
+		}
+
+		let stats = manager.stats();
+		let removed = stats.passes.iter().map(|pass| pass.removed).sum::<usize>();
+		let added = stats.passes.iter().map(|pass| pass.added).sum::<usize>();
+		assert_eq!(removed - added, 6);
+		assert!(!manager.run(&mut context).unwrap());
+	}
+
+	#[test]
+	fn test_stats_count_edits() {
+		let mut context = context(
+			vec![
+				Opcode::encode_ABx(1, 0, 0),     // LOADK 0 0
+				Opcode::encode_ABC(30, 0, 2, 0), // RETURN 0 2
+			],
+			numbers(&[1.0, 2.0]),
+		);
+
+		// replacing an instruction adds one and removes one
+		let mut manager = PassManager::new();
+		manager.add(FnPass::new("reload", |ctx, _| {
+			let load = IRInstruction::new_abx(1, 0, 1);
+			ctx.instructions.replace_range(0..1, vec![load])?;
+			Ok(true)
+		}));
+		assert!(manager.run(&mut context).unwrap());
+
+		let stats = &manager.stats().passes[0];
+		assert_eq!((stats.changes, stats.added, stats.removed), (1, 1, 1));
+	}
+
+	struct Broken;
+
+	impl Pass for Broken {
+		fn name(&self) -> &str {
+			"broken"
+		}
+
+		fn run(&mut self, ctx: &mut IRContext, _: &mut Analyses) -> Result<bool, EditError> {
+			let last = ctx.instructions.len() - 1;
+			ctx.instructions.remove(last)?;
+			Ok(true)
+		}
+	}
+
+	#[test]
+	fn test_verify_between_passes() {
+		let mut context = context(
+			vec![Opcode::encode_ABC(30, 0, 1, 0)], // RETURN 0 1
+			vec![],
+		);
+
+		let mut manager = PassManager::new().verify(true);
+		manager.add(Broken);
+		assert!(matches!(
+			manager.run(&mut context),
+			Err(PassError::Verify(name, _)) if name == "broken"
+		));
+	}
+}
@@ -0,0 +1,65 @@
+use super::{context::IRInstruction, IRContext};
+use bytecode::lua51::instructions::Value;
+
+pub mod copies;
+pub mod dce;
+pub mod differential;
+pub mod flatten;
+pub mod fold;
+pub mod jumps;
+pub mod manager;
+pub mod peephole;
+pub mod strings;
+pub mod verify;
+
+/// Raw A, B and C fields, Bx is returned as B
+pub(crate) fn operands(inst: &IRInstruction) -> (u8, u16, u16) {
+	let raw = |value: Option<&Value>| match value {
+		Some(Value::Reg(v)) => *v as u16,
+		Some(Value::Kst(v)) | Some(Value::RK(v)) => *v as u16,
+		_ => 0,
+	};
+	let b = match inst.get_b().value() {
+		Some(b) => raw(Some(b)),
+		None => raw(inst.get_bx().value()),
+	};
+	(
+		raw(inst.get_a().value()) as u8,
+		b,
+		raw(inst.get_c().value()),
+	)
+}
+
+/// Whether the instruction before `pc` may skip it
+pub(crate) fn follows_skip(ctx: &IRContext, pc: usize) -> bool {
+	pc > 0 && {
+		let inst = ctx.instructions.get(pc - 1).unwrap();
+		!inst.is_pseudo() && inst.is_skip()
+	}
+}
+
+/// xorshift64*, small and the same on every platform
+pub(crate) struct Rng(u64);
+
+impl Rng {
+	/// Seeds through splitmix64, so close seeds give unrelated streams
+	pub(crate) fn new(seed: u64) -> Self {
+		let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		// the state must not be zero
+		Self((z ^ (z >> 31)).max(1))
+	}
+
+	pub(crate) fn next(&mut self) -> u64 {
+		self.0 ^= self.0 >> 12;
+		self.0 ^= self.0 << 25;
+		self.0 ^= self.0 >> 27;
+		self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+	}
+
+	/// Uniform in 0..n
+	pub(crate) fn below(&mut self, n: usize) -> usize {
+		(self.next() % n as u64) as usize
+	}
+}
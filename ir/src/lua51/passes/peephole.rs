@@ -0,0 +1,593 @@
+use super::{follows_skip, manager::Analyses};
+use crate::lua51::{
+	context::{EditError, IRInstruction},
+	dataflow::{DataflowResults, Liveness, RegisterSet},
+	get_opcode_name, IRContext,
+};
+use bytecode::lua51::instructions::{Opcode, Value, OPCODES};
+use std::{collections::HashMap, error::Error, fmt::Display};
+
+#[derive(Debug, PartialEq)]
+pub enum PatternError {
+	/// no Lua 5.1 opcode has this name
+	UnknownOpcode(String),
+	/// the opcode has a different number of operands
+	OperandCount(String),
+	/// the operand is neither a number, `_` nor a variable name
+	InvalidOperand(String),
+	/// a replacement uses a variable the pattern does not bind
+	UnboundVariable(String),
+	/// replacements cannot produce jumps, their offsets depend on the position
+	Jump(String),
+}
+
+impl Display for PatternError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::UnknownOpcode(name) => write!(f, "unknown opcode {name}"),
+			Self::OperandCount(inst) => write!(f, "wrong number of operands in `{inst}`"),
+			Self::InvalidOperand(operand) => write!(f, "invalid operand `{operand}`"),
+			Self::UnboundVariable(var) => write!(f, "variable `{var}` is not bound by the pattern"),
+			Self::Jump(inst) => write!(f, "replacement `{inst}` is a jump"),
+		}
+	}
+}
+
+impl Error for PatternError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+	Any,
+	Literal(i64),
+	Var(String),
+	Flipped(String), // `!v`, only in replacements
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+	opcode: usize,
+	operands: Vec<Term>,
+}
+
+fn opcode_by_name(name: &str) -> Option<usize> {
+	(0..OPCODES.len()).find(|opcode| get_opcode_name(*opcode).as_deref() == Some(name))
+}
+
+/// Fields an opcode has, in the order instructions display them
+fn field_count(opcode: usize) -> usize {
+	let template = &OPCODES[opcode];
+	[
+		template.get_a(),
+		template.get_b(),
+		template.get_bx(),
+		template.get_sbx(),
+		template.get_c(),
+	]
+	.iter()
+	.filter(|field| field.is_some())
+	.count()
+}
+
+fn field_values(inst: &IRInstruction) -> Vec<i64> {
+	[
+		inst.get_a(),
+		inst.get_b(),
+		inst.get_bx(),
+		inst.get_sbx(),
+		inst.get_c(),
+	]
+	.iter()
+	.filter_map(|operand| operand.value())
+	.map(|value| match value {
+		Value::Reg(v) => *v as i64,
+		Value::Kst(v) | Value::RK(v) => *v as i64,
+		Value::sBx(v) => *v as i64,
+	})
+	.collect()
+}
+
+/// Instruction with the fields of `opcode` set to `values` in display order
+fn build(opcode: usize, values: &[i64]) -> IRInstruction {
+	let mut values = values.iter().map(|value| *value as u32);
+	let mut next = |present: bool| if present { values.next().unwrap() } else { 0 };
+	match &OPCODES[opcode] {
+		Opcode::iABx(..) => {
+			let a = next(true);
+			IRInstruction::new_abx(opcode, a as u8, next(true))
+		}
+		template => {
+			let a = next(template.get_a().is_some());
+			let b = next(template.get_b().is_some());
+			let c = next(template.get_c().is_some());
+			IRInstruction::new_abc(opcode, a as u8, b as u16, c as u16)
+		}
+	}
+}
+
+/// Parses `;` separated instructions like `LOADK r k; MOVE s r`
+fn parse(source: &str, replacement: bool) -> Result<Vec<Pattern>, PatternError> {
+	let mut patterns = vec![];
+
+	for inst in source
+		.split(';')
+		.map(str::trim)
+		.filter(|inst| !inst.is_empty())
+	{
+		let mut words = inst.split_whitespace();
+		let name = words.next().unwrap();
+		let opcode = opcode_by_name(&name.to_uppercase())
+			.ok_or_else(|| PatternError::UnknownOpcode(name.to_string()))?;
+		if replacement && matches!(opcode, 22 | 31 | 32) {
+			return Err(PatternError::Jump(inst.to_string()));
+		}
+
+		let operands = words
+			.map(|word| {
+				let var = |name: &str| {
+					let valid = name.starts_with(|c: char| c.is_ascii_alphabetic())
+						&& name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+					valid.then(|| name.to_string())
+				};
+				match word {
+					"_" if !replacement => Some(Term::Any),
+					_ if word
+						.starts_with(['-', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9']) =>
+					{
+						word.parse().ok().map(Term::Literal)
+					}
+					_ if replacement && word.starts_with('!') => var(&word[1..]).map(Term::Flipped),
+					_ => var(word).map(Term::Var),
+				}
+				.ok_or_else(|| PatternError::InvalidOperand(word.to_string()))
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+		if operands.len() != field_count(opcode) {
+			return Err(PatternError::OperandCount(inst.to_string()));
+		}
+
+		patterns.push(Pattern { opcode, operands });
+	}
+
+	Ok(patterns)
+}
+
+/**
+ * Match - Instructions a rule's pattern matched, with the values its
+ * variables are bound to
+ */
+pub struct Match<'a> {
+	ctx: &'a IRContext,
+	pc: usize,
+	len: usize,
+	bindings: HashMap<String, i64>,
+	analyses: &'a Analyses,
+}
+
+impl Match<'_> {
+	pub fn ctx(&self) -> &IRContext {
+		self.ctx
+	}
+
+	/// pc of the first matched instruction
+	pub fn pc(&self) -> usize {
+		self.pc
+	}
+
+	/// Number of matched instructions
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Value bound to `var`, panics if the pattern does not bind it
+	pub fn get(&self, var: &str) -> i64 {
+		self.bindings[var]
+	}
+
+	fn liveness(&self) -> &DataflowResults<Liveness> {
+		self.analyses.liveness(self.ctx)
+	}
+
+	/// Whether `reg` is never read after the matched instructions
+	pub fn is_dead(&self, reg: u8) -> bool {
+		!self.liveness().is_live_after(self.pc + self.len - 1, reg)
+	}
+
+	/// Registers closures capture as upvalues anywhere in the function
+	pub fn captured(&self) -> RegisterSet {
+		*self.liveness().analysis().captured()
+	}
+}
+
+type Condition = Box<dyn Fn(&Match) -> bool>;
+type Rewrite = Box<dyn Fn(&Match) -> Vec<IRInstruction>>;
+
+/**
+ * Rule - A peephole rewrite declared as an instruction pattern, side
+ * conditions and a replacement. Pattern operands are numbers, `_` or
+ * variables, a variable used twice must match the same value both times
+ */
+pub struct Rule {
+	name: String,
+	pattern: Vec<Pattern>,
+	conditions: Vec<Condition>,
+	rewrite: Rewrite,
+}
+
+impl Rule {
+	/// Rule matching `pattern` that removes the matched instructions until given a replacement
+	pub fn new(name: &str, pattern: &str) -> Result<Self, PatternError> {
+		Ok(Self {
+			name: name.to_string(),
+			pattern: parse(pattern, false)?,
+			conditions: vec![],
+			rewrite: Box::new(|_| vec![]),
+		})
+	}
+
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Only rewrites matches `condition` holds for
+	pub fn when(mut self, condition: impl Fn(&Match) -> bool + 'static) -> Self {
+		self.conditions.push(Box::new(condition));
+		self
+	}
+
+	/// Only rewrites matches where the register bound to `var` is dead afterwards
+	pub fn when_dead(self, var: &str) -> Self {
+		let var = var.to_string();
+		self.when(move |m| m.is_dead(m.get(&var) as u8))
+	}
+
+	/// Replaces matches with `template`, instructions in pattern syntax where `!v` is `v` xor 1
+	pub fn replace(mut self, template: &str) -> Result<Self, PatternError> {
+		let template = parse(template, true)?;
+		for term in template.iter().flat_map(|pattern| &pattern.operands) {
+			if let Term::Var(var) | Term::Flipped(var) = term {
+				if !self.binds(var) {
+					return Err(PatternError::UnboundVariable(var.clone()));
+				}
+			}
+		}
+
+		self.rewrite = Box::new(move |m| {
+			template
+				.iter()
+				.map(|pattern| {
+					let values = pattern
+						.operands
+						.iter()
+						.map(|term| match term {
+							Term::Literal(value) => *value,
+							Term::Var(var) => m.get(var),
+							Term::Flipped(var) => m.get(var) ^ 1,
+							Term::Any => unreachable!(),
+						})
+						.collect::<Vec<_>>();
+					build(pattern.opcode, &values)
+				})
+				.collect()
+		});
+		Ok(self)
+	}
+
+	/// Replaces matches with the instructions `rewrite` computes
+	pub fn replace_with(
+		mut self,
+		rewrite: impl Fn(&Match) -> Vec<IRInstruction> + 'static,
+	) -> Self {
+		self.rewrite = Box::new(rewrite);
+		self
+	}
+
+	fn binds(&self, var: &str) -> bool {
+		self.pattern
+			.iter()
+			.flat_map(|pattern| &pattern.operands)
+			.any(|term| *term == Term::Var(var.to_string()))
+	}
+
+	/// Binds the pattern to the instructions at `pc`
+	fn bind(&self, ctx: &IRContext, pc: usize) -> Option<HashMap<String, i64>> {
+		let len = self.pattern.len();
+		if len == 0 || pc + len > ctx.instructions.len() {
+			return None;
+		}
+		// a CLOSURE or SETLIST cannot be separated from its pseudo instructions
+		if ctx
+			.instructions
+			.get(pc + len)
+			.is_some_and(|inst| inst.is_pseudo())
+		{
+			return None;
+		}
+
+		let mut bindings = HashMap::new();
+		for (i, pattern) in self.pattern.iter().enumerate() {
+			let inst = ctx.instructions.get(pc + i).unwrap();
+			// only the last instruction may skip, and jumps may only land on the first
+			if !inst.is(pattern.opcode)
+				|| inst.is_pseudo()
+				|| (i + 1 < len && inst.is_skip())
+				|| (i > 0 && !inst.labels().is_empty())
+			{
+				return None;
+			}
+
+			for (term, value) in pattern.operands.iter().zip(field_values(inst)) {
+				let expected = match term {
+					Term::Literal(literal) => *literal,
+					Term::Var(var) => *bindings.entry(var.clone()).or_insert(value),
+					_ => value,
+				};
+				if expected != value {
+					return None;
+				}
+			}
+		}
+
+		Some(bindings)
+	}
+}
+
+/**
+ * Peephole - Applies rewrite rules to short instruction sequences until
+ * none matches anymore. Rules are tried in the order they were added
+ */
+#[derive(Default)]
+pub struct Peephole {
+	rules: Vec<Rule>,
+}
+
+impl Peephole {
+	/// Engine without any rules
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Engine with the built-in Lua 5.1 rules
+	pub fn lua51() -> Self {
+		let mut peephole = Self::new();
+		for rule in lua51_rules() {
+			peephole.add_rule(rule);
+		}
+		peephole
+	}
+
+	pub fn add_rule(&mut self, rule: Rule) {
+		self.rules.push(rule);
+	}
+
+	pub fn rules(&self) -> &[Rule] {
+		&self.rules
+	}
+
+	/// Rewrites `ctx` until no rule matches, returns the number of rewrites
+	pub fn run(&self, ctx: &mut IRContext, analyses: &mut Analyses) -> Result<usize, EditError> {
+		let longest = self
+			.rules
+			.iter()
+			.map(|rule| rule.pattern.len())
+			.max()
+			.unwrap_or(0);
+		let mut rewrites = 0;
+		let mut start = 0;
+
+		while let Some((pc, len, insts)) = self.find(ctx, analyses, start) {
+			analyses.invalidate(&[]);
+			ctx.instructions.replace_range(pc..pc + len, insts)?;
+			rewrites += 1;
+			// earlier instructions may form a new match with the replacement
+			start = pc.saturating_sub(longest);
+		}
+
+		Ok(rewrites)
+	}
+
+	/// First rewrite at or after `start` as its pc, length and replacement
+	fn find(
+		&self,
+		ctx: &IRContext,
+		analyses: &Analyses,
+		start: usize,
+	) -> Option<(usize, usize, Vec<IRInstruction>)> {
+		for pc in start..ctx.instructions.len() {
+			for rule in &self.rules {
+				let Some(bindings) = rule.bind(ctx, pc) else {
+					continue;
+				};
+				let m = Match {
+					ctx,
+					pc,
+					len: rule.pattern.len(),
+					bindings,
+					analyses,
+				};
+				if !rule.conditions.iter().all(|condition| condition(&m)) {
+					continue;
+				}
+
+				let len = m.len;
+				let insts = (rule.rewrite)(&m);
+				// an instruction a skip jumps over can only be swapped for another
+				if follows_skip(ctx, pc) && (len != 1 || insts.len() != 1) {
+					continue;
+				}
+				// a rewrite producing what it matched would never stop
+				let matched = (pc..pc + len).map(|pc| ctx.instructions.get(pc).unwrap());
+				if insts.len() == len
+					&& insts.iter().zip(matched).all(|(new, old)| {
+						new.opcode() == old.opcode() && new.to_string() == old.to_string()
+					}) {
+					continue;
+				}
+
+				return Some((pc, len, insts));
+			}
+		}
+
+		None
+	}
+}
+
+/**
+ * lua51_rules - Built-in rules
+ * loadk-move:    `LOADK r k; MOVE s r` loads straight into s
+ * not-test:      `NOT t x; TEST t c` tests x with the inverted flag
+ * loadnil-merge: overlapping or adjacent LOADNILs become one
+ * close-return:  RETURN closes the upvalues itself
+ * close-unused:  CLOSE of registers no closure captures
+ * move-self:     `MOVE a a`
+ */
+pub fn lua51_rules() -> Vec<Rule> {
+	let rules = || -> Result<Vec<Rule>, PatternError> {
+		Ok(vec![
+			Rule::new("loadk-move", "LOADK r k; MOVE s r")?
+				.when_dead("r")
+				.replace("LOADK s k")?,
+			Rule::new("not-test", "NOT t x; TEST t c")?
+				.when_dead("t")
+				.replace("TEST x !c")?,
+			Rule::new("loadnil-merge", "LOADNIL a b; LOADNIL c d")?
+				.when(|m| m.get("c") >= m.get("a") && m.get("c") <= m.get("b") + 1)
+				.replace_with(|m| {
+					let (a, b, d) = (m.get("a"), m.get("b"), m.get("d"));
+					vec![IRInstruction::new_abc(3, a as u8, b.max(d) as u16, 0)]
+				}),
+			Rule::new("close-return", "CLOSE _; RETURN a b")?.replace("RETURN a b")?,
+			Rule::new("close-unused", "CLOSE a")?
+				.when(|m| m.captured().iter().all(|reg| (reg as i64) < m.get("a"))),
+			Rule::new("move-self", "MOVE a a")?,
+		])
+	};
+	rules().expect("built-in rules are valid")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{PatternError, Peephole, Rule};
+	use crate::lua51::{
+		fixtures::{context, listing, numbers, proto},
+		passes::manager::Analyses,
+		IRContext,
+	};
+	use bytecode::lua51::{instructions::Opcode, Proto};
+
+	#[test]
+	fn test_lua51_rules() {
+		let mut context = IRContext::from_proto(Proto {
+			nparams: 1,
+			..proto(
+				vec![
+					Opcode::encode_ABx(1, 1, 0),     // LOADK 1 0
+					Opcode::encode_ABC(0, 2, 1, 0),  // MOVE 2 1
+					Opcode::encode_ABC(19, 3, 0, 0), // NOT 3 0
+					Opcode::encode_ABC(26, 3, 0, 1), // TEST 3 1
+					Opcode::encode_AsBx(22, 0, 1),   // JMP 1
+					Opcode::encode_ABC(0, 4, 4, 0),  // MOVE 4 4
+					Opcode::encode_ABC(3, 4, 5, 0),  // LOADNIL 4 5
+					Opcode::encode_ABC(3, 6, 7, 0),  // LOADNIL 6 7
+					Opcode::encode_ABC(35, 1, 0, 0), // CLOSE 1
+					Opcode::encode_ABC(30, 2, 2, 0), // RETURN 2 2
+				],
+				numbers(&[1.0]),
+				vec![],
+			)
+		});
+
+		assert_eq!(
+			Peephole::lua51()
+				.run(&mut context, &mut Analyses::default())
+				.unwrap(),
+			5
+		);
+		assert_eq!(
+			listing(&context),
+			vec![
+				"LOADK     \t2 0",
+				"TEST      \t0 0",
+				"JMP       \t0",
+				"LOADNIL   \t4 7",
+				"RETURN    \t2 2",
+			]
+		);
+	}
+
+	#[test]
+	fn test_conditions() {
+		let mut context = IRContext::from_proto(proto(
+			vec![
+				Opcode::encode_ABx(1, 1, 0),     // LOADK 1 0
+				Opcode::encode_ABC(0, 2, 1, 0),  // MOVE 2 1, R1 is still read
+				Opcode::encode_ABx(36, 3, 0),    // CLOSURE 3 0
+				Opcode::encode_ABC(0, 0, 2, 0),  // captures R2
+				Opcode::encode_ABC(35, 2, 0, 0), // CLOSE 2
+				Opcode::encode_ABC(0, 0, 1, 0),  // MOVE 0 1
+				Opcode::encode_ABC(30, 1, 2, 0), // RETURN 1 2
+			],
+			numbers(&[1.0]),
+			vec![Proto {
+				nupvals: 1,
+				..Default::default()
+			}],
+		));
+		assert_eq!(
+			Peephole::lua51()
+				.run(&mut context, &mut Analyses::default())
+				.unwrap(),
+			0
+		);
+	}
+
+	#[test]
+	fn test_custom_rules() {
+		let mut peephole = Peephole::new();
+		peephole.add_rule(
+			Rule::new("mul-one", "MUL a b 256")
+				.unwrap()
+				.when(|m| m.ctx().constants.get(0).unwrap().is_number(1.0))
+				.replace("MOVE a b")
+				.unwrap(),
+		);
+
+		let mut context = context(
+			vec![
+				Opcode::encode_ABC(14, 1, 0, 0x100), // MUL 1 0 256
+				Opcode::encode_ABC(30, 1, 2, 0),     // RETURN 1 2
+			],
+			numbers(&[1.0]),
+		);
+		assert_eq!(
+			peephole
+				.run(&mut context, &mut Analyses::default())
+				.unwrap(),
+			1
+		);
+		assert_eq!(listing(&context)[0], "MOVE      \t1 0");
+
+		assert_eq!(
+			Rule::new("bad", "MOVE a").err(),
+			Some(PatternError::OperandCount("MOVE a".to_string()))
+		);
+		assert_eq!(
+			Rule::new("bad", "FOO a").err(),
+			Some(PatternError::UnknownOpcode("FOO".to_string()))
+		);
+		assert_eq!(
+			Rule::new("bad", "MOVE a b").unwrap().replace("JMP a").err(),
+			Some(PatternError::Jump("JMP a".to_string()))
+		);
+		assert_eq!(
+			Rule::new("bad", "MOVE a b")
+				.unwrap()
+				.replace("MOVE a c")
+				.err(),
+			Some(PatternError::UnboundVariable("c".to_string()))
+		);
+	}
+}
@@ -0,0 +1,577 @@
+use super::{follows_skip, operands, Rng};
+use crate::{
+	lua51::{
+		context::{EditError, IRConstants, IRInstruction, IRInstructions, Label, MAX_STACK},
+		IRContext,
+	},
+	traits::IROperand,
+};
+use bytecode::lua51::{instructions::Value, Constants, Proto};
+use std::collections::HashMap;
+
+/// Source of the decoders the pass adds, functions with one are not encrypted again
+pub const DECODER_SOURCE: &str = "=(strings)";
+
+const HEX: &[u8] = b"0123456789abcdef";
+
+/** Scheme - How EncryptStrings hides the bytes of a string */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+	/// XOR with a key of the function that rolls on by every encrypted byte,
+	/// written as hex digits
+	Xor,
+	/// digits of a base between 16 and 94 over printable characters the
+	/// function shuffles
+	BaseN,
+	/// Xor with a key of every string, written in front of it
+	PerString,
+}
+
+/// Keys of one function
+struct Cipher {
+	scheme: Scheme,
+	/// every byte is two digits of base alphabet.len()
+	alphabet: Vec<u8>,
+	key: u8,
+}
+
+impl Cipher {
+	fn new(scheme: Scheme, rng: &mut Rng) -> Self {
+		let alphabet = match scheme {
+			Scheme::BaseN => {
+				let mut printable = (b'!'..=b'~').collect::<Vec<_>>();
+				for i in (1..printable.len()).rev() {
+					printable.swap(i, rng.below(i + 1));
+				}
+				printable.truncate(16 + rng.below(printable.len() - 15));
+				printable
+			}
+			_ => HEX.to_vec(),
+		};
+		Self {
+			scheme,
+			alphabet,
+			key: rng.below(256) as u8,
+		}
+	}
+
+	fn encrypt(&self, plain: &[u8], rng: &mut Rng) -> String {
+		let mut bytes = vec![];
+		let mut key = self.key;
+		if self.scheme == Scheme::PerString {
+			key = rng.below(256) as u8;
+			bytes.push(key);
+		}
+		for byte in plain {
+			match self.scheme {
+				Scheme::BaseN => bytes.push(*byte),
+				_ => {
+					let encrypted = byte ^ key;
+					bytes.push(encrypted);
+					key = key.wrapping_add(encrypted);
+				}
+			}
+		}
+
+		let base = self.alphabet.len();
+		bytes
+			.into_iter()
+			.flat_map(|byte| {
+				let byte = byte as usize;
+				[self.alphabet[byte / base], self.alphabet[byte % base]]
+			})
+			.map(char::from)
+			.collect()
+	}
+}
+
+/// Instructions and constants of a function being written
+struct Asm {
+	code: Vec<IRInstruction>,
+	constants: IRConstants,
+	labels: usize,
+	bound: Vec<Label>,
+}
+
+impl Asm {
+	fn new() -> Self {
+		Self {
+			code: vec![],
+			constants: IRConstants::from_constants(vec![]),
+			labels: 0,
+			bound: vec![],
+		}
+	}
+
+	fn emit(&mut self, mut inst: IRInstruction) {
+		for label in self.bound.drain(..) {
+			inst.add_label(label);
+		}
+		self.code.push(inst);
+	}
+
+	fn abc(&mut self, opcode: usize, a: u8, b: u16, c: u16) {
+		self.emit(IRInstruction::new_abc(opcode, a, b, c));
+	}
+
+	fn jump(&mut self, opcode: usize, a: u8, target: Label) {
+		self.emit(IRInstruction::new_jump(opcode, a, target));
+	}
+
+	fn label(&mut self) -> Label {
+		self.labels += 1;
+		Label(self.labels - 1)
+	}
+
+	/// Labels the next instruction
+	fn bind(&mut self, label: Label) {
+		self.bound.push(label);
+	}
+
+	/// RK operand of a number constant
+	fn number(&mut self, n: f64) -> u16 {
+		self.constants.add_number(n) as u16 + 0x100
+	}
+
+	/// RK operand of a string constant
+	fn string(&mut self, s: &str) -> u16 {
+		self.constants.add_string(s) as u16 + 0x100
+	}
+
+	/// LOADK of the constant `rk` names
+	fn loadk(&mut self, a: u8, rk: u16) {
+		self.emit(IRInstruction::new_abx(1, a, rk as u32 - 0x100));
+	}
+
+	/// R(dest) = map[byte(blob, R(at))] * base + map[byte(blob, R(at) + 1)],
+	/// clobbering registers 22 to 25
+	fn digits(&mut self, dest: u8, at: u8, base: usize) {
+		let base = self.number(base as f64);
+		let one = self.number(1.0);
+		self.abc(0, 22, 2, 0);
+		self.abc(0, 23, 0, 0);
+		self.abc(0, 24, at as u16, 0);
+		self.abc(28, 22, 3, 2);
+		self.abc(6, 22, 4, 22);
+		self.abc(14, 22, 22, base);
+		self.abc(0, 23, 2, 0);
+		self.abc(0, 24, 0, 0);
+		self.abc(12, 25, at as u16, one);
+		self.abc(28, 23, 3, 2);
+		self.abc(6, 23, 4, 23);
+		self.abc(12, dest, 22, 23);
+	}
+}
+
+/**
+ * decoder - The function `cipher` blobs are passed to, which returns them
+ * decrypted and keeps them in the table of its upvalue. Strings are indexed
+ * for `byte` and `char`, so it needs no globals
+ */
+fn decoder(cipher: &Cipher) -> Result<IRContext, EditError> {
+	// 0 blob, 1 cache, 2 byte, 3 char, 4 digit values, 5 result, 6 key,
+	// 7-10 loops, 11 byte, 12-21 XOR, 22-25 temporaries
+	let mut asm = Asm::new();
+	let [decode, map_body, map_loop, body, next] = [(); 5].map(|_| asm.label());
+	let base = cipher.alphabet.len();
+	let [one, two, digits] = [1.0, 2.0, base as f64].map(|n| asm.number(n));
+	let alphabet = String::from_utf8(cipher.alphabet.clone()).unwrap();
+	let alphabet = asm.string(&alphabet);
+	let [byte, char, empty] = ["byte", "char", ""].map(|s| asm.string(s));
+
+	asm.abc(4, 1, 0, 0);
+	asm.abc(6, 2, 1, 0);
+	asm.abc(26, 2, 0, 0);
+	asm.jump(22, 0, decode);
+	asm.abc(30, 2, 2, 0);
+
+	asm.bind(decode);
+	asm.abc(6, 2, 0, byte);
+	asm.abc(6, 3, 0, char);
+	asm.abc(10, 4, 0, 0);
+	// for i = 1, #alphabet do map[byte(alphabet, i)] = i - 1 end
+	asm.loadk(7, one);
+	asm.loadk(8, digits);
+	asm.loadk(9, one);
+	asm.jump(32, 7, map_loop);
+	asm.bind(map_body);
+	asm.abc(0, 22, 2, 0);
+	asm.loadk(23, alphabet);
+	asm.abc(0, 24, 10, 0);
+	asm.abc(28, 22, 3, 2);
+	asm.abc(13, 23, 10, one);
+	asm.abc(9, 4, 22, 23);
+	asm.bind(map_loop);
+	asm.jump(31, 7, map_body);
+
+	asm.loadk(5, empty);
+	let start = match cipher.scheme {
+		Scheme::Xor => {
+			let key = asm.number(cipher.key as f64);
+			asm.loadk(6, key);
+			one
+		}
+		Scheme::PerString => {
+			asm.loadk(10, one);
+			asm.digits(6, 10, base);
+			asm.number(3.0)
+		}
+		Scheme::BaseN => one,
+	};
+	// for i = start, #blob, 2 do
+	asm.loadk(7, start);
+	asm.abc(20, 8, 0, 0);
+	asm.loadk(9, two);
+	asm.jump(32, 7, next);
+	asm.bind(body);
+	asm.digits(11, 10, base);
+
+	let plain = match cipher.scheme {
+		Scheme::BaseN => 11,
+		_ => {
+			// x = 0 a = e b = key p = 1
+			// for _ = 1, 8 do
+			//     if a % 2 ~= b % 2 then x = x + p end
+			//     a = (a - a % 2) / 2 b = (b - b % 2) / 2 p = p * 2
+			// end
+			// key = (key + e) % 256
+			let [bits_body, bits_loop, same] = [(); 3].map(|_| asm.label());
+			let [zero, eight, wrap] = [0.0, 8.0, 256.0].map(|n| asm.number(n));
+			asm.loadk(12, zero);
+			asm.abc(0, 13, 11, 0);
+			asm.abc(0, 14, 6, 0);
+			asm.loadk(15, one);
+			asm.loadk(16, one);
+			asm.loadk(17, eight);
+			asm.loadk(18, one);
+			asm.jump(32, 16, bits_loop);
+			asm.bind(bits_body);
+			asm.abc(16, 20, 13, two);
+			asm.abc(16, 21, 14, two);
+			asm.abc(23, 1, 20, 21);
+			asm.jump(22, 0, same);
+			asm.abc(12, 12, 12, 15);
+			asm.bind(same);
+			asm.abc(13, 13, 13, 20);
+			asm.abc(15, 13, 13, two);
+			asm.abc(13, 14, 14, 21);
+			asm.abc(15, 14, 14, two);
+			asm.abc(14, 15, 15, two);
+			asm.bind(bits_loop);
+			asm.jump(31, 16, bits_body);
+			asm.abc(12, 6, 6, 11);
+			asm.abc(16, 6, 6, wrap);
+			12
+		}
+	};
+	// result = result .. char(x)
+	asm.abc(0, 22, 3, 0);
+	asm.abc(0, 23, plain, 0);
+	asm.abc(28, 22, 2, 2);
+	asm.abc(0, 23, 22, 0);
+	asm.abc(0, 22, 5, 0);
+	asm.abc(21, 5, 22, 23);
+	asm.bind(next);
+	asm.jump(31, 7, body);
+	asm.abc(9, 1, 0, 5);
+	asm.abc(30, 5, 2, 0);
+
+	let mut code = IRInstructions::from_ir(asm.code);
+	code.resolve_labels()?;
+	Ok(IRContext::from_proto(Proto {
+		source: DECODER_SOURCE.to_string(),
+		nupvals: 1,
+		nparams: 1,
+		max_stack_size: 26,
+		instructions: code.to_instructions(),
+		constants: asm
+			.constants
+			.get_all()
+			.iter()
+			.map(|constant| constant.get().clone())
+			.collect(),
+		..Default::default()
+	}))
+}
+
+/**
+ * EncryptStrings - Replaces the string constants LOADK, GETGLOBAL, GETTABLE,
+ * SELF and SETTABLE name with encrypted blobs, decrypted at runtime by a
+ * decoder closure the function creates on entry. Globals are read from
+ * getfenv(1), so the function looks up the global getfenv on entry when it
+ * reads any. The same seed gives the same keys for the same functions in
+ * the same order
+ */
+pub struct EncryptStrings {
+	scheme: Scheme,
+	rng: Rng,
+}
+
+impl EncryptStrings {
+	pub fn new(scheme: Scheme, seed: u64) -> Self {
+		Self {
+			scheme,
+			rng: Rng::new(seed),
+		}
+	}
+
+	/// Encrypts the strings of `ctx` but not of its closures, returns whether any were.
+	/// Functions without registers to spare are left alone
+	pub fn run(&mut self, ctx: &mut IRContext) -> Result<bool, EditError> {
+		let is_decoder = |ctx: &IRContext| ctx.source() == DECODER_SOURCE;
+		if is_decoder(ctx) || ctx.closures.iter().any(|closure| is_decoder(closure)) {
+			return Ok(false);
+		}
+		if sites(ctx).is_empty() {
+			return Ok(false);
+		}
+
+		let cipher = Cipher::new(self.scheme, &mut self.rng);
+		let mut encrypted = ctx.clone();
+		match self.encrypt(&mut encrypted, &cipher) {
+			Ok(()) => {
+				*ctx = encrypted;
+				Ok(true)
+			}
+			Err(EditError::NoFreeRegister(_)) => Ok(false),
+			Err(err) => Err(err),
+		}
+	}
+
+	fn encrypt(&mut self, ctx: &mut IRContext, cipher: &Cipher) -> Result<(), EditError> {
+		let globals = sites(ctx).iter().any(|pc| {
+			let inst = ctx.instructions.get(*pc).unwrap();
+			inst.is(5)
+		});
+		let names = ["(decoder)", "(strings)", "(getfenv)"];
+		let first = ctx.reserve_registers(&names[..2 + globals as usize])?;
+		let (decode, cache, getfenv) = (first, first + 1, first + 2);
+
+		// temporaries past every register the function uses
+		let t = ctx.max_stack_size();
+		if t as usize + 3 > MAX_STACK {
+			return Err(EditError::NoFreeRegister(0));
+		}
+		ctx.set_max_stack_size(t + 3);
+
+		let mut blobs = HashMap::new();
+		let mut blob = |ctx: &mut IRContext, plain: String| -> u32 {
+			*blobs.entry(plain).or_insert_with_key(|plain| {
+				let blob = cipher.encrypt(plain.as_bytes(), &mut self.rng);
+				ctx.constants.add_string(&blob)
+			}) as u32
+		};
+		// R(reg) = decoder(blob), clobbering R(reg + 1)
+		let load = |reg: u8, blob: u32| {
+			vec![
+				IRInstruction::new_abc(0, reg, decode as u16, 0),
+				IRInstruction::new_abx(1, reg + 1, blob),
+				IRInstruction::new_abc(28, reg, 2, 2),
+			]
+		};
+
+		for pc in sites(ctx).into_iter().rev() {
+			let inst = ctx.instructions.get(pc).unwrap().clone();
+			let (a, mut b, mut c) = operands(&inst);
+			let mut insts = vec![];
+			match inst.opcode() {
+				1 => {
+					let plain = string_constant(ctx, inst.get_bx()).unwrap();
+					insts.extend(load(t, blob(ctx, plain)));
+					insts.push(IRInstruction::new_abc(0, a, t as u16, 0));
+				}
+				5 => {
+					let plain = string_constant(ctx, inst.get_bx()).unwrap();
+					let level = ctx.constants.add_number(1.0) as u32;
+					insts.push(IRInstruction::new_abc(0, t, getfenv as u16, 0));
+					insts.push(IRInstruction::new_abx(1, t + 1, level));
+					insts.push(IRInstruction::new_abc(28, t, 2, 2));
+					insts.extend(load(t + 1, blob(ctx, plain)));
+					insts.push(IRInstruction::new_abc(6, a, t as u16, t as u16 + 1));
+				}
+				opcode => {
+					// SETTABLE keys and values, GETTABLE and SELF keys
+					if opcode == 9 {
+						if let Some(plain) = string_constant(ctx, inst.get_b()) {
+							insts.extend(load(t, blob(ctx, plain)));
+							b = t as u16;
+						}
+					}
+					if let Some(plain) = string_constant(ctx, inst.get_c()) {
+						insts.extend(load(t + 1, blob(ctx, plain)));
+						c = t as u16 + 1;
+					}
+					insts.push(IRInstruction::new_abc(opcode, a, b, c));
+				}
+			}
+			ctx.instructions.replace_range(pc..pc + 1, insts)?;
+		}
+
+		let mut prologue = vec![
+			IRInstruction::new_abc(10, cache, 0, 0),
+			IRInstruction::new_abx(36, decode, ctx.closures.len() as u32),
+			IRInstruction::new_abc(0, 0, cache as u16, 0).into_pseudo(),
+		];
+		if globals {
+			let name = ctx.constants.add_string("getfenv") as u32;
+			prologue.push(IRInstruction::new_abx(5, getfenv, name));
+		}
+		ctx.closures.push(Box::new(decoder(cipher)?));
+
+		// jumps back to the start do not run the prologue again
+		let added = prologue.len();
+		ctx.instructions.insert_before(0, prologue)?;
+		let labels = ctx.instructions.get_mut(0).unwrap().take_labels();
+		for label in labels {
+			ctx.instructions.get_mut(added).unwrap().add_label(label);
+		}
+		ctx.instructions.resolve_labels()?;
+
+		ctx.remove_unused_constants();
+		Ok(())
+	}
+}
+
+fn string_constant(ctx: &IRContext, operand: IROperand<Value>) -> Option<String> {
+	match ctx.constants.get(operand.get_kst()?)?.get() {
+		Constants::String(string) => Some(string.clone()),
+		_ => None,
+	}
+}
+
+/// pcs of the instructions naming string constants the pass replaces,
+/// except the ones a skip may jump over
+fn sites(ctx: &IRContext) -> Vec<usize> {
+	(0..ctx.instructions.len())
+		.filter(|pc| {
+			let inst = ctx.instructions.get(*pc).unwrap();
+			let named = match inst.opcode() {
+				1 | 5 => vec![inst.get_bx()],
+				6 | 11 => vec![inst.get_c()],
+				9 => vec![inst.get_b(), inst.get_c()],
+				_ => vec![],
+			};
+			!inst.is_pseudo()
+				&& !follows_skip(ctx, *pc)
+				&& named
+					.into_iter()
+					.any(|operand| string_constant(ctx, operand).is_some())
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{EncryptStrings, Scheme, DECODER_SOURCE};
+	use crate::lua51::{
+		fixtures::{self, run, string},
+		passes::manager::PassManager,
+		vm::{Compat, Value},
+		IRContext,
+	};
+	use bytecode::lua51::{instructions::Opcode, Constants, Proto};
+
+	fn proto(nparams: u8, instructions: Vec<u32>, constants: &[&str]) -> Proto {
+		let constants = constants
+			.iter()
+			.map(|s| s.parse().map_or_else(|_| string(s), Constants::Number))
+			.collect();
+		Proto {
+			nparams,
+			source_lines: Some(vec![1; instructions.len()]),
+			..fixtures::proto(instructions, constants, vec![])
+		}
+	}
+
+	fn chunk() -> Proto {
+		// local function f(x) return x .. "!" end
+		let f = proto(
+			1,
+			vec![
+				Opcode::encode_ABC(0, 1, 0, 0),  // MOVE 1 0
+				Opcode::encode_ABx(1, 2, 0),     // LOADK 2 "!"
+				Opcode::encode_ABC(21, 1, 1, 2), // CONCAT 1 1 2
+				Opcode::encode_ABC(30, 1, 2, 0), // RETURN 1 2
+			],
+			&["!"],
+		);
+		// local t = {} t.key = "value"
+		// return string.rep("ab", 3), t.key, t.key:upper(), f("hi")
+		Proto {
+			prototypes: vec![f],
+			..proto(
+				0,
+				vec![
+					Opcode::encode_ABx(5, 0, 0),            // GETGLOBAL 0 "string"
+					Opcode::encode_ABC(6, 0, 0, 0x101),     // GETTABLE 0 0 "rep"
+					Opcode::encode_ABx(1, 1, 2),            // LOADK 1 "ab"
+					Opcode::encode_ABx(1, 2, 3),            // LOADK 2 3
+					Opcode::encode_ABC(28, 0, 3, 2),        // CALL 0 3 2
+					Opcode::encode_ABC(10, 1, 0, 0),        // NEWTABLE 1 0 0
+					Opcode::encode_ABC(9, 1, 0x104, 0x105), // SETTABLE 1 "key" "value"
+					Opcode::encode_ABC(6, 2, 1, 0x104),     // GETTABLE 2 1 "key"
+					Opcode::encode_ABC(11, 3, 2, 0x106),    // SELF 3 2 "upper"
+					Opcode::encode_ABC(28, 3, 2, 2),        // CALL 3 2 2
+					Opcode::encode_ABx(36, 4, 0),           // CLOSURE 4 0
+					Opcode::encode_ABx(1, 5, 7),            // LOADK 5 "hi"
+					Opcode::encode_ABC(28, 4, 2, 2),        // CALL 4 2 2
+					Opcode::encode_ABC(0, 1, 0, 0),         // MOVE 1 0
+					Opcode::encode_ABC(30, 1, 5, 0),        // RETURN 1 5
+				],
+				&["string", "rep", "ab", "3", "key", "value", "upper", "hi"],
+			)
+		}
+	}
+
+	/// String constants of every function but the decoders
+	fn strings(ctx: &IRContext) -> Vec<String> {
+		let mut strings = ctx
+			.constants
+			.get_all()
+			.iter()
+			.filter_map(|constant| match constant.get() {
+				Constants::String(s) => Some(s.clone()),
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+		for closure in &ctx.closures {
+			if closure.source() != DECODER_SOURCE {
+				strings.extend(self::strings(closure));
+			}
+		}
+		strings
+	}
+
+	#[test]
+	fn test_encrypt_strings() {
+		let expected = ["ababab", "value", "VALUE", "hi!"]
+			.map(Value::from)
+			.to_vec();
+		assert_eq!(
+			run(Compat::Lua51, &IRContext::from_proto(chunk())),
+			Ok(expected.clone())
+		);
+
+		let encrypt = |scheme: Scheme, seed: u64| {
+			let mut ctx = IRContext::from_proto(chunk());
+			let mut manager = PassManager::new().fixpoint(4);
+			manager.add(EncryptStrings::new(scheme, seed));
+			manager.run(&mut ctx).unwrap();
+			ctx
+		};
+		for scheme in [Scheme::Xor, Scheme::BaseN, Scheme::PerString] {
+			let ctx = encrypt(scheme, 7);
+			for plain in ["string", "rep", "ab", "key", "value", "upper", "hi", "!"] {
+				assert!(!strings(&ctx).iter().any(|s| s == plain), "{plain} left");
+			}
+			for compat in [Compat::Lua51, Compat::Rerubi] {
+				assert_eq!(run(compat, &ctx), Ok(expected.clone()), "{scheme:?}");
+			}
+
+			// the seed alone picks the keys
+			let proto = |ctx: IRContext| format!("{:?}", ctx.to_proto());
+			assert_eq!(proto(encrypt(scheme, 7)), proto(ctx));
+			assert_ne!(proto(encrypt(scheme, 7)), proto(encrypt(scheme, 8)));
+		}
+	}
+}
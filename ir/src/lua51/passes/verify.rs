@@ -0,0 +1,151 @@
+use super::dce::required_stack_size;
+use crate::lua51::{context::MAX_STACK, IRContext};
+use std::{error::Error, fmt::Display};
+
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+	/// the function does not end in RETURN
+	MissingReturn,
+	/// the jump at pc lands outside of the function
+	JumpOutOfBounds(usize),
+	/// the sBx of the jump at pc no longer points at its label
+	StaleOffset(usize),
+	/// the instruction at pc names a constant past the end of the pool
+	ConstantOutOfBounds(usize),
+	/// the CLOSURE at pc names a function that does not exist
+	ClosureOutOfBounds(usize),
+	/// pc is marked pseudo but is executed, or the other way around
+	PseudoMismatch(usize),
+	/// the instructions need more registers than max_stack_size
+	StackOverflow(usize),
+}
+
+impl Display for VerifyError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::MissingReturn => write!(f, "function does not end in RETURN"),
+			Self::JumpOutOfBounds(pc) => write!(f, "jump at pc {pc} lands outside of the function"),
+			Self::StaleOffset(pc) => write!(f, "jump at pc {pc} does not land on its label"),
+			Self::ConstantOutOfBounds(pc) => write!(f, "pc {pc} names a missing constant"),
+			Self::ClosureOutOfBounds(pc) => {
+				write!(f, "CLOSURE at pc {pc} names a missing function")
+			}
+			Self::PseudoMismatch(pc) => {
+				write!(f, "pc {pc} is wrongly marked as pseudo instruction")
+			}
+			Self::StackOverflow(size) => write!(f, "instructions need {size} registers"),
+		}
+	}
+}
+
+impl Error for VerifyError {}
+
+/**
+ * verify - Checks the invariants every pass must keep, roughly what
+ * luaG_checkcode enforces plus the consistency of labels and pseudo
+ * instructions. Closures are not verified
+ */
+pub fn verify(ctx: &IRContext) -> Result<(), VerifyError> {
+	let len = ctx.instructions.len();
+	match ctx.instructions.get(len.wrapping_sub(1)) {
+		Some(inst) if inst.is(30) && !inst.is_pseudo() => {}
+		_ => return Err(VerifyError::MissingReturn),
+	}
+
+	let pseudo = ctx.find_pseudo_instructions();
+	for (pc, inst) in ctx.instructions.iter().enumerate() {
+		if inst.is_pseudo() != pseudo[pc] {
+			return Err(VerifyError::PseudoMismatch(pc));
+		}
+		if inst.is_pseudo() {
+			continue;
+		}
+
+		if let Some(target) = inst.jump_target(pc) {
+			if target >= len {
+				return Err(VerifyError::JumpOutOfBounds(pc));
+			}
+			if let Some(label) = inst.target() {
+				if ctx.instructions.find_label(label) != Some(target) {
+					return Err(VerifyError::StaleOffset(pc));
+				}
+			}
+		}
+
+		let constants = [inst.get_bx(), inst.get_b(), inst.get_c()];
+		if constants
+			.iter()
+			.filter_map(|operand| operand.get_kst())
+			.any(|kst| kst >= ctx.constants.len())
+		{
+			return Err(VerifyError::ConstantOutOfBounds(pc));
+		}
+
+		if inst.is(36) {
+			let closure = inst.get_bx().get_reg().map(|bx| bx as usize);
+			if closure.is_none_or(|closure| closure >= ctx.closures.len()) {
+				return Err(VerifyError::ClosureOutOfBounds(pc));
+			}
+		}
+	}
+
+	let required = required_stack_size(ctx);
+	if required > ctx.max_stack_size() as usize || required > MAX_STACK {
+		return Err(VerifyError::StackOverflow(required));
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{verify, VerifyError};
+	use crate::lua51::fixtures::context;
+	use bytecode::lua51::{instructions::Opcode, Constants};
+
+	#[test]
+	fn test_verify() {
+		let ok = context(
+			vec![
+				Opcode::encode_ABx(1, 0, 0),     // LOADK 0 0
+				Opcode::encode_ABC(30, 0, 2, 0), // RETURN 0 2
+			],
+			vec![Constants::Nil],
+		);
+		assert_eq!(verify(&ok), Ok(()));
+
+		let cases = [
+			(
+				vec![Opcode::encode_ABx(1, 0, 0)],
+				VerifyError::MissingReturn,
+			),
+			(
+				vec![
+					Opcode::encode_AsBx(22, 0, 5),
+					Opcode::encode_ABC(30, 0, 1, 0),
+				],
+				VerifyError::JumpOutOfBounds(0),
+			),
+			(
+				vec![Opcode::encode_ABx(1, 0, 1), Opcode::encode_ABC(30, 0, 1, 0)],
+				VerifyError::ConstantOutOfBounds(0),
+			),
+			(
+				vec![
+					Opcode::encode_ABx(36, 0, 0),
+					Opcode::encode_ABC(30, 0, 1, 0),
+				],
+				VerifyError::ClosureOutOfBounds(0),
+			),
+			(
+				vec![Opcode::encode_ABx(1, 2, 0), Opcode::encode_ABC(30, 0, 1, 0)],
+				VerifyError::StackOverflow(3),
+			),
+		];
+		for (instructions, error) in cases {
+			let mut context = context(instructions, vec![Constants::Nil]);
+			context.set_max_stack_size(2);
+			assert_eq!(verify(&context), Err(error));
+		}
+	}
+}
@@ -0,0 +1,201 @@
+use super::{Phi, SSABlock, SSAFunction, SSAInstruction, SSAValue, ValueDef, ValueInfo};
+use crate::lua51::{
+	dataflow::{def_use, solve, Liveness},
+	Dominators, IRContext, CFG,
+};
+use std::collections::BTreeSet;
+
+struct Renamer {
+	values: Vec<ValueInfo>,
+	versions: Vec<usize>,
+	stacks: Vec<Vec<SSAValue>>,
+}
+
+impl Renamer {
+	fn define(&mut self, register: u8, def: ValueDef) -> SSAValue {
+		let value = SSAValue(self.values.len());
+		self.values.push(ValueInfo {
+			register,
+			version: self.versions[register as usize],
+			def,
+		});
+		self.versions[register as usize] += 1;
+		self.stacks[register as usize].push(value);
+		value
+	}
+
+	fn current(&self, register: u8) -> SSAValue {
+		*self.stacks[register as usize].last().unwrap()
+	}
+}
+
+pub fn construct(ctx: &IRContext) -> SSAFunction {
+	let cfg = CFG::from_instructions(&ctx.instructions);
+	let dominators = Dominators::new(&cfg);
+	let liveness = solve(&cfg, Liveness::new(ctx));
+	let table = def_use(ctx);
+	let pseudo = ctx.pseudo_instructions();
+
+	let registers = table
+		.iter()
+		.flat_map(|du| du.uses.iter().chain(du.defs.iter()))
+		.map(|reg| reg as usize + 1)
+		.max()
+		.unwrap_or(0)
+		.max(ctx.max_stack_size() as usize);
+
+	let mut blocks = (0..cfg.len())
+		.map(|block| SSABlock {
+			phis: vec![],
+			instructions: vec![],
+			reachable: dominators.is_reachable(block),
+			target: *cfg.get_block(block).unwrap().target(),
+			successors: cfg.successors(block),
+			predecessors: cfg.predecessors(block),
+		})
+		.collect::<Vec<_>>();
+
+	// place phis on the iterated dominance frontier of every definition,
+	// pruned to registers that are live into the frontier block
+	let mut def_blocks = vec![BTreeSet::new(); registers];
+	for (block, ssa_block) in blocks.iter().enumerate() {
+		if !ssa_block.reachable {
+			continue;
+		}
+		for pc in cfg.get_block(block).unwrap().range().clone() {
+			for reg in table[pc].defs.iter() {
+				def_blocks[reg as usize].insert(block);
+			}
+		}
+	}
+
+	let mut phi_registers = vec![BTreeSet::new(); cfg.len()];
+	for (register, defs) in def_blocks.iter().enumerate() {
+		let mut worklist = defs.iter().copied().collect::<Vec<_>>();
+		let mut placed = BTreeSet::new();
+		while let Some(block) = worklist.pop() {
+			for frontier in dominators.frontier(block) {
+				if placed.contains(frontier)
+					|| !liveness.block_entry(*frontier).contains(register as u8)
+				{
+					continue;
+				}
+				placed.insert(*frontier);
+				phi_registers[*frontier].insert(register as u8);
+				if !defs.contains(frontier) {
+					worklist.push(*frontier);
+				}
+			}
+		}
+	}
+
+	let mut renamer = Renamer {
+		values: vec![],
+		versions: vec![0; registers],
+		stacks: vec![vec![]; registers],
+	};
+	let entry = (0..registers)
+		.map(|reg| renamer.define(reg as u8, ValueDef::Entry))
+		.collect::<Vec<_>>();
+
+	for (block, registers) in phi_registers.iter().enumerate() {
+		blocks[block].phis = registers
+			.iter()
+			.map(|register| Phi {
+				register: *register,
+				dest: SSAValue(usize::MAX), // named while renaming
+				args: if block == 0 {
+					vec![(None, entry[*register as usize])]
+				} else {
+					vec![]
+				},
+			})
+			.collect();
+	}
+
+	// rename along the dominator tree, (block, exiting) events keep the walk iterative
+	let mut walk = vec![(0, false)];
+	let mut pushed: Vec<Vec<u8>> = vec![vec![]; cfg.len()];
+	while let Some((block, exiting)) = walk.pop() {
+		if cfg.is_empty() {
+			break;
+		}
+		if exiting {
+			for register in pushed[block].drain(..) {
+				renamer.stacks[register as usize].pop();
+			}
+			continue;
+		}
+
+		for phi in 0..blocks[block].phis.len() {
+			let register = blocks[block].phis[phi].register;
+			blocks[block].phis[phi].dest = renamer.define(register, ValueDef::Phi(block));
+			pushed[block].push(register);
+		}
+
+		for pc in cfg.get_block(block).unwrap().range().clone() {
+			let du = &table[pc];
+			let index = blocks[block].instructions.len();
+
+			// registers that may keep their old value are read as well
+			let mut uses = du.uses;
+			let mut may = du.defs;
+			may.difference(&du.kills);
+			uses.union(&may);
+
+			let uses = uses.iter().map(|reg| (reg, renamer.current(reg))).collect();
+			let defs = du
+				.defs
+				.iter()
+				.map(|reg| {
+					pushed[block].push(reg);
+					(
+						reg,
+						renamer.define(reg, ValueDef::Instruction(block, index)),
+					)
+				})
+				.collect();
+
+			blocks[block].instructions.push(SSAInstruction {
+				pc,
+				instruction: ctx.instructions.get(pc).unwrap().clone(),
+				uses,
+				defs,
+				pseudo: pseudo[pc],
+			});
+		}
+
+		for succ in blocks[block].successors.clone() {
+			for phi in &mut blocks[succ].phis {
+				phi.args.push((Some(block), renamer.current(phi.register)));
+			}
+		}
+
+		walk.push((block, true));
+		for child in dominators.children(block).iter().rev() {
+			walk.push((*child, false));
+		}
+	}
+
+	// unreachable code is kept verbatim, it never binds values
+	for (block, ssa_block) in blocks.iter_mut().enumerate() {
+		if ssa_block.reachable {
+			continue;
+		}
+		for pc in cfg.get_block(block).unwrap().range().clone() {
+			ssa_block.instructions.push(SSAInstruction {
+				pc,
+				instruction: ctx.instructions.get(pc).unwrap().clone(),
+				uses: vec![],
+				defs: vec![],
+				pseudo: pseudo[pc],
+			});
+		}
+	}
+
+	SSAFunction {
+		blocks,
+		values: renamer.values,
+		max_stack_size: ctx.max_stack_size(),
+	}
+}
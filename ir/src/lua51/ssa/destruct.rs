@@ -0,0 +1,529 @@
+use super::{SSAFunction, SSAInstruction, SSAValue};
+use crate::lua51::{
+	cfg::Target,
+	context::{EditError, IRInstruction, IRInstructions, Label},
+	IRContext,
+};
+use bytecode::lua51::instructions::Value;
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	error::Error,
+	fmt::Display,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum SSAError {
+	/// a value is still live when another write reuses its register
+	Interference(SSAValue, u8),
+	/// a copy cycle needs a scratch register and none is free below max_stack_size
+	NoScratchRegister(usize),
+	/// copies on the edge cannot be placed without breaking an implicit skip
+	UnsplittableEdge(usize, usize),
+	/// the distance of a jump does not fit into sBx
+	JumpOutOfRange(usize),
+}
+
+impl Display for SSAError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Interference(value, reg) => write!(
+				f,
+				"value {} is overwritten in register {} while still live",
+				value.0, reg
+			),
+			Self::NoScratchRegister(block) => {
+				write!(
+					f,
+					"no scratch register available for copies into block {block}"
+				)
+			}
+			Self::UnsplittableEdge(pred, succ) => {
+				write!(f, "cannot place copies on edge {pred} -> {succ}")
+			}
+			Self::JumpOutOfRange(pc) => write!(f, "jump at {pc} is out of sBx range"),
+		}
+	}
+}
+
+impl Error for SSAError {}
+
+type ParallelCopy = (u8, u8); // dest, src
+
+struct Emitted {
+	inst: IRInstruction,
+	target: Option<usize>, // block the instruction jumps to
+	pc: Option<usize>,     // instruction it was lowered from
+}
+
+#[derive(Default)]
+struct EmitBlock {
+	insts: Vec<Emitted>,
+	fallthrough: Option<usize>, // block that must follow, a JMP is added otherwise
+}
+
+fn plain(inst: IRInstruction) -> Emitted {
+	Emitted {
+		inst,
+		target: None,
+		pc: None,
+	}
+}
+
+fn mov(dest: u8, src: u8) -> Emitted {
+	plain(IRInstruction::new_abc(0, dest, src as u16, 0))
+}
+
+fn jmp(target: usize) -> Emitted {
+	Emitted {
+		inst: IRInstruction::new_asbx(22, 0, 0),
+		target: Some(target),
+		pc: None,
+	}
+}
+
+/// Operands that name a single register and can be renamed in place
+fn free_operands(inst: &IRInstruction) -> &'static [char] {
+	match inst.opcode() {
+		0 | 18 | 19 | 20 => &['b'],
+		6 | 11 | 12..=17 | 23..=25 => &['b', 'c'],
+		7 | 8 | 26 => &['a'],
+		9 => &['a', 'b', 'c'],
+		27 if inst.get_a().get_reg() != inst.get_b().get_reg() => &['b'],
+		_ => &[],
+	}
+}
+
+fn is_free(inst: &IRInstruction, reg: u8) -> bool {
+	free_operands(inst).iter().any(|operand| {
+		let value = match operand {
+			'a' => inst.get_a(),
+			'b' => inst.get_b(),
+			_ => inst.get_c(),
+		};
+		value.get_reg() == Some(reg)
+	})
+}
+
+fn rename(inst: &mut IRInstruction, from: u8, to: u8) {
+	for operand in free_operands(inst) {
+		let value = match operand {
+			'a' => inst.get_a(),
+			'b' => inst.get_b(),
+			_ => inst.get_c(),
+		};
+		let renamed = match value.value() {
+			Some(Value::Reg(reg)) if *reg == from => Value::Reg(to),
+			Some(Value::RK(rk)) if *rk == from as u32 => Value::RK(to as u32),
+			_ => continue,
+		};
+		match operand {
+			'a' => inst.set_a(renamed),
+			'b' => inst.set_b(renamed),
+			_ => inst.set_c(renamed),
+		}
+	}
+}
+
+/// Orders parallel copies (dest, src) so no source is overwritten before it is read
+fn sequentialize(mut copies: Vec<(u8, u8)>, scratch: Option<u8>) -> Option<Vec<Emitted>> {
+	copies.retain(|(dest, src)| dest != src);
+
+	let mut out = vec![];
+	while !copies.is_empty() {
+		let ready = copies
+			.iter()
+			.position(|(dest, _)| !copies.iter().any(|(_, src)| src == dest));
+		if let Some(ready) = ready {
+			let (dest, src) = copies.remove(ready);
+			out.push(mov(dest, src));
+			continue;
+		}
+
+		// only cycles are left, free one destination by saving it first
+		let tmp = scratch?;
+		let (dest, _) = copies[0];
+		out.push(mov(tmp, dest));
+		for (_, src) in &mut copies {
+			if *src == dest {
+				*src = tmp;
+			}
+		}
+	}
+
+	Some(out)
+}
+
+type LiveSets = Vec<BTreeSet<SSAValue>>;
+
+/// Values live into (excluding phi results) and out of every block
+fn liveness(func: &SSAFunction) -> (LiveSets, LiveSets) {
+	let len = func.blocks.len();
+	let mut live_in = vec![BTreeSet::new(); len];
+	let mut live_out = vec![BTreeSet::new(); len];
+
+	let mut changed = true;
+	while changed {
+		changed = false;
+		for (i, block) in func.blocks.iter().enumerate().rev() {
+			if !block.reachable {
+				continue;
+			}
+
+			let mut out = BTreeSet::new();
+			for succ in block.successors() {
+				out.extend(live_in[*succ].iter().copied());
+				for phi in &func.blocks[*succ].phis {
+					for (pred, arg) in &phi.args {
+						if *pred == Some(i) {
+							out.insert(*arg);
+						}
+					}
+				}
+			}
+
+			let mut live = out.clone();
+			for inst in block.instructions.iter().rev() {
+				for (_, def) in &inst.defs {
+					live.remove(def);
+				}
+				for (_, used) in &inst.uses {
+					live.insert(*used);
+				}
+			}
+			for phi in &block.phis {
+				live.remove(&phi.dest);
+			}
+
+			if out != live_out[i] || live != live_in[i] {
+				live_out[i] = out;
+				live_in[i] = live;
+				changed = true;
+			}
+		}
+	}
+
+	(live_in, live_out)
+}
+
+fn check_interference(func: &SSAFunction, live_out: &LiveSets) -> Result<(), SSAError> {
+	let loc = |value: &SSAValue| func.value(*value).register;
+
+	for (i, block) in func.blocks.iter().enumerate() {
+		if !block.reachable {
+			continue;
+		}
+
+		let mut live = live_out[i].clone();
+		for inst in block.instructions.iter().rev() {
+			let defined = inst.defs.iter().map(|(_, def)| *def).collect::<Vec<_>>();
+			for (reg, _) in &inst.defs {
+				if let Some(value) = live
+					.iter()
+					.find(|value| !defined.contains(value) && loc(value) == *reg)
+				{
+					return Err(SSAError::Interference(*value, *reg));
+				}
+			}
+			live.retain(|value| !defined.contains(value));
+			live.extend(inst.uses.iter().map(|(_, used)| *used));
+
+			// a use bound to another register is copied into place right before
+			for (reg, used) in &inst.uses {
+				if loc(used) == *reg || is_free(&inst.instruction, *reg) {
+					continue;
+				}
+				if let Some(value) = live.iter().find(|value| loc(value) == *reg) {
+					return Err(SSAError::Interference(*value, *reg));
+				}
+			}
+		}
+
+		for phi in &block.phis {
+			if let Some(value) = live
+				.iter()
+				.find(|value| **value != phi.dest && loc(value) == phi.register)
+			{
+				return Err(SSAError::Interference(*value, phi.register));
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Lowers one instruction, renaming or copying uses bound to other registers
+fn lower_instruction(func: &SSAFunction, inst: &SSAInstruction) -> (Vec<(u8, u8)>, IRInstruction) {
+	let mut lowered = inst.instruction.clone();
+	lowered.take_labels();
+	lowered.set_target(None);
+	let mut copies = vec![];
+	for (reg, used) in &inst.uses {
+		let loc = func.value(*used).register;
+		if loc == *reg {
+			continue;
+		}
+		if is_free(&inst.instruction, *reg) {
+			rename(&mut lowered, *reg, loc);
+		} else {
+			copies.push((*reg, loc));
+		}
+	}
+	(copies, lowered)
+}
+
+pub fn destruct(func: &SSAFunction, ctx: &mut IRContext) -> Result<(), SSAError> {
+	let (live_in, live_out) = liveness(func);
+	check_interference(func, &live_out)?;
+
+	let len = func.blocks.len();
+	let max_stack_size = func.max_stack_size();
+	let mut emits = (0..len).map(|_| EmitBlock::default()).collect::<Vec<_>>();
+
+	for (i, block) in func.blocks.iter().enumerate() {
+		let mut owner = 0; // where copies for pseudo instructions are placed
+		for inst in &block.instructions {
+			let (copies, lowered) = lower_instruction(func, inst);
+			let at = if inst.pseudo {
+				owner
+			} else {
+				emits[i].insts.len()
+			};
+			// copies into operand ranges only reuse registers that are free there
+			let copies = sequentialize(copies, None).ok_or(SSAError::NoScratchRegister(i))?;
+			let copied = copies.len();
+			emits[i].insts.splice(at..at, copies);
+			if inst.pseudo {
+				owner += copied;
+			} else {
+				owner = at + copied;
+			}
+
+			let target = match (lowered.opcode(), block.target()) {
+				(22, Target::Jmp(target))
+				| (32, Target::ForPrep(target))
+				| (31, Target::ForLoop(target, _)) => Some(*target),
+				_ => None,
+			};
+			emits[i].insts.push(Emitted {
+				inst: lowered,
+				target,
+				pc: Some(inst.pc),
+			});
+		}
+
+		emits[i].fallthrough = match block.target() {
+			Target::NOP if i + 1 < len => Some(i + 1),
+			Target::ForLoop(_, fall) => Some(*fall),
+			_ => None,
+		};
+	}
+
+	// gather phi arguments that do not already live in the phi register
+	let mut edges: BTreeMap<(Option<usize>, usize), Vec<ParallelCopy>> = BTreeMap::new();
+	for (i, block) in func.blocks.iter().enumerate() {
+		for phi in &block.phis {
+			for (pred, arg) in &phi.args {
+				let loc = func.value(*arg).register;
+				if loc != phi.register {
+					edges
+						.entry((*pred, i))
+						.or_default()
+						.push((phi.register, loc));
+				}
+			}
+		}
+	}
+
+	let mut prologue = None;
+	let mut attached: Vec<Vec<usize>> = vec![vec![]; len];
+	let mut trailing = vec![];
+	for ((pred, succ), copies) in edges {
+		let loc = |value: &SSAValue| func.value(*value).register;
+		let phi_registers = func.blocks[succ]
+			.phis
+			.iter()
+			.map(|phi| phi.register)
+			.collect::<BTreeSet<_>>();
+
+		let mut busy = live_in[succ].iter().map(loc).collect::<BTreeSet<_>>();
+		for (dest, _) in &copies {
+			if let Some(value) = live_in[succ].iter().find(|value| loc(value) == *dest) {
+				return Err(SSAError::Interference(*value, *dest));
+			}
+		}
+		busy.extend(phi_registers);
+		busy.extend(copies.iter().flat_map(|(dest, src)| [*dest, *src]));
+		let scratch = (0..max_stack_size).find(|reg| !busy.contains(reg));
+		let insts =
+			sequentialize(copies.clone(), scratch).ok_or(SSAError::NoScratchRegister(succ))?;
+
+		let pred = match pred {
+			Some(pred) => pred,
+			None => {
+				// copies on the edge from the caller run before the first block
+				prologue = Some(emits.len());
+				emits.push(EmitBlock {
+					insts,
+					fallthrough: Some(0),
+				});
+				continue;
+			}
+		};
+
+		let reachable_preds = func.blocks[succ]
+			.predecessors()
+			.iter()
+			.filter(|pred| func.blocks[**pred].reachable)
+			.count();
+		if reachable_preds == 1 && succ != 0 {
+			emits[succ].insts.splice(0..0, insts);
+			continue;
+		}
+
+		if func.blocks[pred].successors().len() == 1 {
+			let block = &mut emits[pred];
+			let last = block.insts.last().map(|emitted| &emitted.inst);
+			let touched = match last {
+				Some(inst) if inst.is(32) => {
+					let a = inst.get_a().get_reg().unwrap();
+					(a..=a.saturating_add(3)).collect::<Vec<_>>()
+				}
+				Some(inst) if inst.is(2) => vec![inst.get_a().get_reg().unwrap()],
+				_ => vec![],
+			};
+			if copies
+				.iter()
+				.any(|(dest, src)| touched.contains(dest) || touched.contains(src))
+			{
+				return Err(SSAError::UnsplittableEdge(pred, succ));
+			}
+
+			let at = match last {
+				Some(inst) if inst.is(22) || inst.is(32) || inst.is(2) => block.insts.len() - 1,
+				_ => block.insts.len(),
+			};
+			let skip = pred
+				.checked_sub(1)
+				.and_then(|prev| emits[prev].insts.last())
+				.is_some_and(|emitted| emitted.inst.is_skip());
+			if at != 0 || !skip {
+				emits[pred].insts.splice(at..at, insts);
+				continue;
+			}
+
+			// the skip before would land on the copies, the jump goes through a block of its own
+			if !emits[pred]
+				.insts
+				.first()
+				.is_some_and(|emitted| emitted.inst.is(22))
+			{
+				return Err(SSAError::UnsplittableEdge(pred, succ));
+			}
+			let split = emits.len();
+			emits[pred].insts[at].target = Some(split);
+			emits.push(EmitBlock {
+				insts,
+				fallthrough: Some(succ),
+			});
+			trailing.push(split);
+			continue;
+		}
+
+		// the edge is critical, copies get a block of their own
+		let split = emits.len();
+		emits.push(EmitBlock {
+			insts,
+			fallthrough: Some(succ),
+		});
+		match *func.blocks[pred].target() {
+			Target::BinCond(fall, skip) if succ == skip && fall != skip => {
+				attached[fall].push(split)
+			}
+			Target::TForLoop(skip) if succ == skip => attached[pred + 1].push(split),
+			Target::ForLoop(back, fall) if succ == fall && back != fall => {
+				emits[pred].fallthrough = Some(split);
+				attached[pred].push(split);
+			}
+			Target::ForLoop(back, _) if succ == back => {
+				for emitted in &mut emits[pred].insts {
+					if emitted.target == Some(back) {
+						emitted.target = Some(split);
+					}
+				}
+				trailing.push(split);
+			}
+			_ => return Err(SSAError::UnsplittableEdge(pred, succ)),
+		}
+	}
+
+	let mut order = vec![];
+	order.extend(prologue);
+	for (block, attached) in attached.iter().enumerate() {
+		order.push(block);
+		order.extend(attached.iter().copied());
+	}
+	order.extend(trailing);
+
+	for (i, block) in order.iter().enumerate() {
+		if let Some(fallthrough) = emits[*block].fallthrough {
+			if order.get(i + 1) != Some(&fallthrough) {
+				emits[*block].insts.push(jmp(fallthrough));
+			}
+		}
+	}
+
+	// every block is labelled by its index, empty blocks share the label of the next one
+	let mut insts = vec![];
+	let mut pending = vec![];
+	let mut moved = vec![None; ctx.instructions.len()];
+	for block in &order {
+		pending.push(Label(*block));
+		for emitted in emits[*block].insts.drain(..) {
+			if let Some(pc) = emitted.pc {
+				moved[pc] = Some(insts.len());
+			}
+			let mut inst = emitted.inst;
+			for label in pending.drain(..) {
+				inst.add_label(label);
+			}
+			inst.set_target(emitted.target.map(Label));
+			insts.push(inst);
+		}
+	}
+
+	let mut instructions = IRInstructions::from_ir(insts);
+	instructions.resolve_labels().map_err(|err| match err {
+		EditError::JumpOutOfRange(pc) => SSAError::JumpOutOfRange(pc),
+		err => unreachable!("{err}"),
+	})?;
+	instructions.set_moved_locals(ctx.locals(), &moved);
+	ctx.instructions = instructions;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::sequentialize;
+
+	fn run(copies: Vec<(u8, u8)>, scratch: Option<u8>) -> Option<[u8; 4]> {
+		let mut regs = [0, 1, 2, 3];
+		for emitted in sequentialize(copies, scratch)? {
+			let dest = emitted.inst.get_a().get_reg().unwrap();
+			let src = emitted.inst.get_b().get_reg().unwrap();
+			regs[dest as usize] = regs[src as usize];
+		}
+		Some(regs)
+	}
+
+	#[test]
+	fn test_sequentialize() {
+		// chains are ordered so sources are read first
+		assert_eq!(run(vec![(1, 0), (2, 1)], None), Some([0, 0, 1, 3]));
+		// a swap needs a scratch register
+		assert_eq!(run(vec![(0, 1), (1, 0)], None), None);
+		assert_eq!(
+			run(vec![(0, 1), (1, 0)], Some(3)).map(|r| [r[0], r[1]]),
+			Some([1, 0])
+		);
+	}
+}
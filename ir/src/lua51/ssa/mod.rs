@@ -0,0 +1,351 @@
+use super::{cfg::Target, context::IRInstruction, IRContext};
+use std::fmt::Display;
+
+mod construct;
+mod destruct;
+
+pub use destruct::SSAError;
+
+/// Index of a value in `SSAFunction::values`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SSAValue(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueDef {
+	Entry,                     // parameter or the initial nil of a register
+	Phi(usize),                // phi in block
+	Instruction(usize, usize), // block, index in block
+}
+
+#[derive(Debug, Clone)]
+pub struct ValueInfo {
+	pub register: u8,
+	pub version: usize,
+	pub def: ValueDef,
+}
+
+/**
+ * Phi - Merges the versions of `register` flowing in from each predecessor
+ * A predecessor of None is the implicit edge from the caller into block 0
+ */
+#[derive(Debug, Clone)]
+pub struct Phi {
+	pub register: u8,
+	pub dest: SSAValue,
+	pub args: Vec<(Option<usize>, SSAValue)>,
+}
+
+/**
+ * SSAInstruction - An instruction with its register operands bound to values
+ * `uses` and `defs` are keyed by the register the instruction encodes, a
+ * use may be bound to a value living in another register, which is
+ * repaired when lowering
+ */
+#[derive(Clone)]
+pub struct SSAInstruction {
+	pub pc: usize,
+	pub instruction: IRInstruction,
+	pub uses: Vec<(u8, SSAValue)>,
+	pub defs: Vec<(u8, SSAValue)>,
+	pub pseudo: bool, // operand of the previous CLOSURE or SETLIST
+}
+
+pub struct SSABlock {
+	pub phis: Vec<Phi>,
+	pub instructions: Vec<SSAInstruction>,
+	pub reachable: bool,
+	target: Target,
+	successors: Vec<usize>,
+	predecessors: Vec<usize>,
+}
+
+impl SSABlock {
+	pub fn successors(&self) -> &[usize] {
+		&self.successors
+	}
+
+	pub fn predecessors(&self) -> &[usize] {
+		&self.predecessors
+	}
+
+	pub fn target(&self) -> &Target {
+		&self.target
+	}
+}
+
+/**
+ * SSAFunction - Static single assignment view of one IRContext
+ * Every register write, including multret results and for-loop control
+ * registers, creates a new version. Values always live in the register
+ * they were defined in, so unmodified SSA lowers back to the same code
+ */
+pub struct SSAFunction {
+	pub blocks: Vec<SSABlock>,
+	values: Vec<ValueInfo>,
+	max_stack_size: u8,
+}
+
+impl SSAFunction {
+	pub fn from_context(ctx: &IRContext) -> Self {
+		construct::construct(ctx)
+	}
+
+	pub fn value(&self, value: SSAValue) -> &ValueInfo {
+		&self.values[value.0]
+	}
+
+	pub fn values(&self) -> &[ValueInfo] {
+		&self.values
+	}
+
+	pub fn max_stack_size(&self) -> u8 {
+		self.max_stack_size
+	}
+
+	/// Rebinds every use of `from`, including phi arguments, to `to`
+	pub fn replace_uses(&mut self, from: SSAValue, to: SSAValue) {
+		for block in &mut self.blocks {
+			for phi in &mut block.phis {
+				for (_, arg) in &mut phi.args {
+					if *arg == from {
+						*arg = to;
+					}
+				}
+			}
+			for inst in &mut block.instructions {
+				for (_, value) in &mut inst.uses {
+					if *value == from {
+						*value = to;
+					}
+				}
+			}
+		}
+	}
+
+	/// Number of instruction operands and phi arguments reading `value`
+	pub fn use_count(&self, value: SSAValue) -> usize {
+		self.blocks
+			.iter()
+			.map(|block| {
+				block
+					.phis
+					.iter()
+					.flat_map(|phi| phi.args.iter())
+					.filter(|(_, arg)| *arg == value)
+					.count() + block
+					.instructions
+					.iter()
+					.flat_map(|inst| inst.uses.iter())
+					.filter(|(_, used)| *used == value)
+					.count()
+			})
+			.sum()
+	}
+
+	/// Removes an instruction, its definitions must no longer be used
+	pub fn remove_instruction(&mut self, block: usize, index: usize) -> SSAInstruction {
+		let inst = self.blocks[block].instructions.remove(index);
+		for (i, inst) in self.blocks[block]
+			.instructions
+			.iter()
+			.enumerate()
+			.skip(index)
+		{
+			for (_, def) in &inst.defs {
+				self.values[def.0].def = ValueDef::Instruction(block, i);
+			}
+		}
+		inst
+	}
+
+	/// Replaces the instructions of `ctx` with the lowered form of this function
+	pub fn lower(&self, ctx: &mut IRContext) -> Result<(), SSAError> {
+		destruct::destruct(self, ctx)
+	}
+
+	fn fmt_value(&self, value: SSAValue) -> String {
+		let info = &self.values[value.0];
+		format!("r{}_{}", info.register, info.version)
+	}
+}
+
+impl Display for SSAFunction {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		for (i, block) in self.blocks.iter().enumerate() {
+			writeln!(f, "Block {} -> {:?}", i, block.successors)?;
+			for phi in &block.phis {
+				let args = phi
+					.args
+					.iter()
+					.map(|(pred, arg)| match pred {
+						Some(pred) => format!("{}: {}", pred, self.fmt_value(*arg)),
+						None => format!("entry: {}", self.fmt_value(*arg)),
+					})
+					.collect::<Vec<_>>();
+				writeln!(
+					f,
+					"\t\t{} = phi({})",
+					self.fmt_value(phi.dest),
+					args.join(", ")
+				)?;
+			}
+			for inst in &block.instructions {
+				let defs = inst
+					.defs
+					.iter()
+					.map(|(_, def)| self.fmt_value(*def))
+					.collect::<Vec<_>>();
+				let uses = inst
+					.uses
+					.iter()
+					.map(|(_, used)| self.fmt_value(*used))
+					.collect::<Vec<_>>();
+				writeln!(
+					f,
+					"\t{}\t{}\t[{}] <- [{}]",
+					inst.pc,
+					inst.instruction,
+					defs.join(", "),
+					uses.join(", ")
+				)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{SSAError, SSAFunction, ValueDef};
+	use crate::lua51::{
+		fixtures::{context, listing, numbers},
+		IRContext,
+	};
+	use bytecode::lua51::{instructions::Opcode, Local};
+
+	fn locals(scopes: &[(&str, u64, u64)]) -> Vec<Local> {
+		scopes
+			.iter()
+			.map(|(name, start_pc, end_pc)| Local {
+				name: name.to_string(),
+				start_pc: *start_pc,
+				end_pc: *end_pc,
+			})
+			.collect()
+	}
+
+	fn scopes(context: &IRContext) -> Vec<(&str, u64, u64)> {
+		context
+			.locals()
+			.iter()
+			.map(|local| (local.name.as_str(), local.start_pc, local.end_pc))
+			.collect()
+	}
+
+	#[test]
+	fn test_round_trip() {
+		// local s = 0; for i = 1, 3 do s = s + i end; return s
+		let mut context = context(
+			vec![
+				Opcode::encode_ABx(1, 0, 0),
+				Opcode::encode_ABx(1, 1, 1),
+				Opcode::encode_ABx(1, 2, 2),
+				Opcode::encode_ABx(1, 3, 1),
+				Opcode::encode_AsBx(32, 1, 1),
+				Opcode::encode_ABC(12, 0, 0, 4),
+				Opcode::encode_AsBx(31, 1, -2),
+				Opcode::encode_ABC(30, 0, 2, 0),
+				Opcode::encode_ABC(30, 0, 1, 0),
+			],
+			numbers(&[0.0, 1.0, 3.0]),
+		);
+		let loop_scopes = [
+			("s", 1, 9),
+			("(for index)", 4, 7),
+			("(for limit)", 4, 7),
+			("(for step)", 4, 7),
+			("i", 5, 6),
+		];
+		context.instructions.set_locals(locals(&loop_scopes));
+		let original = listing(&context);
+
+		let ssa = SSAFunction::from_context(&context);
+		let loop_phis = ssa.blocks[2]
+			.phis
+			.iter()
+			.map(|phi| phi.register)
+			.collect::<Vec<_>>();
+		assert_eq!(loop_phis, vec![0, 1, 4]);
+		assert!(ssa.blocks[1].phis.is_empty());
+
+		// the ADD reads the accumulator through the loop phi
+		let add = &ssa.blocks[1].instructions[0];
+		assert_eq!(ssa.value(add.uses[0].1).def, ValueDef::Phi(2));
+
+		ssa.lower(&mut context).unwrap();
+		assert_eq!(listing(&context), original);
+		assert_eq!(scopes(&context), loop_scopes);
+	}
+
+	#[test]
+	fn test_phi_copies() {
+		// local r = a; if a ~= b then r = b end; return r
+		let mut context = context(
+			vec![
+				Opcode::encode_ABC(0, 2, 0, 0),
+				Opcode::encode_ABC(23, 0, 0, 1),
+				Opcode::encode_AsBx(22, 0, 1),
+				Opcode::encode_ABC(0, 2, 1, 0),
+				Opcode::encode_ABC(30, 2, 2, 0),
+			],
+			vec![],
+		);
+		context
+			.instructions
+			.set_locals(locals(&[("a", 0, 5), ("b", 0, 5), ("r", 1, 5)]));
+		let mut ssa = SSAFunction::from_context(&context);
+
+		// propagate `r = a` into the phi, the copy moves onto the edge
+		let moved = ssa.blocks[0].instructions[0].defs[0].1;
+		let a = ssa.blocks[0].instructions[0].uses[0].1;
+		ssa.replace_uses(moved, a);
+		ssa.remove_instruction(0, 0);
+		assert_eq!(ssa.use_count(moved), 0);
+
+		// the JMP follows the EQ, so its copy gets a block of its own
+		ssa.lower(&mut context).unwrap();
+		assert_eq!(
+			listing(&context),
+			vec![
+				"EQ        \t0 0 1",
+				"JMP       \t2",
+				"MOVE      \t2 1",
+				"RETURN    \t2 2",
+				"MOVE      \t2 0",
+				"JMP       \t-3",
+			]
+		);
+		// `r` starts where the removed MOVE was, the copies are in every scope
+		assert_eq!(scopes(&context), [("a", 0, 6), ("b", 0, 6), ("r", 0, 6)]);
+	}
+
+	#[test]
+	fn test_interference() {
+		// local b = a; a = 1; return b + b
+		let mut context = context(
+			vec![
+				Opcode::encode_ABC(0, 1, 0, 0),
+				Opcode::encode_ABx(1, 0, 1),
+				Opcode::encode_ABC(12, 2, 1, 1),
+				Opcode::encode_ABC(30, 2, 2, 0),
+			],
+			numbers(&[0.0, 1.0]),
+		);
+		let mut ssa = SSAFunction::from_context(&context);
+		let b = ssa.blocks[0].instructions[0].defs[0].1;
+		let a = ssa.blocks[0].instructions[0].uses[0].1;
+		ssa.replace_uses(b, a);
+
+		assert_eq!(ssa.lower(&mut context), Err(SSAError::Interference(a, 0)));
+	}
+}
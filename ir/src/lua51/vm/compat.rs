@@ -0,0 +1,242 @@
+use super::{
+	meta::{first, is_concatenable},
+	proto::Prototype,
+	value::Value,
+	LuaError, Result, Vm,
+};
+
+/**
+ * Compat - The VM whose behaviour the interpreter reproduces, so chunks can
+ * be checked against where they are deployed before shipping
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compat {
+	/// the reference Lua 5.1 VM
+	#[default]
+	Lua51,
+	/// examples/rerubi.lua running on the reference VM, where
+	/// - RK operands naming a `false` constant read nil
+	/// - `...` gives as many values as the function got arguments, padded
+	///   with nils, and no `arg` table is made
+	/// - calls of Lua functions nest like those of natives, so coroutines can
+	///   not yield in them and some 200 of them overflow the C stack
+	/// - tail calls are plain calls whose arguments lose their nils, the
+	///   rest moving down, and whose results lose the trailing ones
+	/// - concatenation goes pairwise from the left
+	/// - errors leaving a function become strings "Name:Line: message", Name
+	///   being its source with the NUL rerubi keeps or "Code" if it has none
+	/// - messages name operands as they are in rerubi's code, not the chunk's,
+	///   and numeric for loops quote `for` with backticks
+	///
+	/// Not reproduced are the positions inside rerubi.lua the reference VM
+	/// starts its messages with, and B = 0 operands reaching up to the
+	/// highest register written since the last CALL or VARARG rather than
+	/// to what those left, which compiled code does not tell apart
+	Rerubi,
+}
+
+impl Vm {
+	pub fn set_compat(&mut self, compat: Compat) {
+		self.compat = compat;
+	}
+
+	pub fn compat(&self) -> Compat {
+		self.compat
+	}
+
+	/// `err` leaving `proto` with `pc` next, as the function rerubi wraps it
+	/// in rethrows it. Unchanged in other modes and for uncatchable errors
+	pub(super) fn rethrow(&mut self, err: LuaError, proto: &Prototype, pc: usize) -> LuaError {
+		let LuaError::Runtime(value) = err else {
+			return err;
+		};
+		if self.compat != Compat::Rerubi {
+			return LuaError::Runtime(value);
+		}
+		let name = match proto.inherited || proto.source.is_empty() {
+			true => "Code".to_string(),
+			false => format!("{}\0", proto.source),
+		};
+		let line = proto
+			.line(pc.saturating_sub(1))
+			.map_or("?".to_string(), |line| line.to_string());
+		let msg = match self.tostring(&value) {
+			Ok(msg) => msg,
+			Err(err) => return err,
+		};
+		let Some(msg) = msg.to_bytes() else {
+			let kind = msg.type_name();
+			return self.error(format!(
+				"bad argument #3 to 'format' (string expected, got {kind})"
+			));
+		};
+		let mut bytes = format!("{name}:{line}: ").into_bytes();
+		bytes.extend_from_slice(&msg);
+		LuaError::Runtime(Value::string(bytes))
+	}
+
+	/// What rerubi's own code holds operand `rk` of the running instruction
+	/// in, as error messages name it
+	pub(super) fn rerubi_name(&self, rk: usize) -> Option<String> {
+		let frame = self.frames.last()?;
+		let inst = frame.closure.proto.code.get(frame.pc.checked_sub(1)?)?;
+		Some(match inst.op {
+			11 => "local 'B'".to_string(),
+			21 if rk == inst.b => "local 'K'".to_string(),
+			_ => "field '?'".to_string(),
+		})
+	}
+
+	/// Concatenation of `values` from the registers from `first_reg` on,
+	/// pairwise from the left like rerubi's CONCAT
+	pub(super) fn concat_left(&mut self, values: Vec<Value>, first_reg: usize) -> Result<Value> {
+		let mut values = values.into_iter();
+		let mut joined = values.next().unwrap_or_default();
+		for (reg, value) in (first_reg + 1..).zip(values) {
+			if is_concatenable(&joined) && is_concatenable(&value) {
+				let (a, b) = (joined.to_bytes().unwrap(), value.to_bytes().unwrap());
+				joined = Value::string([a, b].concat());
+				continue;
+			}
+			let mut handler = self.metamethod(&joined, "__concat");
+			if handler.is_nil() {
+				handler = self.metamethod(&value, "__concat");
+			}
+			if handler.is_nil() {
+				return Err(match is_concatenable(&joined) {
+					true => self.type_error("concatenate", &value, Some(reg)),
+					false => self.type_error("concatenate", &joined, Some(first_reg)),
+				});
+			}
+			joined = first(self.call(&handler, vec![joined, value])?);
+		}
+		Ok(joined)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Compat;
+	use crate::lua51::{
+		fixtures::{self, run},
+		vm::Value,
+		IRContext,
+	};
+	use bytecode::lua51::{instructions::Opcode, Constants, Proto};
+
+	fn proto(instructions: Vec<u32>, constants: Vec<Constants>, protos: Vec<Proto>) -> Proto {
+		Proto {
+			source_lines: Some(vec![1; instructions.len()]),
+			..fixtures::proto(instructions, constants, protos)
+		}
+	}
+
+	#[test]
+	fn test_rerubi() {
+		let chunk = || {
+			// local function count(a, ...) return select('#', ...) end
+			let count = Proto {
+				nparams: 1,
+				is_vararg_flag: 2,
+				..proto(
+					vec![
+						Opcode::encode_ABx(5, 1, 0),     // GETGLOBAL 1 select
+						Opcode::encode_ABx(1, 2, 1),     // LOADK 2 "#"
+						Opcode::encode_ABC(37, 3, 0, 0), // VARARG 3 0
+						Opcode::encode_ABC(28, 1, 0, 0), // CALL 1 0 0
+						Opcode::encode_ABC(30, 1, 0, 0), // RETURN 1 0
+					],
+					vec![
+						Constants::String("select".to_string()),
+						Constants::String("#".to_string()),
+					],
+					vec![],
+				)
+			};
+			// local function pass(...) return ... end
+			let pass = Proto {
+				is_vararg_flag: 2,
+				..proto(
+					vec![
+						Opcode::encode_ABC(37, 0, 0, 0), // VARARG 0 0
+						Opcode::encode_ABC(30, 0, 0, 0), // RETURN 0 0
+					],
+					vec![],
+					vec![],
+				)
+			};
+			// local n = count(1, 1, 1) local x = nil
+			// return pass(n, nil, x == false)
+			proto(
+				vec![
+					Opcode::encode_ABx(36, 0, 0),          // CLOSURE 0 0
+					Opcode::encode_ABx(1, 1, 0),           // LOADK 1 1
+					Opcode::encode_ABx(1, 2, 0),           // LOADK 2 1
+					Opcode::encode_ABx(1, 3, 0),           // LOADK 3 1
+					Opcode::encode_ABC(28, 0, 4, 2),       // CALL 0 4 2
+					Opcode::encode_ABC(3, 1, 1, 0),        // LOADNIL 1 1
+					Opcode::encode_ABC(23, 1, 1, 256 + 1), // EQ 1 1 false
+					Opcode::encode_AsBx(22, 0, 1),         // JMP 1
+					Opcode::encode_ABC(2, 1, 0, 1),        // LOADBOOL 1 0 1
+					Opcode::encode_ABC(2, 1, 1, 0),        // LOADBOOL 1 1 0
+					Opcode::encode_ABx(36, 2, 1),          // CLOSURE 2 1
+					Opcode::encode_ABC(0, 3, 0, 0),        // MOVE 3 0
+					Opcode::encode_ABC(3, 4, 4, 0),        // LOADNIL 4 4
+					Opcode::encode_ABC(0, 5, 1, 0),        // MOVE 5 1
+					Opcode::encode_ABC(29, 2, 4, 0),       // TAILCALL 2 4 0
+					Opcode::encode_ABC(30, 2, 0, 0),       // RETURN 2 0
+				],
+				vec![Constants::Number(1.0), Constants::Boolean(false)],
+				vec![count, pass],
+			)
+		};
+		assert_eq!(
+			run(Compat::Lua51, &IRContext::from_proto(chunk())),
+			Ok(vec![Value::from(2.0), Value::Nil, Value::Boolean(false)])
+		);
+		assert_eq!(
+			run(Compat::Rerubi, &IRContext::from_proto(chunk())),
+			Ok(vec![Value::from(3.0), Value::Boolean(true)])
+		);
+
+		// (function() return nil + 1 end)()
+		let chunk = || {
+			let add = Proto {
+				source_lines: Some(vec![7; 3]),
+				..proto(
+					vec![
+						Opcode::encode_ABC(3, 0, 0, 0),    // LOADNIL 0 0
+						Opcode::encode_ABC(12, 0, 0, 256), // ADD 0 0 1
+						Opcode::encode_ABC(30, 0, 2, 0),   // RETURN 0 2
+					],
+					vec![Constants::Number(1.0)],
+					vec![],
+				)
+			};
+			Proto {
+				source: "@t.lua".to_string(),
+				source_lines: Some(vec![1, 2, 3]),
+				..proto(
+					vec![
+						Opcode::encode_ABx(36, 0, 0),    // CLOSURE 0 0
+						Opcode::encode_ABC(28, 0, 1, 1), // CALL 0 1 1
+						Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+					],
+					vec![],
+					vec![add],
+				)
+			}
+		};
+		assert_eq!(
+			run(Compat::Lua51, &IRContext::from_proto(chunk())),
+			Err("t.lua:7: attempt to perform arithmetic on a nil value".to_string())
+		);
+		assert_eq!(
+			run(Compat::Rerubi, &IRContext::from_proto(chunk())),
+			Err(
+				"@t.lua\0:2: Code:7: attempt to perform arithmetic on field '?' (a nil value)"
+					.to_string()
+			)
+		);
+	}
+}
@@ -0,0 +1,259 @@
+use super::{Action, Breakpoint, Debugger, Reason, Stop, Vm};
+use crate::lua51::get_opcode_name;
+use std::io::{BufRead, BufReader, Stdin, Stdout, Write};
+
+const HELP: &str = "\
+continue, c           run to the next breakpoint or watch
+step, s               run one instruction
+next, n               run one instruction, stepping over calls
+finish, out           run until this function returns
+break, b LINE         stop at a source line
+break, b [FN]@PC      stop at a pc of main.0.1 style FN, the frame's if left out
+delete N              remove breakpoint N
+watch REG             stop when register REG of the frame's function changes
+unwatch N             remove watch N
+info                  list breakpoints and watches
+where, bt             show the call stack
+frame N               select the frame N calls up the stack
+regs, r               show registers of the selected frame
+upvals, u             show upvalues of the selected frame
+consts, k             show constants of the selected frame
+quit, q               abort the run
+";
+
+/// `path` as the console writes functions, "main" and its closures below
+fn function_name(path: &[usize]) -> String {
+	path.iter()
+		.fold("main".to_string(), |name, idx| format!("{name}.{idx}"))
+}
+
+/// Function path in the form `function_name` writes, "main" optional
+fn parse_function(text: &str) -> Option<Vec<usize>> {
+	let text = text.strip_prefix("main").unwrap_or(text);
+	text.split('.')
+		.filter(|part| !part.is_empty())
+		.map(|part| part.parse().ok())
+		.collect()
+}
+
+/**
+ * Console - A line-oriented debugger frontend: announces each stop and
+ * reads commands until one runs the VM on. Reaching the end of its input
+ * continues the run without stopping to ask again
+ */
+pub struct Console<R: BufRead, W: Write> {
+	input: R,
+	output: W,
+	/// frame commands look at, by calls up the stack
+	level: usize,
+	done: bool,
+}
+
+impl Console<BufReader<Stdin>, Stdout> {
+	/// A console on the terminal
+	pub fn stdio() -> Self {
+		Self::new(BufReader::new(std::io::stdin()), std::io::stdout())
+	}
+}
+
+impl<R: BufRead + 'static, W: Write + 'static> Console<R, W> {
+	pub fn new(input: R, output: W) -> Self {
+		Self {
+			input,
+			output,
+			level: 0,
+			done: false,
+		}
+	}
+
+	/// Debugs what `vm` runs from now on with this console
+	pub fn attach(mut self, vm: &mut Vm, debugger: Debugger) {
+		vm.set_debugger(debugger, move |vm, debugger, stop| {
+			self.handle(vm, debugger, stop)
+		});
+	}
+
+	/// Announces `stop` and runs commands until one says how to go on
+	pub fn handle(&mut self, vm: &Vm, debugger: &mut Debugger, stop: &Stop) -> Action {
+		if self.done {
+			return Action::Continue;
+		}
+		self.level = 0;
+		let reason = match &stop.reason {
+			Reason::Breakpoint(index) => format!("breakpoint {index}"),
+			Reason::Watch { index, old, new } => format!("watch {index}: {old:?} -> {new:?}"),
+			Reason::Step => "step".to_string(),
+		};
+		let mut at = format!("{}@{}", function_name(&stop.path), stop.pc);
+		if let Some(line) = stop.line {
+			at += &format!(" line {line}");
+		}
+		let _ = writeln!(self.output, "stopped at {at} ({reason})");
+		self.instruction(vm, stop.pc);
+
+		loop {
+			let _ = write!(self.output, "> ");
+			let _ = self.output.flush();
+			let mut line = String::new();
+			if !matches!(self.input.read_line(&mut line), Ok(1..)) {
+				self.done = true;
+				return Action::Continue;
+			}
+			let words = line.split_whitespace().collect::<Vec<_>>();
+			if let Some(action) = self.command(vm, debugger, &words) {
+				return action;
+			}
+		}
+	}
+
+	/// Writes the instruction at `pc` of the selected frame
+	fn instruction(&mut self, vm: &Vm, pc: usize) {
+		let Some(inst) = vm
+			.prototype(self.level)
+			.and_then(|proto| proto.code.get(pc).copied())
+		else {
+			return;
+		};
+		let name = get_opcode_name(inst.op as usize).unwrap_or_else(|| "?".to_string());
+		let operands = match inst.op {
+			1 | 5 | 7 | 36 => format!("{} {}", inst.a, inst.bx),
+			22 | 31 | 32 => format!("{} {}", inst.a, inst.sbx),
+			_ => format!("{} {} {}", inst.a, inst.b, inst.c),
+		};
+		let _ = writeln!(self.output, "{pc:>5}  {name:<9} {operands}");
+	}
+
+	/// Runs one command, the action to go on with if it was one
+	fn command(&mut self, vm: &Vm, debugger: &mut Debugger, words: &[&str]) -> Option<Action> {
+		let arg = |n: usize| words.get(n).and_then(|word| word.parse::<usize>().ok());
+		let out = &mut self.output;
+		match words {
+			[] => {}
+			["continue" | "c"] => return Some(Action::Continue),
+			["step" | "s"] => return Some(Action::Step),
+			["next" | "n"] => return Some(Action::StepOver),
+			["finish" | "out"] => return Some(Action::StepOut),
+			["quit" | "q"] => return Some(Action::Abort),
+			["break" | "b", target] => {
+				let breakpoint = match target.split_once('@') {
+					Some((function, pc)) => {
+						let path = match function {
+							"" => vm.prototype(self.level).map(|proto| proto.path.clone()),
+							_ => parse_function(function),
+						};
+						path.zip(pc.parse().ok())
+							.map(|(path, pc)| Breakpoint::Pc { path, pc })
+					}
+					None => target.parse().ok().map(Breakpoint::Line),
+				};
+				match breakpoint {
+					Some(breakpoint) => {
+						let index = debugger.add_breakpoint(breakpoint);
+						let _ = writeln!(out, "breakpoint {index} set");
+					}
+					None => {
+						let _ = writeln!(out, "expected a line or [function]@pc");
+					}
+				}
+			}
+			["delete", _] => match arg(1).filter(|&n| n < debugger.breakpoints.len()) {
+				Some(n) => {
+					debugger.breakpoints.remove(n);
+				}
+				None => {
+					let _ = writeln!(out, "no such breakpoint");
+				}
+			},
+			["watch", reg] => {
+				let reg = reg.trim_start_matches('r').parse().ok();
+				match reg.zip(vm.prototype(self.level)) {
+					Some((reg, proto)) => {
+						let index = debugger.add_watch(proto.path.clone(), reg);
+						let _ = writeln!(out, "watch {index} set");
+					}
+					None => {
+						let _ = writeln!(out, "expected a register");
+					}
+				}
+			}
+			["unwatch", _] => match arg(1).filter(|&n| n < debugger.watches.len()) {
+				Some(n) => {
+					debugger.watches.remove(n);
+				}
+				None => {
+					let _ = writeln!(out, "no such watch");
+				}
+			},
+			["info"] => {
+				for (i, breakpoint) in debugger.breakpoints.iter().enumerate() {
+					let _ = match breakpoint {
+						Breakpoint::Pc { path, pc } => {
+							writeln!(out, "breakpoint {i}: {}@{pc}", function_name(path))
+						}
+						Breakpoint::Line(line) => writeln!(out, "breakpoint {i}: line {line}"),
+					};
+				}
+				for (i, watch) in debugger.watches.iter().enumerate() {
+					let function = function_name(&watch.path);
+					let _ = writeln!(out, "watch {i}: {function} r{}", watch.reg);
+				}
+			}
+			["where" | "bt"] => {
+				for (level, frame) in vm.call_stack().iter().enumerate() {
+					let mut at = format!("#{level} {}@{}", function_name(&frame.path), frame.pc);
+					if let Some(line) = frame.line {
+						at += &format!(" line {line}");
+					}
+					let _ = writeln!(out, "{at} {}", frame.source);
+				}
+			}
+			["frame", _] => match arg(1).filter(|&n| vm.prototype(n).is_some()) {
+				Some(level) => {
+					self.level = level;
+					let pc = vm.call_stack()[level].pc;
+					self.instruction(vm, pc);
+				}
+				None => {
+					let _ = writeln!(out, "no such frame");
+				}
+			},
+			["regs" | "r"] => {
+				for (reg, value) in vm
+					.registers(self.level)
+					.unwrap_or_default()
+					.iter()
+					.enumerate()
+				{
+					let _ = writeln!(out, "r{reg} = {value:?}");
+				}
+			}
+			["upvals" | "u"] => {
+				for (idx, (name, value)) in vm
+					.upvalues(self.level)
+					.unwrap_or_default()
+					.iter()
+					.enumerate()
+				{
+					let _ = writeln!(out, "u{idx} {name} = {value:?}");
+				}
+			}
+			["consts" | "k"] => {
+				for (idx, value) in vm
+					.constants(self.level)
+					.unwrap_or_default()
+					.iter()
+					.enumerate()
+				{
+					let _ = writeln!(out, "k{idx} = {value:?}");
+				}
+			}
+			["help" | "h"] => {
+				let _ = write!(out, "{HELP}");
+			}
+			_ => {
+				let _ = writeln!(out, "unknown command, try help");
+			}
+		}
+		None
+	}
+}
@@ -0,0 +1,459 @@
+use super::{LuaError, Prototype, Result, Value, Vm};
+use std::rc::Rc;
+
+/// Where execution stops
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Breakpoint {
+	/// before the instruction at `pc` of the function at `path` in the chunk
+	Pc { path: Vec<usize>, pc: usize },
+	/// before the first instruction of each run of those on the line
+	Line(u64),
+}
+
+/// A register of the function at `path` stopped at when its value changes
+#[derive(Debug, Clone)]
+pub struct Watch {
+	pub path: Vec<usize>,
+	pub reg: usize,
+	/// value seen before the last instruction
+	last: Option<Value>,
+}
+
+/// What the handler wants done after a stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+	/// run until a breakpoint or watch
+	Continue,
+	/// stop before the next instruction, in whichever function it is
+	Step,
+	/// stop before the next instruction of this function or its callers
+	StepOver,
+	/// stop once this function has returned
+	StepOut,
+	/// end the run with `LuaError::Aborted`
+	Abort,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+	/// by its index in the breakpoints
+	Breakpoint(usize),
+	/// by its index in the watches, the register having changed
+	Watch {
+		index: usize,
+		old: Value,
+		new: Value,
+	},
+	/// stepping, or paused before the run
+	Step,
+}
+
+/// Where execution stopped and why, at an instruction not yet run
+#[derive(Debug, Clone)]
+pub struct Stop {
+	pub reason: Reason,
+	pub path: Vec<usize>,
+	pub pc: usize,
+	pub line: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+	Run,
+	Step,
+	/// until the frame count is at most this
+	Over(usize),
+	/// until the frame count is below this
+	Out(usize),
+}
+
+/// Told of every stop while the debugger is set, runs the VM on from it
+/// once it returns
+pub type Handler = dyn FnMut(&Vm, &mut Debugger, &Stop) -> Action;
+
+/**
+ * Debugger - Breakpoints, watches and stepping state of a VM. The VM
+ * checks them before each instruction and calls its handler when one
+ * hits, which can inspect the VM and change them
+ */
+#[derive(Debug, Clone)]
+pub struct Debugger {
+	pub breakpoints: Vec<Breakpoint>,
+	pub watches: Vec<Watch>,
+	mode: Mode,
+}
+
+impl Default for Debugger {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Debugger {
+	pub fn new() -> Self {
+		Self {
+			breakpoints: vec![],
+			watches: vec![],
+			mode: Mode::Run,
+		}
+	}
+
+	/// Stops before the next instruction, the first if not running yet
+	pub fn pause(&mut self) {
+		self.mode = Mode::Step;
+	}
+
+	pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+		self.breakpoints.push(breakpoint);
+		self.breakpoints.len() - 1
+	}
+
+	pub fn add_watch(&mut self, path: Vec<usize>, reg: usize) -> usize {
+		self.watches.push(Watch {
+			path,
+			reg,
+			last: None,
+		});
+		self.watches.len() - 1
+	}
+
+	/// Why to stop before `pc` of `proto` with `frames` frames, if at all
+	fn check(&mut self, vm: &Vm, proto: &Prototype, pc: usize, frames: usize) -> Option<Reason> {
+		let base = vm.frames.last().unwrap().base;
+		let mut reason = None;
+		for (index, watch) in self.watches.iter_mut().enumerate() {
+			if watch.path != proto.path || watch.reg >= proto.max_stack {
+				continue;
+			}
+			let new = vm.stack.get(base + watch.reg).cloned().unwrap_or_default();
+			match watch.last.replace(new.clone()) {
+				Some(old) if old != new && reason.is_none() => {
+					reason = Some(Reason::Watch { index, old, new })
+				}
+				_ => {}
+			}
+		}
+
+		let line = proto.line(pc);
+		let hit = self
+			.breakpoints
+			.iter()
+			.position(|breakpoint| match breakpoint {
+				Breakpoint::Pc { path, pc: at } => *path == proto.path && *at == pc,
+				Breakpoint::Line(at) => {
+					line == Some(*at) && (pc == 0 || proto.line(pc - 1) != line)
+				}
+			});
+		if let Some(index) = hit {
+			return Some(Reason::Breakpoint(index));
+		}
+		let stepped = match self.mode {
+			Mode::Run => false,
+			Mode::Step => true,
+			Mode::Over(depth) => frames <= depth,
+			Mode::Out(depth) => frames < depth,
+		};
+		match stepped {
+			true => reason.or(Some(Reason::Step)),
+			false => reason,
+		}
+	}
+}
+
+/// A Lua function on the call stack
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameInfo {
+	pub path: Vec<usize>,
+	/// the instruction it is at, the call for all but the innermost
+	pub pc: usize,
+	pub line: Option<u64>,
+	pub source: String,
+}
+
+impl Vm {
+	/// Checks `debugger` before each instruction from now on, calling
+	/// `handler` when it stops
+	pub fn set_debugger(
+		&mut self,
+		debugger: Debugger,
+		handler: impl FnMut(&Vm, &mut Debugger, &Stop) -> Action + 'static,
+	) {
+		self.debugger = Some((debugger, Box::new(handler)));
+	}
+
+	/// Removes the debugger, giving back its state
+	pub fn take_debugger(&mut self) -> Option<Debugger> {
+		self.debugger.take().map(|(debugger, _)| debugger)
+	}
+
+	/// Stops before the instruction the running frame is at if the
+	/// debugger says so
+	pub(super) fn debug_instruction(&mut self) -> Result<()> {
+		let Some((mut debugger, mut handler)) = self.debugger.take() else {
+			return Ok(());
+		};
+		let frame = self.frames.last().unwrap();
+		let (proto, pc) = (frame.closure.proto.clone(), frame.pc);
+		let frames = self.frames.len();
+
+		let mut result = Ok(());
+		if let Some(reason) = debugger.check(self, &proto, pc, frames) {
+			let stop = Stop {
+				reason,
+				path: proto.path.clone(),
+				pc,
+				line: proto.line(pc),
+			};
+			debugger.mode = match handler(self, &mut debugger, &stop) {
+				Action::Continue => Mode::Run,
+				Action::Step => Mode::Step,
+				Action::StepOver => Mode::Over(frames),
+				Action::StepOut => Mode::Out(frames),
+				Action::Abort => {
+					result = Err(LuaError::Aborted);
+					Mode::Run
+				}
+			};
+		}
+		self.debugger = Some((debugger, handler));
+		result
+	}
+
+	/// Lua frames on the stack of the running thread, innermost first
+	pub fn call_stack(&self) -> Vec<FrameInfo> {
+		(0..self.frames.len())
+			.map(|level| {
+				let frame = &self.frames[self.frames.len() - 1 - level];
+				let pc = self.frame_pc(level).unwrap();
+				let proto = &frame.closure.proto;
+				FrameInfo {
+					path: proto.path.clone(),
+					pc,
+					line: proto.line(pc),
+					source: proto.chunk_name(),
+				}
+			})
+			.collect()
+	}
+
+	/// Instruction the Lua frame `level` calls up the stack is at
+	fn frame_pc(&self, level: usize) -> Option<usize> {
+		let frame = self.frames.len().checked_sub(level + 1)?;
+		let pc = self.frames[frame].pc;
+		// only a frame stopped before an instruction has not fetched it
+		let running = level == 0 && self.natives.last() != Some(&self.frames.len());
+		match running {
+			true => Some(pc),
+			false => Some(pc.saturating_sub(1)),
+		}
+	}
+
+	pub fn prototype(&self, level: usize) -> Option<&Rc<Prototype>> {
+		let frame = self.frames.len().checked_sub(level + 1)?;
+		Some(&self.frames[frame].closure.proto)
+	}
+
+	/// Registers of the Lua frame `level` calls up the stack
+	pub fn registers(&self, level: usize) -> Option<Vec<Value>> {
+		let frame = &self.frames[self.frames.len().checked_sub(level + 1)?];
+		let registers = (0..frame.closure.proto.max_stack)
+			.map(|reg| self.get(frame.base + reg))
+			.collect();
+		Some(registers)
+	}
+
+	pub fn register(&self, level: usize, reg: usize) -> Option<Value> {
+		self.registers(level)?.get(reg).cloned()
+	}
+
+	/// Upvalues of the Lua frame `level` calls up the stack with their
+	/// names, "?" where stripped
+	pub fn upvalues(&self, level: usize) -> Option<Vec<(String, Value)>> {
+		let frame = &self.frames[self.frames.len().checked_sub(level + 1)?];
+		let closure = &frame.closure;
+		let upvalues = closure
+			.upvalues
+			.iter()
+			.enumerate()
+			.map(|(idx, upvalue)| {
+				let name = closure.proto.upvalue_names.get(idx);
+				let name = name.map_or("?", String::as_str).to_string();
+				(name, self.get_upvalue(upvalue))
+			})
+			.collect();
+		Some(upvalues)
+	}
+
+	pub fn constants(&self, level: usize) -> Option<&[Value]> {
+		Some(&self.prototype(level)?.constants)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Action, Breakpoint, Debugger, Reason, Stop};
+	use crate::lua51::{
+		vm::{Console, LuaError, Result, Value, Vm},
+		IRContext,
+	};
+	use bytecode::lua51::{instructions::Opcode, Constants, Proto};
+	use std::{cell::RefCell, io::Write, rc::Rc};
+
+	/// local function f(x) local y = x * 2 return y + 1 end
+	/// local a = f(1)
+	/// local b = f(a)
+	/// return b
+	fn chunk() -> IRContext {
+		let f = Proto {
+			nparams: 1,
+			max_stack_size: 3,
+			instructions: [
+				Opcode::encode_ABC(14, 1, 0, 256), // MUL 1 0 2
+				Opcode::encode_ABC(12, 2, 1, 257), // ADD 2 1 1
+				Opcode::encode_ABC(30, 2, 2, 0),   // RETURN 2 2
+				Opcode::encode_ABC(30, 0, 1, 0),   // RETURN 0 1
+			]
+			.into_iter()
+			.map(Opcode::from_serialized)
+			.collect(),
+			constants: vec![Constants::Number(2.0), Constants::Number(1.0)],
+			source_lines: Some(vec![1, 1, 1, 1]),
+			..Default::default()
+		};
+		let main = Proto {
+			max_stack_size: 4,
+			instructions: [
+				Opcode::encode_ABx(36, 0, 0),    // CLOSURE 0 0
+				Opcode::encode_ABC(0, 1, 0, 0),  // MOVE 1 0
+				Opcode::encode_ABx(1, 2, 0),     // LOADK 2 1
+				Opcode::encode_ABC(28, 1, 2, 2), // CALL 1 2 2
+				Opcode::encode_ABC(0, 2, 0, 0),  // MOVE 2 0
+				Opcode::encode_ABC(0, 3, 1, 0),  // MOVE 3 1
+				Opcode::encode_ABC(28, 2, 2, 2), // CALL 2 2 2
+				Opcode::encode_ABC(30, 2, 2, 0), // RETURN 2 2
+				Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+			]
+			.into_iter()
+			.map(Opcode::from_serialized)
+			.collect(),
+			constants: vec![Constants::Number(1.0)],
+			prototypes: vec![f],
+			source_lines: Some(vec![1, 2, 2, 2, 3, 3, 3, 4, 4]),
+			..Default::default()
+		};
+		IRContext::from_proto(main)
+	}
+
+	/// Runs the chunk under `debugger`, answering stops with `actions` in
+	/// turn, and gives the stops with register 1 at each
+	fn debug(debugger: Debugger, actions: Vec<Action>) -> (Vec<(Stop, Value)>, Result<Vec<Value>>) {
+		let stops = Rc::new(RefCell::new(vec![]));
+		let mut vm = Vm::new();
+		let (seen, mut actions) = (stops.clone(), actions.into_iter());
+		vm.set_debugger(debugger, move |vm, _, stop| {
+			let reg = vm.register(0, 1).unwrap();
+			seen.borrow_mut().push((stop.clone(), reg));
+			actions.next().unwrap_or(Action::Continue)
+		});
+		let main = vm.load(&chunk());
+		let result = vm.call(&main, vec![]);
+		let stops = stops.take();
+		(stops, result)
+	}
+
+	fn at(stops: &[(Stop, Value)]) -> Vec<(Vec<usize>, usize, Value)> {
+		stops
+			.iter()
+			.map(|(stop, reg)| (stop.path.clone(), stop.pc, reg.clone()))
+			.collect()
+	}
+
+	#[test]
+	fn test_stepping() {
+		let mut debugger = Debugger::new();
+		debugger.add_breakpoint(Breakpoint::Pc {
+			path: vec![0],
+			pc: 1,
+		});
+		let actions = vec![
+			Action::StepOut,
+			Action::StepOver,
+			Action::Continue,
+			Action::Abort,
+		];
+		let (stops, result) = debug(debugger, actions);
+		assert_eq!(
+			at(&stops),
+			[
+				(vec![0], 1, Value::Number(2.0)),
+				(vec![], 4, Value::Number(3.0)),
+				(vec![], 5, Value::Number(3.0)),
+				(vec![0], 1, Value::Number(6.0)),
+			]
+		);
+		assert_eq!(stops[1].0.reason, Reason::Step);
+		assert!(matches!(result, Err(LuaError::Aborted)));
+
+		// the first instruction of line 3 only, then changes of a
+		let mut debugger = Debugger::new();
+		debugger.add_breakpoint(Breakpoint::Line(3));
+		debugger.add_watch(vec![], 1);
+		let (stops, result) = debug(debugger, vec![]);
+		let reasons = stops
+			.iter()
+			.map(|(stop, _)| (stop.pc, stop.reason.clone()))
+			.collect::<Vec<_>>();
+		assert!(matches!(
+			reasons[0],
+			(
+				2,
+				Reason::Watch {
+					index: 0,
+					old: Value::Nil,
+					..
+				}
+			)
+		));
+		assert_eq!(reasons[1], (4, Reason::Breakpoint(0)));
+		assert_eq!(reasons.len(), 2);
+		assert_eq!(result.unwrap(), [Value::Number(7.0)]);
+	}
+
+	struct Shared(Rc<RefCell<Vec<u8>>>);
+
+	impl Write for Shared {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.0.borrow_mut().write(buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_console() {
+		let input = "break main.0@1\ncontinue\nwhere\nregs\nk\nquit\n";
+		let output = Rc::new(RefCell::new(vec![]));
+		let console = Console::new(input.as_bytes(), Shared(output.clone()));
+
+		let mut vm = Vm::new();
+		let mut debugger = Debugger::new();
+		debugger.pause();
+		console.attach(&mut vm, debugger);
+		let main = vm.load(&chunk());
+		assert!(matches!(vm.call(&main, vec![]), Err(LuaError::Aborted)));
+
+		let output = String::from_utf8(output.take()).unwrap();
+		for expected in [
+			"stopped at main@0 line 1 (step)\n    0  CLOSURE   0 0\n",
+			"breakpoint 0 set\n",
+			"stopped at main.0@1 line 1 (breakpoint 0)\n    1  ADD       2 1 257\n",
+			"#0 main.0@1 line 1 ?\n#1 main@3 line 2 ?\n",
+			"r0 = 1\nr1 = 2\nr2 = nil\n",
+			"k0 = 2\nk1 = 1\n",
+		] {
+			assert!(output.contains(expected), "{expected:?} not in {output:?}");
+		}
+	}
+}
@@ -0,0 +1,705 @@
+use super::{
+	compat::Compat,
+	meta::Arith,
+	proto::{Inst, Prototype, VARARG_NEEDSARG},
+	table::Table,
+	thread::Resume,
+	value::{Closure, Function, Native, Upvalue, UpvalueRef, Value},
+	LuaError, Result, Vm, FIELDS_PER_FLUSH, MAX_FRAMES,
+};
+use std::{cell::RefCell, rc::Rc};
+
+/// Where the results of a call go
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Return {
+	/// back to the host that called the function
+	Host,
+	/// into the registers of the calling frame from `dest` on, all of them
+	/// when `want` is None
+	Stack { dest: usize, want: Option<usize> },
+}
+
+/** Frame - A Lua function being run */
+pub(super) struct Frame {
+	pub closure: Rc<Closure>,
+	pub base: usize,
+	pub pc: usize,
+	/// end of the values an open call or VARARG left on the stack
+	pub top: usize,
+	pub varargs: Vec<Value>,
+	pub ret: Return,
+}
+
+/// Size of the array a NEWTABLE operand asks for, luaO_fb2int
+fn fb2int(x: usize) -> usize {
+	if x < 8 {
+		x
+	} else {
+		((x & 7) + 8) << ((x >> 3) - 1)
+	}
+}
+
+impl Vm {
+	/// First stack slot no frame uses
+	pub(super) fn frame_top(&self) -> usize {
+		self.frames.last().map_or(0, |frame| {
+			(frame.base + frame.closure.proto.max_stack).max(frame.top)
+		})
+	}
+
+	pub(super) fn set(&mut self, idx: usize, value: Value) {
+		if idx >= self.stack.len() {
+			self.stack.resize(idx + 1, Value::Nil);
+		}
+		self.stack[idx] = value;
+	}
+
+	pub(super) fn get(&self, idx: usize) -> Value {
+		self.stack.get(idx).cloned().unwrap_or_default()
+	}
+
+	fn frame(&mut self) -> &mut Frame {
+		self.frames.last_mut().unwrap()
+	}
+
+	/// Starts `closure` with `nargs` arguments at `base`
+	pub(super) fn enter(
+		&mut self,
+		closure: Rc<Closure>,
+		base: usize,
+		nargs: usize,
+		ret: Return,
+	) -> Result<()> {
+		if self.frames.len() >= MAX_FRAMES {
+			return Err(self.error("stack overflow"));
+		}
+		self.check_depth()?;
+		let proto = closure.proto.clone();
+		let end = base + proto.max_stack.max(nargs);
+		if self.stack.len() < end {
+			self.stack.resize(end, Value::Nil);
+		}
+
+		let rerubi = self.compat == Compat::Rerubi;
+		let mut varargs = match proto.vararg != 0 && nargs > proto.nparams {
+			true => self.stack[base + proto.nparams..base + nargs].to_vec(),
+			false => vec![],
+		};
+		// rerubi's `...` counts the parameters too
+		if rerubi && proto.vararg != 0 {
+			varargs.resize(nargs, Value::Nil);
+		}
+		// parameters not passed and all other registers start as nil
+		for slot in &mut self.stack[base + proto.nparams.min(nargs)..end] {
+			*slot = Value::Nil;
+		}
+		if proto.vararg & VARARG_NEEDSARG != 0 && !rerubi {
+			let mut arg = Table::from_list(varargs.clone());
+			arg.set_str("n", Value::Number(varargs.len() as f64));
+			self.set(base + proto.nparams, Value::table(arg));
+		}
+
+		self.frames.push(Frame {
+			closure,
+			base,
+			pc: 0,
+			top: base,
+			varargs,
+			ret,
+		});
+		Ok(())
+	}
+
+	/// Hands the results of a call to where `ret` says
+	pub(super) fn deliver(&mut self, mut results: Vec<Value>, ret: Return) {
+		match ret {
+			Return::Host => self.results = results,
+			Return::Stack { dest, want } => {
+				let count = want.unwrap_or(results.len());
+				results.resize(count, Value::Nil);
+				for (i, value) in results.into_iter().enumerate() {
+					self.set(dest + i, value);
+				}
+				if want.is_none() {
+					self.frame().top = dest + count;
+				}
+			}
+		}
+	}
+
+	/// Ends the running frame with `results`
+	fn leave(&mut self, results: Vec<Value>) {
+		let frame = self.frames.pop().unwrap();
+		self.close_upvalues(frame.base);
+		if let Return::Host = frame.ret {
+			self.stack.truncate(frame.base);
+		}
+		self.deliver(results, frame.ret);
+	}
+
+	/// Runs until the frame count drops to `stop`
+	pub(super) fn execute(&mut self, stop: usize) -> Result<()> {
+		while self.frames.len() > stop {
+			self.step()?;
+		}
+		Ok(())
+	}
+
+	/// The function in `func` or its __call metamethod, which then gets
+	/// the object as first argument
+	fn callable(
+		&mut self,
+		func: usize,
+		nargs: usize,
+		hint: Option<usize>,
+	) -> Result<(Function, usize)> {
+		match self.get(func) {
+			Value::Function(function) => Ok((function, nargs)),
+			value => {
+				let Value::Function(handler) = self.metamethod(&value, "__call") else {
+					return Err(self.type_error("call", &value, hint));
+				};
+				for i in (func..=func + nargs).rev() {
+					let value = self.get(i);
+					self.set(i + 1, value);
+				}
+				self.set(func, Value::Function(handler.clone()));
+				Ok((handler, nargs + 1))
+			}
+		}
+	}
+
+	/// Runs `native`, which counts as a level of the stack for the
+	/// positions errors get
+	pub(super) fn call_native(&mut self, native: &Native, args: Vec<Value>) -> Result<Vec<Value>> {
+		self.check_depth()?;
+		self.natives.push(self.frames.len());
+		let result = (native.func)(self, args);
+		self.natives.pop();
+		result
+	}
+
+	/// Calls the function in `func` with the `nargs` values after it,
+	/// `hint` being the register it was loaded from
+	pub(super) fn call_at(
+		&mut self,
+		func: usize,
+		nargs: usize,
+		ret: Return,
+		hint: Option<usize>,
+	) -> Result<()> {
+		let (function, nargs) = self.callable(func, nargs, hint)?;
+		self.hook_call(&function);
+		match (function, nargs) {
+			(Function::Lua(closure), nargs) => self.enter(closure, func + 1, nargs, ret),
+			(Function::Native(native), nargs) => {
+				let args = self.stack[func + 1..func + 1 + nargs].to_vec();
+				match self.call_native(&native, args) {
+					Ok(results) => {
+						self.deliver(results, ret);
+						Ok(())
+					}
+					Err(LuaError::Yield(values)) => {
+						self.yielded = Some(Resume::Deliver(ret));
+						Err(LuaError::Yield(values))
+					}
+					Err(err) => Err(err),
+				}
+			}
+		}
+	}
+
+	/// Replaces the running frame by a call to the function in `func`
+	fn tail_call(&mut self, reg: usize, nargs: usize) -> Result<()> {
+		let base = self.frame().base;
+		let func = base + reg;
+		self.close_upvalues(base);
+		let (function, nargs) = self.callable(func, nargs, Some(reg))?;
+		self.hook_call(&function);
+		match (function, nargs) {
+			(Function::Lua(closure), nargs) => {
+				for i in 0..nargs {
+					let value = self.get(func + 1 + i);
+					self.set(base + i, value);
+				}
+				let frame = self.frames.pop().unwrap();
+				self.enter(closure, base, nargs, frame.ret)
+			}
+			(Function::Native(native), nargs) => {
+				let args = self.stack[func + 1..func + 1 + nargs].to_vec();
+				match self.call_native(&native, args) {
+					Ok(results) => {
+						self.leave(results);
+						Ok(())
+					}
+					Err(LuaError::Yield(values)) => {
+						self.yielded = Some(Resume::Return);
+						Err(LuaError::Yield(values))
+					}
+					Err(err) => Err(err),
+				}
+			}
+		}
+	}
+
+	/// Resumes a frame that yielded with the values of the resume
+	pub(super) fn resume_with(&mut self, resume: Resume, values: Vec<Value>) {
+		match resume {
+			Resume::Deliver(ret) => self.deliver(values, ret),
+			Resume::Return => self.leave(values),
+			Resume::Finish => self.results = values,
+		}
+	}
+
+	/// Open upvalue for the stack slot `index` of the running thread
+	fn find_upvalue(&mut self, index: usize) -> UpvalueRef {
+		let pos = self
+			.open
+			.partition_point(|upvalue| match &*upvalue.borrow() {
+				Upvalue::Open { index: other, .. } => *other < index,
+				Upvalue::Closed(_) => true,
+			});
+		if let Some(upvalue) = self.open.get(pos) {
+			if matches!(&*upvalue.borrow(), Upvalue::Open { index: other, .. } if *other == index) {
+				return upvalue.clone();
+			}
+		}
+		let upvalue = Rc::new(RefCell::new(Upvalue::Open {
+			thread: self.thread.clone(),
+			index,
+		}));
+		self.open.insert(pos, upvalue.clone());
+		upvalue
+	}
+
+	/// Closes the upvalues of the slots from `level` on
+	pub(super) fn close_upvalues(&mut self, level: usize) {
+		let pos = self
+			.open
+			.partition_point(|upvalue| match &*upvalue.borrow() {
+				Upvalue::Open { index, .. } => *index < level,
+				Upvalue::Closed(_) => true,
+			});
+		for upvalue in self.open.split_off(pos) {
+			let value = match &*upvalue.borrow() {
+				Upvalue::Open { index, .. } => self.get(*index),
+				Upvalue::Closed(value) => value.clone(),
+			};
+			*upvalue.borrow_mut() = Upvalue::Closed(value);
+		}
+	}
+
+	pub(super) fn get_upvalue(&self, upvalue: &UpvalueRef) -> Value {
+		match &*upvalue.borrow() {
+			Upvalue::Closed(value) => value.clone(),
+			Upvalue::Open { thread, index } if Rc::ptr_eq(thread, &self.thread) => self.get(*index),
+			Upvalue::Open { thread, index } => thread.borrow().stack[*index].clone(),
+		}
+	}
+
+	pub(super) fn set_upvalue(&mut self, upvalue: &UpvalueRef, value: Value) {
+		let open = match &mut *upvalue.borrow_mut() {
+			Upvalue::Closed(slot) => {
+				*slot = value;
+				return;
+			}
+			Upvalue::Open { thread, index } if Rc::ptr_eq(thread, &self.thread) => *index,
+			Upvalue::Open { thread, index } => {
+				thread.borrow_mut().stack[*index] = value;
+				return;
+			}
+		};
+		self.set(open, value);
+	}
+
+	/// Describes the value the running frame has in register or constant
+	/// `rk`, like " global 'x'" in error messages
+	pub(super) fn describe(&self, rk: usize) -> Option<String> {
+		if self.compat == Compat::Rerubi {
+			return self.rerubi_name(rk);
+		}
+		let frame = self.frames.last()?;
+		let proto = &frame.closure.proto;
+		let pc = frame.pc.checked_sub(1)?;
+		describe_register(proto, rk, pc)
+	}
+
+	/// Executes one instruction of the running frame
+	fn step(&mut self) -> Result<()> {
+		if self.debugger.is_some() {
+			self.debug_instruction()?;
+		}
+		let frame = self.frames.last_mut().unwrap();
+		let closure = frame.closure.clone();
+		let proto = &closure.proto;
+		let base = frame.base;
+		let pc = frame.pc;
+		let Some(&Inst {
+			op,
+			a,
+			b,
+			c,
+			bx,
+			sbx,
+			..
+		}) = proto.code.get(pc)
+		else {
+			return Err(self.error("ran past the end of the function"));
+		};
+		frame.pc += 1;
+		self.count_instruction()?;
+		self.hook_instruction(proto, pc);
+
+		let rerubi = self.compat == Compat::Rerubi;
+		let rk = |vm: &Vm, x: usize| match x {
+			// rerubi reads constants as `K or R(x)`, and R(x) past 255 is nil
+			256.. if rerubi && matches!(proto.constants[x - 256], Value::Boolean(false)) => {
+				Value::Nil
+			}
+			256.. => proto.constants[x - 256].clone(),
+			_ => vm.get(base + x),
+		};
+		let quote = if rerubi { '`' } else { '\'' };
+		let number = |vm: &Vm, reg: usize, what: &str| {
+			vm.get(base + reg)
+				.to_number()
+				.ok_or_else(|| vm.error(format!("{quote}for{quote} {what} must be a number")))
+		};
+
+		match op {
+			// MOVE
+			0 => self.set(base + a, self.get(base + b)),
+			// LOADK
+			1 => self.set(base + a, proto.constants[bx].clone()),
+			// LOADBOOL
+			2 => {
+				self.set(base + a, Value::Boolean(b != 0));
+				if c != 0 {
+					self.frame().pc += 1;
+				}
+			}
+			// LOADNIL
+			3 => (a..=b).for_each(|reg| self.set(base + reg, Value::Nil)),
+			// GETUPVAL
+			4 => {
+				let value = self.get_upvalue(&closure.upvalues[b]);
+				self.set(base + a, value);
+			}
+			// GETGLOBAL
+			5 => {
+				let env = Value::Table(closure.env.borrow().clone());
+				let value = self.index_with(&env, &proto.constants[bx], None)?;
+				self.set(base + a, value);
+			}
+			// GETTABLE
+			6 => {
+				let value = self.index_with(&self.get(base + b), &rk(self, c), Some(b))?;
+				self.set(base + a, value);
+			}
+			// SETGLOBAL
+			7 => {
+				let env = Value::Table(closure.env.borrow().clone());
+				let value = self.get(base + a);
+				self.set_index_with(&env, proto.constants[bx].clone(), value, None)?;
+			}
+			// SETUPVAL
+			8 => self.set_upvalue(&closure.upvalues[b], self.get(base + a)),
+			// SETTABLE
+			9 => {
+				let (key, value) = (rk(self, b), rk(self, c));
+				self.set_index_with(&self.get(base + a), key, value, Some(a))?;
+			}
+			// NEWTABLE
+			10 => {
+				let (array, hash) = (fb2int(b).min(1 << 16), fb2int(c).min(1 << 16));
+				self.charge_table(array + hash)?;
+				let table = Table::with_capacity(array, hash);
+				self.set(base + a, Value::table(table));
+			}
+			// SELF
+			11 => {
+				let object = self.get(base + b);
+				let method = self.index_with(&object, &rk(self, c), Some(b))?;
+				self.set(base + a + 1, object);
+				self.set(base + a, method);
+			}
+			// ADD SUB MUL DIV MOD POW
+			12..=17 => {
+				let op = [
+					Arith::Add,
+					Arith::Sub,
+					Arith::Mul,
+					Arith::Div,
+					Arith::Mod,
+					Arith::Pow,
+				][op as usize - 12];
+				let value = self.arith(op, &rk(self, b), &rk(self, c), [b, c])?;
+				self.set(base + a, value);
+			}
+			// UNM
+			18 => {
+				let operand = self.get(base + b);
+				let value = self.arith(Arith::Unm, &operand, &operand, [b, b])?;
+				self.set(base + a, value);
+			}
+			// NOT
+			19 => self.set(base + a, Value::Boolean(!self.get(base + b).truthy())),
+			// LEN
+			20 => {
+				let value = self.length(&self.get(base + b), Some(b))?;
+				self.set(base + a, value);
+			}
+			// CONCAT
+			21 => {
+				let values = self.stack[base + b..=base + c].to_vec();
+				let value = match rerubi {
+					true => self.concat_left(values, b)?,
+					false => self.concat(values, b)?,
+				};
+				if let Value::String(s) = &value {
+					self.charge(s.len())?;
+				}
+				self.set(base + a, value);
+			}
+			// JMP
+			22 => self.jump(sbx),
+			// EQ LT LE
+			23..=25 => {
+				let (left, right) = (rk(self, b), rk(self, c));
+				let result = match op {
+					23 => self.equals(&left, &right)?,
+					24 => self.less_than(&left, &right)?,
+					_ => self.less_equal(&left, &right)?,
+				};
+				self.hook_branch(proto, pc, result == (a != 0));
+				if result != (a != 0) {
+					self.frame().pc += 1;
+				}
+			}
+			// TEST
+			26 => {
+				let taken = self.get(base + a).truthy() == (c != 0);
+				self.hook_branch(proto, pc, taken);
+				if !taken {
+					self.frame().pc += 1;
+				}
+			}
+			// TESTSET
+			27 => {
+				let value = self.get(base + b);
+				self.hook_branch(proto, pc, value.truthy() == (c != 0));
+				if value.truthy() == (c != 0) {
+					self.set(base + a, value);
+				} else {
+					self.frame().pc += 1;
+				}
+			}
+			// CALL
+			28 => {
+				let nargs = match b {
+					0 => self.frame().top - (base + a + 1),
+					_ => b - 1,
+				};
+				let want = c.checked_sub(1);
+				let ret = Return::Stack {
+					dest: base + a,
+					want,
+				};
+				match rerubi {
+					// rerubi's loop calls from within the caller's
+					true => {
+						let args = (0..nargs).map(|i| self.get(base + a + 1 + i)).collect();
+						let results = self.call_with(&self.get(base + a), args, Some(a))?;
+						self.deliver(results, ret);
+					}
+					false => self.call_at(base + a, nargs, ret, Some(a))?,
+				}
+			}
+			// TAILCALL
+			29 => {
+				let nargs = match b {
+					0 => self.frame().top - (base + a + 1),
+					_ => b - 1,
+				};
+				match rerubi {
+					// rerubi collects the arguments with `#`, skipping nils,
+					// and counts the results with `pairs`, missing trailing ones
+					true => {
+						let mut args = (0..nargs)
+							.map(|i| self.get(base + a + 1 + i))
+							.filter(|value| !value.is_nil())
+							.collect::<Vec<_>>();
+						args.resize(nargs, Value::Nil);
+						let mut results = self.call_with(&self.get(base + a), args, Some(a))?;
+						let count = results.iter().rposition(|value| !value.is_nil());
+						results.truncate(count.map_or(0, |last| last + 1));
+						self.leave(results);
+					}
+					false => self.tail_call(a, nargs)?,
+				}
+			}
+			// RETURN
+			30 => {
+				let end = match b {
+					0 => self.frame().top,
+					_ => base + a + b - 1,
+				};
+				let results = (base + a..end).map(|idx| self.get(idx)).collect();
+				self.leave(results);
+			}
+			// FORLOOP
+			31 => {
+				let step = number(self, a + 2, "step")?;
+				let idx = number(self, a, "initial value")? + step;
+				let limit = number(self, a + 1, "limit")?;
+				let taken = (step > 0.0 && idx <= limit) || (step <= 0.0 && limit <= idx);
+				self.hook_branch(proto, pc, taken);
+				if taken {
+					self.jump(sbx);
+					self.set(base + a, Value::Number(idx));
+					self.set(base + a + 3, Value::Number(idx));
+				}
+			}
+			// FORPREP
+			32 => {
+				let init = number(self, a, "initial value")?;
+				let limit = number(self, a + 1, "limit")?;
+				let step = number(self, a + 2, "step")?;
+				self.set(base + a, Value::Number(init - step));
+				self.set(base + a + 1, Value::Number(limit));
+				self.set(base + a + 2, Value::Number(step));
+				self.jump(sbx);
+			}
+			// TFORLOOP
+			33 => {
+				let iterator = self.get(base + a);
+				let args = vec![self.get(base + a + 1), self.get(base + a + 2)];
+				let mut results = self.call_with(&iterator, args, Some(a))?;
+				results.resize(c.max(1), Value::Nil);
+				for (i, value) in results.into_iter().take(c).enumerate() {
+					self.set(base + a + 3 + i, value);
+				}
+				let control = self.get(base + a + 3);
+				self.hook_branch(proto, pc, !control.is_nil());
+				if control.is_nil() {
+					self.frame().pc += 1;
+				} else {
+					self.set(base + a + 2, control);
+				}
+			}
+			// SETLIST
+			34 => {
+				let count = match b {
+					0 => self.frame().top - (base + a + 1),
+					_ => b,
+				};
+				let block = match c {
+					0 => {
+						let raw = proto.code.get(pc + 1).map_or(0, |inst| inst.raw);
+						self.frame().pc += 1;
+						raw as usize
+					}
+					_ => c,
+				};
+				let Value::Table(table) = self.get(base + a) else {
+					return Err(self.error("SETLIST on a value that is not a table"));
+				};
+				for i in 1..=count {
+					let idx = (block.max(1) - 1) * FIELDS_PER_FLUSH + i;
+					let (key, value) = (Value::Number(idx as f64), self.get(base + a + i));
+					self.charge_set(&table, &key, &value)?;
+					table.borrow_mut().set(key, value).unwrap();
+				}
+			}
+			// CLOSE
+			35 => self.close_upvalues(base + a),
+			// CLOSURE
+			36 => {
+				let child = proto.protos[bx].clone();
+				self.charge_closure(child.nupvalues)?;
+				let mut upvalues = Vec::with_capacity(child.nupvalues);
+				for i in 0..child.nupvalues {
+					let Some(capture) = proto.code.get(pc + 1 + i) else {
+						return Err(self.error("CLOSURE without its captures"));
+					};
+					upvalues.push(match capture.op {
+						0 => self.find_upvalue(base + capture.b),
+						4 => closure.upvalues[capture.b].clone(),
+						_ => return Err(self.error("CLOSURE capture is not MOVE or GETUPVAL")),
+					});
+				}
+				self.frame().pc += child.nupvalues;
+				let function = Closure {
+					proto: child,
+					upvalues,
+					env: RefCell::new(closure.env.borrow().clone()),
+				};
+				self.set(base + a, Value::Function(Function::Lua(Rc::new(function))));
+			}
+			// VARARG
+			37 => {
+				let varargs = self.frame().varargs.clone();
+				let count = match b {
+					0 => varargs.len(),
+					_ => b - 1,
+				};
+				for i in 0..count {
+					self.set(base + a + i, varargs.get(i).cloned().unwrap_or_default());
+				}
+				if b == 0 {
+					self.frame().top = base + a + count;
+				}
+			}
+			_ => return Err(self.error(format!("invalid opcode {op}"))),
+		}
+		Ok(())
+	}
+
+	fn jump(&mut self, sbx: isize) {
+		let frame = self.frame();
+		frame.pc = frame.pc.wrapping_add_signed(sbx);
+	}
+}
+
+/// Name of what register `rk` holds at `pc`, from debug info or the
+/// instruction that loaded it
+fn describe_register(proto: &Prototype, rk: usize, pc: usize) -> Option<String> {
+	if rk >= 256 {
+		return None;
+	}
+	if let Some(name) = proto.local_name(rk, pc) {
+		return Some(format!("local '{name}'"));
+	}
+	let constant = |k: usize| match proto.constants.get(k) {
+		Some(Value::String(s)) => Some(String::from_utf8_lossy(s).into_owned()),
+		_ => None,
+	};
+
+	// the last instruction before `pc` writing the register, on the
+	// straight path to it
+	let (at, inst) = proto.code[..pc]
+		.iter()
+		.enumerate()
+		.rev()
+		.take_while(|(_, inst)| !matches!(inst.op, 22 | 31 | 32))
+		.find(|(_, inst)| match inst.op {
+			3 => (inst.a..=inst.b).contains(&rk),
+			11 => inst.a == rk || inst.a + 1 == rk,
+			28 | 37 => inst.a <= rk,
+			7..=9 | 23..=26 | 30 | 34 | 35 => false,
+			_ => inst.a == rk,
+		})?;
+	match inst.op {
+		0 if inst.b < inst.a => describe_register(proto, inst.b, at),
+		4 => Some(format!(
+			"upvalue '{}'",
+			proto.upvalue_names.get(inst.b).map_or("?", String::as_str)
+		)),
+		5 => constant(inst.bx).map(|name| format!("global '{name}'")),
+		6 if inst.c >= 256 => constant(inst.c - 256).map(|name| format!("field '{name}'")),
+		11 if inst.a == rk && inst.c >= 256 => {
+			constant(inst.c - 256).map(|name| format!("method '{name}'"))
+		}
+		_ => None,
+	}
+}
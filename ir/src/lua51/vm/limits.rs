@@ -0,0 +1,175 @@
+use super::{LuaError, Prototype, Result, Table, TableRef, Value, Vm};
+use std::{fmt::Display, mem::size_of, rc::Rc};
+
+/// Bytes charged for a new table, besides its slots
+const TABLE_COST: usize = size_of::<Table>();
+/// Bytes charged for each key and value stored
+const SLOT_COST: usize = size_of::<Value>();
+/// Bytes charged for a new closure, besides its upvalues
+const CLOSURE_COST: usize = 64;
+/// Bytes charged for a new coroutine, besides the stack it grows
+const THREAD_COST: usize = 256;
+
+/**
+ * Limits - Bounds on what a chunk may do, so untrusted ones can be run
+ * during analysis. None leaves that resource unbounded
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+	/// instructions executed over the life of the VM
+	pub instructions: Option<u64>,
+	/// estimated bytes of strings, tables and closures created, never
+	/// given back as nothing is collected
+	pub memory: Option<usize>,
+	/// Lua functions and natives running at once on a thread
+	pub depth: Option<usize>,
+}
+
+/// What the VM has used of what `Limits` bounds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+	pub instructions: u64,
+	pub memory: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+	Instructions,
+	Memory,
+	Depth,
+}
+
+impl Limit {
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Instructions => "instruction budget",
+			Self::Memory => "memory ceiling",
+			Self::Depth => "call depth limit",
+		}
+	}
+}
+
+/**
+ * LimitError - A limit being exceeded, with the function that was running
+ * and the pc of the instruction it was at. Unlike runtime errors `pcall`
+ * does not catch these
+ */
+#[derive(Clone)]
+pub struct LimitError {
+	pub limit: Limit,
+	/// None when no Lua function was running
+	pub proto: Option<Rc<Prototype>>,
+	pub pc: usize,
+}
+
+impl std::fmt::Debug for LimitError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("LimitError")
+			.field("limit", &self.limit)
+			.field(
+				"proto",
+				&self.proto.as_ref().map(|proto| proto.chunk_name()),
+			)
+			.field("pc", &self.pc)
+			.finish()
+	}
+}
+
+impl Display for LimitError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{} exceeded", self.limit.name())?;
+		if let Some(proto) = &self.proto {
+			write!(f, " at pc {} of {}", self.pc, proto.chunk_name())?;
+		}
+		Ok(())
+	}
+}
+
+impl Vm {
+	pub fn limits(&self) -> Limits {
+		self.limits
+	}
+
+	/// Bounds what is run from now on, counting what was used before
+	pub fn set_limits(&mut self, limits: Limits) {
+		self.limits = limits;
+	}
+
+	pub fn usage(&self) -> Usage {
+		self.usage
+	}
+
+	/// `limit` exceeded at the instruction the running frame is at
+	pub(super) fn limit_error(&self, limit: Limit) -> LuaError {
+		let (proto, pc) = match self.frames.last() {
+			Some(frame) => (
+				Some(frame.closure.proto.clone()),
+				frame.pc.saturating_sub(1),
+			),
+			None => (None, 0),
+		};
+		LuaError::Limit(LimitError { limit, proto, pc })
+	}
+
+	/// Counts an instruction against the budget
+	pub(super) fn count_instruction(&mut self) -> Result<()> {
+		self.usage.instructions += 1;
+		match self.limits.instructions {
+			Some(max) if self.usage.instructions > max => {
+				Err(self.limit_error(Limit::Instructions))
+			}
+			_ => Ok(()),
+		}
+	}
+
+	/// Fails if one more call would go past the depth limit
+	pub(super) fn check_depth(&self) -> Result<()> {
+		match self.limits.depth {
+			Some(max) if self.frames.len() + self.natives.len() >= max => {
+				Err(self.limit_error(Limit::Depth))
+			}
+			_ => Ok(()),
+		}
+	}
+
+	/// Accounts for `bytes` being allocated, natives building large values
+	/// call it before doing so
+	pub fn charge(&mut self, bytes: usize) -> Result<()> {
+		let total = self.usage.memory.saturating_add(bytes);
+		if self.limits.memory.is_some_and(|max| total > max) {
+			return Err(self.limit_error(Limit::Memory));
+		}
+		self.usage.memory = total;
+		Ok(())
+	}
+
+	pub(super) fn charge_table(&mut self, slots: usize) -> Result<()> {
+		self.charge(TABLE_COST + slots * SLOT_COST)
+	}
+
+	/// Accounts for `count` new keys with their values
+	pub(super) fn charge_slots(&mut self, count: usize) -> Result<()> {
+		self.charge(count * 2 * SLOT_COST)
+	}
+
+	/// Accounts for `table[key] = value` adding a key
+	pub(super) fn charge_set(
+		&mut self,
+		table: &TableRef,
+		key: &Value,
+		value: &Value,
+	) -> Result<()> {
+		match !value.is_nil() && table.borrow().get(key).is_nil() {
+			true => self.charge_slots(1),
+			false => Ok(()),
+		}
+	}
+
+	pub(super) fn charge_closure(&mut self, upvalues: usize) -> Result<()> {
+		self.charge(CLOSURE_COST + upvalues * SLOT_COST)
+	}
+
+	pub(super) fn charge_thread(&mut self) -> Result<()> {
+		self.charge(THREAD_COST)
+	}
+}
@@ -0,0 +1,300 @@
+use super::{
+	value::{TableRef, Value},
+	LuaError, Result, Vm, MAX_TAG_LOOP,
+};
+use std::rc::Rc;
+
+/// Arithmetic operators and the events of their metamethods
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arith {
+	Add,
+	Sub,
+	Mul,
+	Div,
+	Mod,
+	Pow,
+	Unm,
+}
+
+impl Arith {
+	fn event(self) -> &'static str {
+		match self {
+			Self::Add => "__add",
+			Self::Sub => "__sub",
+			Self::Mul => "__mul",
+			Self::Div => "__div",
+			Self::Mod => "__mod",
+			Self::Pow => "__pow",
+			Self::Unm => "__unm",
+		}
+	}
+
+	pub fn apply(self, a: f64, b: f64) -> f64 {
+		match self {
+			Self::Add => a + b,
+			Self::Sub => a - b,
+			Self::Mul => a * b,
+			Self::Div => a / b,
+			Self::Mod => a - (a / b).floor() * b,
+			Self::Pow => a.powf(b),
+			Self::Unm => -a,
+		}
+	}
+}
+
+pub(super) fn first(results: Vec<Value>) -> Value {
+	results.into_iter().next().unwrap_or_default()
+}
+
+pub(super) fn is_concatenable(value: &Value) -> bool {
+	matches!(value, Value::String(_) | Value::Number(_))
+}
+
+impl Vm {
+	/// Metatable of a value, strings share one
+	pub fn metatable(&self, value: &Value) -> Option<TableRef> {
+		match value {
+			Value::Table(table) => table.borrow().metatable.clone(),
+			Value::String(_) => self.string_meta.clone(),
+			_ => None,
+		}
+	}
+
+	/// Handler for `event` in the metatable of `value`, nil if none
+	pub fn metamethod(&self, value: &Value, event: &str) -> Value {
+		self.metatable(value)
+			.map(|meta| meta.borrow().get_str(event))
+			.unwrap_or_default()
+	}
+
+	/// Error for an operation a value does not support, naming the register
+	/// or constant `hint` of the running frame it came from if known
+	pub(super) fn type_error(&self, action: &str, value: &Value, hint: Option<usize>) -> LuaError {
+		let kind = value.type_name();
+		match hint.and_then(|rk| self.describe(rk)) {
+			Some(what) => self.error(format!("attempt to {action} {what} (a {kind} value)")),
+			None => self.error(format!("attempt to {action} a {kind} value")),
+		}
+	}
+
+	fn order_error(&self, a: &Value, b: &Value) -> LuaError {
+		match (a.type_name(), b.type_name()) {
+			(t1, t2) if t1 == t2 => self.error(format!("attempt to compare two {t1} values")),
+			(t1, t2) => self.error(format!("attempt to compare {t1} with {t2}")),
+		}
+	}
+
+	pub(super) fn arith(
+		&mut self,
+		op: Arith,
+		a: &Value,
+		b: &Value,
+		hints: [usize; 2],
+	) -> Result<Value> {
+		if let (Some(x), Some(y)) = (a.to_number(), b.to_number()) {
+			return Ok(Value::Number(op.apply(x, y)));
+		}
+		let mut handler = self.metamethod(a, op.event());
+		if handler.is_nil() {
+			handler = self.metamethod(b, op.event());
+		}
+		if !handler.is_nil() {
+			return Ok(first(self.call(&handler, vec![a.clone(), b.clone()])?));
+		}
+		match a.to_number() {
+			None => Err(self.type_error("perform arithmetic on", a, Some(hints[0]))),
+			Some(_) => Err(self.type_error("perform arithmetic on", b, Some(hints[1]))),
+		}
+	}
+
+	/// Arithmetic with metamethods, for natives
+	pub fn arithmetic(&mut self, op: Arith, a: &Value, b: &Value) -> Result<Value> {
+		self.arith(op, a, b, [256, 256])
+	}
+
+	/// Handler of both values for a comparison, if they agree on it
+	fn comparison_handler(&self, a: &Value, b: &Value, event: &str) -> Option<Value> {
+		let handler = self.metamethod(a, event);
+		(!handler.is_nil() && handler == self.metamethod(b, event)).then_some(handler)
+	}
+
+	/// `a == b` with the __eq metamethod
+	pub fn equals(&mut self, a: &Value, b: &Value) -> Result<bool> {
+		match (a, b) {
+			(Value::Table(x), Value::Table(y)) if !Rc::ptr_eq(x, y) => {
+				match self.comparison_handler(a, b, "__eq") {
+					Some(handler) => {
+						Ok(first(self.call(&handler, vec![a.clone(), b.clone()])?).truthy())
+					}
+					None => Ok(false),
+				}
+			}
+			_ => Ok(a == b),
+		}
+	}
+
+	/// `a < b` with the __lt metamethod
+	pub fn less_than(&mut self, a: &Value, b: &Value) -> Result<bool> {
+		match (a, b) {
+			(Value::Number(x), Value::Number(y)) => Ok(x < y),
+			(Value::String(x), Value::String(y)) => Ok(x < y),
+			_ if a.type_name() != b.type_name() => Err(self.order_error(a, b)),
+			_ => match self.comparison_handler(a, b, "__lt") {
+				Some(handler) => {
+					Ok(first(self.call(&handler, vec![a.clone(), b.clone()])?).truthy())
+				}
+				None => Err(self.order_error(a, b)),
+			},
+		}
+	}
+
+	/// `a <= b` with the __le metamethod, or `not (b < a)` with __lt
+	pub fn less_equal(&mut self, a: &Value, b: &Value) -> Result<bool> {
+		match (a, b) {
+			(Value::Number(x), Value::Number(y)) => Ok(x <= y),
+			(Value::String(x), Value::String(y)) => Ok(x <= y),
+			_ if a.type_name() != b.type_name() => Err(self.order_error(a, b)),
+			_ => {
+				if let Some(handler) = self.comparison_handler(a, b, "__le") {
+					return Ok(first(self.call(&handler, vec![a.clone(), b.clone()])?).truthy());
+				}
+				match self.comparison_handler(b, a, "__lt") {
+					Some(handler) => {
+						Ok(!first(self.call(&handler, vec![b.clone(), a.clone()])?).truthy())
+					}
+					None => Err(self.order_error(a, b)),
+				}
+			}
+		}
+	}
+
+	pub(super) fn index_with(
+		&mut self,
+		object: &Value,
+		key: &Value,
+		mut hint: Option<usize>,
+	) -> Result<Value> {
+		let mut object = object.clone();
+		for _ in 0..MAX_TAG_LOOP {
+			let handler = match &object {
+				Value::Table(table) => {
+					let value = table.borrow().get(key);
+					if !value.is_nil() {
+						return Ok(value);
+					}
+					match self.metamethod(&object, "__index") {
+						Value::Nil => return Ok(Value::Nil),
+						handler => handler,
+					}
+				}
+				_ => match self.metamethod(&object, "__index") {
+					Value::Nil => return Err(self.type_error("index", &object, hint)),
+					handler => handler,
+				},
+			};
+			if let Value::Function(_) = handler {
+				return Ok(first(self.call(&handler, vec![object, key.clone()])?));
+			}
+			object = handler;
+			hint = None;
+		}
+		Err(self.error("loop in gettable"))
+	}
+
+	/// `object[key]` with the __index metamethod
+	pub fn index(&mut self, object: &Value, key: &Value) -> Result<Value> {
+		self.index_with(object, key, None)
+	}
+
+	pub(super) fn set_index_with(
+		&mut self,
+		object: &Value,
+		key: Value,
+		value: Value,
+		mut hint: Option<usize>,
+	) -> Result<()> {
+		let mut object = object.clone();
+		for _ in 0..MAX_TAG_LOOP {
+			let handler = match &object {
+				Value::Table(table) => {
+					let handler = match table.borrow().get(&key).is_nil() {
+						true => self.metamethod(&object, "__newindex"),
+						false => Value::Nil,
+					};
+					if handler.is_nil() {
+						self.charge_set(table, &key, &value)?;
+						let result = table.borrow_mut().set(key, value);
+						return result.map_err(|msg| self.error(msg));
+					}
+					handler
+				}
+				_ => match self.metamethod(&object, "__newindex") {
+					Value::Nil => return Err(self.type_error("index", &object, hint)),
+					handler => handler,
+				},
+			};
+			if let Value::Function(_) = handler {
+				self.call(&handler, vec![object, key, value])?;
+				return Ok(());
+			}
+			object = handler;
+			hint = None;
+		}
+		Err(self.error("loop in settable"))
+	}
+
+	/// `object[key] = value` with the __newindex metamethod
+	pub fn set_index(&mut self, object: &Value, key: Value, value: Value) -> Result<()> {
+		self.set_index_with(object, key, value, None)
+	}
+
+	/// `#value`, tables never consult __len
+	pub(super) fn length(&mut self, value: &Value, hint: Option<usize>) -> Result<Value> {
+		match value {
+			Value::String(s) => Ok(Value::Number(s.len() as f64)),
+			Value::Table(table) => Ok(Value::Number(table.borrow().len() as f64)),
+			_ => match self.metamethod(value, "__len") {
+				Value::Nil => Err(self.type_error("get length of", value, hint)),
+				handler => Ok(first(self.call(&handler, vec![value.clone()])?)),
+			},
+		}
+	}
+
+	/// Concatenation of `values`, which were loaded from the registers from
+	/// `first_reg` on. Runs of strings and numbers are joined at once, the
+	/// rest pairwise from the right through __concat
+	pub(super) fn concat(&mut self, mut values: Vec<Value>, first_reg: usize) -> Result<Value> {
+		while values.len() > 1 {
+			let n = values.len();
+			let (a, b) = (&values[n - 2], &values[n - 1]);
+			if is_concatenable(a) && is_concatenable(b) {
+				let count = 2 + values[..n - 2]
+					.iter()
+					.rev()
+					.take_while(|value| is_concatenable(value))
+					.count();
+				let joined = values
+					.drain(n - count..)
+					.flat_map(|value| value.to_bytes().unwrap().to_vec())
+					.collect::<Vec<_>>();
+				values.push(Value::string(joined));
+				continue;
+			}
+
+			let mut handler = self.metamethod(a, "__concat");
+			if handler.is_nil() {
+				handler = self.metamethod(b, "__concat");
+			}
+			if handler.is_nil() {
+				return Err(match is_concatenable(a) {
+					true => self.type_error("concatenate", b, Some(first_reg + n - 1)),
+					false => self.type_error("concatenate", a, Some(first_reg + n - 2)),
+				});
+			}
+			let args = values.split_off(n - 2);
+			let result = first(self.call(&handler, args)?);
+			values.push(result);
+		}
+		Ok(values.pop().unwrap_or_default())
+	}
+}
@@ -0,0 +1,517 @@
+mod compat;
+mod console;
+mod debug;
+mod exec;
+mod limits;
+mod meta;
+mod proto;
+pub mod stdlib;
+mod table;
+mod thread;
+mod trace;
+mod value;
+
+pub use compat::Compat;
+pub use console::Console;
+pub use debug::{Action, Breakpoint, Debugger, FrameInfo, Handler, Reason, Stop, Watch};
+pub use limits::{Limit, LimitError, Limits, Usage};
+pub use meta::Arith;
+pub use proto::Prototype;
+pub use table::Table;
+pub use thread::{Status, Thread};
+pub use trace::{Branch, CallSite, Callee, Event, FunctionTrace, Hook, Target, Trace};
+pub use value::{
+	Closure, Function, Native, NativeFn, TableRef, ThreadRef, Upvalue, UpvalueRef, Value,
+};
+
+use super::IRContext;
+use bytecode::lua51::Proto;
+use exec::{Frame, Return};
+use std::{cell::RefCell, collections::HashMap, fmt::Display, io::Write, rc::Rc};
+use thread::Resume;
+
+/// Frames of Lua functions one thread may have
+const MAX_FRAMES: usize = 20000;
+/// Calls from the host, natives and metamethods that may be in progress
+const MAX_HOST_DEPTH: usize = 200;
+/// Links of __index and __newindex chains followed
+const MAX_TAG_LOOP: usize = 100;
+/// Array items a SETLIST block holds
+const FIELDS_PER_FLUSH: usize = 50;
+
+#[derive(Debug, Clone)]
+pub enum LuaError {
+	/// an error raised with a value, messages start with where it happened
+	Runtime(Value),
+	/// the running coroutine yielding, never leaves the VM
+	Yield(Vec<Value>),
+	/// a limit of the sandbox being exceeded, which Lua code can not catch
+	Limit(LimitError),
+	/// the debugger ending the run, which Lua code can not catch either
+	Aborted,
+}
+
+impl Display for LuaError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Runtime(value @ (Value::String(_) | Value::Number(_))) => write!(f, "{value}"),
+			Self::Runtime(value) => write!(f, "(error object is a {} value)", value.type_name()),
+			Self::Yield(_) => write!(f, "attempt to yield across metamethod/C-call boundary"),
+			Self::Limit(err) => write!(f, "{err}"),
+			Self::Aborted => write!(f, "aborted by the debugger"),
+		}
+	}
+}
+
+impl std::error::Error for LuaError {}
+
+pub type Result<T> = std::result::Result<T, LuaError>;
+
+/// Sink for what Lua code prints
+pub type Output = dyn FnMut(&[u8]);
+
+/**
+ * Vm - A Lua 5.1 interpreter running IR contexts in process, so transformed
+ * chunks can be checked by running them. Values live on one stack per
+ * thread; the running thread's stack and frames are held here
+ */
+pub struct Vm {
+	globals: TableRef,
+	string_meta: Option<TableRef>,
+	stack: Vec<Value>,
+	frames: Vec<Frame>,
+	/// natives running, each by the number of Lua frames below it
+	natives: Vec<usize>,
+	open: Vec<UpvalueRef>,
+	/// the running thread, whose state is swapped out while it is not
+	thread: ThreadRef,
+	main: ThreadRef,
+	/// what the last frame returning to the host gave back
+	results: Vec<Value>,
+	/// calls from the host in progress
+	depth: usize,
+	/// where the values of the next resume go, set as a yield leaves
+	yielded: Option<Resume>,
+	/// sink `print` writes to
+	output: Box<Output>,
+	limits: Limits,
+	usage: Usage,
+	/// objects `tostring` has shown, by address
+	ids: HashMap<usize, (usize, Value)>,
+	hook: Option<Box<Hook>>,
+	debugger: Option<(Debugger, Box<Handler>)>,
+	compat: Compat,
+}
+
+impl Default for Vm {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Vm {
+	/// A VM with empty globals
+	pub fn new() -> Self {
+		let main = Rc::new(RefCell::new(Thread::default()));
+		main.borrow_mut().status = Status::Running;
+		Self {
+			globals: Rc::new(RefCell::new(Table::new())),
+			string_meta: None,
+			stack: vec![],
+			frames: vec![],
+			natives: vec![],
+			open: vec![],
+			thread: main.clone(),
+			main,
+			results: vec![],
+			depth: 0,
+			yielded: None,
+			output: Box::new(|bytes| {
+				let _ = std::io::stdout().write_all(bytes);
+			}),
+			limits: Limits::default(),
+			usage: Usage::default(),
+			ids: HashMap::new(),
+			hook: None,
+			debugger: None,
+			compat: Compat::default(),
+		}
+	}
+
+	/// Sends what the chunk prints to `output` instead of stdout
+	pub fn set_output(&mut self, output: impl FnMut(&[u8]) + 'static) {
+		self.output = Box::new(output);
+	}
+
+	pub fn write(&mut self, bytes: &[u8]) {
+		(self.output)(bytes);
+	}
+
+	pub fn globals(&self) -> TableRef {
+		self.globals.clone()
+	}
+
+	pub fn global(&self, name: &str) -> Value {
+		self.globals.borrow().get_str(name)
+	}
+
+	pub fn set_global(&mut self, name: &str, value: Value) {
+		self.globals.borrow_mut().set_str(name, value);
+	}
+
+	/// Sets the metatable all strings share
+	pub fn set_string_metatable(&mut self, meta: Option<TableRef>) {
+		self.string_meta = meta;
+	}
+
+	/// Main function of `ctx` as a closure over the globals
+	pub fn load(&mut self, ctx: &IRContext) -> Value {
+		let proto = Prototype::new(ctx, "", &[]);
+		let upvalues = (0..proto.nupvalues)
+			.map(|_| Rc::new(RefCell::new(Upvalue::Closed(Value::Nil))))
+			.collect();
+		Value::Function(Function::Lua(Rc::new(Closure {
+			proto,
+			upvalues,
+			env: RefCell::new(self.globals.clone()),
+		})))
+	}
+
+	pub fn load_proto(&mut self, proto: Proto) -> Value {
+		self.load(&IRContext::from_proto(proto))
+	}
+
+	/// Error with `msg`, prefixed with the position of the innermost Lua
+	/// function like the reference VM does
+	pub fn error(&self, msg: impl Display) -> LuaError {
+		// a native raising it blames its caller, the VM the running function
+		let level = match self.natives.last() {
+			Some(&below) if below == self.frames.len() => 1,
+			_ => 0,
+		};
+		LuaError::Runtime(Value::string(format!("{}{msg}", self.location(level))))
+	}
+
+	/// "chunk:line: " of the function `level` calls up the stack, 0 being
+	/// the running one. Empty if there is none or it is a native
+	pub fn location(&self, level: usize) -> String {
+		// rerubi's functions are all in rerubi.lua, which is not loaded here
+		if self.compat == Compat::Rerubi {
+			return String::new();
+		}
+		let mut frames = self.frames.len();
+		let mut natives = self.natives.len();
+		for _ in 0..level {
+			match natives.checked_sub(1).map(|top| self.natives[top]) {
+				Some(below) if below >= frames => natives -= 1,
+				_ if frames > 0 => frames -= 1,
+				_ => return String::new(),
+			}
+		}
+		if natives
+			.checked_sub(1)
+			.is_some_and(|top| self.natives[top] >= frames)
+		{
+			return String::new();
+		}
+		let Some(frame) = frames.checked_sub(1).map(|top| &self.frames[top]) else {
+			return String::new();
+		};
+		let proto = &frame.closure.proto;
+		let line = proto.line(frame.pc.saturating_sub(1)).unwrap_or(0);
+		format!("{}:{line}: ", proto.chunk_name())
+	}
+
+	/// Calls `func` with `args`, giving all its results. Coroutines can not
+	/// yield through it
+	pub fn call(&mut self, func: &Value, args: Vec<Value>) -> Result<Vec<Value>> {
+		self.call_with(func, args, None)
+	}
+
+	/// `call` with `hint` being the register of the running frame `func` was
+	/// loaded from, for error messages
+	fn call_with(
+		&mut self,
+		func: &Value,
+		args: Vec<Value>,
+		hint: Option<usize>,
+	) -> Result<Vec<Value>> {
+		if self.depth >= MAX_HOST_DEPTH {
+			return Err(self.error("C stack overflow"));
+		}
+		let base = self.frame_top();
+		let frames = self.frames.len();
+		let nargs = args.len();
+		self.set(base, func.clone());
+		for (i, value) in args.into_iter().enumerate() {
+			self.set(base + 1 + i, value);
+		}
+
+		self.depth += 1;
+		let result = self
+			.call_at(base, nargs, Return::Host, hint)
+			.and_then(|_| self.execute(frames));
+		self.depth -= 1;
+
+		match result {
+			Ok(()) => Ok(std::mem::take(&mut self.results)),
+			Err(err) => {
+				let unwound = self
+					.frames
+					.get(frames)
+					.map(|frame| (frame.closure.clone(), frame.pc));
+				self.close_upvalues(base);
+				self.frames.truncate(frames);
+				self.stack.truncate(base);
+				let err = match err {
+					LuaError::Yield(_) => {
+						self.yielded = None;
+						self.error("attempt to yield across metamethod/C-call boundary")
+					}
+					err => err,
+				};
+				match unwound {
+					Some((closure, pc)) => Err(self.rethrow(err, &closure.proto, pc)),
+					None => Err(err),
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Limit, Limits, LuaError, Table, Value, Vm};
+	use crate::lua51::fixtures::{proto, string};
+	use bytecode::lua51::{instructions::Opcode, Constants, Proto};
+
+	fn run(vm: &mut Vm, proto: Proto) -> Vec<Value> {
+		let main = vm.load_proto(proto);
+		vm.call(&main, vec![]).unwrap()
+	}
+
+	#[test]
+	fn test_loop() {
+		// local s = 0 for i = 1, 10 do s = s + i end return s
+		let code = vec![
+			Opcode::encode_ABx(1, 0, 0),     // LOADK 0 0
+			Opcode::encode_ABx(1, 1, 1),     // LOADK 1 1
+			Opcode::encode_ABx(1, 2, 2),     // LOADK 2 10
+			Opcode::encode_ABx(1, 3, 1),     // LOADK 3 1
+			Opcode::encode_AsBx(32, 1, 1),   // FORPREP 1 1
+			Opcode::encode_ABC(12, 0, 0, 4), // ADD 0 0 4
+			Opcode::encode_AsBx(31, 1, -2),  // FORLOOP 1 -2
+			Opcode::encode_ABC(30, 0, 2, 0), // RETURN 0 2
+		];
+		let constants = vec![
+			Constants::Number(0.0),
+			Constants::Number(1.0),
+			Constants::Number(10.0),
+		];
+		let results = run(&mut Vm::new(), proto(code, constants, vec![]));
+		assert_eq!(results, [Value::Number(55.0)]);
+	}
+
+	#[test]
+	fn test_closures() {
+		// local n = 0
+		// local function inc(...) n = n + select('#', ...) return n, ... end
+		// inc(1, 2) return inc(3)
+		let inc = proto(
+			vec![
+				Opcode::encode_ABC(4, 0, 0, 0),  // GETUPVAL 0 0
+				Opcode::encode_ABx(5, 1, 0),     // GETGLOBAL 1 select
+				Opcode::encode_ABx(1, 2, 1),     // LOADK 2 "#"
+				Opcode::encode_ABC(37, 3, 0, 0), // VARARG 3 0
+				Opcode::encode_ABC(28, 1, 0, 2), // CALL 1 0 2
+				Opcode::encode_ABC(12, 0, 0, 1), // ADD 0 0 1
+				Opcode::encode_ABC(8, 0, 0, 0),  // SETUPVAL 0 0
+				Opcode::encode_ABC(37, 1, 0, 0), // VARARG 1 0
+				Opcode::encode_ABC(30, 0, 0, 0), // RETURN 0 0
+			],
+			vec![string("select"), string("#")],
+			vec![],
+		);
+		let inc = Proto {
+			nupvals: 1,
+			is_vararg_flag: 2,
+			..inc
+		};
+		let main = proto(
+			vec![
+				Opcode::encode_ABx(1, 0, 0),     // LOADK 0 0
+				Opcode::encode_ABx(36, 1, 0),    // CLOSURE 1 0
+				Opcode::encode_ABC(0, 0, 0, 0),  // MOVE 0 0
+				Opcode::encode_ABC(0, 2, 1, 0),  // MOVE 2 1
+				Opcode::encode_ABx(1, 3, 1),     // LOADK 3 1
+				Opcode::encode_ABx(1, 4, 2),     // LOADK 4 2
+				Opcode::encode_ABC(28, 2, 3, 1), // CALL 2 3 1
+				Opcode::encode_ABC(0, 2, 1, 0),  // MOVE 2 1
+				Opcode::encode_ABx(1, 3, 3),     // LOADK 3 3
+				Opcode::encode_ABC(29, 2, 2, 0), // TAILCALL 2 2 0
+				Opcode::encode_ABC(30, 2, 0, 0), // RETURN 2 0
+			],
+			vec![
+				Constants::Number(0.0),
+				Constants::Number(1.0),
+				Constants::Number(2.0),
+				Constants::Number(3.0),
+			],
+			vec![inc],
+		);
+
+		let mut vm = Vm::new();
+		vm.set_global(
+			"select",
+			Value::native("select", |_, args| {
+				Ok(vec![Value::Number((args.len() - 1) as f64)])
+			}),
+		);
+		let results = run(&mut vm, main);
+		assert_eq!(results, [Value::Number(3.0), Value::Number(3.0)]);
+	}
+
+	#[test]
+	fn test_metatables() {
+		// return t.x + 1, t.y
+		let code = vec![
+			Opcode::encode_ABx(5, 0, 0),           // GETGLOBAL 0 t
+			Opcode::encode_ABC(6, 0, 0, 256 + 1),  // GETTABLE 0 0 "x"
+			Opcode::encode_ABC(12, 0, 0, 256 + 2), // ADD 0 0 1
+			Opcode::encode_ABx(5, 1, 0),           // GETGLOBAL 1 t
+			Opcode::encode_ABC(6, 1, 1, 256 + 3),  // GETTABLE 1 1 "y"
+			Opcode::encode_ABC(30, 0, 3, 0),       // RETURN 0 3
+		];
+		let constants = vec![
+			string("t"),
+			string("x"),
+			Constants::Number(1.0),
+			string("y"),
+		];
+
+		let mut vm = Vm::new();
+		let mut fallback = Table::new();
+		fallback.set_str("y", Value::from("inherited"));
+		let mut meta = Table::new();
+		meta.set_str("__index", Value::table(fallback));
+		meta.set_str(
+			"__add",
+			Value::native("add", |_, _| Ok(vec![Value::from(42.0)])),
+		);
+		let mut x = Table::new();
+		x.metatable = Some(std::rc::Rc::new(std::cell::RefCell::new(meta)));
+		let mut t = Table::new();
+		t.set_str("x", Value::table(x));
+		vm.set_global("t", Value::table(t));
+
+		let results = run(&mut vm, proto(code.clone(), constants.clone(), vec![]));
+		assert_eq!(results, [Value::from(42.0), Value::Nil]);
+
+		vm.set_global("t", Value::Nil);
+		let main = vm.load_proto(proto(code, constants, vec![]));
+		let err = vm.call(&main, vec![]).unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"?:0: attempt to index global 't' (a nil value)"
+		);
+	}
+
+	#[test]
+	fn test_coroutine() {
+		// for i = 1, 3 do yield(i) end return "done"
+		let code = vec![
+			Opcode::encode_ABx(1, 0, 0),     // LOADK 0 1
+			Opcode::encode_ABx(1, 1, 1),     // LOADK 1 3
+			Opcode::encode_ABx(1, 2, 0),     // LOADK 2 1
+			Opcode::encode_AsBx(32, 0, 3),   // FORPREP 0 3
+			Opcode::encode_ABx(5, 4, 2),     // GETGLOBAL 4 yield
+			Opcode::encode_ABC(0, 5, 3, 0),  // MOVE 5 3
+			Opcode::encode_ABC(28, 4, 2, 1), // CALL 4 2 1
+			Opcode::encode_AsBx(31, 0, -4),  // FORLOOP 0 -4
+			Opcode::encode_ABx(1, 0, 3),     // LOADK 0 "done"
+			Opcode::encode_ABC(30, 0, 2, 0), // RETURN 0 2
+		];
+		let constants = vec![
+			Constants::Number(1.0),
+			Constants::Number(3.0),
+			string("yield"),
+			string("done"),
+		];
+
+		let mut vm = Vm::new();
+		vm.set_global(
+			"yield",
+			Value::native("yield", |vm, args| vm.yield_values(args)),
+		);
+		let body = vm.load_proto(proto(code, constants, vec![]));
+		let thread = vm.create_thread(body);
+		let mut results = vec![];
+		for _ in 0..4 {
+			results.extend(vm.resume(&thread, vec![]).unwrap());
+		}
+		assert_eq!(
+			results,
+			[
+				Value::from(1.0),
+				Value::from(2.0),
+				Value::from(3.0),
+				Value::from("done")
+			]
+		);
+		assert!(matches!(
+			vm.resume(&thread, vec![]),
+			Err(LuaError::Runtime(_))
+		));
+		assert!(vm.yield_values(vec![]).is_err());
+	}
+
+	#[test]
+	fn test_limits() {
+		// while true do end
+		let spin = proto(vec![Opcode::encode_AsBx(22, 0, -1)], vec![], vec![]);
+		let mut vm = Vm::new();
+		vm.set_limits(Limits {
+			instructions: Some(100),
+			..Limits::default()
+		});
+		let main = vm.load_proto(spin);
+		let Err(LuaError::Limit(err)) = vm.call(&main, vec![]) else {
+			panic!("budget not enforced");
+		};
+		assert_eq!((err.limit, err.pc), (Limit::Instructions, 0));
+		assert_eq!(vm.usage().instructions, 101);
+
+		// function f() return f() + 1 end f()
+		let f = proto(
+			vec![
+				Opcode::encode_ABx(5, 0, 0),           // GETGLOBAL 0 f
+				Opcode::encode_ABC(28, 0, 1, 2),       // CALL 0 1 2
+				Opcode::encode_ABC(12, 0, 0, 256 + 1), // ADD 0 0 1
+				Opcode::encode_ABC(30, 0, 2, 0),       // RETURN 0 2
+			],
+			vec![string("f"), Constants::Number(1.0)],
+			vec![],
+		);
+		let main = proto(
+			vec![
+				Opcode::encode_ABx(36, 0, 0),    // CLOSURE 0 0
+				Opcode::encode_ABx(7, 0, 0),     // SETGLOBAL 0 f
+				Opcode::encode_ABC(28, 0, 1, 1), // CALL 0 1 1
+				Opcode::encode_ABC(30, 0, 1, 0), // RETURN 0 1
+			],
+			vec![string("f")],
+			vec![f],
+		);
+		let mut vm = Vm::new();
+		vm.set_limits(Limits {
+			depth: Some(50),
+			..Limits::default()
+		});
+		let main = vm.load_proto(main);
+		let Err(LuaError::Limit(err)) = vm.call(&main, vec![]) else {
+			panic!("depth not enforced");
+		};
+		assert_eq!((err.limit, err.pc), (Limit::Depth, 1));
+		assert_eq!(vm.frames.len(), 0);
+	}
+}
@@ -0,0 +1,152 @@
+use super::value::Value;
+use crate::lua51::IRContext;
+use bytecode::lua51::{instructions::Opcode, Constants, Local};
+use std::rc::Rc;
+
+/// An instruction with its fields decoded up front
+#[derive(Debug, Clone, Copy)]
+pub struct Inst {
+	pub op: u8,
+	pub a: usize,
+	pub b: usize,
+	pub c: usize,
+	pub bx: usize,
+	pub sbx: isize,
+	/// the instruction as encoded, what a SETLIST with C = 0 reads
+	pub raw: u32,
+}
+
+impl Inst {
+	pub fn decode(raw: u32) -> Self {
+		let bx = (raw >> 14) as usize;
+		Self {
+			op: (raw & 0x3f) as u8,
+			a: ((raw >> 6) & 0xff) as usize,
+			b: ((raw >> 23) & 0x1ff) as usize,
+			c: ((raw >> 14) & 0x1ff) as usize,
+			bx,
+			sbx: bx as isize - 0x1ffff,
+			raw,
+		}
+	}
+}
+
+/**
+ * Prototype - A function ready to run: decoded instructions, constants as
+ * values and the debug info error messages use
+ */
+pub struct Prototype {
+	pub source: String,
+	/// whether `source` is its parent's, its own being empty
+	pub inherited: bool,
+	pub code: Vec<Inst>,
+	pub constants: Vec<Value>,
+	pub protos: Vec<Rc<Prototype>>,
+	pub nparams: usize,
+	pub vararg: u8,
+	pub max_stack: usize,
+	pub nupvalues: usize,
+	pub lines: Vec<u64>,
+	pub locals: Vec<Local>,
+	pub upvalue_names: Vec<String>,
+	/// indices of the closures leading to it from the main function of
+	/// its chunk, empty for the main function
+	pub path: Vec<usize>,
+}
+
+/// Vararg flag asking for the `arg` table of Lua 5.0
+pub const VARARG_NEEDSARG: u8 = 4;
+
+impl Prototype {
+	/// Prepares `ctx` found at `path` and its closures, which inherit its
+	/// source when their own is empty like in stripped chunks
+	pub fn new(ctx: &IRContext, parent: &str, path: &[usize]) -> Rc<Self> {
+		let source = match ctx.source() {
+			"" => parent.to_string(),
+			source => source.to_string(),
+		};
+		Rc::new(Self {
+			inherited: ctx.source().is_empty(),
+			code: ctx
+				.instructions
+				.to_instructions()
+				.iter()
+				.map(|(opcode, inst)| Inst::decode(Opcode::to_serialized(*opcode, inst)))
+				.collect(),
+			constants: ctx
+				.constants
+				.get_all()
+				.iter()
+				.map(|constant| match constant.get() {
+					Constants::Nil => Value::Nil,
+					Constants::Boolean(b) => Value::Boolean(*b),
+					Constants::Number(n) => Value::Number(*n),
+					Constants::String(s) => Value::string(s),
+				})
+				.collect(),
+			protos: ctx
+				.closures
+				.iter()
+				.enumerate()
+				.map(|(idx, closure)| Self::new(closure, &source, &[path, &[idx]].concat()))
+				.collect(),
+			nparams: ctx.nparams() as usize,
+			vararg: ctx.vararg(),
+			max_stack: ctx.max_stack_size() as usize,
+			nupvalues: ctx.nupvalues() as usize,
+			lines: match ctx.instructions.iter().any(|inst| inst.line().is_some()) {
+				true => ctx.instructions.lines(),
+				false => vec![],
+			},
+			locals: ctx.locals().to_vec(),
+			upvalue_names: ctx.upvalue_names().to_vec(),
+			path: path.to_vec(),
+			source,
+		})
+	}
+
+	/// Name of the chunk as error messages show it, luaO_chunkid
+	pub fn chunk_name(&self) -> String {
+		const ID_SIZE: usize = 60;
+		let source = self.source.as_bytes();
+		let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+		match source.first() {
+			None => "?".to_string(),
+			Some(b'=') => text(&source[1..source.len().min(ID_SIZE)]),
+			Some(b'@') => {
+				let name = &source[1..];
+				let room = ID_SIZE - " '...' ".len() - 1;
+				match name.len() > room {
+					true => format!("...{}", text(&name[name.len() - room..])),
+					false => text(name),
+				}
+			}
+			Some(_) => {
+				let line = source
+					.iter()
+					.position(|c| matches!(c, b'\n' | b'\r'))
+					.unwrap_or(source.len());
+				let room = ID_SIZE - " [string \"...\"] ".len() - 1;
+				let len = line.min(room);
+				match len < source.len() {
+					true => format!("[string \"{}...\"]", text(&source[..len])),
+					false => format!("[string \"{}\"]", text(source)),
+				}
+			}
+		}
+	}
+
+	/// Source line of the instruction at `pc`
+	pub fn line(&self, pc: usize) -> Option<u64> {
+		self.lines.get(pc).copied()
+	}
+
+	/// Name of the local in `reg` at `pc`, from debug info
+	pub fn local_name(&self, reg: usize, pc: usize) -> Option<&str> {
+		self.locals
+			.iter()
+			.filter(|local| local.start_pc as usize <= pc && pc < local.end_pc as usize)
+			.nth(reg)
+			.map(|local| local.name.as_str())
+	}
+}
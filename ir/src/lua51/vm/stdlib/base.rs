@@ -0,0 +1,400 @@
+use super::{
+	arg, arg_error, check_any, check_integer, check_string, check_table, opt_integer, register,
+	type_expected,
+};
+use crate::lua51::vm::{Function, LuaError, Result, Value, Vm};
+use std::rc::Rc;
+
+/// Results `unpack` gives at most, LUAI_MAXCSTACK
+const MAX_UNPACK: i64 = 8000;
+
+pub fn open(vm: &mut Vm) {
+	let globals = vm.globals();
+	register(
+		&globals,
+		&[
+			("assert", assert),
+			("collectgarbage", collectgarbage),
+			("error", error),
+			("getfenv", getfenv),
+			("getmetatable", getmetatable),
+			("ipairs", ipairs),
+			("next", next),
+			("pairs", pairs),
+			("pcall", pcall),
+			("print", print),
+			("rawequal", rawequal),
+			("rawget", rawget),
+			("rawset", rawset),
+			("select", select),
+			("setfenv", setfenv),
+			("setmetatable", setmetatable),
+			("tonumber", tonumber),
+			("tostring", tostring),
+			("type", type_),
+			("unpack", unpack),
+			("xpcall", xpcall),
+		],
+	);
+	vm.set_global("_G", Value::Table(globals));
+	vm.set_global("_VERSION", Value::from("Lua 5.1"));
+}
+
+impl Vm {
+	/// `tostring`, with the __tostring metamethod
+	pub fn tostring(&mut self, value: &Value) -> Result<Value> {
+		let handler = self.metamethod(value, "__tostring");
+		if !handler.is_nil() {
+			return Ok(self
+				.call(&handler, vec![value.clone()])?
+				.into_iter()
+				.next()
+				.unwrap_or_default());
+		}
+		match value {
+			Value::String(_) => Ok(value.clone()),
+			_ if value.address().is_some() => {
+				let id = self.object_id(value)?;
+				Ok(Value::string(format!("{}: {id:#010x}", value.type_name())))
+			}
+			_ => Ok(Value::string(value.to_string())),
+		}
+	}
+
+	/// Number `tostring` shows an object by instead of its address, given
+	/// out in order so output does not depend on the allocator. Objects
+	/// are kept alive so their addresses are not reused
+	fn object_id(&mut self, value: &Value) -> Result<usize> {
+		let address = value.address().unwrap();
+		if let Some((id, _)) = self.ids.get(&address) {
+			return Ok(*id);
+		}
+		self.charge_slots(1)?;
+		let id = self.ids.len() + 1;
+		self.ids.insert(address, (id, value.clone()));
+		Ok(id)
+	}
+}
+
+fn assert(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	if arg(&args, 1).truthy() {
+		return Ok(args);
+	}
+	check_any(vm, "assert", &args, 1)?;
+	let msg = match arg(&args, 2) {
+		Value::Nil => "assertion failed!".to_string(),
+		_ => String::from_utf8_lossy(&check_string(vm, "assert", &args, 2)?).into_owned(),
+	};
+	Err(vm.error(msg))
+}
+
+fn collectgarbage(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let option = match arg(&args, 1) {
+		Value::Nil => Rc::from(&b"collect"[..]),
+		_ => check_string(vm, "collectgarbage", &args, 1)?,
+	};
+	match &*option {
+		b"count" => Ok(vec![Value::Number(vm.usage().memory as f64 / 1024.0)]),
+		b"step" => Ok(vec![Value::Boolean(false)]),
+		b"collect" | b"stop" | b"restart" | b"setpause" | b"setstepmul" => {
+			Ok(vec![Value::Number(0.0)])
+		}
+		option => Err(arg_error(
+			vm,
+			"collectgarbage",
+			1,
+			format!("invalid option '{}'", String::from_utf8_lossy(option)),
+		)),
+	}
+}
+
+fn error(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let value = arg(&args, 1);
+	let level = opt_integer(vm, "error", &args, 2, 1)?;
+	let value = match &value {
+		Value::String(_) | Value::Number(_) if level > 0 => {
+			let mut msg = vm.location(level as usize).into_bytes();
+			msg.extend_from_slice(&value.to_bytes().unwrap());
+			Value::string(msg)
+		}
+		_ => value,
+	};
+	Err(LuaError::Runtime(value))
+}
+
+/// Function `getfenv` and `setfenv` act on: the argument itself or the
+/// Lua function that many levels up, None for level 0
+fn env_function(vm: &Vm, name: &str, args: &[Value]) -> Result<Option<Value>> {
+	if let value @ Value::Function(_) = arg(args, 1) {
+		return Ok(Some(value));
+	}
+	let level = opt_integer(vm, name, args, 1, 1)?;
+	if level < 0 {
+		return Err(arg_error(vm, name, 1, "level must be non-negative"));
+	}
+	if level == 0 {
+		return Ok(None);
+	}
+	match vm.frames.iter().rev().nth(level as usize - 1) {
+		Some(frame) => Ok(Some(Value::Function(Function::Lua(frame.closure.clone())))),
+		None => Err(arg_error(vm, name, 1, "invalid level")),
+	}
+}
+
+fn getfenv(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	match env_function(vm, "getfenv", &args)? {
+		Some(Value::Function(Function::Lua(closure))) => {
+			Ok(vec![Value::Table(closure.env.borrow().clone())])
+		}
+		_ => Ok(vec![Value::Table(vm.globals())]),
+	}
+}
+
+fn setfenv(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let env = check_table(vm, "setfenv", &args, 2)?;
+	match env_function(vm, "setfenv", &args)? {
+		None => {
+			vm.globals = env;
+			Ok(vec![])
+		}
+		Some(Value::Function(Function::Lua(closure))) => {
+			*closure.env.borrow_mut() = env;
+			Ok(vec![Value::Function(Function::Lua(closure))])
+		}
+		Some(_) => Err(vm.error("'setfenv' cannot change environment of given object")),
+	}
+}
+
+fn getmetatable(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let value = check_any(vm, "getmetatable", &args, 1)?;
+	let Some(meta) = vm.metatable(&value) else {
+		return Ok(vec![Value::Nil]);
+	};
+	let protected = meta.borrow().get_str("__metatable");
+	match protected {
+		Value::Nil => Ok(vec![Value::Table(meta)]),
+		protected => Ok(vec![protected]),
+	}
+}
+
+fn setmetatable(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "setmetatable", &args, 1)?;
+	let meta = match arg(&args, 2) {
+		Value::Nil => None,
+		Value::Table(meta) => Some(meta),
+		_ => return Err(type_expected(vm, "setmetatable", &args, 2, "nil or table")),
+	};
+	let protected = table
+		.borrow()
+		.metatable
+		.as_ref()
+		.is_some_and(|current| !current.borrow().get_str("__metatable").is_nil());
+	if protected {
+		return Err(vm.error("cannot change a protected metatable"));
+	}
+	table.borrow_mut().metatable = meta;
+	Ok(vec![Value::Table(table)])
+}
+
+fn ipairs_next(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "ipairs", &args, 1)?;
+	let idx = check_integer(vm, "ipairs", &args, 2)? + 1;
+	let value = table.borrow().get(&Value::Number(idx as f64));
+	match value {
+		Value::Nil => Ok(vec![Value::Nil]),
+		value => Ok(vec![Value::Number(idx as f64), value]),
+	}
+}
+
+fn ipairs(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let value = check_any(vm, "ipairs", &args, 1)?;
+	Ok(vec![
+		Value::native("ipairs_next", ipairs_next),
+		value,
+		Value::Number(0.0),
+	])
+}
+
+fn next(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "next", &args, 1)?;
+	let entry = table.borrow().next(&arg(&args, 2));
+	match entry {
+		Some(Some((key, value))) => Ok(vec![key, value]),
+		Some(None) => Ok(vec![Value::Nil]),
+		None => Err(vm.error("invalid key to 'next'")),
+	}
+}
+
+fn pairs(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "pairs", &args, 1)?;
+	Ok(vec![
+		Value::native("next", next),
+		Value::Table(table),
+		Value::Nil,
+	])
+}
+
+fn pcall(vm: &mut Vm, mut args: Vec<Value>) -> Result<Vec<Value>> {
+	let func = check_any(vm, "pcall", &args, 1)?;
+	args.remove(0);
+	match vm.call(&func, args) {
+		Ok(mut results) => {
+			results.insert(0, Value::Boolean(true));
+			Ok(results)
+		}
+		Err(LuaError::Runtime(value)) => Ok(vec![Value::Boolean(false), value]),
+		Err(err) => Err(err),
+	}
+}
+
+fn xpcall(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let func = arg(&args, 1);
+	let handler = check_any(vm, "xpcall", &args, 2)?;
+	match vm.call(&func, vec![]) {
+		Ok(mut results) => {
+			results.insert(0, Value::Boolean(true));
+			Ok(results)
+		}
+		Err(LuaError::Runtime(value)) => {
+			let value = match vm.call(&handler, vec![value]) {
+				Ok(results) => results.into_iter().next().unwrap_or_default(),
+				Err(LuaError::Runtime(value)) => value,
+				Err(err) => return Err(err),
+			};
+			Ok(vec![Value::Boolean(false), value])
+		}
+		Err(err) => Err(err),
+	}
+}
+
+fn print(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let mut line = vec![];
+	for (i, value) in args.iter().enumerate() {
+		let Value::String(s) = vm.tostring(value)? else {
+			return Err(vm.error("'tostring' must return a string to 'print'"));
+		};
+		if i > 0 {
+			line.push(b'\t');
+		}
+		line.extend_from_slice(&s);
+	}
+	line.push(b'\n');
+	vm.write(&line);
+	Ok(vec![])
+}
+
+fn rawequal(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let a = check_any(vm, "rawequal", &args, 1)?;
+	let b = check_any(vm, "rawequal", &args, 2)?;
+	Ok(vec![Value::Boolean(a == b)])
+}
+
+fn rawget(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "rawget", &args, 1)?;
+	let key = check_any(vm, "rawget", &args, 2)?;
+	let value = table.borrow().get(&key);
+	Ok(vec![value])
+}
+
+fn rawset(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "rawset", &args, 1)?;
+	let key = check_any(vm, "rawset", &args, 2)?;
+	let value = check_any(vm, "rawset", &args, 3)?;
+	vm.charge_set(&table, &key, &value)?;
+	let result = table.borrow_mut().set(key, value);
+	result.map_err(|msg| vm.error(msg))?;
+	Ok(vec![Value::Table(table)])
+}
+
+fn select(vm: &mut Vm, mut args: Vec<Value>) -> Result<Vec<Value>> {
+	let total = args.len() as i64;
+	if let Value::String(s) = arg(&args, 1) {
+		if s.first() == Some(&b'#') {
+			return Ok(vec![Value::Number((total - 1) as f64)]);
+		}
+	}
+	let n = match check_integer(vm, "select", &args, 1)? {
+		n if n < 0 => total + n,
+		n => n.min(total),
+	};
+	if n < 1 {
+		return Err(arg_error(vm, "select", 1, "index out of range"));
+	}
+	Ok(args.split_off(n as usize))
+}
+
+/// Integer in `base` like strtoul, surrounding whitespace allowed
+fn parse_integer(s: &[u8], base: u32) -> Option<f64> {
+	let s = std::str::from_utf8(s)
+		.ok()?
+		.trim_matches(|c: char| c.is_ascii_whitespace());
+	let (negative, digits) = match s.strip_prefix('-') {
+		Some(rest) => (true, rest),
+		None => (false, s.strip_prefix('+').unwrap_or(s)),
+	};
+	if digits.is_empty() {
+		return None;
+	}
+	let n = digits.chars().try_fold(0.0, |n, c| {
+		c.to_digit(base).map(|digit| n * base as f64 + digit as f64)
+	})?;
+	Some(if negative { -n } else { n })
+}
+
+fn tonumber(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let base = opt_integer(vm, "tonumber", &args, 2, 10)?;
+	if base == 10 {
+		let value = check_any(vm, "tonumber", &args, 1)?;
+		return Ok(vec![value.to_number().map_or(Value::Nil, Value::Number)]);
+	}
+	let s = check_string(vm, "tonumber", &args, 1)?;
+	if !(2..=36).contains(&base) {
+		return Err(arg_error(vm, "tonumber", 2, "base out of range"));
+	}
+	Ok(vec![
+		parse_integer(&s, base as u32).map_or(Value::Nil, Value::Number)
+	])
+}
+
+fn tostring(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let value = check_any(vm, "tostring", &args, 1)?;
+	Ok(vec![vm.tostring(&value)?])
+}
+
+fn type_(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let value = check_any(vm, "type", &args, 1)?;
+	Ok(vec![Value::from(value.type_name())])
+}
+
+fn unpack(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "unpack", &args, 1)?;
+	let first = opt_integer(vm, "unpack", &args, 2, 1)?;
+	let last = match arg(&args, 3) {
+		Value::Nil => table.borrow().len() as i64,
+		_ => check_integer(vm, "unpack", &args, 3)?,
+	};
+	if first > last {
+		return Ok(vec![]);
+	}
+	if last.saturating_sub(first) >= MAX_UNPACK {
+		return Err(vm.error("too many results to unpack"));
+	}
+	let table = table.borrow();
+	Ok((first..=last)
+		.map(|idx| table.get(&Value::Number(idx as f64)))
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::parse_integer;
+
+	#[test]
+	fn test_parse_integer() {
+		assert_eq!(parse_integer(b" ff ", 16), Some(255.0));
+		assert_eq!(parse_integer(b"-101", 2), Some(-5.0));
+		assert_eq!(parse_integer(b"z", 36), Some(35.0));
+		assert_eq!(parse_integer(b"12", 2), None);
+		assert_eq!(parse_integer(b"", 8), None);
+	}
+}
@@ -0,0 +1,80 @@
+use super::{arg, arg_error, library, type_expected};
+use crate::lua51::vm::{Function, LuaError, Result, ThreadRef, Value, Vm};
+
+pub fn open(vm: &mut Vm) {
+	library(
+		vm,
+		"coroutine",
+		&[
+			("create", create),
+			("resume", resume),
+			("running", running),
+			("status", status),
+			("wrap", wrap),
+			("yield", yield_),
+		],
+	);
+}
+
+fn check_thread(vm: &Vm, name: &str, args: &[Value], n: usize) -> Result<ThreadRef> {
+	match arg(args, n) {
+		Value::Thread(thread) => Ok(thread),
+		_ => Err(type_expected(vm, name, args, n, "coroutine")),
+	}
+}
+
+fn check_function(vm: &Vm, name: &str, args: &[Value]) -> Result<Value> {
+	match arg(args, 1) {
+		value @ Value::Function(Function::Lua(_)) => Ok(value),
+		_ => Err(arg_error(vm, name, 1, "Lua function expected")),
+	}
+}
+
+fn create(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let body = check_function(vm, "create", &args)?;
+	vm.charge_thread()?;
+	Ok(vec![Value::Thread(vm.create_thread(body))])
+}
+
+fn resume(vm: &mut Vm, mut args: Vec<Value>) -> Result<Vec<Value>> {
+	let thread = check_thread(vm, "resume", &args, 1)?;
+	args.remove(0);
+	match vm.resume(&thread, args) {
+		Ok(mut results) => {
+			results.insert(0, Value::Boolean(true));
+			Ok(results)
+		}
+		Err(LuaError::Runtime(value)) => Ok(vec![Value::Boolean(false), value]),
+		Err(err) => Err(err),
+	}
+}
+
+fn running(vm: &mut Vm, _: Vec<Value>) -> Result<Vec<Value>> {
+	Ok(vec![vm.running().map_or(Value::Nil, Value::Thread)])
+}
+
+fn status(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let thread = check_thread(vm, "status", &args, 1)?;
+	Ok(vec![Value::from(vm.thread_status(&thread).name())])
+}
+
+fn wrap(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let body = check_function(vm, "wrap", &args)?;
+	vm.charge_thread()?;
+	let thread = vm.create_thread(body);
+	Ok(vec![Value::native("wrap", move |vm, args| {
+		match vm.resume(&thread, args) {
+			// errors get the position of the caller like `error` adds
+			Err(LuaError::Runtime(value @ (Value::String(_) | Value::Number(_)))) => {
+				let mut msg = vm.location(1).into_bytes();
+				msg.extend_from_slice(&value.to_bytes().unwrap());
+				Err(LuaError::Runtime(Value::string(msg)))
+			}
+			result => result,
+		}
+	})])
+}
+
+fn yield_(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	vm.yield_values(args)
+}
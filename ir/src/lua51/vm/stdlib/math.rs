@@ -0,0 +1,165 @@
+use super::{arg_error, check_integer, check_number, library, LibFn};
+use crate::lua51::vm::{Result, Value, Vm};
+use std::{cell::Cell, rc::Rc};
+
+/// Seed `math.random` starts from, so runs are reproducible
+const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+pub fn open(vm: &mut Vm) {
+	let functions: &[(&str, LibFn)] = &[
+		("abs", |vm, args| unary(vm, "abs", args, f64::abs)),
+		("acos", |vm, args| unary(vm, "acos", args, f64::acos)),
+		("asin", |vm, args| unary(vm, "asin", args, f64::asin)),
+		("atan", |vm, args| unary(vm, "atan", args, f64::atan)),
+		("atan2", |vm, args| binary(vm, "atan2", args, f64::atan2)),
+		("ceil", |vm, args| unary(vm, "ceil", args, f64::ceil)),
+		("cos", |vm, args| unary(vm, "cos", args, f64::cos)),
+		("cosh", |vm, args| unary(vm, "cosh", args, f64::cosh)),
+		("deg", |vm, args| unary(vm, "deg", args, f64::to_degrees)),
+		("exp", |vm, args| unary(vm, "exp", args, f64::exp)),
+		("floor", |vm, args| unary(vm, "floor", args, f64::floor)),
+		("fmod", |vm, args| binary(vm, "fmod", args, |a, b| a % b)),
+		("frexp", frexp),
+		("ldexp", ldexp),
+		("log", |vm, args| unary(vm, "log", args, f64::ln)),
+		("log10", |vm, args| unary(vm, "log10", args, f64::log10)),
+		("max", |vm, args| {
+			fold(vm, "max", args, |a, b| if b > a { b } else { a })
+		}),
+		("min", |vm, args| {
+			fold(vm, "min", args, |a, b| if b < a { b } else { a })
+		}),
+		("modf", modf),
+		("pow", |vm, args| binary(vm, "pow", args, f64::powf)),
+		("rad", |vm, args| unary(vm, "rad", args, f64::to_radians)),
+		("sin", |vm, args| unary(vm, "sin", args, f64::sin)),
+		("sinh", |vm, args| unary(vm, "sinh", args, f64::sinh)),
+		("sqrt", |vm, args| unary(vm, "sqrt", args, f64::sqrt)),
+		("tan", |vm, args| unary(vm, "tan", args, f64::tan)),
+		("tanh", |vm, args| unary(vm, "tanh", args, f64::tanh)),
+	];
+	let math = library(vm, "math", functions);
+	let mut math = math.borrow_mut();
+	math.set_str("pi", Value::Number(std::f64::consts::PI));
+	math.set_str("huge", Value::Number(f64::INFINITY));
+
+	let state = Rc::new(Cell::new(DEFAULT_SEED));
+	let seed = state.clone();
+	math.set_str(
+		"random",
+		Value::native("random", move |vm, args| random(vm, args, &state)),
+	);
+	math.set_str(
+		"randomseed",
+		Value::native("randomseed", move |vm, args| {
+			let n = check_integer(vm, "randomseed", &args, 1)?;
+			seed.set((DEFAULT_SEED ^ n as u64).max(1));
+			Ok(vec![])
+		}),
+	);
+}
+
+fn unary(vm: &mut Vm, name: &str, args: Vec<Value>, f: fn(f64) -> f64) -> Result<Vec<Value>> {
+	let x = check_number(vm, name, &args, 1)?;
+	Ok(vec![Value::Number(f(x))])
+}
+
+fn binary(vm: &mut Vm, name: &str, args: Vec<Value>, f: fn(f64, f64) -> f64) -> Result<Vec<Value>> {
+	let x = check_number(vm, name, &args, 1)?;
+	let y = check_number(vm, name, &args, 2)?;
+	Ok(vec![Value::Number(f(x, y))])
+}
+
+fn fold(vm: &mut Vm, name: &str, args: Vec<Value>, f: fn(f64, f64) -> f64) -> Result<Vec<Value>> {
+	let mut result = check_number(vm, name, &args, 1)?;
+	for n in 2..=args.len() {
+		result = f(result, check_number(vm, name, &args, n)?);
+	}
+	Ok(vec![Value::Number(result)])
+}
+
+/// `x` split into a mantissa in [0.5, 1) and a power of two
+fn split_exponent(x: f64) -> (f64, i32) {
+	if x == 0.0 || !x.is_finite() {
+		return (x, 0);
+	}
+	// scale subnormals into the normal range first
+	let (x, bias) = match x.abs() < f64::MIN_POSITIVE {
+		true => (x * 2f64.powi(64), -64),
+		false => (x, 0),
+	};
+	let bits = x.to_bits();
+	let exponent = ((bits >> 52) & 0x7ff) as i32 - 1022;
+	let mantissa = f64::from_bits((bits & !(0x7ff << 52)) | (1022 << 52));
+	(mantissa, exponent + bias)
+}
+
+fn frexp(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let (mantissa, exponent) = split_exponent(check_number(vm, "frexp", &args, 1)?);
+	Ok(vec![
+		Value::Number(mantissa),
+		Value::Number(exponent as f64),
+	])
+}
+
+fn ldexp(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let mut x = check_number(vm, "ldexp", &args, 1)?;
+	let mut exponent = check_integer(vm, "ldexp", &args, 2)?.clamp(-10000, 10000) as i32;
+	// in steps, so the power of two itself never overflows
+	while exponent > 1000 {
+		x *= 2f64.powi(1000);
+		exponent -= 1000;
+	}
+	while exponent < -1000 {
+		x *= 2f64.powi(-1000);
+		exponent += 1000;
+	}
+	Ok(vec![Value::Number(x * 2f64.powi(exponent))])
+}
+
+fn modf(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let x = check_number(vm, "modf", &args, 1)?;
+	let fraction = match x.is_infinite() {
+		true => 0.0,
+		false => x.fract(),
+	};
+	Ok(vec![Value::Number(x.trunc()), Value::Number(fraction)])
+}
+
+fn random(vm: &mut Vm, args: Vec<Value>, state: &Cell<u64>) -> Result<Vec<Value>> {
+	// xorshift64*
+	let mut x = state.get();
+	x ^= x >> 12;
+	x ^= x << 25;
+	x ^= x >> 27;
+	state.set(x);
+	let r = (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64;
+
+	let (low, high) = match args.len() {
+		0 => return Ok(vec![Value::Number(r)]),
+		1 => (1, check_integer(vm, "random", &args, 1)?),
+		2 => (
+			check_integer(vm, "random", &args, 1)?,
+			check_integer(vm, "random", &args, 2)?,
+		),
+		_ => return Err(vm.error("wrong number of arguments")),
+	};
+	if low > high {
+		return Err(arg_error(vm, "random", args.len(), "interval is empty"));
+	}
+	let n = (r * (high - low + 1) as f64).floor() + low as f64;
+	Ok(vec![Value::Number(n)])
+}
+
+#[cfg(test)]
+mod tests {
+	use super::split_exponent;
+
+	#[test]
+	fn test_split_exponent() {
+		assert_eq!(split_exponent(8.0), (0.5, 4));
+		assert_eq!(split_exponent(-3.0), (-0.75, 2));
+		assert_eq!(split_exponent(0.0), (0.0, 0));
+		assert_eq!(split_exponent(f64::from_bits(1)), (0.5, -1073));
+	}
+}
@@ -0,0 +1,297 @@
+mod base;
+mod coroutine;
+mod math;
+mod pattern;
+mod string;
+mod table;
+
+pub use pattern::{find, match_at, Capture, Match};
+pub use string::format;
+
+use super::{LuaError, Result, Table, TableRef, Value, Vm};
+use std::{cell::RefCell, fmt::Display, rc::Rc};
+
+pub type LibFn = fn(&mut Vm, Vec<Value>) -> Result<Vec<Value>>;
+
+impl Vm {
+	/// Opens the base, coroutine, string, table and math libraries
+	pub fn open_libs(&mut self) {
+		base::open(self);
+		coroutine::open(self);
+		string::open(self);
+		table::open(self);
+		math::open(self);
+	}
+
+	/// Opens only what `allowed` names, a global like "print" or "string"
+	/// or a single library function like "string.format", for running
+	/// untrusted chunks. Strings index the allowed part of `string`
+	pub fn open_sandbox(&mut self, allowed: &[&str]) {
+		let globals = std::mem::replace(&mut self.globals, Rc::new(RefCell::new(Table::new())));
+		self.open_libs();
+		let all = std::mem::replace(&mut self.globals, globals);
+		let all = all.borrow();
+
+		for name in allowed {
+			let (global, field) = match name.split_once('.') {
+				Some((global, field)) => (global, Some(field)),
+				None => (*name, None),
+			};
+			if global == "_G" {
+				self.set_global(global, Value::Table(self.globals()));
+				continue;
+			}
+			let value = all.get_str(global);
+			let Value::Table(library) = &value else {
+				self.set_global(global, value);
+				continue;
+			};
+			// libraries are copied, so allowing a function adds it to them
+			let target = match self.global(global) {
+				Value::Table(target) => target,
+				_ => {
+					let target = Rc::new(RefCell::new(Table::new()));
+					self.set_global(global, Value::Table(target.clone()));
+					target
+				}
+			};
+			let library = library.borrow();
+			let mut key = Value::Nil;
+			while let Some(Some((k, v))) = library.next(&key) {
+				if field.is_none() || k.to_str_lossy().as_deref() == field {
+					target.borrow_mut().set(k.clone(), v).unwrap();
+				}
+				key = k;
+			}
+		}
+
+		let meta = match self.global("string") {
+			Value::Table(string) => {
+				let mut meta = Table::new();
+				meta.set_str("__index", Value::Table(string));
+				Some(Rc::new(RefCell::new(meta)))
+			}
+			_ => None,
+		};
+		self.set_string_metatable(meta);
+	}
+}
+
+fn register(table: &TableRef, functions: &[(&str, LibFn)]) {
+	let mut table = table.borrow_mut();
+	for (name, func) in functions {
+		table.set_str(name, Value::native(name, *func));
+	}
+}
+
+/// Table of `functions`, set as global `name`
+fn library(vm: &mut Vm, name: &str, functions: &[(&str, LibFn)]) -> TableRef {
+	let table = Rc::new(RefCell::new(Table::new()));
+	register(&table, functions);
+	vm.set_global(name, Value::Table(table.clone()));
+	table
+}
+
+fn arg(args: &[Value], n: usize) -> Value {
+	args.get(n - 1).cloned().unwrap_or_default()
+}
+
+fn arg_error(vm: &Vm, name: &str, n: usize, msg: impl Display) -> LuaError {
+	vm.error(format!("bad argument #{n} to '{name}' ({msg})"))
+}
+
+fn type_expected(vm: &Vm, name: &str, args: &[Value], n: usize, expected: &str) -> LuaError {
+	let got = args.get(n - 1).map_or("no value", Value::type_name);
+	arg_error(vm, name, n, format!("{expected} expected, got {got}"))
+}
+
+fn check_any(vm: &Vm, name: &str, args: &[Value], n: usize) -> Result<Value> {
+	match args.get(n - 1) {
+		Some(value) => Ok(value.clone()),
+		None => Err(arg_error(vm, name, n, "value expected")),
+	}
+}
+
+fn check_number(vm: &Vm, name: &str, args: &[Value], n: usize) -> Result<f64> {
+	arg(args, n)
+		.to_number()
+		.ok_or_else(|| type_expected(vm, name, args, n, "number"))
+}
+
+/// Number argument truncated to an integer like `lua_tointeger`
+fn check_integer(vm: &Vm, name: &str, args: &[Value], n: usize) -> Result<i64> {
+	Ok(check_number(vm, name, args, n)? as i64)
+}
+
+fn opt_integer(vm: &Vm, name: &str, args: &[Value], n: usize, default: i64) -> Result<i64> {
+	match arg(args, n) {
+		Value::Nil => Ok(default),
+		_ => check_integer(vm, name, args, n),
+	}
+}
+
+fn check_string(vm: &Vm, name: &str, args: &[Value], n: usize) -> Result<Rc<[u8]>> {
+	arg(args, n)
+		.to_bytes()
+		.ok_or_else(|| type_expected(vm, name, args, n, "string"))
+}
+
+fn check_table(vm: &Vm, name: &str, args: &[Value], n: usize) -> Result<TableRef> {
+	match arg(args, n) {
+		Value::Table(table) => Ok(table),
+		_ => Err(type_expected(vm, name, args, n, "table")),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::lua51::{
+		fixtures::{proto, string},
+		vm::{Compat, Limit, Limits, LuaError, Value, Vm},
+		IRContext,
+	};
+	use bytecode::{
+		lua51::{compile, deserialize_bytecode, instructions::Opcode, Constants, Proto},
+		Bytecode,
+	};
+	use std::{cell::RefCell, env::temp_dir, fs::canonicalize, path::Path, rc::Rc};
+
+	fn compile_file(path: &str) -> Bytecode {
+		let path = canonicalize(Path::new(path)).expect("Unable to find test file");
+		compile(&path).expect("Unable to compile bytecode")
+	}
+
+	#[test]
+	fn test_rerubi() {
+		let rerubi = compile_file("../examples/rerubi.lua");
+		let hello = compile_file("../examples/hello.lua");
+
+		let output = Rc::new(RefCell::new(vec![]));
+		let mut vm = Vm::new();
+		vm.open_libs();
+		let sink = output.clone();
+		vm.set_output(move |bytes| sink.borrow_mut().extend_from_slice(bytes));
+
+		let (_, proto) = deserialize_bytecode(&rerubi);
+		let main = vm.load(&IRContext::from_proto(proto));
+		let load = vm.call(&main, vec![]).unwrap().remove(0);
+		let chunk = vm
+			.call(&load, vec![Value::string(hello.buff)])
+			.unwrap()
+			.remove(0);
+		vm.call(&chunk, vec![]).unwrap();
+		assert_eq!(output.borrow().as_slice(), b"Hello, World!\n");
+	}
+
+	#[test]
+	fn test_rerubi_compat() {
+		let source = r##"
+			local function count(a, ...) return select("#", ...) end
+			local function pass(...) return ... end
+			local x
+			print(count(1, 2, 3), x == false, "a" .. 1 .. 2)
+			print(pcall(function() error("inner", 0) end))
+			print(coroutine.resume(coroutine.create(function() coroutine.yield(1) end)))
+			return pass(1, nil, 2, nil)
+		"##;
+		let path = temp_dir().join(format!("rerubi-{}.lua", std::process::id()));
+		std::fs::write(&path, source).unwrap();
+		let chunk = compile(&path).expect("Unable to compile bytecode");
+		let _ = std::fs::remove_file(&path);
+		let rerubi = compile_file("../examples/rerubi.lua");
+
+		// what a run gives back and prints, under rerubi.lua or emulating it
+		let run = |under_rerubi: bool| {
+			let output = Rc::new(RefCell::new(vec![]));
+			let mut vm = Vm::new();
+			vm.open_libs();
+			let sink = output.clone();
+			vm.set_output(move |bytes| sink.borrow_mut().extend_from_slice(bytes));
+			let main = match under_rerubi {
+				true => {
+					let (_, proto) = deserialize_bytecode(&rerubi);
+					let main = vm.load(&IRContext::from_proto(proto));
+					let load = vm.call(&main, vec![]).unwrap().remove(0);
+					let args = vec![Value::string(&chunk.buff)];
+					vm.call(&load, args).unwrap().remove(0)
+				}
+				false => {
+					vm.set_compat(Compat::Rerubi);
+					let (_, proto) = deserialize_bytecode(&chunk);
+					vm.load(&IRContext::from_proto(proto))
+				}
+			};
+			let results = vm.call(&main, vec![]).map_err(|err| err.to_string());
+			let output = output.take();
+			(results, output)
+		};
+		let (results, output) = run(true);
+		assert_eq!(results, Ok(vec![Value::from(1.0), Value::from(2.0)]));
+		assert_eq!((results, output), run(false));
+	}
+
+	#[test]
+	fn test_error_position() {
+		// return pcall(error, "x"), pcall(error, "y", 2)
+		let code = [
+			Opcode::encode_ABx(5, 0, 0),     // GETGLOBAL 0 pcall
+			Opcode::encode_ABx(5, 1, 1),     // GETGLOBAL 1 error
+			Opcode::encode_ABx(1, 2, 2),     // LOADK 2 "x"
+			Opcode::encode_ABC(28, 0, 3, 3), // CALL 0 3 3
+			Opcode::encode_ABx(5, 2, 0),     // GETGLOBAL 2 pcall
+			Opcode::encode_ABx(5, 3, 1),     // GETGLOBAL 3 error
+			Opcode::encode_ABx(1, 4, 3),     // LOADK 4 "y"
+			Opcode::encode_ABx(1, 5, 4),     // LOADK 5 2
+			Opcode::encode_ABC(28, 2, 4, 0), // CALL 2 4 0
+			Opcode::encode_ABC(30, 0, 0, 0), // RETURN 0 0
+		];
+		let constants = ["pcall", "error", "x", "y"]
+			.map(string)
+			.into_iter()
+			.chain([Constants::Number(2.0)])
+			.collect();
+		let proto = Proto {
+			source: "=test".to_string(),
+			source_lines: Some(vec![1; code.len()]),
+			..proto(code.to_vec(), constants, vec![])
+		};
+
+		let mut vm = Vm::new();
+		vm.open_libs();
+		let main = vm.load_proto(proto);
+		let results = vm.call(&main, vec![]).unwrap();
+		// error blames pcall, a native, and level 2 the chunk calling it
+		assert_eq!(
+			results,
+			[
+				Value::Boolean(false),
+				Value::from("x"),
+				Value::Boolean(false),
+				Value::from("test:1: y"),
+			]
+		);
+	}
+
+	#[test]
+	fn test_sandbox() {
+		let mut vm = Vm::new();
+		vm.open_sandbox(&["pcall", "string.rep"]);
+		vm.set_limits(Limits {
+			memory: Some(1 << 20),
+			..Limits::default()
+		});
+		assert!(vm.global("print").is_nil());
+		let rep = vm.index(&Value::from("x"), &Value::from("rep")).unwrap();
+		let format = vm.index(&Value::from("x"), &Value::from("format"));
+		assert!(format.unwrap().is_nil());
+
+		// pcall(string.rep, "x", 1e9) is not caught
+		let args = vec![rep, Value::from("x"), Value::Number(1e9)];
+		let result = vm.call(&vm.global("pcall"), args);
+		let Err(LuaError::Limit(err)) = result else {
+			panic!("memory ceiling not enforced");
+		};
+		assert_eq!(err.limit, Limit::Memory);
+		assert!(vm.usage().memory < 1 << 20);
+	}
+}
@@ -0,0 +1,396 @@
+use std::ops::Range;
+
+const MAX_CAPTURES: usize = 32;
+const ESC: u8 = b'%';
+/// Characters that make `string.find` use patterns instead of plain search
+pub const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+/// A capture made by a pattern match
+#[derive(Debug, Clone, PartialEq)]
+pub enum Capture {
+	String(Range<usize>),
+	/// `()`, the position in the subject counting from 1
+	Position(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Length {
+	Unfinished,
+	Position,
+	Closed(usize),
+}
+
+type Result<T> = std::result::Result<T, String>;
+
+/// State of one match of a pattern against a subject, a port of the
+/// matcher of lstrlib.c
+struct Matcher<'a> {
+	src: &'a [u8],
+	pat: &'a [u8],
+	captures: Vec<(usize, Length)>,
+}
+
+fn match_class(c: u8, class: u8) -> bool {
+	let matches = match class.to_ascii_lowercase() {
+		b'a' => c.is_ascii_alphabetic(),
+		b'c' => c.is_ascii_control(),
+		b'd' => c.is_ascii_digit(),
+		b'l' => c.is_ascii_lowercase(),
+		b'p' => c.is_ascii_punctuation(),
+		b's' => c.is_ascii_whitespace() || c == b'\x0b',
+		b'u' => c.is_ascii_uppercase(),
+		b'w' => c.is_ascii_alphanumeric(),
+		b'x' => c.is_ascii_hexdigit(),
+		b'z' => c == 0,
+		_ => return class == c,
+	};
+	match class.is_ascii_uppercase() {
+		true => !matches,
+		false => matches,
+	}
+}
+
+impl Matcher<'_> {
+	/// End of the single character class starting at `p`
+	fn class_end(&self, mut p: usize) -> Result<usize> {
+		let c = self.pat[p];
+		p += 1;
+		match c {
+			ESC => match p < self.pat.len() {
+				true => Ok(p + 1),
+				false => Err("malformed pattern (ends with '%')".to_string()),
+			},
+			b'[' => {
+				if self.pat.get(p) == Some(&b'^') {
+					p += 1;
+				}
+				loop {
+					// the first character is never the closing bracket
+					let Some(&c) = self.pat.get(p) else {
+						return Err("malformed pattern (missing ']')".to_string());
+					};
+					p += 1;
+					if c == ESC && p < self.pat.len() {
+						p += 1;
+					}
+					if self.pat.get(p) == Some(&b']') {
+						return Ok(p + 1);
+					}
+				}
+			}
+			_ => Ok(p),
+		}
+	}
+
+	/// Whether `c` is in the set from `p` on the '[' to `end` on the ']'
+	fn match_bracket(&self, c: u8, mut p: usize, end: usize) -> bool {
+		let mut found = true;
+		if self.pat[p + 1] == b'^' {
+			found = false;
+			p += 1;
+		}
+		loop {
+			p += 1;
+			if p >= end {
+				return !found;
+			}
+			if self.pat[p] == ESC {
+				p += 1;
+				if match_class(c, self.pat[p]) {
+					return found;
+				}
+			} else if self.pat[p + 1] == b'-' && p + 2 < end {
+				p += 2;
+				if self.pat[p - 2] <= c && c <= self.pat[p] {
+					return found;
+				}
+			} else if self.pat[p] == c {
+				return found;
+			}
+		}
+	}
+
+	fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+		let Some(&c) = self.src.get(s) else {
+			return false;
+		};
+		match self.pat[p] {
+			b'.' => true,
+			ESC => match_class(c, self.pat[p + 1]),
+			b'[' => self.match_bracket(c, p, ep - 1),
+			pc => pc == c,
+		}
+	}
+
+	/// End of the match of the pattern from `p` on at `s`
+	fn do_match(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>> {
+		loop {
+			if p == self.pat.len() {
+				return Ok(Some(s));
+			}
+			match self.pat[p] {
+				b'(' if self.pat.get(p + 1) == Some(&b')') => {
+					return self.start_capture(s, p + 2, Length::Position)
+				}
+				b'(' => return self.start_capture(s, p + 1, Length::Unfinished),
+				b')' => return self.end_capture(s, p + 1),
+				b'$' if p + 1 == self.pat.len() => {
+					return Ok((s == self.src.len()).then_some(s));
+				}
+				ESC if self.pat.get(p + 1) == Some(&b'b') => match self.match_balance(s, p + 2)? {
+					Some(end) => {
+						s = end;
+						p += 4;
+						continue;
+					}
+					None => return Ok(None),
+				},
+				ESC if self.pat.get(p + 1) == Some(&b'f') => {
+					p += 2;
+					if self.pat.get(p) != Some(&b'[') {
+						return Err("missing '[' after '%f' in pattern".to_string());
+					}
+					let ep = self.class_end(p)?;
+					let previous = s.checked_sub(1).map_or(0, |prev| self.src[prev]);
+					let current = self.src.get(s).copied().unwrap_or(0);
+					if self.match_bracket(previous, p, ep - 1)
+						|| !self.match_bracket(current, p, ep - 1)
+					{
+						return Ok(None);
+					}
+					p = ep;
+					continue;
+				}
+				ESC if self.pat.get(p + 1).is_some_and(u8::is_ascii_digit) => {
+					match self.match_capture(s, self.pat[p + 1])? {
+						Some(end) => {
+							s = end;
+							p += 2;
+							continue;
+						}
+						None => return Ok(None),
+					}
+				}
+				_ => {}
+			}
+
+			let ep = self.class_end(p)?;
+			let matched = self.single_match(s, p, ep);
+			match self.pat.get(ep) {
+				Some(b'?') => {
+					if matched {
+						if let Some(end) = self.do_match(s + 1, ep + 1)? {
+							return Ok(Some(end));
+						}
+					}
+					p = ep + 1;
+				}
+				Some(b'*') => return self.max_expand(s, p, ep),
+				Some(b'+') if matched => return self.max_expand(s + 1, p, ep),
+				Some(b'+') => return Ok(None),
+				Some(b'-') => return self.min_expand(s, p, ep),
+				_ if matched => {
+					s += 1;
+					p = ep;
+				}
+				_ => return Ok(None),
+			}
+		}
+	}
+
+	fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>> {
+		let mut count = 0;
+		while self.single_match(s + count, p, ep) {
+			count += 1;
+		}
+		for i in (0..=count).rev() {
+			if let Some(end) = self.do_match(s + i, ep + 1)? {
+				return Ok(Some(end));
+			}
+		}
+		Ok(None)
+	}
+
+	fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>> {
+		loop {
+			if let Some(end) = self.do_match(s, ep + 1)? {
+				return Ok(Some(end));
+			}
+			if !self.single_match(s, p, ep) {
+				return Ok(None);
+			}
+			s += 1;
+		}
+	}
+
+	fn start_capture(&mut self, s: usize, p: usize, length: Length) -> Result<Option<usize>> {
+		if self.captures.len() >= MAX_CAPTURES {
+			return Err("too many captures".to_string());
+		}
+		self.captures.push((s, length));
+		let result = self.do_match(s, p)?;
+		if result.is_none() {
+			self.captures.pop();
+		}
+		Ok(result)
+	}
+
+	fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>> {
+		let Some(level) = self
+			.captures
+			.iter()
+			.rposition(|(_, length)| *length == Length::Unfinished)
+		else {
+			return Err("invalid pattern capture".to_string());
+		};
+		self.captures[level].1 = Length::Closed(s - self.captures[level].0);
+		let result = self.do_match(s, p)?;
+		if result.is_none() {
+			self.captures[level].1 = Length::Unfinished;
+		}
+		Ok(result)
+	}
+
+	fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>> {
+		let (Some(&open), Some(&close)) = (self.pat.get(p), self.pat.get(p + 1)) else {
+			return Err("unbalanced pattern".to_string());
+		};
+		if self.src.get(s) != Some(&open) {
+			return Ok(None);
+		}
+		let mut depth = 1;
+		for (i, &c) in self.src.iter().enumerate().skip(s + 1) {
+			if c == close {
+				depth -= 1;
+				if depth == 0 {
+					return Ok(Some(i + 1));
+				}
+			} else if c == open {
+				depth += 1;
+			}
+		}
+		Ok(None)
+	}
+
+	fn match_capture(&self, s: usize, digit: u8) -> Result<Option<usize>> {
+		let level = digit.checked_sub(b'1').map(usize::from);
+		let range = match level.and_then(|level| self.captures.get(level)) {
+			Some(&(start, Length::Closed(len))) => start..start + len,
+			Some((_, Length::Position)) => return Ok(None),
+			_ => return Err("invalid capture index".to_string()),
+		};
+		let len = range.len();
+		Ok(
+			(self.src.len() - s >= len && self.src[range] == self.src[s..s + len])
+				.then_some(s + len),
+		)
+	}
+
+	fn finish(&self) -> Result<Vec<Capture>> {
+		self.captures
+			.iter()
+			.map(|&(start, length)| match length {
+				Length::Closed(len) => Ok(Capture::String(start..start + len)),
+				Length::Position => Ok(Capture::Position(start + 1)),
+				Length::Unfinished => Err("unfinished capture".to_string()),
+			})
+			.collect()
+	}
+}
+
+/** Match - Where a pattern matched and what it captured */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+	pub range: Range<usize>,
+	/// empty when the pattern has no captures
+	pub captures: Vec<Capture>,
+}
+
+impl Match {
+	/// Captures, or the whole match when the pattern has none
+	pub fn values(&self) -> Vec<Capture> {
+		match self.captures.is_empty() {
+			true => vec![Capture::String(self.range.clone())],
+			false => self.captures.clone(),
+		}
+	}
+}
+
+/// Match of `pat` starting exactly at `start`, '^' is not special
+pub fn match_at(src: &[u8], pat: &[u8], start: usize) -> Result<Option<Match>> {
+	let mut matcher = Matcher {
+		src,
+		pat,
+		captures: vec![],
+	};
+	match matcher.do_match(start, 0)? {
+		Some(end) => Ok(Some(Match {
+			range: start..end,
+			captures: matcher.finish()?,
+		})),
+		None => Ok(None),
+	}
+}
+
+/// First match of `pat` in `src` at or after `init`, which is anchored to
+/// it by a leading '^'
+pub fn find(src: &[u8], pat: &[u8], init: usize) -> Result<Option<Match>> {
+	let (anchored, pat) = match pat.first() {
+		Some(b'^') => (true, &pat[1..]),
+		_ => (false, pat),
+	};
+	for start in init..=src.len() {
+		if let Some(found) = match_at(src, pat, start)? {
+			return Ok(Some(found));
+		}
+		if anchored {
+			break;
+		}
+	}
+	Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{find, Capture};
+
+	fn captures(src: &str, pat: &str) -> Option<Vec<String>> {
+		let found = find(src.as_bytes(), pat.as_bytes(), 0).unwrap()?;
+		Some(
+			found
+				.values()
+				.into_iter()
+				.map(|capture| match capture {
+					Capture::String(range) => src[range].to_string(),
+					Capture::Position(pos) => pos.to_string(),
+				})
+				.collect(),
+		)
+	}
+
+	#[test]
+	fn test_find() {
+		assert_eq!(captures("hello world", "o w"), Some(vec!["o w".into()]));
+		assert_eq!(
+			captures("key = value", "(%w+)%s*=%s*(%w+)"),
+			Some(vec!["key".into(), "value".into()])
+		);
+		assert_eq!(captures("  x", "^x"), None);
+		assert_eq!(captures("f(a(b)c)d", "%b()"), Some(vec!["(a(b)c)".into()]));
+		assert_eq!(
+			captures("THE (quick) fox", "%f[%a]%a+%f[%A]"),
+			Some(vec!["THE".into()])
+		);
+		assert_eq!(captures("abcabc", "()(b)(c)%2"), None);
+		assert_eq!(captures("abcbc", "a(bc)%1$"), Some(vec!["bc".into()]));
+		assert_eq!(captures("aaab", "a-b"), Some(vec!["aaab".into()]));
+		assert_eq!(captures("[]x", "[]]"), Some(vec!["]".into()]));
+		assert_eq!(captures("x", "()"), Some(vec!["1".into()]));
+		assert_eq!(captures("a-z", "[%-]"), Some(vec!["-".into()]));
+
+		assert!(find(b"x", b"%", 0).is_err());
+		assert!(find(b"x", b"[a", 0).is_err());
+		assert!(find(b"x", b"(x", 0).is_err());
+		assert!(find(b"x", b"x)", 0).is_err());
+	}
+}
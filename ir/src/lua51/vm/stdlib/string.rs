@@ -0,0 +1,618 @@
+use super::{
+	arg, arg_error, check_integer, check_string, library, opt_integer,
+	pattern::{self, Capture, Match, SPECIALS},
+	type_expected,
+};
+use crate::lua51::vm::{Function, Result, Table, Value, Vm};
+use std::{cell::Cell, rc::Rc};
+
+pub fn open(vm: &mut Vm) {
+	let string = library(
+		vm,
+		"string",
+		&[
+			("byte", byte),
+			("char", char),
+			("find", find),
+			("format", format_),
+			("gmatch", gmatch),
+			("gsub", gsub),
+			("len", len),
+			("lower", lower),
+			("match", match_),
+			("rep", rep),
+			("reverse", reverse),
+			("sub", sub),
+			("upper", upper),
+		],
+	);
+	let mut meta = Table::new();
+	meta.set_str("__index", Value::Table(string));
+	vm.set_string_metatable(Some(Rc::new(std::cell::RefCell::new(meta))));
+}
+
+/// String value made by the library, counted against the memory ceiling
+fn new_string(vm: &mut Vm, s: impl AsRef<[u8]>) -> Result<Value> {
+	vm.charge(s.as_ref().len())?;
+	Ok(Value::string(s))
+}
+
+/// Position `pos` counting from the end when negative, like posrelat
+fn relative(pos: i64, len: usize) -> i64 {
+	match pos {
+		pos if pos < 0 => len as i64 + pos + 1,
+		pos => pos,
+	}
+}
+
+fn len(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let s = check_string(vm, "len", &args, 1)?;
+	Ok(vec![Value::Number(s.len() as f64)])
+}
+
+fn sub(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let s = check_string(vm, "sub", &args, 1)?;
+	let start = relative(check_integer(vm, "sub", &args, 2)?, s.len()).max(1);
+	let end = relative(opt_integer(vm, "sub", &args, 3, -1)?, s.len()).min(s.len() as i64);
+	match start <= end {
+		true => Ok(vec![new_string(vm, &s[start as usize - 1..end as usize])?]),
+		false => Ok(vec![Value::from("")]),
+	}
+}
+
+fn upper(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let s = check_string(vm, "upper", &args, 1)?;
+	Ok(vec![new_string(vm, s.to_ascii_uppercase())?])
+}
+
+fn lower(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let s = check_string(vm, "lower", &args, 1)?;
+	Ok(vec![new_string(vm, s.to_ascii_lowercase())?])
+}
+
+fn rep(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let s = check_string(vm, "rep", &args, 1)?;
+	let n = check_integer(vm, "rep", &args, 2)?.max(0) as usize;
+	// charged before building, which could exhaust the host
+	vm.charge(s.len().saturating_mul(n))?;
+	Ok(vec![Value::string(s.repeat(n))])
+}
+
+fn reverse(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let s = check_string(vm, "reverse", &args, 1)?;
+	let reversed: Vec<_> = s.iter().rev().copied().collect();
+	Ok(vec![new_string(vm, reversed)?])
+}
+
+fn byte(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let s = check_string(vm, "byte", &args, 1)?;
+	let start = relative(opt_integer(vm, "byte", &args, 2, 1)?, s.len()).max(1);
+	let end = relative(opt_integer(vm, "byte", &args, 3, start)?, s.len()).min(s.len() as i64);
+	if start > end {
+		return Ok(vec![]);
+	}
+	Ok(s[start as usize - 1..end as usize]
+		.iter()
+		.map(|c| Value::Number(*c as f64))
+		.collect())
+}
+
+fn char(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let mut s = Vec::with_capacity(args.len());
+	for n in 1..=args.len() {
+		match u8::try_from(check_integer(vm, "char", &args, n)?) {
+			Ok(c) => s.push(c),
+			Err(_) => return Err(arg_error(vm, "char", n, "invalid value")),
+		}
+	}
+	Ok(vec![new_string(vm, s)?])
+}
+
+fn capture_value(src: &[u8], capture: Capture) -> Value {
+	match capture {
+		Capture::String(range) => Value::string(&src[range]),
+		Capture::Position(pos) => Value::Number(pos as f64),
+	}
+}
+
+/// `string.find` and `string.match`
+fn find_aux(vm: &mut Vm, args: Vec<Value>, name: &str, find: bool) -> Result<Vec<Value>> {
+	let s = check_string(vm, name, &args, 1)?;
+	let pat = check_string(vm, name, &args, 2)?;
+	let init = (relative(opt_integer(vm, name, &args, 3, 1)?, s.len()) - 1).clamp(0, s.len() as i64)
+		as usize;
+
+	if find && (arg(&args, 4).truthy() || !pat.iter().any(|c| SPECIALS.contains(c))) {
+		let found = match pat.is_empty() {
+			true => Some(init),
+			false => s[init..]
+				.windows(pat.len())
+				.position(|window| window == &*pat)
+				.map(|pos| init + pos),
+		};
+		return Ok(match found {
+			Some(start) => vec![
+				Value::Number((start + 1) as f64),
+				Value::Number((start + pat.len()) as f64),
+			],
+			None => vec![Value::Nil],
+		});
+	}
+
+	match pattern::find(&s, &pat, init).map_err(|msg| vm.error(msg))? {
+		Some(found) if find => {
+			let mut results = vec![
+				Value::Number((found.range.start + 1) as f64),
+				Value::Number(found.range.end as f64),
+			];
+			results.extend(
+				found
+					.captures
+					.into_iter()
+					.map(|capture| capture_value(&s, capture)),
+			);
+			Ok(results)
+		}
+		Some(found) => Ok(found
+			.values()
+			.into_iter()
+			.map(|capture| capture_value(&s, capture))
+			.collect()),
+		None => Ok(vec![Value::Nil]),
+	}
+}
+
+fn find(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	find_aux(vm, args, "find", true)
+}
+
+fn match_(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	find_aux(vm, args, "match", false)
+}
+
+fn gmatch(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let s = check_string(vm, "gmatch", &args, 1)?;
+	let pat = check_string(vm, "gmatch", &args, 2)?;
+	let position = Cell::new(0);
+	Ok(vec![Value::native("gmatch_next", move |vm, _| {
+		for start in position.get()..=s.len() {
+			let found = pattern::match_at(&s, &pat, start).map_err(|msg| vm.error(msg))?;
+			if let Some(found) = found {
+				// an empty match moves on by one
+				position.set(found.range.end.max(start + 1));
+				return Ok(found
+					.values()
+					.into_iter()
+					.map(|capture| capture_value(&s, capture))
+					.collect());
+			}
+		}
+		position.set(s.len() + 1);
+		Ok(vec![])
+	})])
+}
+
+fn replacement(
+	vm: &mut Vm,
+	src: &[u8],
+	found: &Match,
+	repl: &Value,
+	out: &mut Vec<u8>,
+) -> Result<()> {
+	let whole = &src[found.range.clone()];
+	let value = match repl {
+		Value::Table(_) => {
+			let key = capture_value(src, found.values().remove(0));
+			vm.index(repl, &key)?
+		}
+		Value::Function(_) => {
+			let captures = found
+				.values()
+				.into_iter()
+				.map(|capture| capture_value(src, capture))
+				.collect();
+			vm.call(repl, captures)?
+				.into_iter()
+				.next()
+				.unwrap_or_default()
+		}
+		_ => {
+			let repl = repl.to_bytes().unwrap();
+			let mut i = 0;
+			while i < repl.len() {
+				match (repl[i], repl.get(i + 1)) {
+					(b'%', Some(&c)) if c.is_ascii_digit() => {
+						let capture = match c {
+							b'0' => Capture::String(found.range.clone()),
+							_ => match found.values().get((c - b'1') as usize) {
+								Some(capture) => capture.clone(),
+								None => return Err(vm.error("invalid capture index")),
+							},
+						};
+						out.extend_from_slice(&capture_value(src, capture).to_bytes().unwrap());
+						i += 2;
+					}
+					(b'%', Some(&c)) => {
+						out.push(c);
+						i += 2;
+					}
+					(c, _) => {
+						out.push(c);
+						i += 1;
+					}
+				}
+			}
+			return Ok(());
+		}
+	};
+	match value {
+		Value::Nil | Value::Boolean(false) => out.extend_from_slice(whole),
+		Value::String(_) | Value::Number(_) => out.extend_from_slice(&value.to_bytes().unwrap()),
+		value => {
+			let kind = value.type_name();
+			return Err(vm.error(format!("invalid replacement value (a {kind})")));
+		}
+	}
+	Ok(())
+}
+
+fn gsub(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let src = check_string(vm, "gsub", &args, 1)?;
+	let pat = check_string(vm, "gsub", &args, 2)?;
+	let repl = arg(&args, 3);
+	if !matches!(
+		repl,
+		Value::Number(_) | Value::String(_) | Value::Table(_) | Value::Function(_)
+	) {
+		return Err(type_expected(vm, "gsub", &args, 3, "string/function/table"));
+	}
+	let max = match arg(&args, 4) {
+		Value::Nil => i64::MAX,
+		_ => check_integer(vm, "gsub", &args, 4)?,
+	};
+	let (anchored, pat) = match pat.first() {
+		Some(b'^') => (true, &pat[1..]),
+		_ => (false, &pat[..]),
+	};
+
+	let mut out = vec![];
+	let mut s = 0;
+	let mut count = 0;
+	while count < max {
+		let found = pattern::match_at(&src, pat, s).map_err(|msg| vm.error(msg))?;
+		if let Some(found) = &found {
+			count += 1;
+			replacement(vm, &src, found, &repl, &mut out)?;
+		}
+		match found {
+			Some(found) if found.range.end > s => s = found.range.end,
+			_ if s < src.len() => {
+				out.push(src[s]);
+				s += 1;
+			}
+			_ => break,
+		}
+		if anchored {
+			break;
+		}
+	}
+	out.extend_from_slice(&src[s..]);
+	Ok(vec![new_string(vm, out)?, Value::Number(count as f64)])
+}
+
+fn format_(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let fmt = check_string(vm, "format", &args, 1)?;
+	match format(&fmt, &args[1..]) {
+		Ok(s) => Ok(vec![new_string(vm, s)?]),
+		Err(msg) => Err(vm.error(msg)),
+	}
+}
+
+/// Flags, width and precision of a `string.format` item
+#[derive(Default)]
+struct Spec {
+	left: bool,
+	plus: bool,
+	space: bool,
+	alt: bool,
+	zero: bool,
+	width: usize,
+	precision: Option<usize>,
+}
+
+impl Spec {
+	/// `body` padded to the width, zeros go between sign and digits
+	fn pad(&self, sign: &str, body: &str, zeros: bool) -> String {
+		let len = sign.len() + body.len();
+		let fill = self.width.saturating_sub(len);
+		match (self.left, zeros && self.zero) {
+			(true, _) => format!("{sign}{body}{}", " ".repeat(fill)),
+			(false, true) => format!("{sign}{}{body}", "0".repeat(fill)),
+			(false, false) => format!("{}{sign}{body}", " ".repeat(fill)),
+		}
+	}
+
+	fn sign(&self, negative: bool) -> &'static str {
+		match (negative, self.plus, self.space) {
+			(true, _, _) => "-",
+			(false, true, _) => "+",
+			(false, false, true) => " ",
+			_ => "",
+		}
+	}
+
+	fn integer(&self, digits: String) -> String {
+		match self.precision {
+			Some(0) if digits == "0" => String::new(),
+			Some(precision) => format!("{digits:0>precision$}"),
+			None => digits,
+		}
+	}
+
+	fn float(&self, n: f64, conversion: u8) -> String {
+		let sign = self.sign(n.is_sign_negative());
+		let upper = conversion.is_ascii_uppercase();
+		if !n.is_finite() {
+			let body = match (n.is_nan(), upper) {
+				(true, false) => "nan",
+				(true, true) => "NAN",
+				(false, false) => "inf",
+				(false, true) => "INF",
+			};
+			return self.pad(sign, body, false);
+		}
+
+		let n = n.abs();
+		let precision = self.precision.unwrap_or(6);
+		let body = match conversion.to_ascii_lowercase() {
+			b'f' => fixed(n, precision, self.alt),
+			b'e' => exponent(n, precision, self.alt),
+			_ => {
+				let precision = precision.max(1);
+				let exp = exponent(n, precision - 1, false)
+					.rsplit_once('e')
+					.map_or(0, |(_, exp)| exp.parse::<i32>().unwrap());
+				let body = match exp < -4 || exp >= precision as i32 {
+					true => exponent(n, precision - 1, self.alt),
+					false => fixed(n, (precision as i32 - 1 - exp) as usize, self.alt),
+				};
+				match self.alt {
+					true => body,
+					false => trim_zeros(&body),
+				}
+			}
+		};
+		let body = match upper {
+			true => body.to_ascii_uppercase(),
+			false => body,
+		};
+		self.pad(sign, &body, true)
+	}
+}
+
+fn fixed(n: f64, precision: usize, alt: bool) -> String {
+	match (precision, alt) {
+		(0, true) => format!("{n:.0}."),
+		_ => format!("{n:.precision$}"),
+	}
+}
+
+/// `%e`, with a sign and two digits at least in the exponent like C
+fn exponent(n: f64, precision: usize, alt: bool) -> String {
+	let s = format!("{n:.precision$e}");
+	let (mantissa, exp) = s.split_once('e').unwrap();
+	let exp = exp.parse::<i32>().unwrap();
+	let dot = if alt && precision == 0 { "." } else { "" };
+	let sign = if exp < 0 { '-' } else { '+' };
+	format!("{mantissa}{dot}e{sign}{:02}", exp.abs())
+}
+
+fn trim_zeros(s: &str) -> String {
+	let (mantissa, exp) = match s.find('e') {
+		Some(pos) => s.split_at(pos),
+		None => (s, ""),
+	};
+	let mantissa = match mantissa.contains('.') {
+		true => mantissa.trim_end_matches('0').trim_end_matches('.'),
+		false => mantissa,
+	};
+	format!("{mantissa}{exp}")
+}
+
+/// `string.format` of `fmt` with `args`, the error message on failure
+pub fn format(fmt: &[u8], args: &[Value]) -> std::result::Result<Vec<u8>, String> {
+	let mut out = vec![];
+	let mut next = 0;
+	let mut i = 0;
+	while i < fmt.len() {
+		if fmt[i] != b'%' {
+			out.push(fmt[i]);
+			i += 1;
+			continue;
+		}
+		i += 1;
+		if fmt.get(i) == Some(&b'%') {
+			out.push(b'%');
+			i += 1;
+			continue;
+		}
+
+		let mut spec = Spec::default();
+		let start = i;
+		while let Some(&c) = fmt.get(i).filter(|c| b"-+ #0".contains(c)) {
+			match c {
+				b'-' => spec.left = true,
+				b'+' => spec.plus = true,
+				b' ' => spec.space = true,
+				b'#' => spec.alt = true,
+				_ => spec.zero = true,
+			}
+			i += 1;
+		}
+		if i - start >= 6 {
+			return Err("invalid format (repeated flags)".to_string());
+		}
+		let digits = |i: &mut usize| {
+			let start = *i;
+			while *i < fmt.len() && *i - start < 2 && fmt[*i].is_ascii_digit() {
+				*i += 1;
+			}
+			std::str::from_utf8(&fmt[start..*i])
+				.unwrap()
+				.parse::<usize>()
+				.ok()
+		};
+		spec.width = digits(&mut i).unwrap_or(0);
+		if fmt.get(i) == Some(&b'.') {
+			i += 1;
+			spec.precision = Some(digits(&mut i).unwrap_or(0));
+		}
+		if fmt.get(i).is_some_and(u8::is_ascii_digit) {
+			return Err("invalid format (width or precision too long)".to_string());
+		}
+
+		let conversion = fmt.get(i).copied().unwrap_or(0);
+		i += 1;
+		next += 1;
+		let n = next + 1;
+		let value = args.get(next - 1);
+		let bad = |expected: &str| {
+			let got = value.map_or("no value", Value::type_name);
+			format!("bad argument #{n} to 'format' ({expected} expected, got {got})")
+		};
+		let number = || {
+			value
+				.and_then(Value::to_number)
+				.ok_or_else(|| bad("number"))
+		};
+
+		let item = match conversion {
+			b'c' => {
+				let c = number()? as i64 as u8;
+				let fill = vec![b' '; spec.width.saturating_sub(1)];
+				match spec.left {
+					true => [vec![c], fill].concat(),
+					false => [fill, vec![c]].concat(),
+				}
+			}
+			b'd' | b'i' => {
+				let n = number()? as i64;
+				let digits = spec.integer(n.unsigned_abs().to_string());
+				let zeros = spec.precision.is_none();
+				spec.pad(spec.sign(n < 0), &digits, zeros).into_bytes()
+			}
+			b'o' | b'u' | b'x' | b'X' => {
+				let n = number()? as i64 as u64;
+				let digits = match conversion {
+					b'o' => format!("{n:o}"),
+					b'u' => n.to_string(),
+					b'x' => format!("{n:x}"),
+					_ => format!("{n:X}"),
+				};
+				let mut digits = spec.integer(digits);
+				if spec.alt && conversion == b'o' && !digits.starts_with('0') {
+					digits.insert(0, '0');
+				}
+				let prefix = match (spec.alt && n != 0, conversion) {
+					(true, b'x') => "0x",
+					(true, b'X') => "0X",
+					_ => "",
+				};
+				let zeros = spec.precision.is_none();
+				spec.pad(prefix, &digits, zeros).into_bytes()
+			}
+			b'e' | b'E' | b'f' | b'g' | b'G' => spec.float(number()?, conversion).into_bytes(),
+			b'q' => {
+				let s = value
+					.and_then(Value::to_bytes)
+					.ok_or_else(|| bad("string"))?;
+				let mut item = vec![b'"'];
+				for &c in s.iter() {
+					match c {
+						b'"' | b'\\' | b'\n' => item.extend([b'\\', c]),
+						b'\r' => item.extend(b"\\r"),
+						0 => item.extend(b"\\000"),
+						c => item.push(c),
+					}
+				}
+				item.push(b'"');
+				item
+			}
+			b's' => {
+				let s = value
+					.and_then(Value::to_bytes)
+					.ok_or_else(|| bad("string"))?;
+				let s = match spec.precision {
+					Some(precision) => &s[..precision.min(s.len())],
+					None => &s[..],
+				};
+				let fill = spec.width.saturating_sub(s.len());
+				let mut item = vec![b' '; fill];
+				match spec.left {
+					true => item.splice(0..0, s.iter().copied()),
+					false => item.splice(fill..fill, s.iter().copied()),
+				};
+				item
+			}
+			c => {
+				let c = String::from_utf8_lossy(&[c]).into_owned();
+				return Err(format!("invalid option '%{c}' to 'format'"));
+			}
+		};
+		out.extend(item);
+	}
+	Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::format;
+	use crate::lua51::vm::Value;
+
+	fn fmt(f: &str, args: &[Value]) -> String {
+		String::from_utf8(format(f.as_bytes(), args).unwrap()).unwrap()
+	}
+
+	#[test]
+	fn test_format() {
+		let n = |n: f64| Value::Number(n);
+		assert_eq!(
+			fmt("%d|%5d|%-5d|%05d", &[n(3.9), n(-42.0), n(7.0), n(-7.0)]),
+			"3|  -42|7    |-0007"
+		);
+		assert_eq!(
+			fmt("%x %X %#x %o", &[n(255.0), n(255.0), n(255.0), n(8.0)]),
+			"ff FF 0xff 10"
+		);
+		assert_eq!(
+			fmt(
+				"%.3f %e %g %g",
+				&[n(1.23456), n(12345.678), n(0.0001), n(1e20)]
+			),
+			"1.235 1.234568e+04 0.0001 1e+20"
+		);
+		assert_eq!(
+			fmt("%g %.3g %10.2f", &[n(100000.0), n(2.0 / 3.0), n(-1.5)]),
+			"100000 0.667      -1.50"
+		);
+		assert_eq!(
+			fmt(
+				"%s|%-4s|%.2s|%c",
+				&[
+					Value::from("a"),
+					Value::from("b"),
+					Value::from("xyz"),
+					n(65.0)
+				]
+			),
+			"a|b   |xy|A"
+		);
+		assert_eq!(fmt("%q", &[Value::from("a\"b\n\0")]), "\"a\\\"b\\\n\\000\"");
+		assert_eq!(fmt("%s %%", &[n(1.5)]), "1.5 %");
+		assert_eq!(
+			format(b"%d", &[]).unwrap_err(),
+			"bad argument #2 to 'format' (number expected, got no value)"
+		);
+		assert!(format(b"%y", &[n(1.0)]).is_err());
+	}
+}
@@ -0,0 +1,210 @@
+use super::{arg, check_integer, check_string, check_table, library, opt_integer, type_expected};
+use crate::lua51::vm::{Result, TableRef, Value, Vm};
+use std::rc::Rc;
+
+pub fn open(vm: &mut Vm) {
+	library(
+		vm,
+		"table",
+		&[
+			("concat", concat),
+			("foreach", foreach),
+			("foreachi", foreachi),
+			("getn", getn),
+			("insert", insert),
+			("maxn", maxn),
+			("remove", remove),
+			("setn", setn),
+			("sort", sort),
+		],
+	);
+}
+
+fn get(table: &TableRef, idx: i64) -> Value {
+	table.borrow().get(&Value::Number(idx as f64))
+}
+
+fn set(table: &TableRef, idx: i64, value: Value) {
+	table
+		.borrow_mut()
+		.set(Value::Number(idx as f64), value)
+		.unwrap();
+}
+
+fn concat(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "concat", &args, 1)?;
+	let sep = match arg(&args, 2) {
+		Value::Nil => Rc::from(&b""[..]),
+		_ => check_string(vm, "concat", &args, 2)?,
+	};
+	let first = opt_integer(vm, "concat", &args, 3, 1)?;
+	let last = match arg(&args, 4) {
+		Value::Nil => table.borrow().len() as i64,
+		_ => check_integer(vm, "concat", &args, 4)?,
+	};
+
+	let mut out = vec![];
+	for idx in first..=last {
+		let Some(s) = get(&table, idx).to_bytes() else {
+			return Err(vm.error(format!(
+				"invalid value (at index {idx}) in table for 'concat'"
+			)));
+		};
+		out.extend_from_slice(&s);
+		if idx != last {
+			out.extend_from_slice(&sep);
+		}
+	}
+	vm.charge(out.len())?;
+	Ok(vec![Value::string(out)])
+}
+
+fn foreach(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "foreach", &args, 1)?;
+	let func = arg(&args, 2);
+	if !matches!(func, Value::Function(_)) {
+		return Err(type_expected(vm, "foreach", &args, 2, "function"));
+	}
+	let mut key = Value::Nil;
+	loop {
+		let entry = table.borrow().next(&key);
+		let Some(Some((k, v))) = entry else {
+			return Ok(vec![]);
+		};
+		let result = vm
+			.call(&func, vec![k.clone(), v])?
+			.into_iter()
+			.next()
+			.unwrap_or_default();
+		if !result.is_nil() {
+			return Ok(vec![result]);
+		}
+		key = k;
+	}
+}
+
+fn foreachi(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "foreachi", &args, 1)?;
+	let func = arg(&args, 2);
+	if !matches!(func, Value::Function(_)) {
+		return Err(type_expected(vm, "foreachi", &args, 2, "function"));
+	}
+	let len = table.borrow().len() as i64;
+	for idx in 1..=len {
+		let args = vec![Value::Number(idx as f64), get(&table, idx)];
+		let result = vm.call(&func, args)?.into_iter().next().unwrap_or_default();
+		if !result.is_nil() {
+			return Ok(vec![result]);
+		}
+	}
+	Ok(vec![])
+}
+
+fn getn(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "getn", &args, 1)?;
+	let len = table.borrow().len();
+	Ok(vec![Value::Number(len as f64)])
+}
+
+fn setn(vm: &mut Vm, _: Vec<Value>) -> Result<Vec<Value>> {
+	Err(vm.error("'setn' is obsolete"))
+}
+
+fn maxn(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "maxn", &args, 1)?;
+	let table = table.borrow();
+	let mut max = 0.0;
+	let mut key = Value::Nil;
+	while let Some(Some((k, _))) = table.next(&key) {
+		if let Value::Number(n) = k {
+			if n > max {
+				max = n;
+			}
+		}
+		key = k;
+	}
+	Ok(vec![Value::Number(max)])
+}
+
+fn insert(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "insert", &args, 1)?;
+	let mut end = table.borrow().len() as i64 + 1;
+	let (pos, value) = match args.len() {
+		2 => (end, arg(&args, 2)),
+		3 => {
+			let pos = check_integer(vm, "insert", &args, 2)?;
+			end = end.max(pos);
+			for idx in (pos + 1..=end).rev() {
+				set(&table, idx, get(&table, idx - 1));
+			}
+			(pos, arg(&args, 3))
+		}
+		_ => return Err(vm.error("wrong number of arguments to 'insert'")),
+	};
+	vm.charge_slots(1)?;
+	set(&table, pos, value);
+	Ok(vec![])
+}
+
+fn remove(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "remove", &args, 1)?;
+	let end = table.borrow().len() as i64;
+	let pos = opt_integer(vm, "remove", &args, 2, end)?;
+	if !(1..=end).contains(&pos) {
+		return Ok(vec![]);
+	}
+	let value = get(&table, pos);
+	for idx in pos..end {
+		set(&table, idx, get(&table, idx + 1));
+	}
+	set(&table, end, Value::Nil);
+	Ok(vec![value])
+}
+
+/// Merge sort, as comparisons may fail or call back into the VM
+fn merge_sort(
+	values: Vec<Value>,
+	less: &mut impl FnMut(&Value, &Value) -> Result<bool>,
+) -> Result<Vec<Value>> {
+	if values.len() <= 1 {
+		return Ok(values);
+	}
+	let mut left = values;
+	let right = left.split_off(left.len() / 2);
+	let left = merge_sort(left, less)?;
+	let right = merge_sort(right, less)?;
+
+	let mut merged = Vec::with_capacity(left.len() + right.len());
+	let mut left = left.into_iter().peekable();
+	let mut right = right.into_iter().peekable();
+	while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+		match less(b, a)? {
+			true => merged.push(right.next().unwrap()),
+			false => merged.push(left.next().unwrap()),
+		}
+	}
+	merged.extend(left);
+	merged.extend(right);
+	Ok(merged)
+}
+
+fn sort(vm: &mut Vm, args: Vec<Value>) -> Result<Vec<Value>> {
+	let table = check_table(vm, "sort", &args, 1)?;
+	let comp = arg(&args, 2);
+	if !matches!(comp, Value::Nil | Value::Function(_)) {
+		return Err(type_expected(vm, "sort", &args, 2, "function"));
+	}
+	let len = table.borrow().len() as i64;
+	let values = (1..=len).map(|idx| get(&table, idx)).collect();
+	let sorted = merge_sort(values, &mut |a, b| match &comp {
+		Value::Nil => vm.less_than(a, b),
+		comp => Ok(vm
+			.call(comp, vec![a.clone(), b.clone()])?
+			.first()
+			.is_some_and(Value::truthy)),
+	})?;
+	for (idx, value) in sorted.into_iter().enumerate() {
+		set(&table, idx as i64 + 1, value);
+	}
+	Ok(vec![])
+}
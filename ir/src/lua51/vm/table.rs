@@ -0,0 +1,263 @@
+use super::value::{TableRef, Value};
+use std::{collections::HashMap, rc::Rc};
+
+/// Hashable identity of a table key, numbers by value and references by
+/// address
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+	Boolean(bool),
+	Number(u64),
+	String(Rc<[u8]>),
+	Reference(usize),
+}
+
+impl Key {
+	fn new(value: &Value) -> Option<Self> {
+		Some(match value {
+			Value::Nil => return None,
+			Value::Boolean(b) => Self::Boolean(*b),
+			Value::Number(n) if n.is_nan() => return None,
+			// -0 and 0 are the same key
+			Value::Number(n) => Self::Number((n + 0.0).to_bits()),
+			Value::String(s) => Self::String(s.clone()),
+			_ => Self::Reference(value.address().unwrap()),
+		})
+	}
+}
+
+/// Position of `key` in the array part
+fn array_index(key: &Value) -> Option<usize> {
+	match key {
+		Value::Number(n) if n.fract() == 0.0 && *n >= 1.0 && *n <= usize::MAX as f64 => {
+			Some(*n as usize - 1)
+		}
+		_ => None,
+	}
+}
+
+/**
+ * Table - A Lua table. Keys 1..n live in the array part, the rest in
+ * insertion order so traversal is deterministic. Entries set to nil stay
+ * until new keys are added, so fields can be cleared while traversing
+ */
+#[derive(Default)]
+pub struct Table {
+	array: Vec<Value>,
+	entries: Vec<(Value, Value)>,
+	index: HashMap<Key, usize>,
+	removed: usize,
+	pub metatable: Option<TableRef>,
+}
+
+impl Table {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn with_capacity(array: usize, hash: usize) -> Self {
+		Self {
+			array: Vec::with_capacity(array),
+			entries: Vec::with_capacity(hash),
+			index: HashMap::with_capacity(hash),
+			..Self::default()
+		}
+	}
+
+	/// Table holding `values` at 1..n
+	pub fn from_list(values: Vec<Value>) -> Self {
+		let mut table = Self::new();
+		for (i, value) in values.into_iter().enumerate() {
+			table.set_int(i + 1, value);
+		}
+		table
+	}
+
+	pub fn get(&self, key: &Value) -> Value {
+		if let Some(idx) = array_index(key) {
+			if let Some(value) = self.array.get(idx) {
+				return value.clone();
+			}
+		}
+		Key::new(key)
+			.and_then(|key| self.index.get(&key))
+			.map(|idx| self.entries[*idx].1.clone())
+			.unwrap_or_default()
+	}
+
+	pub fn get_str(&self, key: &str) -> Value {
+		self.get(&Value::string(key))
+	}
+
+	pub fn get_int(&self, key: usize) -> Value {
+		self.get(&Value::Number(key as f64))
+	}
+
+	/// Sets a field, fails on nil and NaN keys
+	pub fn set(&mut self, key: Value, value: Value) -> Result<(), &'static str> {
+		match key {
+			Value::Nil => return Err("table index is nil"),
+			Value::Number(n) if n.is_nan() => return Err("table index is NaN"),
+			_ => {}
+		}
+
+		if let Some(idx) = array_index(&key) {
+			if idx < self.array.len() {
+				self.array[idx] = value;
+				while self.array.last().is_some_and(Value::is_nil) {
+					self.array.pop();
+				}
+				return Ok(());
+			}
+			if idx == self.array.len() && !value.is_nil() {
+				self.remove_entry(&key);
+				self.array.push(value);
+				self.migrate();
+				return Ok(());
+			}
+		}
+
+		let hashed = Key::new(&key).unwrap();
+		match self.index.get(&hashed) {
+			Some(idx) => {
+				let entry = &mut self.entries[*idx].1;
+				if entry.is_nil() != value.is_nil() {
+					if value.is_nil() {
+						self.removed += 1;
+					} else {
+						self.removed -= 1;
+					}
+				}
+				*entry = value;
+			}
+			None if value.is_nil() => {}
+			None => {
+				if self.removed > self.entries.len() / 2 {
+					self.compact();
+				}
+				self.index.insert(hashed, self.entries.len());
+				self.entries.push((key, value));
+			}
+		}
+		Ok(())
+	}
+
+	pub fn set_str(&mut self, key: &str, value: Value) {
+		self.set(Value::string(key), value).unwrap();
+	}
+
+	pub fn set_int(&mut self, key: usize, value: Value) {
+		self.set(Value::Number(key as f64), value).unwrap();
+	}
+
+	fn remove_entry(&mut self, key: &Value) {
+		if let Some(idx) = Key::new(key).and_then(|key| self.index.get(&key)) {
+			let entry = &mut self.entries[*idx].1;
+			if !entry.is_nil() {
+				*entry = Value::Nil;
+				self.removed += 1;
+			}
+		}
+	}
+
+	/// Moves the keys following the array part out of the hash part
+	fn migrate(&mut self) {
+		loop {
+			let key = Value::Number((self.array.len() + 1) as f64);
+			let Some(idx) = Key::new(&key).and_then(|key| self.index.get(&key).copied()) else {
+				return;
+			};
+			let value = std::mem::take(&mut self.entries[idx].1);
+			if value.is_nil() {
+				return;
+			}
+			self.removed += 1;
+			self.array.push(value);
+		}
+	}
+
+	fn compact(&mut self) {
+		self.entries.retain(|(_, value)| !value.is_nil());
+		self.index = self
+			.entries
+			.iter()
+			.enumerate()
+			.map(|(idx, (key, _))| (Key::new(key).unwrap(), idx))
+			.collect();
+		self.removed = 0;
+	}
+
+	/// A border of the table, what `#` gives
+	pub fn len(&self) -> usize {
+		self.array.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.array.is_empty() && self.entries.len() == self.removed
+	}
+
+	/// Field after `key` in traversal order, nil starting it. None when
+	/// `key` is not in the table
+	pub fn next(&self, key: &Value) -> Option<Option<(Value, Value)>> {
+		let start = match key {
+			Value::Nil => 0,
+			_ => match array_index(key) {
+				Some(idx) if idx < self.array.len() => idx + 1,
+				idx => match Key::new(key).and_then(|key| self.index.get(&key)) {
+					Some(pos) => self.array.len() + pos + 1,
+					// the end of the array part, cleared while traversing
+					None if idx.is_some() => self.array.len(),
+					None => return None,
+				},
+			},
+		};
+
+		let array = self
+			.array
+			.iter()
+			.enumerate()
+			.skip(start)
+			.find_map(|(idx, value)| {
+				(!value.is_nil()).then(|| (Value::Number((idx + 1) as f64), value.clone()))
+			});
+		if array.is_some() {
+			return Some(array);
+		}
+		let skip = start.saturating_sub(self.array.len());
+		Some(
+			self.entries
+				.iter()
+				.skip(skip)
+				.find(|(_, value)| !value.is_nil())
+				.cloned(),
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Table, Value};
+
+	#[test]
+	fn test_table() {
+		let mut table = Table::new();
+		table.set_int(2, Value::from("b"));
+		table.set_str("x", Value::from(1.0));
+		assert_eq!(table.len(), 0);
+		table.set_int(1, Value::from("a"));
+		assert_eq!(table.len(), 2);
+		assert_eq!(table.get_int(2), Value::from("b"));
+		assert_eq!(table.get(&Value::Number(-0.0)), Value::Nil);
+		assert!(table.set(Value::Nil, Value::Nil).is_err());
+
+		// traversal survives clearing the field just visited
+		let mut key = Value::Nil;
+		let mut seen = vec![];
+		while let Some(Some((k, v))) = table.next(&key) {
+			table.set(k.clone(), Value::Nil).unwrap();
+			seen.push(v);
+			key = k;
+		}
+		assert_eq!(seen, [Value::from("a"), Value::from("b"), Value::from(1.0)]);
+		assert!(table.is_empty());
+	}
+}
@@ -0,0 +1,195 @@
+use super::{
+	exec::{Frame, Return},
+	value::{Function, ThreadRef, UpvalueRef, Value},
+	LuaError, Result, Vm, MAX_HOST_DEPTH,
+};
+use std::{cell::RefCell, rc::Rc};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Status {
+	#[default]
+	Suspended,
+	Running,
+	/// resumed another coroutine
+	Normal,
+	Dead,
+}
+
+impl Status {
+	/// Name `coroutine.status` gives
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Suspended => "suspended",
+			Self::Running => "running",
+			Self::Normal => "normal",
+			Self::Dead => "dead",
+		}
+	}
+}
+
+/// What the values of the next resume of a suspended coroutine become
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Resume {
+	/// results of the call that yielded
+	Deliver(Return),
+	/// results of the frame that yielded in a tail call
+	Return,
+	/// results of the coroutine, whose body yielded itself
+	Finish,
+}
+
+/**
+ * Thread - A coroutine. The state of the running one lives in the VM, the
+ * others keep theirs here
+ */
+#[derive(Default)]
+pub struct Thread {
+	pub(super) stack: Vec<Value>,
+	pub(super) frames: Vec<Frame>,
+	pub(super) natives: Vec<usize>,
+	pub(super) open: Vec<UpvalueRef>,
+	pub(super) status: Status,
+	/// function a coroutine not started yet runs
+	pub(super) body: Option<Value>,
+	pub(super) resume: Option<Resume>,
+}
+
+impl Thread {
+	pub fn status(&self) -> Status {
+		self.status
+	}
+}
+
+impl Vm {
+	/// Makes `to` the running thread, returning the one that was
+	fn switch(&mut self, to: ThreadRef) -> ThreadRef {
+		let from = std::mem::replace(&mut self.thread, to);
+		{
+			let mut saved = from.borrow_mut();
+			saved.stack = std::mem::take(&mut self.stack);
+			saved.frames = std::mem::take(&mut self.frames);
+			saved.natives = std::mem::take(&mut self.natives);
+			saved.open = std::mem::take(&mut self.open);
+		}
+		let mut loaded = self.thread.borrow_mut();
+		self.stack = std::mem::take(&mut loaded.stack);
+		self.frames = std::mem::take(&mut loaded.frames);
+		self.natives = std::mem::take(&mut loaded.natives);
+		self.open = std::mem::take(&mut loaded.open);
+		drop(loaded);
+		from
+	}
+
+	/// New suspended coroutine running `body`
+	pub fn create_thread(&mut self, body: Value) -> ThreadRef {
+		Rc::new(RefCell::new(Thread {
+			body: Some(body),
+			..Thread::default()
+		}))
+	}
+
+	/// The running coroutine, None on the main thread
+	pub fn running(&self) -> Option<ThreadRef> {
+		(!Rc::ptr_eq(&self.thread, &self.main)).then(|| self.thread.clone())
+	}
+
+	/// Status of `thread` as seen from the running one
+	pub fn thread_status(&self, thread: &ThreadRef) -> Status {
+		thread.borrow().status
+	}
+
+	/// Runs `thread` until it yields or returns, giving what it passed
+	pub fn resume(&mut self, thread: &ThreadRef, args: Vec<Value>) -> Result<Vec<Value>> {
+		match thread.borrow().status {
+			Status::Suspended => {}
+			Status::Dead => {
+				return Err(LuaError::Runtime(Value::from(
+					"cannot resume dead coroutine",
+				)))
+			}
+			_ => {
+				let msg = "cannot resume non-suspended coroutine";
+				return Err(LuaError::Runtime(Value::from(msg)));
+			}
+		}
+		if self.depth >= MAX_HOST_DEPTH {
+			return Err(self.error("C stack overflow"));
+		}
+
+		self.depth += 1;
+		let previous = self.switch(thread.clone());
+		previous.borrow_mut().status = Status::Normal;
+		thread.borrow_mut().status = Status::Running;
+
+		let result = self.run_thread(args);
+		let status = match &result {
+			Err(LuaError::Yield(_)) => Status::Suspended,
+			_ => Status::Dead,
+		};
+		if status == Status::Dead {
+			self.close_upvalues(0);
+			self.frames.clear();
+		}
+		let resume = self.yielded.take();
+		{
+			let mut thread = thread.borrow_mut();
+			thread.status = status;
+			thread.resume = resume;
+		}
+
+		self.switch(previous);
+		self.thread.borrow_mut().status = Status::Running;
+		self.depth -= 1;
+		match result {
+			Err(LuaError::Yield(values)) => Ok(values),
+			result => result,
+		}
+	}
+
+	fn run_thread(&mut self, args: Vec<Value>) -> Result<Vec<Value>> {
+		let body = self.thread.borrow_mut().body.take();
+		match body {
+			Some(Value::Function(Function::Native(native))) => {
+				match self.call_native(&native, args) {
+					Err(LuaError::Yield(values)) => {
+						self.yielded = Some(Resume::Finish);
+						Err(LuaError::Yield(values))
+					}
+					result => result,
+				}
+			}
+			Some(Value::Function(Function::Lua(closure))) => {
+				for (i, value) in args.iter().enumerate() {
+					self.set(i, value.clone());
+				}
+				self.enter(closure, 0, args.len(), Return::Host)?;
+				if let Err(err) = self.execute(0) {
+					return Err(match self.frames.first() {
+						Some(frame) => {
+							let (closure, pc) = (frame.closure.clone(), frame.pc);
+							self.rethrow(err, &closure.proto, pc)
+						}
+						None => err,
+					});
+				}
+				Ok(std::mem::take(&mut self.results))
+			}
+			Some(value) => Err(self.type_error("call", &value, None)),
+			None => {
+				let resume = self.thread.borrow_mut().resume.take();
+				self.resume_with(resume.unwrap_or(Resume::Finish), args);
+				self.execute(0)?;
+				Ok(std::mem::take(&mut self.results))
+			}
+		}
+	}
+
+	/// Suspends the running coroutine with `values`, what a native yielding
+	/// returns
+	pub fn yield_values(&mut self, values: Vec<Value>) -> Result<Vec<Value>> {
+		match self.running() {
+			Some(_) => Err(LuaError::Yield(values)),
+			None => Err(self.error("attempt to yield from outside a coroutine")),
+		}
+	}
+}
@@ -0,0 +1,345 @@
+use super::{Function, Prototype, Vm};
+use crate::lua51::{IRContext, CFG};
+use std::{
+	cell::RefCell,
+	collections::{BTreeMap, HashMap},
+	rc::Rc,
+};
+
+/// What a hook is told about
+#[derive(Clone, Copy)]
+pub enum Event<'a> {
+	/// the instruction at `pc` is about to run
+	Instruction { proto: &'a Rc<Prototype>, pc: usize },
+	/// the test or loop at `pc` ran, `taken` when control goes on to the
+	/// jump after a test or back into a loop
+	Branch {
+		proto: &'a Rc<Prototype>,
+		pc: usize,
+		taken: bool,
+	},
+	/// a function is called from the instruction at `pc`, from the host or
+	/// a native when `caller` is None
+	Call {
+		caller: Option<(&'a Rc<Prototype>, usize)>,
+		callee: Callee<'a>,
+	},
+}
+
+#[derive(Clone, Copy)]
+pub enum Callee<'a> {
+	Lua(&'a Rc<Prototype>),
+	Native(&'a str),
+}
+
+/// Called with every event while set, can be slow
+pub type Hook = dyn FnMut(&Event);
+
+impl Vm {
+	/// Calls `hook` with what runs from now on, replacing any hook set
+	pub fn set_hook(&mut self, hook: impl FnMut(&Event) + 'static) {
+		self.hook = Some(Box::new(hook));
+	}
+
+	pub fn clear_hook(&mut self) {
+		self.hook = None;
+	}
+
+	/// Sets a hook recording a `Trace` of what runs from now on
+	pub fn trace(&mut self) -> Rc<RefCell<Trace>> {
+		let trace = Rc::new(RefCell::new(Trace::default()));
+		let recorder = trace.clone();
+		self.set_hook(move |event| recorder.borrow_mut().record(event));
+		trace
+	}
+
+	pub(super) fn hook_instruction(&mut self, proto: &Rc<Prototype>, pc: usize) {
+		if let Some(hook) = &mut self.hook {
+			hook(&Event::Instruction { proto, pc });
+		}
+	}
+
+	pub(super) fn hook_branch(&mut self, proto: &Rc<Prototype>, pc: usize, taken: bool) {
+		if let Some(hook) = &mut self.hook {
+			hook(&Event::Branch { proto, pc, taken });
+		}
+	}
+
+	/// Tells the hook about a call to `callee` from the running function
+	pub(super) fn hook_call(&mut self, callee: &Function) {
+		let Some(hook) = &mut self.hook else {
+			return;
+		};
+		let caller = match (self.natives.last(), self.frames.last()) {
+			(Some(&below), _) if below == self.frames.len() => None,
+			(_, Some(frame)) => Some((&frame.closure.proto, frame.pc.saturating_sub(1))),
+			_ => None,
+		};
+		let callee = match callee {
+			Function::Lua(closure) => Callee::Lua(&closure.proto),
+			Function::Native(native) => Callee::Native(&native.name),
+		};
+		hook(&Event::Call { caller, callee });
+	}
+}
+
+/// How often a test or loop went each way
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Branch {
+	pub taken: u64,
+	pub not_taken: u64,
+}
+
+/** FunctionTrace - What ran of one function, by pc */
+#[derive(Debug, Clone, Default)]
+pub struct FunctionTrace {
+	pub hits: Vec<u64>,
+	pub branches: BTreeMap<usize, Branch>,
+}
+
+impl FunctionTrace {
+	pub fn hits(&self, pc: usize) -> u64 {
+		self.hits.get(pc).copied().unwrap_or(0)
+	}
+
+	/// Times each block of `cfg` was entered, by the hits of its first
+	/// instruction
+	pub fn block_hits(&self, cfg: &CFG) -> Vec<u64> {
+		cfg.iter()
+			.map(|block| self.hits(block.range().start))
+			.collect()
+	}
+}
+
+/// A function called by its path in the chunk, or a native by name
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Target {
+	Lua(Vec<usize>),
+	Native(String),
+}
+
+/// Where a call was made from, the path of the function and the pc
+pub type CallSite = (Vec<usize>, usize);
+
+/**
+ * Trace - Hit counts, branch counts and call edges of the functions of a
+ * chunk, by their paths in it. Functions of other chunks run meanwhile are
+ * counted with those at the same paths
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+	pub functions: HashMap<Vec<usize>, FunctionTrace>,
+	/// calls by where they were made from, None for the host or a native
+	pub calls: BTreeMap<(Option<CallSite>, Target), u64>,
+}
+
+impl Trace {
+	pub fn record(&mut self, event: &Event) {
+		match *event {
+			Event::Instruction { proto, pc } => {
+				let function = self.functions.entry(proto.path.clone()).or_default();
+				if function.hits.len() <= pc {
+					function.hits.resize(proto.code.len().max(pc + 1), 0);
+				}
+				function.hits[pc] += 1;
+			}
+			Event::Branch { proto, pc, taken } => {
+				let function = self.functions.entry(proto.path.clone()).or_default();
+				let branch = function.branches.entry(pc).or_default();
+				match taken {
+					true => branch.taken += 1,
+					false => branch.not_taken += 1,
+				}
+			}
+			Event::Call { caller, callee } => {
+				let caller = caller.map(|(proto, pc)| (proto.path.clone(), pc));
+				let callee = match callee {
+					Callee::Lua(proto) => Target::Lua(proto.path.clone()),
+					Callee::Native(name) => Target::Native(name.to_string()),
+				};
+				*self.calls.entry((caller, callee)).or_default() += 1;
+			}
+		}
+	}
+
+	pub fn function(&self, path: &[usize]) -> Option<&FunctionTrace> {
+		self.functions.get(path)
+	}
+
+	/// Graphviz graph of the blocks of the function at `path` in `ctx`,
+	/// coloured from blue for rarely run to red for hottest, grey if never
+	pub fn heat_graph(&self, ctx: &IRContext, path: &[usize]) -> String {
+		let ctx = path
+			.iter()
+			.fold(ctx, |ctx, &idx| ctx.closures[idx].as_ref());
+		let cfg = CFG::from_instructions(&ctx.instructions);
+		let empty = FunctionTrace::default();
+		let function = self.function(path).unwrap_or(&empty);
+		let hits = function.block_hits(&cfg);
+
+		let mut graph = cfg.graph_with(|i, block| {
+			let range = block.range();
+			let mut label = format!("Block {i}: {range:?}\\nhits: {}", hits[i]);
+			if let Some(branch) = range
+				.end
+				.checked_sub(1)
+				.and_then(|last| function.branches.get(&last))
+			{
+				label += &format!("\\ntaken: {} not taken: {}", branch.taken, branch.not_taken);
+			}
+			label
+		});
+		let hottest = hits.iter().copied().max().unwrap_or(0);
+		for (i, &count) in hits.iter().enumerate() {
+			let name = format!("Block{i}");
+			graph.set_attribute(&name, "style", "filled");
+			graph.set_attribute(&name, "fillcolor", &heat(count, hottest));
+		}
+		format!("{}", graph)
+	}
+
+	/// Hits of the source lines of `ctx` and its closures, by the most run
+	/// instruction on each. Empty if it was stripped of `source_lines`
+	pub fn lines(&self, ctx: &IRContext) -> BTreeMap<u64, u64> {
+		let mut lines = BTreeMap::new();
+		self.count_lines(ctx, &mut vec![], &mut lines);
+		lines
+	}
+
+	fn count_lines(&self, ctx: &IRContext, path: &mut Vec<usize>, lines: &mut BTreeMap<u64, u64>) {
+		let function = self.function(path);
+		for (pc, inst) in ctx.instructions.iter().enumerate() {
+			let Some(line) = inst.line() else {
+				continue;
+			};
+			let hits = function.map_or(0, |function| function.hits(pc));
+			let count = lines.entry(line).or_insert(0);
+			*count = (*count).max(hits);
+		}
+		for (idx, closure) in ctx.closures.iter().enumerate() {
+			path.push(idx);
+			self.count_lines(closure, path, lines);
+			path.pop();
+		}
+	}
+
+	/// Line coverage in the manner of gcov: hits of each line, "#####" for
+	/// those never run and "-" for those without code. Lines of `source`
+	/// are shown next to them if given, otherwise only lines with code
+	pub fn line_report(&self, ctx: &IRContext, source: Option<&str>) -> String {
+		let lines = self.lines(ctx);
+		let count = |line: u64| match lines.get(&line) {
+			Some(0) => "#####".to_string(),
+			Some(hits) => hits.to_string(),
+			None => "-".to_string(),
+		};
+		let mut report = String::new();
+		match source {
+			Some(source) => {
+				for (i, text) in source.lines().enumerate() {
+					let line = i as u64 + 1;
+					report += &format!("{:>9}:{:>5}:{}\n", count(line), line, text);
+				}
+			}
+			None => {
+				for &line in lines.keys() {
+					report += &format!("{:>9}:{:>5}:\n", count(line), line);
+				}
+			}
+		}
+		report
+	}
+}
+
+/// Fill colour of a block run `count` times, on a log scale up to `hottest`
+fn heat(count: u64, hottest: u64) -> String {
+	if count == 0 {
+		return "#dddddd".to_string();
+	}
+	let ratio = match hottest {
+		0 | 1 => 1.0,
+		_ => (count as f64).ln() / (hottest as f64).ln(),
+	};
+	// hue from blue to red
+	format!("{:.3} 0.600 1.000", (1.0 - ratio) * 0.667)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Branch, Target, Vm};
+	use crate::lua51::IRContext;
+	use bytecode::lua51::{instructions::Opcode, Constants, Proto};
+
+	#[test]
+	fn test_trace() {
+		// local s = 0
+		// for i = 1, 3 do
+		// 	if i ~= 2 then s = s + i end
+		// end
+		// print(s) return s
+		let code = [
+			Opcode::encode_ABx(1, 0, 0),       // LOADK 0 0
+			Opcode::encode_ABx(1, 1, 1),       // LOADK 1 1
+			Opcode::encode_ABx(1, 2, 2),       // LOADK 2 3
+			Opcode::encode_ABx(1, 3, 1),       // LOADK 3 1
+			Opcode::encode_AsBx(32, 1, 3),     // FORPREP 1 3
+			Opcode::encode_ABC(23, 1, 4, 260), // EQ 1 4 2
+			Opcode::encode_AsBx(22, 0, 1),     // JMP 1
+			Opcode::encode_ABC(12, 0, 0, 4),   // ADD 0 0 4
+			Opcode::encode_AsBx(31, 1, -4),    // FORLOOP 1 -4
+			Opcode::encode_ABx(5, 5, 3),       // GETGLOBAL 5 print
+			Opcode::encode_ABC(0, 6, 0, 0),    // MOVE 6 0
+			Opcode::encode_ABC(28, 5, 2, 1),   // CALL 5 2 1
+			Opcode::encode_ABC(30, 0, 2, 0),   // RETURN 0 2
+			Opcode::encode_ABC(30, 0, 1, 0),   // RETURN 0 1
+		];
+		let proto = Proto {
+			max_stack_size: 7,
+			instructions: code.into_iter().map(Opcode::from_serialized).collect(),
+			constants: vec![
+				Constants::Number(0.0),
+				Constants::Number(1.0),
+				Constants::Number(3.0),
+				Constants::String("print".to_string()),
+				Constants::Number(2.0),
+			],
+			source_lines: Some(vec![1, 2, 2, 2, 2, 3, 3, 3, 2, 5, 5, 5, 5, 5]),
+			..Default::default()
+		};
+		let ctx = IRContext::from_proto(proto);
+
+		let mut vm = Vm::new();
+		vm.open_libs();
+		vm.set_output(|_| {});
+		let trace = vm.trace();
+		let main = vm.load(&ctx);
+		vm.call(&main, vec![]).unwrap();
+		vm.clear_hook();
+
+		let trace = trace.borrow();
+		let function = trace.function(&[]).unwrap();
+		assert_eq!(function.hits, [1, 1, 1, 1, 1, 3, 1, 2, 4, 1, 1, 1, 1, 0]);
+		let branch = |taken, not_taken| Branch { taken, not_taken };
+		assert_eq!(function.branches[&5], branch(1, 2));
+		assert_eq!(function.branches[&8], branch(3, 1));
+		let calls = trace.calls.iter().collect::<Vec<_>>();
+		assert_eq!(
+			calls,
+			[
+				(&(None, Target::Lua(vec![])), &1),
+				(
+					&(Some((vec![], 11)), Target::Native("print".to_string())),
+					&1
+				),
+			]
+		);
+
+		assert_eq!(
+			trace.line_report(&ctx, Some("local s = 0\nfor\nif\nend\nprint")),
+			"        1:    1:local s = 0\n        4:    2:for\n        3:    3:if\n        -:    4:end\n        1:    5:print\n"
+		);
+		let graph = trace.heat_graph(&ctx, &[]);
+		assert!(graph.contains("taken: 1 not taken: 2"));
+		assert!(graph.contains("fillcolor = \"0.000 0.600 1.000\""));
+	}
+}
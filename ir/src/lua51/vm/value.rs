@@ -0,0 +1,194 @@
+use super::{proto::Prototype, table::Table, thread::Thread, Result, Vm};
+use bytecode::lua51::number::{number_to_string, string_to_number};
+use std::{
+	cell::RefCell,
+	fmt::{Debug, Display},
+	rc::Rc,
+};
+
+pub type TableRef = Rc<RefCell<Table>>;
+pub type ThreadRef = Rc<RefCell<Thread>>;
+pub type UpvalueRef = Rc<RefCell<Upvalue>>;
+pub type NativeFn = dyn Fn(&mut Vm, Vec<Value>) -> Result<Vec<Value>>;
+
+/// A Lua value, strings are byte strings like in the reference VM
+#[derive(Clone, Default)]
+pub enum Value {
+	#[default]
+	Nil,
+	Boolean(bool),
+	Number(f64),
+	String(Rc<[u8]>),
+	Table(TableRef),
+	Function(Function),
+	Thread(ThreadRef),
+}
+
+#[derive(Clone)]
+pub enum Function {
+	Lua(Rc<Closure>),
+	Native(Rc<Native>),
+}
+
+/** Native - A function implemented by the host */
+pub struct Native {
+	pub name: String,
+	pub func: Box<NativeFn>,
+}
+
+/** Closure - An instance of a prototype with its upvalues and environment */
+pub struct Closure {
+	pub proto: Rc<Prototype>,
+	pub upvalues: Vec<UpvalueRef>,
+	pub env: RefCell<TableRef>,
+}
+
+/// A variable captured by a closure, open while the register it lives in
+/// is on the stack of its thread
+pub enum Upvalue {
+	Open { thread: ThreadRef, index: usize },
+	Closed(Value),
+}
+
+impl Value {
+	pub fn string(s: impl AsRef<[u8]>) -> Self {
+		Self::String(Rc::from(s.as_ref()))
+	}
+
+	pub fn table(table: Table) -> Self {
+		Self::Table(Rc::new(RefCell::new(table)))
+	}
+
+	pub fn native(
+		name: &str,
+		func: impl Fn(&mut Vm, Vec<Value>) -> Result<Vec<Value>> + 'static,
+	) -> Self {
+		Self::Function(Function::Native(Rc::new(Native {
+			name: name.to_string(),
+			func: Box::new(func),
+		})))
+	}
+
+	pub fn is_nil(&self) -> bool {
+		matches!(self, Self::Nil)
+	}
+
+	/// Everything but nil and false is true
+	pub fn truthy(&self) -> bool {
+		!matches!(self, Self::Nil | Self::Boolean(false))
+	}
+
+	pub fn type_name(&self) -> &'static str {
+		match self {
+			Self::Nil => "nil",
+			Self::Boolean(_) => "boolean",
+			Self::Number(_) => "number",
+			Self::String(_) => "string",
+			Self::Table(_) => "table",
+			Self::Function(_) => "function",
+			Self::Thread(_) => "thread",
+		}
+	}
+
+	/// Number the value converts to in arithmetic
+	pub fn to_number(&self) -> Option<f64> {
+		match self {
+			Self::Number(n) => Some(*n),
+			Self::String(s) => string_to_number(std::str::from_utf8(s).ok()?),
+			_ => None,
+		}
+	}
+
+	/// Bytes the value converts to in concatenation
+	pub fn to_bytes(&self) -> Option<Rc<[u8]>> {
+		match self {
+			Self::String(s) => Some(s.clone()),
+			Self::Number(n) => Some(Rc::from(number_to_string(*n).as_bytes())),
+			_ => None,
+		}
+	}
+
+	/// The string as text, invalid UTF-8 replaced
+	pub fn to_str_lossy(&self) -> Option<String> {
+		self.to_bytes()
+			.map(|s| String::from_utf8_lossy(&s).into_owned())
+	}
+
+	pub fn as_table(&self) -> Option<&TableRef> {
+		match self {
+			Self::Table(table) => Some(table),
+			_ => None,
+		}
+	}
+
+	/// Address identifying a reference value, as `tostring` shows it
+	pub fn address(&self) -> Option<usize> {
+		match self {
+			Self::Table(table) => Some(Rc::as_ptr(table) as *const () as usize),
+			Self::Function(Function::Lua(closure)) => {
+				Some(Rc::as_ptr(closure) as *const () as usize)
+			}
+			Self::Function(Function::Native(native)) => {
+				Some(Rc::as_ptr(native) as *const () as usize)
+			}
+			Self::Thread(thread) => Some(Rc::as_ptr(thread) as *const () as usize),
+			_ => None,
+		}
+	}
+}
+
+/// Raw equality, no metamethods
+impl PartialEq for Value {
+	fn eq(&self, other: &Self) -> bool {
+		match (self, other) {
+			(Self::Nil, Self::Nil) => true,
+			(Self::Boolean(a), Self::Boolean(b)) => a == b,
+			(Self::Number(a), Self::Number(b)) => a == b,
+			(Self::String(a), Self::String(b)) => a == b,
+			(Self::Table(_) | Self::Function(_) | Self::Thread(_), _) => {
+				self.type_name() == other.type_name() && self.address() == other.address()
+			}
+			_ => false,
+		}
+	}
+}
+
+impl From<bool> for Value {
+	fn from(b: bool) -> Self {
+		Self::Boolean(b)
+	}
+}
+
+impl From<f64> for Value {
+	fn from(n: f64) -> Self {
+		Self::Number(n)
+	}
+}
+
+impl From<&str> for Value {
+	fn from(s: &str) -> Self {
+		Self::string(s)
+	}
+}
+
+/// Writes the value like `tostring` without metamethods
+impl Display for Value {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Nil => write!(f, "nil"),
+			Self::Boolean(b) => write!(f, "{b}"),
+			Self::Number(n) => write!(f, "{}", number_to_string(*n)),
+			Self::String(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+			_ => write!(f, "{}: {:#010x}", self.type_name(), self.address().unwrap()),
+		}
+	}
+}
+
+impl Debug for Value {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::String(s) => write!(f, "{:?}", String::from_utf8_lossy(s)),
+			_ => write!(f, "{self}"),
+		}
+	}
+}
@@ -13,6 +13,13 @@ pub enum IROperand<T> {
 }
 
 impl<T> IROperand<T> {
+	pub fn value(&self) -> Option<&T> {
+		match self {
+			Self::Operand(_, val) => Some(val),
+			_ => None,
+		}
+	}
+
 	pub fn modify(&mut self, value: T) {
 		match self {
 			Self::Operand(_, val) => *val = value,
@@ -22,6 +29,8 @@ impl<T> IROperand<T> {
 }
 
 pub trait Context {
+	type Error;
+
 	/// Get all instructions by opcode
 	fn get_instructions(&self, opcode: usize) -> Vec<usize>;
 
@@ -29,5 +38,14 @@ pub trait Context {
 	fn get_constant_instructions(&self) -> Vec<usize>;
 
 	/// Remaps all references of kst1 to kst2
-	fn remap_constant(&mut self, kst1: usize, kst2: usize);
+	fn remap_constant(&mut self, kst1: usize, kst2: usize) -> Result<(), Self::Error>;
+
+	/// Remaps all explicit register operands of reg1 to reg2
+	fn remap_register(&mut self, reg1: u8, reg2: u8);
+
+	/// Remaps all references of upvalue up1 to up2, including captures by closures
+	fn remap_upvalue(&mut self, up1: u8, up2: u8);
+
+	/// Remaps all CLOSURE instructions of closure1 to closure2
+	fn remap_closure(&mut self, closure1: usize, closure2: usize);
 }